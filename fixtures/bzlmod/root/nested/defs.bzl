@@ -0,0 +1,2 @@
+def nested_macro():
+    pass
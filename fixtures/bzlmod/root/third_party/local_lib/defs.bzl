@@ -0,0 +1,2 @@
+def local_macro():
+    pass
@@ -0,0 +1,2 @@
+def http_archive(name, urls = [], **kwargs):
+    pass
@@ -0,0 +1,3 @@
+load(":cycle_b.bzl", "b")
+
+a = b
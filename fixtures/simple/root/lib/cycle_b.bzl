@@ -0,0 +1,3 @@
+load(":cycle_a.bzl", "a")
+
+b = 1
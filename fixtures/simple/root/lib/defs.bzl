@@ -0,0 +1,4 @@
+def exported(name, srcs = [], **kwargs):
+    pass
+
+_private = None
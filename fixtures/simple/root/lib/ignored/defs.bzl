@@ -0,0 +1,2 @@
+def ignored_library(name):
+    pass
@@ -0,0 +1,4 @@
+def _impl(ctx):
+    pass
+
+impl_helper = _impl
@@ -0,0 +1,4 @@
+FancyInfo = provider()
+
+def fancy_library(name, **kwargs):
+    pass
@@ -0,0 +1,1113 @@
+//! Helpers for inspecting Starlark syntax trees.
+
+use starlark::{
+    codemap::{ResolvedPos, ResolvedSpan, Span},
+    syntax::AstModule,
+};
+use starlark_syntax::syntax::{
+    ast::{ArgumentP, AstArgument, AstExpr, AstLiteral, AstNoPayload, AstStmt, DefP, ExprP, StmtP},
+    module::AstModuleFields,
+};
+
+/// A function call that encloses a string literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EnclosingCall {
+    /// The name of the called function, e.g. `cc_library`. For method-style calls like
+    /// `native.cc_library` this is the last component.
+    pub(crate) function: Option<String>,
+    /// The name of the argument the literal is passed to, if it is passed by name.
+    pub(crate) argument: Option<String>,
+}
+
+/// Describes where in a module a string literal appears.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct StringLiteralContext {
+    /// The calls enclosing the literal, outermost first.
+    pub(crate) calls: Vec<EnclosingCall>,
+    /// Whether the literal is a key in a dict literal, e.g. a condition in `select()`.
+    pub(crate) is_dict_key: bool,
+}
+
+impl StringLiteralContext {
+    /// Returns the rule and attribute the literal is passed to, e.g. `("cc_library", "srcs")`
+    /// for `cc_library(srcs = ["foo.cc"])`. This is taken from the outermost call, so that
+    /// literals nested in e.g. `select()` are still attributed to the rule.
+    pub(crate) fn rule_attribute(&self) -> Option<(&str, &str)> {
+        let call = self.calls.first()?;
+
+        Some((call.function.as_deref()?, call.argument.as_deref()?))
+    }
+
+    /// Returns the name of the innermost function the literal is passed to.
+    pub(crate) fn innermost_function(&self) -> Option<&str> {
+        self.calls.last()?.function.as_deref()
+    }
+}
+
+/// Returns the name of the function being called by `function`.
+pub(crate) fn call_name(function: &AstExpr) -> Option<&str> {
+    match &function.node {
+        ExprP::Identifier(ident) => Some(ident.node.ident.as_str()),
+        ExprP::Dot(_, name) => Some(name.node.as_str()),
+        _ => None,
+    }
+}
+
+/// A view of a function call expression.
+#[derive(Clone, Copy)]
+pub(crate) struct Call<'a> {
+    /// The name of the called function, see [`call_name()`].
+    pub(crate) function: Option<&'a str>,
+    /// The span of the whole call expression.
+    pub(crate) span: Span,
+    /// The span of the expression being called, i.e. everything before the `(`.
+    pub(crate) function_span: Span,
+    pub(crate) args: &'a [AstArgument],
+}
+
+impl<'a> Call<'a> {
+    /// Returns the value of the argument with the given name.
+    pub(crate) fn named_argument(&self, name: &str) -> Option<&'a AstExpr> {
+        self.args.iter().find_map(|arg| match &arg.node {
+            ArgumentP::Named(arg_name, value) if arg_name.node == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of a positional argument by its index among the positional arguments.
+    pub(crate) fn positional_argument(&self, index: usize) -> Option<&'a AstExpr> {
+        self.args
+            .iter()
+            .filter_map(|arg| match &arg.node {
+                ArgumentP::Positional(value) => Some(value),
+                _ => None,
+            })
+            .nth(index)
+    }
+
+    /// Returns the value of the argument with the given name, if it is a string literal.
+    pub(crate) fn string_argument(&self, name: &str) -> Option<&'a str> {
+        string_literal(self.named_argument(name)?)
+    }
+}
+
+/// Returns the value of a string literal expression.
+pub(crate) fn string_literal(expr: &AstExpr) -> Option<&str> {
+    match &expr.node {
+        ExprP::Literal(AstLiteral::String(string)) => Some(string.node.as_str()),
+        _ => None,
+    }
+}
+
+/// Returns the value of a `True` or `False` expression.
+pub(crate) fn bool_literal(expr: &AstExpr) -> Option<bool> {
+    match &expr.node {
+        ExprP::Identifier(ident) => match ident.node.ident.as_str() {
+            "True" => Some(true),
+            "False" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the elements of a list literal along with their spans, if all of them are string
+/// literals.
+pub(crate) fn string_list_literal(expr: &AstExpr) -> Option<Vec<(&str, Span)>> {
+    match &expr.node {
+        ExprP::List(elements) => elements
+            .iter()
+            .map(|element| Some((string_literal(element)?, element.span)))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Calls `f` for every function call in the module, including nested calls.
+pub(crate) fn visit_calls<'a>(ast: &'a AstModule, mut f: impl FnMut(Call<'a>)) {
+    visit_stmt_calls(ast.statement(), &mut f);
+}
+
+fn visit_stmt_calls<'a>(stmt: &'a AstStmt, f: &mut dyn FnMut(Call<'a>)) {
+    stmt.visit_expr(|expr| visit_expr_calls(expr, f));
+    stmt.visit_stmt(|stmt| visit_stmt_calls(stmt, f));
+}
+
+fn visit_expr_calls<'a>(expr: &'a AstExpr, f: &mut dyn FnMut(Call<'a>)) {
+    if let ExprP::Call(function, args) = &expr.node {
+        f(Call {
+            function: call_name(function),
+            span: expr.span,
+            function_span: function.span,
+            args: &args.args,
+        });
+    }
+
+    expr.visit_expr(|expr| visit_expr_calls(expr, f));
+}
+
+/// The innermost call that encloses a position, see [`find_active_call()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ActiveCall<'a> {
+    /// The name of the called function.
+    pub(crate) function: &'a str,
+    /// The index of the argument at the position. If the position is between arguments, this
+    /// is the index of the next argument.
+    pub(crate) argument_index: usize,
+    /// The name of the argument at the position, if it is passed by name.
+    pub(crate) argument_name: Option<&'a str>,
+}
+
+/// Finds the innermost call whose arguments enclose a position, along with the argument at
+/// that position.
+pub(crate) fn find_active_call(ast: &AstModule, position: ResolvedPos) -> Option<ActiveCall<'_>> {
+    let position = (position.line, position.column);
+    let resolve = |span: Span| {
+        let span = ast.codemap().resolve_span(span);
+        (
+            (span.begin.line, span.begin.column),
+            (span.end.line, span.end.column),
+        )
+    };
+
+    let mut result = None;
+    visit_calls(ast, |call| {
+        let function = match call.function {
+            Some(function) => function,
+            None => return,
+        };
+        let (_, arguments_begin) = resolve(call.function_span);
+        let (_, call_end) = resolve(call.span);
+        // The position must be between the parentheses.
+        if position <= arguments_begin || position >= call_end {
+            return;
+        }
+
+        let mut argument_index = 0;
+        let mut argument_name = None;
+        for (index, arg) in call.args.iter().enumerate() {
+            let (begin, end) = resolve(arg.span);
+            if begin <= position && position <= end {
+                argument_index = index;
+                argument_name = match &arg.node {
+                    ArgumentP::Named(name, _) => Some(name.node.as_str()),
+                    _ => None,
+                };
+                break;
+            } else if end < position {
+                argument_index = index + 1;
+            }
+        }
+
+        // Calls are visited outermost first, so the last match is the innermost call.
+        result = Some(ActiveCall {
+            function,
+            argument_index,
+            argument_name,
+        });
+    });
+
+    result
+}
+
+/// Finds a top-level function definition by name.
+pub(crate) fn find_def<'a>(ast: &'a AstModule, name: &str) -> Option<&'a DefP<AstNoPayload>> {
+    top_level_statements(ast)
+        .into_iter()
+        .find_map(|stmt| match &stmt.node {
+            StmtP::Def(def) if def.name.node.ident == name => Some(def),
+            _ => None,
+        })
+}
+
+/// A symbol imported by a `load` statement.
+pub(crate) struct LoadedSymbol<'a> {
+    /// The name the symbol is bound to in the loading module.
+    pub(crate) local: &'a str,
+    pub(crate) local_span: Span,
+    /// The name of the symbol in the loaded module.
+    pub(crate) their: &'a str,
+    pub(crate) their_span: Span,
+}
+
+/// A `load` statement.
+pub(crate) struct Load<'a> {
+    /// The label of the loaded module, e.g. `//foo:defs.bzl`.
+    pub(crate) module: &'a str,
+    pub(crate) module_span: Span,
+    /// The span of the whole statement.
+    pub(crate) span: Span,
+    pub(crate) symbols: Vec<LoadedSymbol<'a>>,
+}
+
+/// Returns the statements at the top level of the module.
+pub(crate) fn top_level_statements(ast: &AstModule) -> Vec<&AstStmt> {
+    fn collect<'a>(stmt: &'a AstStmt, statements: &mut Vec<&'a AstStmt>) {
+        match &stmt.node {
+            StmtP::Statements(stmts) => {
+                for stmt in stmts {
+                    collect(stmt, statements);
+                }
+            }
+            _ => statements.push(stmt),
+        }
+    }
+
+    let mut statements = Vec::new();
+    collect(ast.statement(), &mut statements);
+    statements
+}
+
+/// Returns the span of the docstring of the module, i.e. of the string literal that is its
+/// first statement.
+pub(crate) fn module_docstring(ast: &AstModule) -> Option<Span> {
+    let stmt = *top_level_statements(ast).first()?;
+    match &stmt.node {
+        StmtP::Expression(expr) if string_literal(expr).is_some() => Some(stmt.span),
+        _ => None,
+    }
+}
+
+/// Returns the `load` statements of the module.
+pub(crate) fn loads(ast: &AstModule) -> Vec<Load<'_>> {
+    top_level_statements(ast)
+        .into_iter()
+        .filter_map(|stmt| match &stmt.node {
+            StmtP::Load(load) => Some(Load {
+                module: &load.module.node,
+                module_span: load.module.span,
+                span: stmt.span,
+                symbols: load
+                    .args
+                    .iter()
+                    .map(|arg| LoadedSymbol {
+                        local: &arg.local.node.ident,
+                        local_span: arg.local.span,
+                        their: &arg.their.node,
+                        their_span: arg.their.span,
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the names bound by the top-level statements of the module, along with the span of
+/// the binding. Symbols which are themselves loaded are not included, since they cannot be
+/// loaded from this module.
+pub(crate) fn top_level_bindings(ast: &AstModule) -> Vec<(&str, Span)> {
+    let mut bindings = Vec::new();
+
+    for stmt in top_level_statements(ast) {
+        match &stmt.node {
+            StmtP::Assign(assign) => assign.lhs.visit_lvalue(|ident| {
+                bindings.push((ident.node.ident.as_str(), ident.span));
+            }),
+            StmtP::Def(def) => bindings.push((def.name.node.ident.as_str(), def.name.span)),
+            _ => {}
+        }
+    }
+
+    bindings
+}
+
+/// Finds the top-level assignment declaring a provider, e.g. `FooInfo = provider()`. Providers
+/// with an `init` callback, e.g. `FooInfo, _new_foo_info = provider(init = ...)`, are found too.
+pub(crate) fn find_provider_declaration(ast: &AstModule, name: &str) -> Option<ResolvedSpan> {
+    top_level_statements(ast).into_iter().find_map(|stmt| {
+        let assign = match &stmt.node {
+            StmtP::Assign(assign) => assign,
+            _ => return None,
+        };
+        let is_provider = match &assign.rhs.node {
+            ExprP::Call(function, _) => call_name(function) == Some("provider"),
+            _ => false,
+        };
+        let mut binds_name = false;
+        assign
+            .lhs
+            .visit_lvalue(|ident| binds_name |= ident.node.ident == name);
+
+        (is_provider && binds_name).then(|| ast.codemap().resolve_span(stmt.span))
+    })
+}
+
+/// Finds the call declaring a repository, i.e. a `bazel_dep` in `MODULE.bazel` or a
+/// repository rule in `WORKSPACE`.
+pub(crate) fn find_repository_declaration(
+    ast: &AstModule,
+    repository: &str,
+) -> Option<ResolvedSpan> {
+    let mut result = None;
+
+    visit_calls(ast, |call| {
+        if result.is_none()
+            && ["name", "repo_name"]
+                .iter()
+                .any(|argument| call.string_argument(argument) == Some(repository))
+        {
+            result = Some(call.span);
+        }
+    });
+
+    result.map(|span| ast.codemap().resolve_span(span))
+}
+
+/// Returns the names of the repositories declared by repository rules in a `WORKSPACE` file,
+/// e.g. `http_archive(name = "foo")`.
+pub(crate) fn declared_repository_names(ast: &AstModule) -> Vec<&str> {
+    let mut names = Vec::new();
+
+    visit_calls(ast, |call| {
+        // `workspace()` names the main repository, which isn't declared by the file.
+        if call.function != Some("workspace") {
+            if let Some(name) = call.string_argument("name") {
+                names.push(name);
+            }
+        }
+    });
+
+    names
+}
+
+/// Returns the files in the `srcs` of the `bzl_library` targets of a BUILD file, along with the
+/// name of the target listing them. Labels of other packages and repositories are skipped, and
+/// files may be written as relative labels, e.g. `":defs.bzl"`.
+pub(crate) fn bzl_library_srcs(ast: &AstModule) -> Vec<(&str, &str)> {
+    let mut srcs = Vec::new();
+
+    visit_calls(ast, |call| {
+        if call.function != Some("bzl_library") {
+            return;
+        }
+        if let (Some(name), Some(files)) = (
+            call.string_argument("name"),
+            call.named_argument("srcs").and_then(string_list_literal),
+        ) {
+            srcs.extend(
+                files
+                    .into_iter()
+                    .map(|(file, _)| file.strip_prefix(':').unwrap_or(file))
+                    .filter(|file| !file.starts_with("//") && !file.starts_with('@'))
+                    .map(|file| (name, file)),
+            );
+        }
+    });
+
+    srcs
+}
+
+/// Returns the names of the targets defined by calls of `function`, along with the spans of the
+/// calls.
+pub(crate) fn named_calls<'a>(ast: &'a AstModule, function: &str) -> Vec<(&'a str, Span)> {
+    let mut calls = Vec::new();
+
+    visit_calls(ast, |call| {
+        if call.function == Some(function) {
+            if let Some(name) = call.string_argument("name") {
+                calls.push((name, call.span));
+            }
+        }
+    });
+
+    calls
+}
+
+/// Finds the call defining a target in a BUILD file, i.e. the rule with the given `name`, or
+/// the rule declaring a file with that name in its `out` or `outs` attribute.
+pub(crate) fn find_target_definition(ast: &AstModule, name: &str) -> Option<ResolvedSpan> {
+    let mut by_name = None;
+    let mut by_output = None;
+
+    visit_calls(ast, |call| {
+        if by_name.is_none() && call.string_argument("name") == Some(name) {
+            by_name = Some(call.span);
+        }
+
+        if by_output.is_none()
+            && (call.string_argument("out") == Some(name)
+                || call
+                    .named_argument("outs")
+                    .and_then(string_list_literal)
+                    .map_or(false, |outs| outs.iter().any(|(out, _)| *out == name)))
+        {
+            by_output = Some(call.span);
+        }
+    });
+
+    by_name
+        .or(by_output)
+        .map(|span| ast.codemap().resolve_span(span))
+}
+
+/// Finds repositories whose sources are at a local path rather than fetched into the output
+/// base, i.e. `local_path_override` in `MODULE.bazel`, or `local_repository` and
+/// `new_local_repository` in `WORKSPACE`. Returns the module or repository name, along with
+/// the path as written.
+pub(crate) fn local_repository_paths(ast: &AstModule) -> Vec<(&str, &str)> {
+    let mut paths = Vec::new();
+
+    visit_calls(ast, |call| {
+        let name_argument = match call.function {
+            Some("local_path_override") => "module_name",
+            Some("local_repository" | "new_local_repository") => "name",
+            _ => return,
+        };
+
+        if let (Some(name), Some(path)) = (
+            call.string_argument(name_argument),
+            call.string_argument("path"),
+        ) {
+            paths.push((name, path));
+        }
+    });
+
+    paths
+}
+
+/// Calls `f` for every string literal in the module, along with its span and the context it
+/// appears in. String literals within `load` statements are not visited.
+pub(crate) fn visit_string_literals(
+    ast: &AstModule,
+    mut f: impl FnMut(&str, Span, &StringLiteralContext),
+) {
+    let mut context = StringLiteralContext::default();
+
+    visit_stmt_string_literals(ast.statement(), &mut context, &mut f);
+}
+
+fn visit_stmt_string_literals(
+    stmt: &AstStmt,
+    context: &mut StringLiteralContext,
+    f: &mut dyn FnMut(&str, Span, &StringLiteralContext),
+) {
+    stmt.visit_expr(|expr| visit_expr_string_literals(expr, context, f));
+    stmt.visit_stmt(|stmt| visit_stmt_string_literals(stmt, context, f));
+}
+
+fn visit_expr_string_literals(
+    expr: &AstExpr,
+    context: &mut StringLiteralContext,
+    f: &mut dyn FnMut(&str, Span, &StringLiteralContext),
+) {
+    match &expr.node {
+        ExprP::Literal(AstLiteral::String(string)) => f(&string.node, string.span, context),
+        ExprP::Call(function, args) => {
+            visit_expr_string_literals(function, context, f);
+
+            let function = call_name(function).map(str::to_owned);
+            let is_dict_key = std::mem::replace(&mut context.is_dict_key, false);
+
+            for arg in &args.args {
+                let (argument, value) = match &arg.node {
+                    ArgumentP::Named(name, value) => (Some(name.node.clone()), value),
+                    ArgumentP::Positional(value)
+                    | ArgumentP::Args(value)
+                    | ArgumentP::KwArgs(value) => (None, value),
+                };
+
+                context.calls.push(EnclosingCall {
+                    function: function.clone(),
+                    argument,
+                });
+                visit_expr_string_literals(value, context, f);
+                context.calls.pop();
+            }
+
+            context.is_dict_key = is_dict_key;
+        }
+        ExprP::Dict(entries) => {
+            let is_dict_key = context.is_dict_key;
+
+            for (key, value) in entries {
+                context.is_dict_key = true;
+                visit_expr_string_literals(key, context, f);
+                context.is_dict_key = false;
+                visit_expr_string_literals(value, context, f);
+            }
+
+            context.is_dict_key = is_dict_key;
+        }
+        _ => expr.visit_expr(|expr| visit_expr_string_literals(expr, context, f)),
+    }
+}
+
+/// Collects the string literals of the module that may be labels, i.e. the modules of its
+/// `load` statements and all other string literals, along with their spans.
+pub(crate) fn label_literals(ast: &AstModule) -> Vec<(String, Span)> {
+    let mut labels = Vec::new();
+
+    for load in loads(ast) {
+        labels.push((load.module.to_owned(), load.module_span));
+    }
+    visit_string_literals(ast, |literal, span, _| {
+        labels.push((literal.to_owned(), span));
+    });
+
+    labels
+}
+
+/// The line and the start and end columns of the contents of a string literal, i.e. without
+/// its prefix and quotes, given its span. Literals that span several lines have no columns.
+pub(crate) fn string_literal_value_columns(
+    ast: &AstModule,
+    span: Span,
+) -> Option<(usize, usize, usize)> {
+    let resolved = ast.codemap().resolve_span(span);
+    if resolved.begin.line != resolved.end.line {
+        return None;
+    }
+
+    let source = ast.codemap().source_span(span);
+    let quote_len = string_literal_quote_len(source);
+    let prefix_len = source.find(['\'', '"']).unwrap_or(0);
+
+    Some((
+        resolved.begin.line,
+        resolved.begin.column + quote_len,
+        resolved.end.column - (quote_len - prefix_len),
+    ))
+}
+
+/// Finds the context of a string literal in the module with the given value, preferring the
+/// literal at `position` if there is one, and otherwise the first one.
+pub(crate) fn find_string_literal_context(
+    ast: &AstModule,
+    value: &str,
+    position: Option<ResolvedPos>,
+) -> Option<StringLiteralContext> {
+    let mut first = None;
+    let mut at_position = None;
+
+    visit_string_literals(ast, |literal, span, context| {
+        if literal != value {
+            return;
+        }
+        if first.is_none() {
+            first = Some(context.clone());
+        }
+        if at_position.is_none()
+            && position.map_or(false, |position| span_contains(ast, span, position))
+        {
+            at_position = Some(context.clone());
+        }
+    });
+
+    at_position.or(first)
+}
+
+/// Whether a span of a module contains a position, including its ends.
+pub(crate) fn span_contains(ast: &AstModule, span: Span, position: ResolvedPos) -> bool {
+    let span = ast.codemap().resolve_span(span);
+    let position = (position.line, position.column);
+
+    (span.begin.line, span.begin.column) <= position && position <= (span.end.line, span.end.column)
+}
+
+/// Finds the name of the identifier at a position, e.g. a provider used in a rule
+/// implementation.
+pub(crate) fn find_identifier_at(ast: &AstModule, position: ResolvedPos) -> Option<&str> {
+    fn visit_stmt<'a>(stmt: &'a AstStmt, f: &mut dyn FnMut(&'a AstExpr)) {
+        stmt.visit_expr(|expr| visit_expr(expr, f));
+        stmt.visit_stmt(|stmt| visit_stmt(stmt, f));
+    }
+    fn visit_expr<'a>(expr: &'a AstExpr, f: &mut dyn FnMut(&'a AstExpr)) {
+        f(expr);
+        expr.visit_expr(|expr| visit_expr(expr, f));
+    }
+
+    let mut result = None;
+    visit_stmt(ast.statement(), &mut |expr| {
+        if let ExprP::Identifier(ident) = &expr.node {
+            if result.is_none() && span_contains(ast, expr.span, position) {
+                result = Some(ident.node.ident.as_str());
+            }
+        }
+    });
+
+    result
+}
+
+/// Finds the value of the string literal at a position, e.g. the label under the cursor.
+/// String literals within `load` statements are not found.
+pub(crate) fn find_string_literal_at(ast: &AstModule, position: ResolvedPos) -> Option<String> {
+    let mut result = None;
+
+    visit_string_literals(ast, |literal, span, _| {
+        if result.is_none() && span_contains(ast, span, position) {
+            result = Some(literal.to_owned());
+        }
+    });
+
+    result
+}
+
+/// Finds the string literal at a position, along with the byte offset of the position in its
+/// value, e.g. to tell which of several labels in it is under the cursor. Escape sequences
+/// before the position aren't accounted for.
+pub(crate) fn find_string_literal_offset_at(
+    ast: &AstModule,
+    position: ResolvedPos,
+) -> Option<(String, usize)> {
+    let mut result = None;
+
+    visit_string_literals(ast, |literal, span, _| {
+        if result.is_some() || !span_contains(ast, span, position) {
+            return;
+        }
+        let begin = ast.codemap().resolve_span(span).begin;
+        let source = ast.codemap().source_span(span);
+
+        // The offset of the position in the source of the literal, which may span several
+        // lines if it's triple-quoted.
+        let mut offset = 0;
+        for (index, line) in source.split_inclusive('\n').enumerate() {
+            if begin.line + index == position.line {
+                let column = match index {
+                    0 => position.column - begin.column,
+                    _ => position.column,
+                };
+                offset += line
+                    .char_indices()
+                    .nth(column)
+                    .map_or(line.len(), |(i, _)| i);
+                break;
+            }
+            offset += line.len();
+        }
+
+        let value_offset = offset.saturating_sub(string_literal_quote_len(source));
+        result = Some((literal.to_owned(), value_offset.min(literal.len())));
+    });
+
+    result
+}
+
+/// The length of the prefix and the opening quotes of the source of a string literal, e.g. 1
+/// for `"foo"` and 4 for `r'''foo'''`. The closing quotes have the same length without the
+/// prefix.
+pub(crate) fn string_literal_quote_len(source: &str) -> usize {
+    let prefix_len = source.find(['\'', '"']).unwrap_or(0);
+    let quotes = &source[prefix_len..];
+    if quotes.starts_with("'''") || quotes.starts_with("\"\"\"") {
+        prefix_len + 3
+    } else {
+        prefix_len + 1
+    }
+}
+
+/// Quotes a value as a Starlark string literal, e.g. `say "hi"` as `"say \"hi\""`. Unlike the
+/// `Debug` format of Rust strings, non-ASCII characters are kept as they are, since Starlark has
+/// no `\u{...}` escapes.
+pub(crate) fn quote_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\{:03o}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Finds the `name` of the outermost call that encloses a position, i.e. the target that is
+/// defined there in a BUILD file.
+pub(crate) fn find_enclosing_target(ast: &AstModule, position: ResolvedPos) -> Option<&str> {
+    let mut result = None;
+
+    // Calls are visited outermost first.
+    visit_calls(ast, |call| {
+        if result.is_none() && span_contains(ast, call.span, position) {
+            result = call.string_argument("name");
+        }
+    });
+
+    result
+}
+
+/// Finds the `select()` whose function name is at a position, e.g. with the cursor on `select`.
+/// Returns the `name` of the target it's in, and the attribute whose value it's part of.
+pub(crate) fn find_select_at(ast: &AstModule, position: ResolvedPos) -> Option<(&str, &str)> {
+    let mut target = None;
+    let mut on_select = false;
+
+    // Calls are visited outermost first.
+    visit_calls(ast, |call| {
+        if !span_contains(ast, call.span, position) {
+            return;
+        }
+        if target.is_none() {
+            target = call.string_argument("name").map(|name| (name, call.args));
+        }
+        if call.function == Some("select") && span_contains(ast, call.function_span, position) {
+            on_select = true;
+        }
+    });
+
+    let (name, args) = target.filter(|_| on_select)?;
+    let attribute = args.iter().find_map(|arg| match &arg.node {
+        ArgumentP::Named(attribute, value) if span_contains(ast, value.span, position) => {
+            Some(attribute.node.as_str())
+        }
+        _ => None,
+    })?;
+
+    Some((name, attribute))
+}
+
+#[cfg(test)]
+mod tests {
+    use starlark::syntax::{AstModule, Dialect};
+
+    use starlark::codemap::ResolvedPos;
+
+    use super::{
+        declared_repository_names, find_active_call, find_identifier_at, find_provider_declaration,
+        find_repository_declaration, find_select_at, find_string_literal_context,
+        find_string_literal_offset_at, find_target_definition, label_literals, loads,
+        local_repository_paths, module_docstring, quote_string, string_literal_quote_len,
+        string_literal_value_columns, top_level_bindings, ActiveCall, EnclosingCall,
+    };
+
+    fn parse(contents: &str) -> AstModule {
+        AstModule::parse("BUILD", contents.to_owned(), &Dialect::Extended).unwrap()
+    }
+
+    #[test]
+    fn finds_active_call() {
+        let ast = parse(
+            r#"
+cc_library(
+    name = "foo",
+    srcs = glob(["*.cc"]),
+)
+"#,
+        );
+        let at = |line, column| find_active_call(&ast, ResolvedPos { line, column });
+
+        assert_eq!(
+            at(2, 10),
+            Some(ActiveCall {
+                function: "cc_library",
+                argument_index: 0,
+                argument_name: Some("name"),
+            })
+        );
+        assert_eq!(
+            at(3, 17),
+            Some(ActiveCall {
+                function: "glob",
+                argument_index: 0,
+                argument_name: None,
+            })
+        );
+        // Between arguments, the next argument is active.
+        assert_eq!(
+            at(3, 0),
+            Some(ActiveCall {
+                function: "cc_library",
+                argument_index: 1,
+                argument_name: None,
+            })
+        );
+        assert_eq!(at(4, 1), None);
+    }
+
+    #[test]
+    fn finds_local_repository_paths() {
+        let ast = parse(
+            r#"
+bazel_dep(name = "foo", version = "1.0")
+local_path_override(module_name = "foo", path = "third_party/foo")
+local_repository(name = "bar", path = "/src/bar")
+new_local_repository(name = "baz", path = "../baz", build_file = "//:baz.BUILD")
+http_archive(name = "qux", urls = ["https://example.com/qux.tar.gz"])
+"#,
+        );
+
+        assert_eq!(
+            local_repository_paths(&ast),
+            vec![
+                ("foo", "third_party/foo"),
+                ("bar", "/src/bar"),
+                ("baz", "../baz"),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_target_definitions() {
+        let ast = parse(
+            r#"
+genrule(name = "gen", outs = ["gen.h", "lib"], cmd = "")
+cc_library(name = "lib")
+"#,
+        );
+        let line = |name| find_target_definition(&ast, name).map(|span| span.begin.line);
+
+        assert_eq!(line("gen"), Some(1));
+        assert_eq!(line("gen.h"), Some(1));
+        // Rules take precedence over files with the same name.
+        assert_eq!(line("lib"), Some(2));
+        assert_eq!(line("missing"), None);
+    }
+
+    #[test]
+    fn finds_rule_and_attribute_of_literal() {
+        let ast = parse(
+            r#"
+cc_library(
+    name = "foo",
+    srcs = ["foo.cc"] + select({"//conditions:default": ["bar.cc"]}),
+)
+"#,
+        );
+
+        let context = find_string_literal_context(&ast, "foo.cc", None).unwrap();
+        assert_eq!(context.rule_attribute(), Some(("cc_library", "srcs")));
+
+        let context = find_string_literal_context(&ast, "bar.cc", None).unwrap();
+        assert_eq!(context.rule_attribute(), Some(("cc_library", "srcs")));
+        assert_eq!(context.innermost_function(), Some("select"));
+        assert!(!context.is_dict_key);
+
+        let context = find_string_literal_context(&ast, "//conditions:default", None).unwrap();
+        assert!(context.is_dict_key);
+        assert_eq!(
+            context.calls.last(),
+            Some(&EnclosingCall {
+                function: Some("select".into()),
+                argument: None,
+            })
+        );
+    }
+
+    #[test]
+    fn prefers_literal_at_position() {
+        let ast = parse(
+            r#"cc_library(name = "lib", srcs = ["lib.cc"])
+cc_test(name = "test", data = ["lib.cc"])
+"#,
+        );
+        let at = |position| {
+            find_string_literal_context(&ast, "lib.cc", position)
+                .unwrap()
+                .rule_attribute()
+                .map(|(rule, attribute)| (rule.to_owned(), attribute.to_owned()))
+        };
+
+        assert_eq!(at(None), Some(("cc_library".into(), "srcs".into())));
+        assert_eq!(
+            at(Some(ResolvedPos {
+                line: 1,
+                column: 34
+            })),
+            Some(("cc_test".into(), "data".into()))
+        );
+        // Positions in other literals fall back to the first literal with the value.
+        assert_eq!(
+            at(Some(ResolvedPos {
+                line: 1,
+                column: 17
+            })),
+            Some(("cc_library".into(), "srcs".into()))
+        );
+    }
+
+    #[test]
+    fn finds_declared_repository_names() {
+        let ast = parse(
+            r#"workspace(name = "main")
+http_archive(name = "rules_foo", urls = ["https://example.com/rules_foo.tar.gz"])
+git_repository(name = "rules_bar", remote = "https://example.com/rules_bar.git")
+rules_foo_dependencies()
+"#,
+        );
+
+        assert_eq!(
+            declared_repository_names(&ast),
+            vec!["rules_foo", "rules_bar"]
+        );
+    }
+
+    #[test]
+    fn finds_repository_declarations() {
+        let ast = parse(
+            r#"
+module(name = "root")
+
+bazel_dep(name = "rules_rust", version = "0.36.2")
+bazel_dep(name = "rules_python", version = "0.31.0", repo_name = "py")
+"#,
+        );
+
+        let span = find_repository_declaration(&ast, "rules_rust").unwrap();
+        assert_eq!(span.begin.line, 3);
+
+        let span = find_repository_declaration(&ast, "py").unwrap();
+        assert_eq!(span.begin.line, 4);
+
+        assert_eq!(find_repository_declaration(&ast, "rules_go"), None);
+    }
+
+    #[test]
+    fn finds_loads_and_bindings() {
+        let ast = parse(
+            r#"
+load("//foo:defs.bzl", "bar", baz = "qux")
+
+def _impl(ctx):
+    local = 1
+
+foo_rule = rule(implementation = _impl)
+a, b = 1, 2
+"#,
+        );
+
+        let loads = loads(&ast);
+        assert_eq!(loads.len(), 1);
+        assert_eq!(loads[0].module, "//foo:defs.bzl");
+        assert_eq!(
+            loads[0]
+                .symbols
+                .iter()
+                .map(|symbol| (symbol.local, symbol.their))
+                .collect::<Vec<_>>(),
+            vec![("bar", "bar"), ("baz", "qux")]
+        );
+
+        assert_eq!(
+            top_level_bindings(&ast)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            vec!["_impl", "foo_rule", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn finds_select_at_position() {
+        let ast = parse(
+            r#"cc_library(
+    name = "foo",
+    srcs = ["foo.cc"] + select({
+        "//conditions:default": ["bar.cc"],
+    }),
+)
+"#,
+        );
+        let at = |line, column| find_select_at(&ast, ResolvedPos { line, column });
+
+        assert_eq!(at(2, 26), Some(("foo", "srcs")));
+        // Only the name of the function counts, so that labels in it have their own hovers.
+        assert_eq!(at(3, 10), None);
+        assert_eq!(at(1, 12), None);
+    }
+
+    #[test]
+    fn finds_provider_declarations() {
+        let ast = parse(
+            r#"FooInfo = provider(fields = ["foo"])
+BarInfo, _new_bar_info = provider(init = _init)
+baz = struct()
+
+def _impl(ctx):
+    return [FooInfo(foo = 1)]
+"#,
+        );
+        let line = |name| find_provider_declaration(&ast, name).map(|span| span.begin.line);
+
+        assert_eq!(line("FooInfo"), Some(0));
+        assert_eq!(line("BarInfo"), Some(1));
+        assert_eq!(line("baz"), None);
+
+        let at = |line, column| find_identifier_at(&ast, ResolvedPos { line, column });
+        assert_eq!(at(5, 14), Some("FooInfo"));
+        assert_eq!(at(5, 24), None);
+    }
+
+    #[test]
+    fn finds_offsets_in_string_literals() {
+        let ast = parse(
+            r#"genrule(
+    cmd = "cp $(location :a) $(location :b)",
+    tools = [r'''x
+    $(location :c)'''],
+)
+"#,
+        );
+        let at = |line, column| find_string_literal_offset_at(&ast, ResolvedPos { line, column });
+
+        let cmd = "cp $(location :a) $(location :b)";
+        assert_eq!(at(1, 11), Some((cmd.to_owned(), 0)));
+        assert_eq!(at(1, 35), Some((cmd.to_owned(), 24)));
+        // Raw and triple-quoted literals have longer prefixes, and may span several lines.
+        assert_eq!(at(3, 16), Some(("x\n    $(location :c)".to_owned(), 18)));
+        assert_eq!(at(0, 3), None);
+
+        assert_eq!(string_literal_quote_len("'foo'"), 1);
+        assert_eq!(string_literal_quote_len("r\"foo\""), 2);
+        assert_eq!(string_literal_quote_len("\"\"\"foo\"\"\""), 3);
+    }
+
+    #[test]
+    fn finds_columns_of_string_literal_values() {
+        let ast = parse(
+            r#"load("//foo:defs.bzl", "x")
+cc_library(deps = ["//a", r"//b", '''//c''', r"""//d""", """x
+y"""])
+"#,
+        );
+        let columns = label_literals(&ast)
+            .into_iter()
+            .map(|(literal, span)| (literal, string_literal_value_columns(&ast, span)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            columns,
+            vec![
+                ("//foo:defs.bzl".to_owned(), Some((0, 6, 20))),
+                ("//a".to_owned(), Some((1, 20, 23))),
+                ("//b".to_owned(), Some((1, 28, 31))),
+                ("//c".to_owned(), Some((1, 37, 40))),
+                ("//d".to_owned(), Some((1, 49, 52))),
+                ("x\ny".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_literals() {
+        let ast = parse("cc_library(name = \"foo\")");
+
+        assert_eq!(find_string_literal_context(&ast, "bar", None), None);
+    }
+
+    #[test]
+    fn finds_module_docstring() {
+        let ast = parse("\"\"\"Rules for foo.\"\"\"\n\nx = 1\n");
+        let span = module_docstring(&ast).unwrap();
+        assert_eq!(ast.codemap().resolve_span(span).end.line, 0);
+
+        assert_eq!(
+            module_docstring(&parse("x = 1\n\"\"\"Not a docstring.\"\"\"\n")),
+            None
+        );
+    }
+
+    #[test]
+    fn quotes_strings() {
+        assert_eq!(quote_string("//foo:bar"), r#""//foo:bar""#);
+        assert_eq!(quote_string(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+        assert_eq!(quote_string("a\nb\u{7}"), r#""a\nb\007""#);
+        assert_eq!(quote_string("größe"), r#""größe""#);
+    }
+}
@@ -19,26 +19,66 @@
 //! the use in a Bazel project.
 
 use std::borrow::Cow;
+use std::cell::Ref;
 use std::cell::RefCell;
+use std::cell::RefMut;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::io;
 use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
-use lsp_types::CompletionItemKind;
+use lsp_types::AnnotatedTextEdit;
+use lsp_types::ChangeAnnotation;
+use lsp_types::CodeAction;
+use lsp_types::CodeActionKind;
+use lsp_types::CompletionItem;
+use lsp_types::Diagnostic;
+use lsp_types::DiagnosticSeverity;
+use lsp_types::DocumentChanges;
+use lsp_types::DocumentHighlight;
+use lsp_types::DocumentLink;
+use lsp_types::FoldingRange;
+use lsp_types::Hover;
+use lsp_types::HoverContents;
+use lsp_types::InsertTextFormat;
+use lsp_types::Location;
+use lsp_types::MarkupContent;
+use lsp_types::MarkupKind;
+use lsp_types::NumberOrString;
+use lsp_types::OneOf;
+use lsp_types::OptionalVersionedTextDocumentIdentifier;
+use lsp_types::Position;
+use lsp_types::Range;
+use lsp_types::SelectionRange;
+use lsp_types::SemanticTokens;
+use lsp_types::SignatureHelp;
+use lsp_types::TextDocumentEdit;
+use lsp_types::TextEdit;
 use lsp_types::Url;
+use lsp_types::WorkspaceEdit;
 use prost::Message;
-use starlark::analysis::find_call_name::AstModuleFindCallName;
 use starlark::analysis::AstModuleLint;
+use starlark::codemap::ResolvedPos;
+use starlark::codemap::ResolvedSpan;
 use starlark::collections::SmallMap;
+use starlark::docs::DocFunction;
 use starlark::docs::DocItem;
+use starlark::docs::DocMember;
 use starlark::docs::DocModule;
 use starlark::errors::EvalMessage;
+use starlark::errors::EvalSeverity;
 use starlark::syntax::AstModule;
 use starlark::syntax::Dialect;
 use starlark_lsp::completion::StringCompletionResult;
@@ -49,12 +89,35 @@ use starlark_lsp::server::LspEvalResult;
 use starlark_lsp::server::LspUrl;
 use starlark_lsp::server::StringLiteralResult;
 use starlark_syntax::slice_vec_ext::VecExt;
+use starlark_syntax::syntax::ast::ArgumentP;
 
+use crate::ast;
+use crate::ast::StringLiteralContext;
+use crate::bazelrc;
 use crate::builtin;
 use crate::client::BazelClient;
+use crate::client::BazelInfo;
+use crate::client::ModuleDependency;
+use crate::client::PartialQueryError;
+use crate::completion;
+use crate::completion::BazelCompletion;
+use crate::completion::CompletionCategory;
+use crate::completion::TargetKind;
+use crate::exports::ExportIndex;
 use crate::file_type::FileType;
+use crate::folding;
+use crate::label;
 use crate::label::Label;
+use crate::label::LabelRepo;
+use crate::lint;
+use crate::query::BuildTarget;
+use crate::rename;
+use crate::selection;
+use crate::semantic_tokens;
+use crate::signature;
+use crate::workspace::BazelVersion;
 use crate::workspace::BazelWorkspace;
+use crate::workspace::BUILTIN_REPOSITORY_NAMES;
 
 #[derive(Debug, thiserror::Error)]
 enum ContextError {
@@ -68,26 +131,38 @@ enum ContextError {
 
 /// Errors when [`LspContext::resolve_load()`] cannot resolve a given path.
 #[derive(thiserror::Error, Debug)]
-enum ResolveLoadError {
+pub(crate) enum ResolveLoadError {
     /// Attempted to resolve a relative path, but no current_file_path was provided,
     /// so it is not known what to resolve the path against.
     #[error("Relative label `{}` provided, but current_file_path could not be determined", .0)]
     MissingCurrentFilePath(Label),
-    /// The scheme provided was not correct or supported.
-    #[error("Url `{}` was expected to be of type `{}`", .1, .0)]
-    WrongScheme(String, LspUrl),
+    /// Attempted to resolve a relative path from a module that isn't a file on disk and isn't
+    /// associated with one, see [`BazelContext::associate_path()`].
+    #[error("Cannot resolve the relative label `{}` from `{}`, because it is not a file on disk. Use an absolute label like `//pkg:name`, or save the file first", .0, .1)]
+    NotOnDisk(Label, LspUrl),
     /// Received a load for an absolute path from the root of the workspace, but the
     /// path to the workspace root was not provided.
     #[error("Label `{}` is absolute from the root of the workspace, but no workspace root was provided", .0)]
     MissingWorkspaceRoot(Label),
-    /// The path contained a repository name that is not known to Bazel.
-    #[error("Cannot resolve label `{}` because the repository `{}` is unknown", .0, .1)]
-    UnknownRepository(Label, String),
+    /// The path contained a repository name that is not known to Bazel. The hint depends on
+    /// whether Bzlmod is enabled, see [`BazelWorkspace::bzlmod_enabled`].
+    #[error("Cannot resolve label `{}` because the repository `@{}` is unknown. {}", .0, .1, unknown_repository_hint(*.2))]
+    UnknownRepository(Label, String, bool),
     /// The path contained a target name that does not resolve to an existing file.
     #[error("Cannot resolve path `{}` because the file does not exist", .0)]
     TargetNotFound(String),
 }
 
+/// How to find out which repositories are known, depending on whether they are declared in
+/// `MODULE.bazel` or in `WORKSPACE`.
+fn unknown_repository_hint(bzlmod_enabled: bool) -> &'static str {
+    if bzlmod_enabled {
+        "Check that it is declared in MODULE.bazel, or run `bazel mod deps` to see the known repositories"
+    } else {
+        "Check that it is declared in the WORKSPACE file"
+    }
+}
+
 /// Errors when [`LspContext::render_as_load()`] cannot render a given path.
 #[derive(thiserror::Error, Debug)]
 enum RenderLoadError {
@@ -112,26 +187,538 @@ enum FilesystemCompletionRoot<'a> {
 enum FilesystemFileCompletionOptions {
     All,
     OnlyLoadable,
+    /// Only files with one of the given extensions.
+    WithExtensions(&'static [&'static str]),
+    /// All files, ranking the ones with one of the given extensions first.
+    PreferExtensions(&'static [&'static str]),
     None,
 }
 
 /// Options for resolving filesystem completions.
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct FilesystemCompletionOptions {
+struct FilesystemCompletionOptions<'a> {
+    /// The text typed after the completion root, which entries are matched against, see
+    /// [`completion::fuzzy_score()`].
+    partial_name: &'a str,
     /// Whether to include directories in the results.
     directories: bool,
     /// Whether to include files in the results.
     files: FilesystemFileCompletionOptions,
     /// Whether to include target names from BUILD files.
     targets: bool,
+    /// Whether to note which targets provide other targets through their `exports`, for
+    /// completing dependencies.
+    annotate_exports: bool,
+}
+
+/// The top-level symbols and loads of a module that is loaded by another module.
+struct LoadedModule {
+    /// The contents the symbols and loads were computed from.
+    contents: String,
+    symbols: Rc<HashSet<String>>,
+    /// The modules loaded by the module that could be resolved, as written in its `load`
+    /// statements and as resolved.
+    loads: Rc<Vec<(String, LspUrl)>>,
+}
+
+/// The labels resolved while reporting the [`BazelContext::semantic_diagnostics()`] of a
+/// document, keyed by the label and the file it is in, so that labels checked by several lints
+/// are only resolved once.
+#[derive(Default)]
+struct LintResolutions {
+    loads: HashMap<(String, LspUrl), Rc<anyhow::Result<LspUrl>>>,
+    folders: HashMap<(String, LspUrl), Option<PathBuf>>,
+}
+
+/// A target of a package, as returned by a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PackageTarget {
+    name: String,
+    /// The rule of the target, e.g. `cc_binary`. This is only known for queries with
+    /// `--output=label_kind`, and is `None` for files.
+    rule: Option<String>,
+}
+
+/// Options that change how the server behaves, which are set from the command line.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ContextOptions {
+    /// Whether to show paths relative to the workspace root, rather than absolute paths.
+    pub(crate) relative_paths: bool,
+    /// The opt-in lints that are enabled, see [`lint::OPT_IN_LINTS`].
+    pub(crate) enabled_lints: HashSet<String>,
+    /// The maximum number of directories, files, and targets offered when completing a path
+    /// or label. If `None`, all of them are offered.
+    pub(crate) max_filesystem_completions: Option<usize>,
+    /// Whether to offer directories and files starting with `.`, e.g. `.git`, when completing a
+    /// path or label.
+    pub(crate) include_hidden_files: bool,
+    /// Severities that override those of the lints built into starlark-rust, keyed by the
+    /// short name of the lint, e.g. `unused-load`. Lints overridden with
+    /// [`EvalSeverity::Disabled`] aren't reported.
+    pub(crate) lint_severities: HashMap<String, EvalSeverity>,
+    /// Options for `bazel cquery` that select the configuration shown in hovers, e.g.
+    /// `--platforms=//:arm`. If empty, the default configuration is used.
+    pub(crate) cquery_flags: Vec<String>,
+    /// Whether to never run Bazel. Workspaces are then found from the filesystem alone, the
+    /// bundled build language is used, and features that need queries are unavailable.
+    pub(crate) offline: bool,
+    /// Whether packages and targets are completed from `bazel query` alone, without reading
+    /// directories, e.g. on network filesystems where that is slow. See
+    /// [`BazelContext::query_label_completions()`].
+    pub(crate) query_only_completions: bool,
 }
 
 pub(crate) struct BazelContext<Client> {
     workspaces: RefCell<HashMap<PathBuf, Rc<BazelWorkspace>>>,
+    /// The most recent contents of documents that have been parsed, keyed by their URL.
+    documents: RefCell<HashMap<LspUrl, String>>,
+    /// Modules that have been loaded by other modules, so that they don't need to be re-parsed
+    /// each time the loading module changes.
+    loaded_modules: RefCell<HashMap<LspUrl, LoadedModule>>,
+    /// The targets of packages that have been queried for completions, keyed by the package
+    /// directory and then by the query.
+    package_targets: RefCell<HashMap<PathBuf, HashMap<String, Vec<PackageTarget>>>>,
+    /// Whether targets are `testonly`, keyed by the package directory and then by the name of
+    /// the target. See [`Self::is_testonly_target()`].
+    testonly_targets: RefCell<HashMap<PathBuf, HashMap<String, bool>>>,
+    /// The repo mappings of repositories, keyed by the workspace root and the canonical name of
+    /// the repository. See [`Self::repo_mapping()`].
+    repo_mappings: RefCell<HashMap<(PathBuf, String), Rc<HashMap<String, String>>>>,
+    /// The direct dependencies of the root module of workspaces, keyed by the workspace root.
+    /// See [`Self::module_dependencies()`].
+    module_dependencies: RefCell<HashMap<PathBuf, Rc<Vec<ModuleDependency>>>>,
+    /// When queries last failed, keyed by their arguments. See [`Self::run_query()`].
+    failed_queries: RefCell<HashMap<String, Instant>>,
+    /// The labels resolved by the lints of [`Self::semantic_diagnostics()`] while it runs.
+    lint_resolutions: RefCell<Option<LintResolutions>>,
+    /// The hashes of the BUILD files on disk that the cached targets of packages are for,
+    /// keyed by the package directory. See [`Self::check_package_targets()`].
+    build_file_hashes: RefCell<HashMap<PathBuf, u64>>,
+    /// The targets of workspaces that have been queried for completions, e.g. the conditions of
+    /// `select()`, keyed by the workspace root and then by the query.
+    workspace_targets: RefCell<HashMap<PathBuf, HashMap<String, Vec<String>>>>,
+    /// The symbols exported by the `.bzl` files of workspaces, keyed by the workspace root.
+    /// See [`Self::export_index()`].
+    export_indexes: RefCell<HashMap<PathBuf, ExportIndex>>,
+    /// The decoded build language and builtins, keyed by the workspace root, so that the build
+    /// language doesn't have to be fetched from Bazel for every request. See
+    /// [`Self::get_bazel_globals()`].
+    bazel_globals:
+        RefCell<HashMap<Option<PathBuf>, (Rc<builtin::BuildLanguage>, Rc<builtin::Builtins>)>>,
+    /// The names of the globals of BUILD and `.bzl` files, keyed by the workspace root, so that
+    /// they don't have to be collected each time a document changes.
+    /// See [`Self::get_bazel_globals_names()`].
+    globals_names: RefCell<HashMap<Option<PathBuf>, Rc<HashSet<String>>>>,
+    /// The deprecated rules and attributes of the build language, cached along with
+    /// [`Self::globals_names`].
+    deprecations: RefCell<HashMap<Option<PathBuf>, Rc<builtin::Deprecations>>>,
+    /// The workspace folders opened in the client, see [`Self::set_workspace_folders()`].
+    workspace_folders: RefCell<Vec<PathBuf>>,
+    /// The paths of in-memory modules, e.g. unsaved buffers, that stand in for files on disk.
+    /// See [`Self::associate_path()`].
+    virtual_paths: RefCell<HashMap<LspUrl, PathBuf>>,
+    /// The position of the cursor in the request that the `starlark_lsp` server is answering,
+    /// see [`Self::set_cursor()`].
+    cursor: RefCell<Option<(LspUrl, ResolvedPos)>>,
+    /// The completions of the last call of [`LspContext::get_string_completion_options()`],
+    /// whose details `StringCompletionResult` can't carry. See
+    /// [`Self::take_string_completions()`].
+    last_string_completions: RefCell<StringCompletions>,
+    /// The directory in which each workspace gets its own output base for queries, see
+    /// [`BazelWorkspace::query_output_base`].
     query_output_base: Option<PathBuf>,
+    options: ContextOptions,
     pub(crate) client: Client,
 }
 
+/// The command that returns the canonical form of the label under the cursor, see
+/// [`BazelContext::canonicalize_label()`].
+pub(crate) const CANONICALIZE_LABEL_COMMAND: &str = "bazel-lsp.canonicalizeLabel";
+
+/// The command that clears all cached information, see [`BazelContext::refresh()`].
+pub(crate) const REFRESH_COMMAND: &str = "bazel-lsp.refresh";
+
+/// The command that associates an in-memory module with a path on disk, see
+/// [`BazelContext::associate_path()`].
+pub(crate) const ASSOCIATE_PATH_COMMAND: &str = "bazel-lsp.associatePath";
+
+/// The command that builds the target under the cursor, see [`BazelContext::build_target_at()`].
+pub(crate) const BUILD_COMMAND: &str = "bazel-lsp.build";
+
+/// The completions of a string literal, see [`BazelContext::take_string_completions()`].
+#[derive(Debug, Default)]
+pub(crate) struct StringCompletions {
+    /// The completions in the order they are offered in.
+    pub(crate) completions: Vec<BazelCompletion>,
+    /// Whether completions were left out, see [`ContextOptions::max_filesystem_completions`].
+    pub(crate) is_incomplete: bool,
+}
+
+/// The result of building a target with [`BazelContext::build_target_at()`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BuildReport {
+    /// The label of the target that was built, e.g. `//foo:bar`.
+    pub(crate) label: String,
+    pub(crate) success: bool,
+    /// What Bazel printed, for showing it to the user.
+    pub(crate) output: String,
+    /// The errors and warnings in the output that refer to a file, along with that file.
+    pub(crate) diagnostics: Vec<(PathBuf, Diagnostic)>,
+}
+
+/// A string literal resolved by [`BazelContext::resolve_string_literal_location()`]. Unlike a
+/// [`StringLiteralResult`], which finds the location with a closure, this can be sent to other
+/// threads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResolvedStringLiteral {
+    /// The file that the string literal refers to.
+    pub(crate) url: LspUrl,
+    /// Where in the file the string literal refers to, or `None` for the start of the file.
+    pub(crate) location: Option<StringLiteralLocation>,
+}
+
+/// A location in the file that a string literal refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StringLiteralLocation {
+    /// The definition of the target with this name, see [`ast::find_target_definition()`].
+    TargetName(String),
+    /// The declaration of the repository with this name, see
+    /// [`ast::find_repository_declaration()`].
+    RepositoryDeclaration(String),
+}
+
+impl ResolvedStringLiteral {
+    /// Converts this into the result of [`LspContext::resolve_string_literal()`], with a
+    /// location finder that looks for the location in the parsed file.
+    pub(crate) fn into_result(self) -> StringLiteralResult {
+        StringLiteralResult {
+            url: self.url,
+            location_finder: match self.location {
+                None => None,
+                Some(StringLiteralLocation::TargetName(name)) => Some(Box::new(move |ast| {
+                    Ok(ast::find_target_definition(ast, &name))
+                })),
+                Some(StringLiteralLocation::RepositoryDeclaration(repository)) => {
+                    Some(Box::new(move |ast| {
+                        Ok(ast::find_repository_declaration(ast, &repository))
+                    }))
+                }
+            },
+        }
+    }
+}
+
+/// The annotation of the edits that [`BazelContext::rename_target()`] makes in other packages.
+const TARGET_REFERENCES_ANNOTATION: &str = "target-references";
+
+/// How long the `.bzl` files of a workspace are scanned for their exported symbols before
+/// answering with the files that have been indexed so far.
+const EXPORT_INDEX_SCAN_BUDGET: Duration = Duration::from_millis(200);
+
+/// The maximum number of `.bzl` files exporting an undefined symbol for which
+/// [`BazelContext::missing_load_code_actions()`] offers to load it. More candidates usually
+/// mean that the symbol is too generic to guess where it should come from.
+const MAX_AUTO_IMPORT_CANDIDATES: usize = 3;
+
+/// The maximum number of modules that are followed from each `load` when looking for `load`
+/// cycles, see [`BazelContext::lint_load_cycles()`].
+const MAX_LOAD_CYCLE_MODULES: usize = 100;
+
+/// Following the loads of a module was stopped after [`MAX_LOAD_CYCLE_MODULES`] modules, see
+/// [`BazelContext::find_load_path()`].
+struct LoadCycleLimitReached;
+
+/// How long a query that failed or timed out isn't run again, since it would most likely fail
+/// again, e.g. until a broken BUILD file is fixed. See [`BazelContext::run_query()`].
+const FAILED_QUERY_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Files that mark the root of a workspace.
+const WORKSPACE_MARKER_FILE_NAMES: [&str; 4] =
+    ["MODULE.bazel", "REPO.bazel", "WORKSPACE.bazel", "WORKSPACE"];
+
+/// Files that declare the external repositories of a workspace, in order of preference.
+const REPOSITORY_DECLARATION_FILE_NAMES: [&str; 3] =
+    ["MODULE.bazel", "WORKSPACE.bazel", "WORKSPACE"];
+
+/// Finds the root of the workspace containing a source file, by looking for the nearest
+/// directory containing one of [`WORKSPACE_MARKER_FILE_NAMES`].
+fn find_workspace_root(uri: &LspUrl) -> Option<PathBuf> {
+    match uri {
+        LspUrl::File(path) => path
+            .parent()
+            .and_then(nearest_workspace_root)
+            .map(Path::to_owned),
+        _ => None,
+    }
+}
+
+/// Finds the nearest directory containing one of [`WORKSPACE_MARKER_FILE_NAMES`], starting at
+/// `dir` itself.
+fn nearest_workspace_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|dir| {
+        WORKSPACE_MARKER_FILE_NAMES
+            .iter()
+            .any(|name| dir.join(name).is_file())
+    })
+}
+
+/// Builds the information `bazel info` would report for a workspace from the filesystem, for
+/// [`ContextOptions::offline`]. The output base is taken from the `.bazelrc`, or from the
+/// `bazel-out` convenience symlink of a previous build. If neither exists, external
+/// repositories can't be found.
+fn offline_bazel_info(workspace_root: &Path) -> BazelInfo {
+    // `bazel-out` points to `<output base>/execroot/<workspace name>/bazel-out`.
+    let execution_root = fs::canonicalize(workspace_root.join("bazel-out"))
+        .ok()
+        .and_then(|bazel_out| Some(bazel_out.parent()?.to_owned()));
+    let output_base = bazelrc::startup_output_base(workspace_root).or_else(|| {
+        execution_root
+            .as_deref()
+            .and_then(|execution_root| Some(execution_root.parent()?.parent()?.to_owned()))
+    });
+    let execution_root = execution_root.unwrap_or_else(|| {
+        output_base
+            .as_deref()
+            .unwrap_or(Path::new(""))
+            .join("execroot")
+            .join("_main")
+    });
+
+    BazelInfo {
+        execution_root: execution_root.to_string_lossy().into_owned(),
+        output_base: output_base
+            .map(|output_base| output_base.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        workspace: workspace_root.to_string_lossy().into_owned(),
+        release: None,
+        install_base: None,
+        bazel_bin: fs::canonicalize(workspace_root.join("bazel-bin"))
+            .ok()
+            .map(|bazel_bin| bazel_bin.to_string_lossy().into_owned()),
+    }
+}
+
+/// Finds the repositories of a workspace that have local sources, by reading the files that
+/// declare its repositories. See [`ast::local_repository_paths()`].
+fn local_repositories(workspace_root: &Path) -> HashMap<String, PathBuf> {
+    let mut repositories = HashMap::new();
+
+    for file_name in REPOSITORY_DECLARATION_FILE_NAMES {
+        let path = workspace_root.join(file_name);
+        let ast = match fs::read_to_string(&path).ok().and_then(|contents| {
+            AstModule::parse(&path.to_string_lossy(), contents, &Dialect::Extended).ok()
+        }) {
+            Some(ast) => ast,
+            None => continue,
+        };
+
+        for (name, repository_path) in ast::local_repository_paths(&ast) {
+            // Relative paths are relative to the workspace root.
+            repositories
+                .entry(name.to_owned())
+                .or_insert_with(|| workspace_root.join(repository_path));
+        }
+    }
+
+    repositories
+}
+
+/// Finds the repositories declared in the `WORKSPACE` file of a workspace, see
+/// [`ast::declared_repository_names()`].
+fn declared_repositories(workspace_root: &Path) -> Vec<String> {
+    for file_name in ["WORKSPACE.bazel", "WORKSPACE"] {
+        let path = workspace_root.join(file_name);
+        if let Some(ast) = fs::read_to_string(&path).ok().and_then(|contents| {
+            AstModule::parse(&path.to_string_lossy(), contents, &Dialect::Extended).ok()
+        }) {
+            return ast::declared_repository_names(&ast)
+                .into_iter()
+                .map(str::to_owned)
+                .collect();
+        }
+    }
+
+    Vec::new()
+}
+
+/// Groups edits of a file for a [`WorkspaceEdit`] with `document_changes`.
+fn text_document_edit(
+    uri: &LspUrl,
+    edits: Vec<OneOf<TextEdit, AnnotatedTextEdit>>,
+) -> anyhow::Result<TextDocumentEdit> {
+    let url = Url::from_file_path(uri.path())
+        .map_err(|_| anyhow!("Cannot convert `{}` to a URL", uri))?;
+
+    Ok(TextDocumentEdit {
+        text_document: OptionalVersionedTextDocumentIdentifier {
+            uri: url,
+            version: None,
+        },
+        edits,
+    })
+}
+
+/// The code of the diagnostics that `starlark` reports for undefined variables.
+const UNDEFINED_VARIABLE_CODE: &str = "using-undefined";
+
+/// Returns the symbol of a diagnostic about an undefined variable, i.e. the identifier it
+/// covers in the contents of the document.
+fn undefined_symbol(diagnostic: &Diagnostic, contents: &str) -> Option<String> {
+    let range = diagnostic.range;
+    if diagnostic.code != Some(NumberOrString::String(UNDEFINED_VARIABLE_CODE.to_owned()))
+        || range.start.line != range.end.line
+    {
+        return None;
+    }
+
+    let symbol: String = contents
+        .lines()
+        .nth(range.start.line as usize)?
+        .chars()
+        .skip(range.start.character as usize)
+        .take(range.end.character.saturating_sub(range.start.character) as usize)
+        .collect();
+    (!symbol.is_empty() && symbol.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        .then_some(symbol)
+}
+
+/// Converts a parse error to a diagnostic. Errors after a bracket that is never closed, e.g. the
+/// end of the file after an unterminated list, are widened to start at that bracket, since the
+/// position where the parser gave up alone doesn't say where the problem is.
+fn parse_error_diagnostic(path: &Path, contents: &str, error: &starlark::Error) -> Diagnostic {
+    let mut message = EvalMessage::from_error(path, error);
+    if let (Some(span), Some(bracket)) = (message.span.as_mut(), unclosed_bracket(contents)) {
+        if (bracket.line, bracket.column) < (span.begin.line, span.begin.column) {
+            span.begin = bracket;
+        }
+    }
+    eval_message_to_lsp_diagnostic(message)
+}
+
+/// Returns the position of the outermost bracket that isn't closed until the end of a file,
+/// skipping brackets in strings and comments.
+fn unclosed_bracket(contents: &str) -> Option<ResolvedPos> {
+    let mut open_brackets = Vec::new();
+    let mut quote: Option<&str> = None;
+    let mut in_comment = false;
+    let (mut line, mut column) = (0, 0);
+
+    let mut chars = contents.char_indices();
+    while let Some((index, c)) = chars.next() {
+        let position = ResolvedPos { line, column };
+        if c == '\n' {
+            line += 1;
+            column = 0;
+            in_comment = false;
+        } else {
+            column += 1;
+        }
+
+        if in_comment {
+            continue;
+        }
+        if let Some(delimiter) = quote {
+            if c == '\\' {
+                if let Some((_, escaped)) = chars.next() {
+                    if escaped == '\n' {
+                        line += 1;
+                        column = 0;
+                    } else {
+                        column += 1;
+                    }
+                }
+            } else if contents[index..].starts_with(delimiter) {
+                for _ in 1..delimiter.len() {
+                    chars.next();
+                    column += 1;
+                }
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '#' => in_comment = true,
+            '"' | '\'' => {
+                let delimiter = match (c, &contents[index..]) {
+                    ('"', rest) if rest.starts_with("\"\"\"") => "\"\"\"",
+                    ('\'', rest) if rest.starts_with("'''") => "'''",
+                    ('"', _) => "\"",
+                    _ => "'",
+                };
+                for _ in 1..delimiter.len() {
+                    chars.next();
+                    column += 1;
+                }
+                quote = Some(delimiter);
+            }
+            '(' | '[' | '{' => open_brackets.push(position),
+            ')' | ']' | '}' => {
+                open_brackets.pop();
+            }
+            _ => {}
+        }
+    }
+
+    // The lexer already reports unterminated strings precisely.
+    if quote.is_some() {
+        return None;
+    }
+    open_brackets.into_iter().next()
+}
+
+/// Parses the errors and warnings in the output of `bazel build` that refer to a location in a
+/// file, either reported by Bazel, e.g. `ERROR: /ws/foo/BUILD:3:11: Compiling foo.cc failed`, or
+/// by the tools it runs, e.g. `foo/foo.cc:3:5: error: expected ';'`. Relative paths are relative
+/// to `workspace_root`.
+fn build_diagnostics(output: &str, workspace_root: &Path) -> Vec<(PathBuf, Diagnostic)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (severity, location) = if let Some(location) = line.strip_prefix("ERROR: ") {
+                (Some(DiagnosticSeverity::ERROR), location)
+            } else if let Some(location) = line.strip_prefix("WARNING: ") {
+                (Some(DiagnosticSeverity::WARNING), location)
+            } else {
+                (None, line)
+            };
+
+            // The path may contain colons itself, e.g. after the drive letter on Windows, so the
+            // line and column are taken from the right of the location.
+            let (location, message) = location.split_once(": ")?;
+            let mut parts = location.rsplitn(3, ':');
+            let column: u32 = parts.next()?.parse().ok()?;
+            let line_number: u32 = parts.next()?.parse().ok()?;
+            let path = parts.next()?;
+            let message = message.trim();
+            let (severity, message) = match severity {
+                Some(severity) => (severity, message),
+                None => {
+                    if let Some(message) = message.strip_prefix("error:") {
+                        (DiagnosticSeverity::ERROR, message.trim())
+                    } else if let Some(message) = message.strip_prefix("warning:") {
+                        (DiagnosticSeverity::WARNING, message.trim())
+                    } else {
+                        return None;
+                    }
+                }
+            };
+
+            // Locations are 1-based.
+            let position = Position::new(line_number.saturating_sub(1), column.saturating_sub(1));
+            Some((
+                workspace_root.join(path),
+                Diagnostic {
+                    range: Range::new(position, position),
+                    severity: Some(severity),
+                    message: message.to_owned(),
+                    ..Default::default()
+                },
+            ))
+        })
+        .collect()
+}
+
 fn is_workspace_file(uri: &LspUrl) -> bool {
     match uri {
         LspUrl::File(path) => path
@@ -144,10 +731,33 @@ fn is_workspace_file(uri: &LspUrl) -> bool {
 }
 
 impl<Client: BazelClient> BazelContext<Client> {
-    pub(crate) fn new(client: Client, query_output_base: Option<PathBuf>) -> anyhow::Result<Self> {
+    pub(crate) fn new(
+        client: Client,
+        query_output_base: Option<PathBuf>,
+        options: ContextOptions,
+    ) -> anyhow::Result<Self> {
         Ok(Self {
             workspaces: RefCell::new(HashMap::new()),
+            documents: RefCell::new(HashMap::new()),
+            loaded_modules: RefCell::new(HashMap::new()),
+            package_targets: RefCell::new(HashMap::new()),
+            testonly_targets: RefCell::new(HashMap::new()),
+            build_file_hashes: RefCell::new(HashMap::new()),
+            repo_mappings: RefCell::new(HashMap::new()),
+            module_dependencies: RefCell::new(HashMap::new()),
+            failed_queries: RefCell::new(HashMap::new()),
+            lint_resolutions: RefCell::new(None),
+            workspace_targets: RefCell::new(HashMap::new()),
+            export_indexes: RefCell::new(HashMap::new()),
+            bazel_globals: RefCell::new(HashMap::new()),
+            globals_names: RefCell::new(HashMap::new()),
+            deprecations: RefCell::new(HashMap::new()),
+            workspace_folders: RefCell::new(Vec::new()),
+            virtual_paths: RefCell::new(HashMap::new()),
+            cursor: RefCell::new(None),
+            last_string_completions: RefCell::new(StringCompletions::default()),
             query_output_base,
+            options,
             client,
         })
     }
@@ -157,779 +767,8034 @@ impl<Client: BazelClient> BazelContext<Client> {
 
         let is_workspace_file = is_workspace_file(uri);
 
-        ast.lint(Some(globals).as_ref())
+        ast.lint(Some(&*globals))
             .into_iter()
             .filter(|lint| !(is_workspace_file && lint.short_name == "misplaced-load"))
-            .map(EvalMessage::from)
+            .filter_map(|lint| {
+                let severity = self.options.lint_severities.get(&lint.short_name).copied();
+                let mut message = EvalMessage::from(lint);
+                match severity {
+                    Some(EvalSeverity::Disabled) => return None,
+                    Some(severity) => message.severity = severity,
+                    None => {}
+                }
+                Some(message)
+            })
             .collect()
     }
 
-    /// Gets the possibly-cached workspace for a directory, or creates a new one if it doesn't exist.
-    /// If the workspace is not given, it is inferred based on the current file.
-    /// Returns None if a workspace cannot be found.
-    fn workspace<P: AsRef<Path>>(
-        &self,
-        workspace_dir: Option<P>,
-        current_file: &LspUrl,
-    ) -> anyhow::Result<Option<Rc<BazelWorkspace>>> {
-        let mut workspaces = self.workspaces.borrow_mut();
-
-        let workspace_dir = match workspace_dir.as_ref() {
-            Some(workspace_dir) => Some(Cow::Borrowed(workspace_dir.as_ref())),
-            None => self.infer_workspace_dir(current_file)?.map(Cow::Owned),
+    /// Reports problems in a document that need the file system, Bazel or other files of the
+    /// workspace to be found, e.g. loads of unknown symbols. Unlike the diagnostics of
+    /// [`LspContext::parse_file_with_contents()`], which are reported on every change, these
+    /// are only reported when a document is opened or saved, or after a refresh.
+    pub(crate) fn semantic_diagnostics(&self, uri: &LspUrl) -> Vec<Diagnostic> {
+        let path = match uri {
+            LspUrl::File(path) => path,
+            _ => return Vec::new(),
         };
-
-        if let Some(workspace_dir) = workspace_dir {
-            if let Some(workspace) = workspaces.get(workspace_dir.as_ref()) {
-                Ok(Some(workspace.clone()))
-            } else {
-                let info = self.client.info(workspace_dir.as_ref())?;
-
-                let workspace =
-                    BazelWorkspace::from_bazel_info(info, self.query_output_base.as_deref())?;
-
-                workspaces.insert(workspace_dir.as_ref().to_owned(), Rc::new(workspace));
-
-                Ok(workspaces.get(workspace_dir.as_ref()).map(|ws| ws.clone()))
-            }
-        } else {
-            Ok(None)
+        let file_type = FileType::from_path(path);
+        if file_type == FileType::Bazelrc {
+            return Vec::new();
         }
-    }
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
 
-    fn infer_workspace_dir(&self, current_file: &LspUrl) -> io::Result<Option<PathBuf>> {
-        if let LspUrl::File(path) = current_file {
-            for dir in path.ancestors().skip(1) {
-                let file = dir.join("DO_NOT_BUILD_HERE");
-                if file.exists() {
-                    return Ok(Some(PathBuf::from(fs::read_to_string(file)?)));
+        *self.lint_resolutions.borrow_mut() = Some(LintResolutions::default());
+        let mut diagnostics = Vec::new();
+        if file_type == FileType::Library {
+            diagnostics.extend(self.suggest_missing_loads(uri, &ast));
+        }
+        diagnostics.extend(self.lint_load_symbols(uri, &ast));
+        diagnostics.extend(self.lint_load_cycles(uri, &ast));
+        diagnostics.extend(self.lint_file_types(uri, &ast));
+        diagnostics.extend(self.lint_unknown_repositories(uri, &ast));
+        if file_type == FileType::Build {
+            // The labels and globs of BUILD file templates refer to the external repository
+            // they are for, not to the directory they're in.
+            let package_dir = path
+                .parent()
+                .filter(|_| !FileType::is_build_file_template(path));
+            if let Some(package_dir) = package_dir {
+                let ignored_directories: Vec<PathBuf> =
+                    match self.workspace(self.lint_workspace_root(uri).as_deref(), uri) {
+                        Ok(Some(workspace)) => workspace
+                            .ignored_directories
+                            .iter()
+                            .map(|directory| workspace.root.join(directory))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                diagnostics.extend(lint::ineffective_glob_excludes(
+                    &ast,
+                    package_dir,
+                    &ignored_directories,
+                ));
+                if self.options.enabled_lints.contains("missing-source-file") {
+                    diagnostics.extend(lint::missing_source_files(&ast, package_dir));
+                }
+                if self.options.enabled_lints.contains("testonly-dependency") {
+                    diagnostics.extend(self.lint_testonly_dependencies(uri, &ast));
                 }
             }
-
-            Ok(None)
-        } else {
-            Ok(None)
         }
+        *self.lint_resolutions.borrow_mut() = None;
+
+        diagnostics
     }
 
-    // TODO: Consider caching this
-    fn repo_mapping_for_file(
-        &self,
-        workspace: &BazelWorkspace,
-        current_file: &LspUrl,
-    ) -> anyhow::Result<HashMap<String, String>> {
-        let current_repository = workspace
-            .get_repository_for_lspurl(current_file)
-            .unwrap_or(Cow::Borrowed(""));
+    /// Resolves a load for a lint, like [`Self::resolve_load()`] from the
+    /// [`Self::lint_workspace_root()`]. See [`LintResolutions`].
+    fn resolve_lint_load(&self, module: &str, current_file: &LspUrl) -> Rc<anyhow::Result<LspUrl>> {
+        let key = (module.to_owned(), current_file.clone());
+        if let Some(resolved) = self
+            .lint_resolutions
+            .borrow()
+            .as_ref()
+            .and_then(|resolutions| resolutions.loads.get(&key))
+        {
+            return resolved.clone();
+        }
 
-        self.client
-            .dump_repo_mapping(workspace, &current_repository)
+        let resolved = Rc::new(self.resolve_load(
+            module,
+            current_file,
+            self.lint_workspace_root(current_file).as_deref(),
+        ));
+        if let Some(resolutions) = self.lint_resolutions.borrow_mut().as_mut() {
+            resolutions.loads.insert(key, resolved.clone());
+        }
+        resolved
     }
 
-    /// Finds the directory that is the root of a package, given a label
-    fn resolve_folder<'a>(
+    /// Resolves the package directory of a label for a lint, like [`Self::resolve_folder()`].
+    /// See [`LintResolutions`].
+    fn resolve_lint_folder(
         &self,
-        label: &Label,
+        literal: &str,
         current_file: &LspUrl,
         workspace: Option<&BazelWorkspace>,
-    ) -> anyhow::Result<PathBuf> {
-        // Find the root we're resolving from. There's quite a few cases to consider here:
-        // - `repository` is empty, and we're resolving from the workspace root.
-        // - `repository` is empty, and we're resolving from a known remote repository.
-        // - `repository` is not empty, and refers to the current repository (the workspace).
-        // - `repository` is not empty, and refers to a known remote repository.
-        //
-        // Also with all of these cases, we need to consider if we have build system
-        // information or not. If not, we can't resolve any remote repositories, and we can't
-        // know whether a repository name refers to the workspace or not.
-        let resolve_root = match &label.repo {
-            // Repository is empty. If we know what file we're resolving from, use the build
-            // system information to check if we're in a known remote repository, and what the
-            // root is. Fall back to the `workspace_root` otherwise.
-            None => {
-                if let Some(repository_name) =
-                    workspace.and_then(|ws| ws.get_repository_for_lspurl(current_file))
-                {
-                    workspace
-                        .map(|ws| ws.get_repository_path(&repository_name))
-                        .map(Cow::Owned)
-                } else {
-                    workspace.map(|ws| Cow::Borrowed(&ws.root))
-                }
-            }
-            // We have a repository name and build system information. Check if the repository
-            // name refers to the workspace, and if so, use the workspace root. If not, check
-            // if it refers to a known remote repository, and if so, use that root.
-            // Otherwise, fail with an error.
-            Some(repository) => {
-                // If we are navigating to another repository, we need to apply the repo mapping.
-                // The repo mapping depends on the current repository, so resolve that first.
-                let repo_mapping = workspace
-                    .and_then(|ws| self.repo_mapping_for_file(ws, current_file).ok())
-                    .unwrap_or_default();
+    ) -> Option<PathBuf> {
+        let key = (literal.to_owned(), current_file.clone());
+        if let Some(folder) = self
+            .lint_resolutions
+            .borrow()
+            .as_ref()
+            .and_then(|resolutions| resolutions.folders.get(&key))
+        {
+            return folder.clone();
+        }
 
-                let remote_repository_name = repo_mapping
-                    .get(&repository.name)
-                    .unwrap_or(&repository.name);
+        let folder = Label::parse(literal)
+            .ok()
+            .and_then(|label| self.resolve_folder(&label, current_file, workspace).ok());
+        if let Some(resolutions) = self.lint_resolutions.borrow_mut().as_mut() {
+            resolutions.folders.insert(key, folder.clone());
+        }
+        folder
+    }
 
-                if matches!(workspace, Some(ws) if ws.workspace_name.as_ref() == Some(&repository.name))
-                {
-                    workspace.map(|ws| Cow::Borrowed(&ws.root))
-                } else if let Some(remote_repository_root) = workspace
-                    .map(|ws| ws.get_repository_path(remote_repository_name))
-                    .map(Cow::Owned)
-                {
-                    Some(remote_repository_root)
-                } else {
-                    return Err(ResolveLoadError::UnknownRepository(
-                        label.clone(),
-                        repository.name.clone(),
-                    )
-                    .into());
-                }
-            }
+    /// Suggests loading the undefined symbols of a `.bzl` file that look like rules or
+    /// providers, if they are exported by a `.bzl` file of the workspace. See
+    /// [`Self::missing_load_code_actions()`] for fixing them.
+    fn suggest_missing_loads(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let workspace = match self.workspace(self.lint_workspace_root(uri).as_deref(), uri) {
+            Ok(Some(workspace)) => workspace,
+            _ => return Vec::new(),
         };
+        let contents = match self.documents.borrow().get(uri) {
+            Some(contents) => contents.clone(),
+            None => return Vec::new(),
+        };
+        let index = self.export_index(&workspace.root);
 
-        if let Some(package) = &label.package {
-            // Resolve from the root of the repository.
-            match resolve_root {
-                Some(resolve_root) => Ok(resolve_root.join(package)),
-                None => Err(ResolveLoadError::MissingWorkspaceRoot(label.clone()).into()),
+        self.lint_module(uri, ast)
+            .into_iter()
+            .map(eval_message_to_lsp_diagnostic)
+            .filter_map(|diagnostic| {
+                let symbol = undefined_symbol(&diagnostic, &contents)
+                    .filter(|symbol| lint::looks_loadable(symbol))?;
+                let file = index.find_exporting_files(&symbol).into_iter().next()?;
+                let module = index.label(file)?;
+
+                Some(Diagnostic {
+                    range: diagnostic.range,
+                    severity: Some(DiagnosticSeverity::HINT),
+                    code: Some(NumberOrString::String("missing-load".to_owned())),
+                    message: format!("`{}` can be loaded from `{}`", symbol, module),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the index of the symbols exported by the `.bzl` files of a workspace, after
+    /// scanning it for at most [`EXPORT_INDEX_SCAN_BUDGET`]. The scan continues the next time
+    /// the index is needed, so results may be incomplete in large workspaces.
+    fn export_index(&self, workspace_root: &Path) -> RefMut<'_, ExportIndex> {
+        let mut index = RefMut::map(self.export_indexes.borrow_mut(), |indexes| {
+            indexes
+                .entry(workspace_root.to_owned())
+                .or_insert_with(|| ExportIndex::new(workspace_root.to_owned()))
+        });
+
+        if !index.is_complete() {
+            let deadline = Instant::now() + EXPORT_INDEX_SCAN_BUDGET;
+            index.scan(&|| Instant::now() >= deadline);
+        }
+
+        index
+    }
+
+    /// Finds the `.bzl` files of a workspace that export a symbol, i.e. that define it at the
+    /// top level without a leading `_`.
+    pub(crate) fn find_exporting_files(&self, symbol: &str, workspace_root: &Path) -> Vec<LspUrl> {
+        self.export_index(workspace_root)
+            .find_exporting_files(symbol)
+            .into_iter()
+            .map(|path| LspUrl::File(path.to_owned()))
+            .collect()
+    }
+
+    /// Offers code actions that load undefined symbols from the `.bzl` files exporting them,
+    /// as long as there are at most [`MAX_AUTO_IMPORT_CANDIDATES`] of them. If the file already
+    /// loads the exporting module, the symbol is added to that `load`. Otherwise a new `load` is
+    /// added after the existing ones, or at the top of the file after its docstring.
+    pub(crate) fn missing_load_code_actions(
+        &self,
+        uri: &LspUrl,
+        diagnostics: &[Diagnostic],
+    ) -> Vec<CodeAction> {
+        let url = match uri {
+            LspUrl::File(path) => match Url::from_file_path(path) {
+                Ok(url) => url,
+                Err(_) => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        let workspace = match self.workspace(self.lint_workspace_root(uri).as_deref(), uri) {
+            Ok(Some(workspace)) => workspace,
+            _ => return Vec::new(),
+        };
+        let contents = match self.documents.borrow().get(uri) {
+            Some(contents) => contents.clone(),
+            None => return Vec::new(),
+        };
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+        let loads = ast::loads(&ast);
+        let position_after = |span| {
+            let end = ast.codemap().resolve_span(span).end;
+            Position::new(end.line as u32, end.column as u32)
+        };
+        // The docstring of a module has to stay its first statement.
+        let new_load_position = loads
+            .last()
+            .map(|load| load.span)
+            .or_else(|| ast::module_docstring(&ast))
+            .map(|span| Position::new(position_after(span).line + 1, 0))
+            .unwrap_or_default();
+
+        let mut actions = Vec::new();
+        for diagnostic in diagnostics {
+            let symbol = match undefined_symbol(diagnostic, &contents) {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+            let files = self.find_exporting_files(&symbol, &workspace.root);
+            if files.len() > MAX_AUTO_IMPORT_CANDIDATES {
+                continue;
             }
-        } else {
-            // If we don't have a package, this is relative to the current file,
-            // so resolve relative paths from the current file.
-            match current_file {
-                LspUrl::File(current_file_path) => {
-                    let current_file_dir = current_file_path.parent();
-                    match current_file_dir {
-                        Some(current_file_dir) => Ok(current_file_dir.to_owned()),
-                        None => Err(ResolveLoadError::MissingCurrentFilePath(label.clone()).into()),
+
+            for file in files.iter().filter(|file| *file != uri) {
+                let existing_load = loads.iter().find(|load| {
+                    matches!(
+                        self.resolve_load(load.module, uri, Some(&workspace.root)),
+                        Ok(loaded) if loaded == *file
+                    )
+                });
+                let (title, edit) = match existing_load {
+                    Some(load) => {
+                        let position = position_after(
+                            load.symbols
+                                .last()
+                                .map_or(load.module_span, |symbol| symbol.their_span),
+                        );
+                        (
+                            format!("Add `{}` to the load of `{}`", symbol, load.module),
+                            TextEdit {
+                                range: lsp_types::Range::new(position, position),
+                                new_text: format!(", {}", ast::quote_string(&symbol)),
+                            },
+                        )
                     }
-                }
-                _ => Err(
-                    ResolveLoadError::WrongScheme("file://".to_owned(), current_file.clone())
-                        .into(),
-                ),
+                    None => {
+                        let module = match self.render_as_load(file, uri, Some(&workspace.root)) {
+                            Ok(module) => module,
+                            Err(_) => continue,
+                        };
+                        (
+                            format!("Load `{}` from `{}`", symbol, module),
+                            TextEdit {
+                                range: lsp_types::Range::new(new_load_position, new_load_position),
+                                new_text: format!(
+                                    "load({}, {})\n",
+                                    ast::quote_string(&module),
+                                    ast::quote_string(&symbol)
+                                ),
+                            },
+                        )
+                    }
+                };
+
+                actions.push(CodeAction {
+                    title,
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(url.clone(), vec![edit])])),
+                        ..Default::default()
+                    }),
+                    // Only one of the candidates is the right one.
+                    is_preferred: Some(files.len() == 1),
+                    ..Default::default()
+                });
             }
         }
+
+        actions
     }
 
-    fn get_filesystem_entries(
+    /// Offers a code action that normalizes the separators of a label in `range`, e.g.
+    /// `//foo//bar:baz` into `//foo/bar:baz`, see [`lint::malformed_label_paths()`].
+    pub(crate) fn normalize_label_path_code_actions(
         &self,
-        from: FilesystemCompletionRoot,
-        current_file: &LspUrl,
-        workspace: Option<&BazelWorkspace>,
-        options: &FilesystemCompletionOptions,
-        results: &mut Vec<StringCompletionResult>,
-    ) -> anyhow::Result<()> {
-        // Find the actual folder on disk we're looking at.
-        let (from_path, render_base) = match from {
-            FilesystemCompletionRoot::Path(path) => (path.to_owned(), ""),
-            FilesystemCompletionRoot::String(str) => {
-                let label = Label::parse(str)?;
-                (self.resolve_folder(&label, current_file, workspace)?, str)
-            }
+        uri: &LspUrl,
+        range: Range,
+    ) -> Vec<CodeAction> {
+        let url = match uri {
+            LspUrl::File(path) => match Url::from_file_path(path) {
+                Ok(url) => url,
+                Err(_) => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+        let position = ResolvedPos {
+            line: range.start.line as usize,
+            column: range.start.character as usize,
         };
 
-        for entry in fs::read_dir(from_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            let file_type = FileType::from_path(&path);
+        ast::label_literals(&ast)
+            .into_iter()
+            .filter(|(_, span)| ast::span_contains(&ast, *span, position))
+            .filter_map(|(label, span)| {
+                let normalized = Label::normalize_path(&label)?;
+                // Only the contents of the literal are replaced, to keep its quotes.
+                let (line, start, end) = ast::string_literal_value_columns(&ast, span)?;
+                Some(CodeAction {
+                    title: format!("Replace with `{}`", normalized),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(
+                            url.clone(),
+                            vec![TextEdit {
+                                range: Range::new(
+                                    Position::new(line as u32, start as u32),
+                                    Position::new(line as u32, end as u32),
+                                ),
+                                new_text: normalized,
+                            }],
+                        )])),
+                        ..Default::default()
+                    }),
+                    is_preferred: Some(true),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
 
-            // NOTE: Safe to `unwrap()` here, because we know that `path` is a file system path. And
-            // since it's an entry in a directory, it must have a file name.
-            let file_name = path.file_name().unwrap().to_string_lossy();
-            if path.is_dir() && options.directories {
-                results.push(StringCompletionResult {
-                    value: file_name.to_string(),
-                    insert_text: Some(format!(
-                        "{}{}",
-                        if render_base.ends_with('/') || render_base.is_empty() {
-                            ""
-                        } else {
-                            "/"
-                        },
-                        file_name
-                    )),
-                    insert_text_offset: render_base.len(),
-                    kind: CompletionItemKind::FOLDER,
-                });
-            } else if path.is_file() {
-                if file_type == FileType::Build {
-                    if options.targets {
-                        if let Some(targets) = self.query_buildable_targets(
-                            &format!(
-                                "{render_base}{}",
-                                if render_base.ends_with(':') { "" } else { ":" }
-                            ),
-                            workspace,
-                        ) {
-                            results.extend(targets.into_iter().map(|target| {
-                                StringCompletionResult {
-                                    value: target.to_owned(),
-                                    insert_text: Some(format!(
-                                        "{}{}",
-                                        if render_base.ends_with(':') { "" } else { ":" },
-                                        target
-                                    )),
-                                    insert_text_offset: render_base.len(),
-                                    kind: CompletionItemKind::PROPERTY,
-                                }
-                            }));
-                        }
-                    }
+    /// Offers a code action that wraps a string passed to a list attribute of a rule into a
+    /// list, e.g. `deps = "//foo"` into `deps = ["//foo"]`, if the string is in `range`. Which
+    /// attributes are lists is taken from the build language.
+    pub(crate) fn wrap_in_list_code_actions(&self, uri: &LspUrl, range: Range) -> Vec<CodeAction> {
+        let url = match uri {
+            LspUrl::File(path) => match Url::from_file_path(path) {
+                Ok(url) => url,
+                Err(_) => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+        let (language, _) = self.get_bazel_globals(uri);
+        let position = ResolvedPos {
+            line: range.start.line as usize,
+            column: range.start.character as usize,
+        };
+
+        let mut actions = Vec::new();
+        ast::visit_calls(&ast, |call| {
+            let rule = match call
+                .function
+                .and_then(|function| language.rule.iter().find(|rule| rule.name == function))
+            {
+                Some(rule) => rule,
+                None => return,
+            };
+
+            for arg in call.args {
+                let (name, value) = match &arg.node {
+                    ArgumentP::Named(name, value) => (name.node.as_str(), value),
+                    _ => continue,
+                };
+                if ast::string_literal(value).is_none()
+                    || !ast::span_contains(&ast, value.span, position)
+                    || !rule.attribute.iter().any(|attribute| {
+                        attribute.name == name && builtin::is_list_attribute(attribute)
+                    })
+                {
                     continue;
-                } else if options.files != FilesystemFileCompletionOptions::None {
-                    // Check if it's in the list of allowed extensions. If we have a list, and it
-                    // doesn't contain the extension, or the file has no extension, skip this file.
-                    if options.files == FilesystemFileCompletionOptions::OnlyLoadable {
-                        if file_type != FileType::Library {
-                            continue;
-                        }
-                    }
+                }
 
-                    results.push(StringCompletionResult {
-                        value: file_name.to_string(),
-                        insert_text: Some(format!(
-                            "{}{}",
-                            if render_base.ends_with(':') || render_base.is_empty() {
-                                ""
-                            } else {
-                                ":"
-                            },
-                            file_name
-                        )),
-                        insert_text_offset: render_base.len(),
-                        kind: CompletionItemKind::FILE,
-                    });
+                let span = ast.codemap().resolve_span(value.span);
+                let start = Position::new(span.begin.line as u32, span.begin.column as u32);
+                let end = Position::new(span.end.line as u32, span.end.column as u32);
+                actions.push(CodeAction {
+                    title: format!("Wrap `{}` in a list", name),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(
+                            url.clone(),
+                            vec![
+                                TextEdit {
+                                    range: Range::new(start, start),
+                                    new_text: "[".to_owned(),
+                                },
+                                TextEdit {
+                                    range: Range::new(end, end),
+                                    new_text: "]".to_owned(),
+                                },
+                            ],
+                        )])),
+                        ..Default::default()
+                    }),
+                    is_preferred: Some(true),
+                    ..Default::default()
+                });
+            }
+        });
+
+        actions
+    }
+
+    /// Reports symbols in `load` statements which are private to the loaded module, or not
+    /// defined by it.
+    fn lint_load_symbols(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        for load in ast::loads(ast) {
+            // Bazel rejects loads of private symbols from any module, including modules in the
+            // same package, so these are reported without resolving the module.
+            let (private, public): (Vec<_>, Vec<_>) = load
+                .symbols
+                .iter()
+                .partition(|symbol| symbol.their.starts_with('_'));
+            for symbol in private {
+                diagnostics.push(lint::diagnostic(
+                    ast,
+                    symbol.their_span,
+                    "load-private-symbol",
+                    DiagnosticSeverity::ERROR,
+                    format!("`{}` is private to `{}`", symbol.their, load.module),
+                ));
+            }
+
+            // Loads which can't be resolved can't be checked.
+            let symbols = match self.loaded_module_symbols(load.module, uri) {
+                Some(symbols) => symbols,
+                None => continue,
+            };
+
+            for symbol in public {
+                if !symbols.contains(symbol.their) {
+                    diagnostics.push(lint::diagnostic(
+                        ast,
+                        symbol.their_span,
+                        "load-symbol-not-found",
+                        DiagnosticSeverity::ERROR,
+                        format!("`{}` is not defined in `{}`", symbol.their, load.module),
+                    ));
                 }
             }
         }
 
-        Ok(())
+        diagnostics
     }
 
-    fn query_buildable_targets(
+    /// Reports loads which lead back to the current file through the loads of the loaded
+    /// modules, which Bazel rejects. At most [`MAX_LOAD_CYCLE_MODULES`] modules are followed
+    /// from each load, and loads for which that wasn't enough are reported as such.
+    fn lint_load_cycles(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        // Modules whose loads were all followed without reaching the current file, which are
+        // shared by the loads since they can't lead to it from any of them.
+        let mut unreachable = HashSet::new();
+
+        for load in ast::loads(ast) {
+            let url = match self.resolve_loaded_module(load.module, uri) {
+                Some(url) => url,
+                None => continue,
+            };
+
+            let mut path = vec![load.module.to_owned()];
+            let mut followed = HashSet::new();
+            match self.find_load_path(&url, uri, &mut path, &mut followed, &mut unreachable) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(LoadCycleLimitReached) => {
+                    diagnostics.push(lint::diagnostic(
+                        ast,
+                        load.span,
+                        "load-cycle",
+                        DiagnosticSeverity::INFORMATION,
+                        format!(
+                            "Stopped looking for load cycles through `{}` after following {} modules",
+                            load.module, MAX_LOAD_CYCLE_MODULES
+                        ),
+                    ));
+                    continue;
+                }
+            }
+            // The last module is the current file.
+            path.pop();
+
+            diagnostics.push(lint::diagnostic(
+                ast,
+                load.span,
+                "load-cycle",
+                DiagnosticSeverity::ERROR,
+                format!(
+                    "Load cycle: this file -> {}this file",
+                    path.iter()
+                        .map(|module| format!("`{}` -> ", module))
+                        .collect::<String>()
+                ),
+            ));
+        }
+
+        diagnostics
+    }
+
+    /// Follows the loads from `from` until `to` is reached, appending the loaded modules to
+    /// `path`. Modules in `followed` aren't followed again from the same load, and modules in
+    /// `unreachable` aren't followed at all, since they don't lead to `to`. Fails once more than
+    /// [`MAX_LOAD_CYCLE_MODULES`] modules were followed.
+    fn find_load_path(
         &self,
-        module: &str,
-        workspace: Option<&BazelWorkspace>,
-    ) -> Option<Vec<String>> {
-        let workspace = workspace?;
+        from: &LspUrl,
+        to: &LspUrl,
+        path: &mut Vec<String>,
+        followed: &mut HashSet<LspUrl>,
+        unreachable: &mut HashSet<LspUrl>,
+    ) -> Result<bool, LoadCycleLimitReached> {
+        if from == to {
+            return Ok(true);
+        }
+        if unreachable.contains(from) || followed.contains(from) {
+            return Ok(false);
+        }
+        if followed.len() >= MAX_LOAD_CYCLE_MODULES {
+            return Err(LoadCycleLimitReached);
+        }
+        followed.insert(from.clone());
 
-        let output = self.client.query(workspace, &format!("{module}*")).ok()?;
+        let loads = match self.loaded_module(from) {
+            Some(module) => module.loads.clone(),
+            None => {
+                unreachable.insert(from.clone());
+                return Ok(false);
+            }
+        };
+        // A module is only known to be unreachable if all of its loads are. Loads that were
+        // skipped because they are still being followed, i.e. that are part of a cycle which
+        // doesn't include `to`, may still lead to it.
+        let mut all_unreachable = true;
+        for (module, url) in loads.iter() {
+            path.push(module.clone());
+            if self.find_load_path(url, to, path, followed, unreachable)? {
+                return Ok(true);
+            }
+            path.pop();
+            all_unreachable &= unreachable.contains(url);
+        }
+        if all_unreachable {
+            unreachable.insert(from.clone());
+        }
 
-        Some(
-            output
-                .lines()
-                .filter_map(|line| line.strip_prefix(module).map(|str| str.to_owned()))
-                .collect(),
-        )
+        Ok(false)
     }
 
-    fn get_build_language_proto(&self, uri: &LspUrl) -> anyhow::Result<Vec<u8>> {
-        let workspace = self
-            .workspace::<PathBuf>(None, uri)?
-            .ok_or_else(|| anyhow!("Cannot find workspace"))?;
+    /// Reports loads from repositories that are unknown to Bazel, see
+    /// [`ResolveLoadError::UnknownRepository`].
+    fn lint_unknown_repositories(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let workspace_root = self.lint_workspace_root(uri);
+        // Without build system information, no repository is known.
+        if !matches!(self.workspace(workspace_root.as_deref(), uri), Ok(Some(_))) {
+            return Vec::new();
+        }
 
-        self.client.build_language(&workspace)
+        ast::loads(ast)
+            .into_iter()
+            .filter_map(|load| {
+                let resolved = self.resolve_lint_load(load.module, uri);
+                let error = resolved.as_ref().as_ref().err()?;
+
+                match error.downcast_ref::<ResolveLoadError>() {
+                    Some(error @ ResolveLoadError::UnknownRepository(..)) => {
+                        Some(lint::diagnostic(
+                            ast,
+                            load.module_span,
+                            "unknown-repository",
+                            DiagnosticSeverity::ERROR,
+                            error.to_string(),
+                        ))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
     }
 
-    /// Returns protos for bazel globals (like int, str, dir; but also e.g. cc_library, alias,
-    /// test_suite etc.).
-    // TODO: Consider caching this
-    fn get_bazel_globals(&self, uri: &LspUrl) -> (builtin::BuildLanguage, builtin::Builtins) {
-        let language_proto = self.get_build_language_proto(uri);
+    /// Reports dependencies on `testonly` targets from targets that aren't `testonly` themselves,
+    /// which Bazel only reports when building. Each dependency is queried, so this is opt-in, and
+    /// only reported with the other [`Self::semantic_diagnostics()`] rather than on every edit.
+    fn lint_testonly_dependencies(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let workspace = match self.workspace(self.lint_workspace_root(uri).as_deref(), uri) {
+            Ok(Some(workspace)) => workspace,
+            _ => return Vec::new(),
+        };
 
-        let language_proto = language_proto
-            .as_deref()
-            .unwrap_or(include_bytes!(env!("DEFAULT_BUILD_LANGUAGE_PB")));
+        let mut default_testonly = false;
+        ast::visit_calls(ast, |call| {
+            if call.function == Some("package") {
+                if let Some(testonly) = call
+                    .named_argument("default_testonly")
+                    .and_then(ast::bool_literal)
+                {
+                    default_testonly = testonly;
+                }
+            }
+        });
+
+        let mut diagnostics = Vec::new();
+        ast::visit_calls(ast, |call| {
+            // Tests are always `testonly`, whatever the attribute says.
+            let is_test = call
+                .function
+                .map_or(false, |function| function.ends_with("_test"));
+            let testonly = call
+                .named_argument("testonly")
+                .and_then(ast::bool_literal)
+                .unwrap_or(default_testonly);
+            if is_test || testonly {
+                return;
+            }
 
-        let language = builtin::BuildLanguage::decode(&language_proto[..]).unwrap();
+            for attribute in lint::DEPENDENCY_ATTRIBUTES {
+                let dependencies = match call
+                    .named_argument(attribute)
+                    .and_then(ast::string_list_literal)
+                {
+                    Some(dependencies) => dependencies,
+                    None => continue,
+                };
 
-        // TODO: builtins are also dependent on bazel version, but there is no way to obtain those,
-        // see https://github.com/bazel-contrib/vscode-bazel/issues/1.
-        let builtins_proto = include_bytes!(env!("BUILTIN_PB"));
-        let builtins = builtin::Builtins::decode(&builtins_proto[..]).unwrap();
+                for (dependency, span) in dependencies {
+                    if self.is_testonly_target(dependency, uri, &workspace) {
+                        diagnostics.push(lint::diagnostic(
+                            ast,
+                            span,
+                            "testonly-dependency",
+                            DiagnosticSeverity::ERROR,
+                            format!(
+                                "`{}` is `testonly`, so only `testonly` targets can depend on it",
+                                dependency
+                            ),
+                        ));
+                    }
+                }
+            }
+        });
 
-        (language, builtins)
+        diagnostics
     }
 
-    fn try_get_environment(&self, uri: &LspUrl) -> anyhow::Result<DocModule> {
-        let file_type = FileType::from_lsp_url(uri);
-        let (language, builtins) = self.get_bazel_globals(uri);
+    /// Whether a label refers to a `testonly` target, by querying its definition. Results are
+    /// cached until the package of the target is invalidated.
+    fn is_testonly_target(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace: &BazelWorkspace,
+    ) -> bool {
+        let label = match Label::parse(literal) {
+            Ok(label) => label,
+            Err(_) => return false,
+        };
+        let package_dir = match self.resolve_lint_folder(literal, current_file, Some(workspace)) {
+            Some(package_dir) => package_dir,
+            None => return false,
+        };
+        // Files are never `testonly`.
+        if package_dir.join(&label.name).exists() {
+            return false;
+        }
 
-        let members: SmallMap<_, _> = builtin::build_language_to_doc_members(&language)
-            .chain(builtin::builtins_to_doc_members(&builtins, file_type))
-            .map(|(name, member)| (name, DocItem::Member(member)))
-            .collect();
+        self.check_package_targets(&package_dir);
+        if let Some(testonly) = self
+            .testonly_targets
+            .borrow()
+            .get(&package_dir)
+            .and_then(|targets| targets.get(&label.name))
+        {
+            return *testonly;
+        }
 
-        Ok(DocModule {
-            docs: None,
-            members,
-        })
-    }
+        let testonly = self
+            .absolute_label(&label, current_file, workspace)
+            .and_then(|absolute| {
+                let label = absolute.to_string();
+                self.run_query(&format!("--output=build {label}"), |client| {
+                    client.query_build(workspace, &label)
+                })
+                .ok()
+            })
+            .and_then(|output| BuildTarget::parse(&output))
+            .map_or(false, |target| target.is_testonly());
 
-    fn get_bazel_globals_names(&self, uri: &LspUrl) -> HashSet<String> {
-        let (language, builtins) = self.get_bazel_globals(uri);
+        self.testonly_targets
+            .borrow_mut()
+            .entry(package_dir)
+            .or_default()
+            .insert(label.name, testonly);
 
-        language
-            .rule
-            .iter()
-            .map(|rule| rule.name.clone())
-            .chain(builtins.global.iter().map(|global| global.name.clone()))
-            .chain(
-                builtin::MISSING_GLOBALS
-                    .iter()
-                    .map(|missing| missing.to_string()),
-            )
-            .collect()
+        testonly
     }
-}
 
-impl<Client: BazelClient> LspContext for BazelContext<Client> {
-    fn parse_file_with_contents(&self, uri: &LspUrl, content: String) -> LspEvalResult {
-        match uri {
-            LspUrl::File(path) => {
-                match AstModule::parse(&path.to_string_lossy(), content, &Dialect::Extended) {
-                    Ok(ast) => {
-                        let diagnostics = self
-                            .lint_module(uri, &ast)
-                            .into_map(eval_message_to_lsp_diagnostic);
-                        LspEvalResult {
-                            diagnostics,
-                            ast: Some(ast),
-                        }
-                    }
-                    Err(e) => {
-                        let diagnostics = vec![eval_message_to_lsp_diagnostic(
-                            EvalMessage::from_error(path, &e),
-                        )];
-                        LspEvalResult {
-                            diagnostics,
-                            ast: None,
-                        }
-                    }
-                }
+    /// Reports references to files that are used as the wrong type of file, i.e. loads of BUILD
+    /// files, and `.bzl` files used as dependencies or packages of a BUILD file.
+    fn lint_file_types(&self, uri: &LspUrl, ast: &AstModule) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let workspace_root = self.lint_workspace_root(uri);
+
+        for load in ast::loads(ast) {
+            let resolved = self.resolve_lint_load(load.module, uri);
+            let url = match resolved.as_ref() {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+
+            let is_build_file = Label::parse(load.module)
+                .map(|label| FileType::from_path(&label.name) == FileType::Build)
+                .unwrap_or(false);
+            if is_build_file && url.path().is_file() {
+                diagnostics.push(lint::diagnostic(
+                    ast,
+                    load.module_span,
+                    "file-type-mismatch",
+                    DiagnosticSeverity::ERROR,
+                    format!("`{}` is a BUILD file, which can't be loaded", load.module),
+                ));
+            } else if FileType::from_lsp_url(uri) == FileType::Configuration
+                && FileType::from_lsp_url(url) != FileType::Configuration
+            {
+                diagnostics.push(lint::diagnostic(
+                    ast,
+                    load.module_span,
+                    "file-type-mismatch",
+                    DiagnosticSeverity::ERROR,
+                    format!(
+                        "`{}` is not a .scl file, which can't be loaded from .scl files",
+                        load.module
+                    ),
+                ));
             }
-            _ => LspEvalResult::default(),
+        }
+
+        if FileType::from_lsp_url(uri) != FileType::Build {
+            return diagnostics;
+        }
+
+        let workspace = self
+            .workspace(workspace_root.as_deref(), uri)
+            .ok()
+            .flatten();
+        ast::visit_string_literals(ast, |literal, span, context| {
+            let is_dependency = context
+                .rule_attribute()
+                .map(|(_, attribute)| lint::DEPENDENCY_ATTRIBUTES.contains(&attribute))
+                .unwrap_or(false);
+            if !is_dependency {
+                return;
+            }
+
+            let label = match Label::parse(literal) {
+                Ok(label) => label,
+                Err(_) => return,
+            };
+            let folder = match self.resolve_lint_folder(literal, uri, workspace.as_deref()) {
+                Some(folder) => folder,
+                None => return,
+            };
+
+            let message = if folder.is_file() {
+                format!(
+                    "The package of `{}` is a file, not a directory containing a BUILD file",
+                    literal
+                )
+            } else if FileType::from_path(&label.name) == FileType::Library
+                && folder.join(&label.name).is_file()
+            {
+                format!("`{}` is a .bzl file, which can't be depended on", literal)
+            } else {
+                return;
+            };
+
+            diagnostics.push(lint::diagnostic(
+                ast,
+                span,
+                "file-type-mismatch",
+                DiagnosticSeverity::ERROR,
+                message,
+            ));
+        });
+
+        diagnostics
+    }
+
+    /// Finds the workspace root to resolve labels from when linting a file. Files in the
+    /// output base can find their workspace on their own.
+    fn lint_workspace_root(&self, current_file: &LspUrl) -> Option<PathBuf> {
+        match self.infer_workspace_dir(current_file) {
+            Ok(Some(_)) => None,
+            _ => find_workspace_root(current_file),
         }
     }
 
-    fn resolve_load(
+    /// Returns the top-level symbols defined by a loaded module, or `None` if the module
+    /// cannot be resolved or parsed.
+    fn loaded_module_symbols(
         &self,
-        path: &str,
+        module: &str,
         current_file: &LspUrl,
-        workspace_root: Option<&Path>,
-    ) -> anyhow::Result<LspUrl> {
-        let label = Label::parse(path)?;
-        let workspace = self.workspace(workspace_root, current_file)?;
+    ) -> Option<Rc<HashSet<String>>> {
+        let url = self.resolve_loaded_module(module, current_file)?;
 
-        let folder = self.resolve_folder(&label, current_file, workspace.as_deref())?;
+        Some(self.loaded_module(&url)?.symbols.clone())
+    }
 
-        // Try the presumed filename first, and check if it exists.
-        let presumed_path = folder.join(label.name);
-        if presumed_path.exists() {
-            return Ok(Url::from_file_path(presumed_path).unwrap().try_into()?);
+    /// Resolves a module loaded by `current_file`, or returns `None` if it can't be resolved to
+    /// a `.bzl` file.
+    fn resolve_loaded_module(&self, module: &str, current_file: &LspUrl) -> Option<LspUrl> {
+        let label = Label::parse(module).ok()?;
+        let url = self
+            .resolve_lint_load(module, current_file)
+            .as_ref()
+            .as_ref()
+            .ok()?
+            .clone();
+
+        // `resolve_load` falls back to the package's BUILD file, which isn't what was loaded.
+        // Loads of actual BUILD files are reported by `lint_file_types` instead.
+        if url.path().file_name() != Path::new(&label.name).file_name()
+            || FileType::from_lsp_url(&url) == FileType::Build
+        {
+            return None;
         }
 
-        // If the presumed filename doesn't exist, try to find a build file from the build system
-        // and use that instead.
-        for build_file_name in FileType::BUILD_FILE_NAMES {
-            let path = folder.join(build_file_name);
-            if path.exists() {
-                return Ok(Url::from_file_path(path).unwrap().try_into()?);
-            }
+        Some(url)
+    }
+
+    /// Parses a loaded module, which is cached until its contents change.
+    fn loaded_module(&self, url: &LspUrl) -> Option<Ref<LoadedModule>> {
+        let contents = self.get_load_contents(url).ok()??;
+
+        let is_cached = self
+            .loaded_modules
+            .borrow()
+            .get(url)
+            .map_or(false, |module| module.contents == contents);
+        if !is_cached {
+            let ast = AstModule::parse(
+                &url.path().to_string_lossy(),
+                contents.clone(),
+                &FileType::from_lsp_url(url).dialect(),
+            )
+            .ok()?;
+            let symbols = ast::top_level_bindings(&ast)
+                .into_iter()
+                .map(|(name, _)| name.to_owned())
+                .collect();
+            let loads = ast::loads(&ast)
+                .into_iter()
+                .filter_map(|load| {
+                    let resolved = self.resolve_loaded_module(load.module, url)?;
+
+                    Some((load.module.to_owned(), resolved))
+                })
+                .collect();
+
+            self.loaded_modules.borrow_mut().insert(
+                url.clone(),
+                LoadedModule {
+                    contents,
+                    symbols: Rc::new(symbols),
+                    loads: Rc::new(loads),
+                },
+            );
         }
 
-        Err(ResolveLoadError::TargetNotFound(path.to_owned()).into())
+        Ref::filter_map(self.loaded_modules.borrow(), |modules| modules.get(url)).ok()
     }
 
-    fn render_as_load(
+    /// Gets the possibly-cached workspace for a directory, or creates a new one if it doesn't exist.
+    /// If the workspace is not given, or the current file is in a workspace nested in it, it is
+    /// inferred based on the current file, see [`Self::infer_workspace_dir()`].
+    /// Returns None if a workspace cannot be found.
+    ///
+    /// Workspaces are cached for the lifetime of the context. Directories that turn out to
+    /// belong to an already known workspace share its `BazelWorkspace`, so that there is only
+    /// ever one per workspace root.
+    fn workspace<P: AsRef<Path>>(
         &self,
-        target: &LspUrl,
+        workspace_dir: Option<P>,
         current_file: &LspUrl,
-        workspace_root: Option<&Path>,
-    ) -> anyhow::Result<String> {
-        let workspace = self.workspace(workspace_root, current_file)?;
+    ) -> anyhow::Result<Option<Rc<BazelWorkspace>>> {
+        let mut workspaces = self.workspaces.borrow_mut();
 
-        match (target, current_file) {
-            // Check whether the target and the current file are in the same package.
-            (LspUrl::File(target_path), LspUrl::File(current_file_path)) if matches!((target_path.parent(), current_file_path.parent()), (Some(a), Some(b)) if a == b) =>
-            {
-                // Then just return a relative path.
-                let target_filename = target_path.file_name();
-                match target_filename {
-                    Some(filename) => Ok(format!(":{}", filename.to_string_lossy())),
-                    None => Err(RenderLoadError::MissingTargetFilename(target_path.clone()).into()),
-                }
+        // A file outside of the given directory belongs to the workspace folder containing it,
+        // if there is one.
+        let workspace_dir: Option<Cow<Path>> = match workspace_dir.as_ref() {
+            Some(workspace_dir) if current_file.path().starts_with(workspace_dir) => {
+                Some(Cow::Borrowed(workspace_dir.as_ref()))
             }
-            (LspUrl::File(target_path), _) => {
-                // Try to find a repository that contains the target, as well as the path to the
-                // target relative to the repository root. If we can't find a repository, we'll
-                // try to resolve the target relative to the workspace root. If we don't have a
-                // workspace root, we'll just use the target path as-is.
-                let (repository, target_path) = &workspace
-                    .as_deref()
-                    .and_then(|ws| ws.get_repository_for_path(target_path))
-                    .map(|(repository, target_path)| (Some(repository), target_path))
-                    .or_else(|| {
-                        workspace_root
-                            .and_then(|root| target_path.strip_prefix(root).ok())
-                            .map(|path| (None, path))
-                    })
-                    .unwrap_or((None, target_path));
+            workspace_dir => self
+                .workspace_folder(current_file)
+                .map(Cow::Owned)
+                .or_else(|| {
+                    workspace_dir.map(|workspace_dir| Cow::Borrowed(workspace_dir.as_ref()))
+                }),
+        };
+        let workspace_dir = match (workspace_dir, self.infer_workspace_dir(current_file)?) {
+            // A file in a workspace nested in the given one belongs to the nested workspace.
+            (Some(workspace_dir), Some(inferred))
+                if self.is_nested_workspace(&workspaces, &workspace_dir, &inferred) =>
+            {
+                Some(Cow::Owned(inferred))
+            }
+            (Some(workspace_dir), _) => Some(workspace_dir),
+            (None, inferred) => inferred.map(Cow::Owned),
+        };
 
-                let target_filename = target_path.file_name();
-                match target_filename {
-                    Some(filename) => Ok(format!(
-                        "@{}//{}:{}",
-                        repository.as_ref().unwrap_or(&Cow::Borrowed("")),
-                        target_path
-                            .parent()
-                            .map(|path| path.to_string_lossy())
-                            .unwrap_or_default(),
-                        filename.to_string_lossy()
-                    )),
-                    None => Err(
-                        RenderLoadError::MissingTargetFilename(target_path.to_path_buf()).into(),
-                    ),
-                }
+        if let Some(workspace_dir) = workspace_dir {
+            // Directories in a known workspace, rather than in a workspace nested in it, belong
+            // to it without running `bazel info` again.
+            let known_workspace = workspaces.get(workspace_dir.as_ref()).cloned().or_else(|| {
+                let root = nearest_workspace_root(&workspace_dir)?;
+                workspaces.values().find(|ws| ws.root == root).cloned()
+            });
+            if let Some(workspace) = known_workspace {
+                workspaces.insert(workspace_dir.as_ref().to_owned(), workspace.clone());
+                Ok(Some(workspace))
+            } else {
+                let info = if self.options.offline {
+                    offline_bazel_info(workspace_dir.as_ref())
+                } else {
+                    self.client.info(workspace_dir.as_ref())?
+                };
+
+                let root = Path::new(&info.workspace);
+                let workspace = match workspaces.values().find(|ws| ws.root == root) {
+                    Some(workspace) => workspace.clone(),
+                    None => {
+                        let mut workspace = BazelWorkspace::from_bazel_info(
+                            info,
+                            self.query_output_base.as_deref(),
+                        )?;
+                        workspace.local_repositories = local_repositories(&workspace.root);
+                        workspace.declared_repositories = declared_repositories(&workspace.root);
+
+                        Rc::new(workspace)
+                    }
+                };
+
+                workspaces.insert(workspace_dir.as_ref().to_owned(), workspace.clone());
+
+                Ok(Some(workspace))
             }
-            _ => Err(RenderLoadError::WrongScheme(
-                "file://".to_owned(),
-                target.clone(),
-                current_file.clone(),
-            )
-            .into()),
+        } else {
+            Ok(None)
         }
     }
 
-    fn resolve_string_literal(
+    /// Returns the client, unless Bazel must not be run, see [`ContextOptions::offline`].
+    fn online_client(&self) -> anyhow::Result<&Client> {
+        if self.options.offline {
+            Err(anyhow!("Bazel is not run in offline mode"))
+        } else {
+            Ok(&self.client)
+        }
+    }
+
+    /// Lists the direct dependencies of the root module of a workspace. Since this runs
+    /// `bazel mod deps`, they are only listed when a `bazel_dep` is first looked up, and cached
+    /// until `MODULE.bazel` is saved, or until the context is refreshed.
+    fn module_dependencies(&self, workspace: &BazelWorkspace) -> Rc<Vec<ModuleDependency>> {
+        if let Some(dependencies) = self.module_dependencies.borrow().get(&workspace.root) {
+            return dependencies.clone();
+        }
+
+        let dependencies = self
+            .online_client()
+            .and_then(|client| client.mod_deps(workspace))
+            .unwrap_or_else(|error| {
+                eprintln!("Cannot list module dependencies: {}", error);
+                Vec::new()
+            });
+        let dependencies = Rc::new(dependencies);
+        self.module_dependencies
+            .borrow_mut()
+            .insert(workspace.root.clone(), dependencies.clone());
+        dependencies
+    }
+
+    /// Finds the dependency of the root module that a `bazel_dep` in the `MODULE.bazel` file of
+    /// a workspace refers to, along with the directory of the module, if it has been fetched.
+    fn module_dependency(
         &self,
         literal: &str,
         current_file: &LspUrl,
         workspace_root: Option<&Path>,
-    ) -> anyhow::Result<Option<StringLiteralResult>> {
-        self.resolve_load(literal, current_file, workspace_root)
-            .map(|url| {
-                let original_target_name = Path::new(literal).file_name();
-                let path_file_name = url.path().file_name();
-                let same_filename = original_target_name == path_file_name;
+    ) -> anyhow::Result<Option<(ModuleDependency, Option<PathBuf>)>> {
+        if current_file.path().file_name() != Some("MODULE.bazel".as_ref()) {
+            return Ok(None);
+        }
+        let is_dependency_name = self
+            .string_literal_context(current_file, literal)
+            .and_then(|context| context.calls.last().cloned())
+            .map_or(false, |call| {
+                call.function.as_deref() == Some("bazel_dep")
+                    && call.argument.as_deref() == Some("name")
+            });
+        if !is_dependency_name {
+            return Ok(None);
+        }
 
-                Some(StringLiteralResult {
-                    url: url.clone(),
-                    // If the target name is the same as the original target name, we don't need to
-                    // do anything. Otherwise, we need to find the function call in the target file
-                    // that has a `name` parameter with the same value as the original target name.
-                    location_finder: if same_filename {
-                        None
-                    } else {
-                        match Label::parse(literal) {
-                            Err(_) => None,
-                            Ok(label) => Some(Box::new(move |ast| {
-                                Ok(ast.find_function_call_with_name(&label.name))
-                            })),
-                        }
-                    },
-                })
-            })
+        let workspace = match self.workspace(workspace_root, current_file)? {
+            Some(workspace) => workspace,
+            None => return Ok(None),
+        };
+        let dependency = self
+            .module_dependencies(&workspace)
+            .iter()
+            .find(|dependency| dependency.apparent_name == literal)
+            .cloned();
+
+        Ok(dependency.map(|dependency| {
+            let path = workspace.module_path(&dependency.name, &dependency.version);
+            (dependency, path)
+        }))
     }
 
-    fn get_load_contents(&self, uri: &LspUrl) -> anyhow::Result<Option<String>> {
-        match uri {
-            LspUrl::File(path) => match path.is_absolute() {
-                true => match fs::read_to_string(path) {
-                    Ok(contents) => Ok(Some(contents)),
-                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
-                    Err(e) => Err(e.into()),
-                },
-                false => Err(ContextError::NotAbsolute(uri.clone()).into()),
-            },
-            LspUrl::Starlark(_) => Ok(None),
-            _ => Err(ContextError::WrongScheme("file://".to_owned(), uri.clone()).into()),
+    /// Sets the most recent contents of a document, before they are passed to
+    /// [`LspContext::parse_file_with_contents()`], so that requests the server answers in the
+    /// meantime see them.
+    pub(crate) fn set_document(&self, uri: &LspUrl, contents: String) {
+        self.documents.borrow_mut().insert(uri.clone(), contents);
+    }
+
+    /// Forgets the contents of a document that was closed in the client. Its file is read from
+    /// disk again when it's needed, e.g. when it's loaded by another document.
+    pub(crate) fn close_document(&self, uri: &LspUrl) {
+        self.documents.borrow_mut().remove(uri);
+        let mut cursor = self.cursor.borrow_mut();
+        if cursor
+            .as_ref()
+            .map_or(false, |(cursor_uri, _)| cursor_uri == uri)
+        {
+            *cursor = None;
         }
     }
 
-    fn get_environment(&self, uri: &LspUrl) -> DocModule {
-        self.try_get_environment(uri).unwrap_or_default()
+    /// Parses the most recent contents of a document that was passed to
+    /// [`LspContext::parse_file_with_contents()`].
+    fn document_ast(&self, uri: &LspUrl) -> Option<AstModule> {
+        let contents = self.documents.borrow().get(uri)?.clone();
+
+        AstModule::parse(
+            &uri.path().to_string_lossy(),
+            contents,
+            &FileType::from_lsp_url(uri).dialect(),
+        )
+        .ok()
     }
 
-    fn get_url_for_global_symbol(
-        &self,
-        _current_file: &LspUrl,
-        _symbol: &str,
-    ) -> anyhow::Result<Option<LspUrl>> {
-        Ok(None)
+    /// Sets the position of the cursor in the request that is forwarded to the `starlark_lsp`
+    /// server, if it has one, since the [`LspContext`] is only told the value of the string
+    /// literal under the cursor rather than where it is.
+    pub(crate) fn set_cursor(&self, cursor: Option<(LspUrl, Position)>) {
+        *self.cursor.borrow_mut() = cursor.map(|(uri, position)| {
+            (
+                uri,
+                ResolvedPos {
+                    line: position.line as usize,
+                    column: position.character as usize,
+                },
+            )
+        });
     }
 
-    fn get_string_completion_options(
-        &self,
-        document_uri: &LspUrl,
-        kind: StringCompletionType,
-        current_value: &str,
-        workspace_root: Option<&Path>,
-    ) -> anyhow::Result<Vec<StringCompletionResult>> {
-        let workspace = self.workspace(workspace_root, document_uri)?;
+    /// Finds the byte offset of the cursor in a string literal of a document, if the cursor is
+    /// on the literal, see [`Self::set_cursor()`].
+    fn cursor_offset(&self, uri: &LspUrl, literal: &str) -> Option<usize> {
+        let position = match &*self.cursor.borrow() {
+            Some((cursor_uri, position)) if cursor_uri == uri => *position,
+            _ => return None,
+        };
+        let (value, offset) =
+            ast::find_string_literal_offset_at(&self.document_ast(uri)?, position)?;
 
-        let offer_repository_names = current_value.is_empty()
-            || current_value == "@"
-            || (current_value.starts_with('@') && !current_value.contains('/'))
-            || (!current_value.contains('/') && !current_value.contains(':'));
+        (value == literal).then_some(offset)
+    }
 
-        let repo_mapping = workspace
-            .as_deref()
-            .and_then(|ws| self.repo_mapping_for_file(ws, document_uri).ok());
+    /// Finds where a string literal with the given value appears in a document. The server only
+    /// tells us the value of the literal, so the literal at the cursor is used if it has that
+    /// value, see [`Self::set_cursor()`], and otherwise the first one with the value.
+    fn string_literal_context(&self, uri: &LspUrl, value: &str) -> Option<StringLiteralContext> {
+        let position = match &*self.cursor.borrow() {
+            Some((cursor_uri, position)) if cursor_uri == uri => Some(*position),
+            _ => None,
+        };
 
-        let mut names = if offer_repository_names {
-            if let Some(workspace) = &workspace {
-                let repo_names = match &repo_mapping {
-                    Some(repo_mappings) => repo_mappings
-                        .keys()
-                        .filter(|key| *key != "")
-                        .map(|key| Cow::Borrowed(key.deref()))
-                        .collect(),
-                    None => workspace.get_repository_names(),
-                };
+        ast::find_string_literal_context(&self.document_ast(uri)?, value, position)
+    }
 
-                repo_names
-                    .into_iter()
-                    .map(|name| {
-                        let name_with_at = format!("@{}", name);
-                        let insert_text = format!("{}//", &name_with_at);
+    /// Sets the workspace folders opened in the client, e.g. from the `initialize` request or a
+    /// `workspace/didChangeWorkspaceFolders` notification. Files are then resolved in the
+    /// folder containing them, even if another workspace root is given.
+    pub(crate) fn set_workspace_folders(&self, folders: Vec<PathBuf>) {
+        *self.workspace_folders.borrow_mut() = folders;
+    }
 
-                        StringCompletionResult {
-                            value: name_with_at,
-                            insert_text: Some(insert_text),
-                            insert_text_offset: 0,
-                            kind: CompletionItemKind::MODULE,
-                        }
-                    })
-                    .collect()
-            } else {
-                Vec::new()
+    /// Finds the workspace folder containing a file, preferring the innermost one if folders
+    /// are nested.
+    fn workspace_folder(&self, current_file: &LspUrl) -> Option<PathBuf> {
+        let path = match current_file {
+            LspUrl::File(path) => path,
+            _ => return None,
+        };
+
+        self.workspace_folders
+            .borrow()
+            .iter()
+            .filter(|folder| path.starts_with(folder))
+            .max_by_key(|folder| folder.components().count())
+            .cloned()
+    }
+
+    /// Infers the workspace a file belongs to. Files in the output base belong to the
+    /// workspace their `DO_NOT_BUILD_HERE` file points to. Other files belong to the nearest
+    /// directory containing one of [`WORKSPACE_MARKER_FILE_NAMES`], so that files in a nested
+    /// workspace are attributed to it rather than to the workspace around it.
+    fn infer_workspace_dir(&self, current_file: &LspUrl) -> io::Result<Option<PathBuf>> {
+        if let LspUrl::File(path) = current_file {
+            for dir in path.ancestors().skip(1) {
+                let file = dir.join("DO_NOT_BUILD_HERE");
+                if file.exists() {
+                    return Ok(Some(PathBuf::from(fs::read_to_string(file)?)));
+                }
             }
+
+            Ok(find_workspace_root(current_file))
         } else {
-            Vec::new()
+            Ok(None)
+        }
+    }
+
+    /// Whether `inner` is a workspace nested in the workspace at `outer`, rather than the same
+    /// workspace or one of its repositories with local sources, e.g. a module with a
+    /// `local_path_override`, which are resolved through the outer workspace.
+    fn is_nested_workspace(
+        &self,
+        workspaces: &HashMap<PathBuf, Rc<BazelWorkspace>>,
+        outer: &Path,
+        inner: &Path,
+    ) -> bool {
+        if inner == outer || !inner.starts_with(outer) {
+            return false;
+        }
+
+        let is_local_repository = match workspaces.get(outer) {
+            Some(workspace) => workspace
+                .local_repositories
+                .values()
+                .any(|path| path == inner),
+            None => local_repositories(outer).values().any(|path| path == inner),
         };
+        !is_local_repository
+    }
 
-        // Complete filenames if we're not in the middle of typing a repository name:
-        // "@foo" -> don't complete filenames (still typing repository)
-        // "@foo/" -> don't complete filenames (need two separating slashes)
-        // "@foo//", "@foo//bar -> complete directories (from `@foo//`)
-        // "@foo//bar/baz" -> complete directories (from `@foo//bar`)
-        // "@foo//bar:baz" -> complete filenames (from `@foo//bar`), and target names if `kind` is `String`
-        // "foo" -> complete directories and filenames (ambiguous, might be a relative path or a repository)
-        let complete_directories = (!current_value.starts_with('@')
-            || current_value.contains("//"))
-            && !current_value.contains(':');
-        let complete_filenames =
-            // Still typing repository
-            (!current_value.starts_with('@') || current_value.contains("//")) &&
-            // Explicitly typing directory
-            (!current_value.contains('/') || current_value.contains(':'));
-        let complete_targets = kind == StringCompletionType::String && complete_filenames;
-        if complete_directories || complete_filenames || complete_targets {
-            if let Some(completion_root) = if complete_directories && complete_filenames {
-                // This must mean we don't have a `/` or `:` separator, so we're completing a relative path.
-                // Use the document URI's directory as the base.
-                document_uri
-                    .path()
-                    .parent()
-                    .map(FilesystemCompletionRoot::Path)
-            } else {
-                // Complete from the last `:` or `/` in the current value.
-                current_value
-                    // NOTE: Can't use `rsplit_once` as we need the value _including_ the value
-                    // we're splitting on.
-                    .rfind(if complete_directories { '/' } else { ':' })
-                    .map(|pos| &current_value[..pos + 1])
-                    .map(FilesystemCompletionRoot::String)
-            } {
-                self.get_filesystem_entries(
-                    completion_root,
-                    document_uri,
-                    workspace.as_deref(),
-                    &FilesystemCompletionOptions {
-                        directories: complete_directories,
-                        files: match (kind, complete_filenames) {
-                            (StringCompletionType::LoadPath, _) => {
-                                FilesystemFileCompletionOptions::OnlyLoadable
-                            }
-                            (StringCompletionType::String, true) => {
-                                FilesystemFileCompletionOptions::All
-                            }
-                            (StringCompletionType::String, false) => {
-                                FilesystemFileCompletionOptions::None
-                            }
-                        },
-                        targets: complete_targets,
-                    },
-                    &mut names,
-                )?;
-            }
+    /// Returns the repo mapping of the repository containing a file, see [`Self::repo_mapping()`].
+    fn repo_mapping_for_file(
+        &self,
+        workspace: &BazelWorkspace,
+        current_file: &LspUrl,
+    ) -> anyhow::Result<Rc<HashMap<String, String>>> {
+        // In `WORKSPACE` mode there are no repo mappings, and apparent names are the canonical
+        // names, which an empty mapping stands for.
+        if !workspace.bzlmod_enabled {
+            return Ok(Rc::default());
         }
 
-        Ok(names)
+        let current_repository = match workspace.get_repository_for_lspurl(current_file) {
+            Some(repository) => repository,
+            None => self
+                .local_repository_for_file(workspace, current_file)
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed("")),
+        };
+
+        // An empty mapping maps every apparent name to itself.
+        if self.options.offline {
+            return Ok(Rc::default());
+        }
+
+        self.repo_mapping(workspace, &current_repository)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use lsp_types::{NumberOrString, Url};
-    use std::path::PathBuf;
+    /// Dumps the repo mapping of a repository, i.e. the canonical names of the repositories
+    /// visible from it, keyed by their apparent names. These only change with `MODULE.bazel`, so
+    /// they are cached until it is saved, or until the context is refreshed.
+    fn repo_mapping(
+        &self,
+        workspace: &BazelWorkspace,
+        repository: &str,
+    ) -> anyhow::Result<Rc<HashMap<String, String>>> {
+        let key = (workspace.root.clone(), repository.to_owned());
+        if let Some(mapping) = self.repo_mappings.borrow().get(&key) {
+            return Ok(mapping.clone());
+        }
 
-    use lsp_types::CompletionItemKind;
-    use serde_json::json;
-    use starlark::{
-        docs::{DocFunction, DocItem, DocMember, DocModule, DocParam, DocString},
-        typing::Ty,
-    };
-    use starlark_lsp::{
-        completion::{StringCompletionResult, StringCompletionType},
-        server::{LspContext, LspUrl},
-    };
+        let mapping = Rc::new(self.client.dump_repo_mapping(workspace, repository)?);
+        self.repo_mappings.borrow_mut().insert(key, mapping.clone());
+        Ok(mapping)
+    }
+
+    /// Finds the canonical name of the repository with local sources that contains a file, e.g.
+    /// a module with a `local_path_override`. Since these aren't in the output base, the name
+    /// is looked up in the repo mapping of the main repository.
+    fn local_repository_for_file(
+        &self,
+        workspace: &BazelWorkspace,
+        current_file: &LspUrl,
+    ) -> Option<String> {
+        let (name, _) = workspace.get_local_repository_for_path(current_file.path())?;
+        self.online_client().ok()?;
+
+        self.repo_mapping(workspace, "")
+            .ok()?
+            .get(name)
+            .filter(|canonical_name| !canonical_name.is_empty())
+            .cloned()
+    }
 
-    use crate::test_fixture::TestFixture;
+    /// Finds the directory that is the root of a package, given a label. `workspace` must be
+    /// the workspace that owns `current_file`, as returned by [`Self::workspace()`], which may
+    /// be nested in the workspace that was opened.
+    fn resolve_folder<'a>(
+        &self,
+        label: &Label,
+        current_file: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+    ) -> anyhow::Result<PathBuf> {
+        // Find the root we're resolving from. There's quite a few cases to consider here:
+        // - `repository` is empty, and we're resolving from the workspace root.
+        // - `repository` is empty, and we're resolving from a known remote repository.
+        // - `repository` is not empty, and refers to the current repository (the workspace).
+        // - `repository` is not empty, and refers to a known remote repository.
+        //
+        // Also with all of these cases, we need to consider if we have build system
+        // information or not. If not, we can't resolve any remote repositories, and we can't
+        // know whether a repository name refers to the workspace or not.
+        let resolve_root = match &label.repo {
+            // Repository is empty. If we know what file we're resolving from, use the build
+            // system information to check if we're in a known remote repository, and what the
+            // root is. Fall back to the `workspace_root` otherwise.
+            None => {
+                if let Some(repository_name) =
+                    workspace.and_then(|ws| ws.get_repository_for_lspurl(current_file))
+                {
+                    workspace
+                        .map(|ws| ws.get_repository_path(&repository_name))
+                        .map(Cow::Owned)
+                } else if let Some((_, repository_root)) =
+                    workspace.and_then(|ws| ws.get_local_repository_for_path(current_file.path()))
+                {
+                    Some(Cow::Borrowed(repository_root))
+                } else {
+                    workspace.map(|ws| Cow::Borrowed(&ws.root))
+                }
+            }
+            // We have a repository name and build system information. Check if the repository
+            // name refers to the workspace, and if so, use the workspace root. If not, check
+            // if it refers to a known remote repository, and if so, use that root.
+            // Otherwise, fail with an error.
+            Some(repository) => {
+                // If we are navigating to another repository, we need to apply the repo mapping.
+                // The repo mapping depends on the current repository, so resolve that first. In
+                // `WORKSPACE` mode, the mapping is empty and the name is used as is.
+                let repo_mapping = workspace
+                    .and_then(|ws| self.repo_mapping_for_file(ws, current_file).ok())
+                    .unwrap_or_default();
+
+                let remote_repository_name = repo_mapping
+                    .get(&repository.name)
+                    .unwrap_or(&repository.name);
+
+                // The main repository is mapped to the empty canonical name.
+                if remote_repository_name.is_empty()
+                    || matches!(workspace, Some(ws) if ws.workspace_name.as_ref() == Some(&repository.name))
+                {
+                    workspace.map(|ws| Cow::Borrowed(&ws.root))
+                } else if let Some(remote_repository_root) = workspace
+                    .map(|ws| ws.get_repository_path(remote_repository_name))
+                    .filter(|path| {
+                        // The repo mapping contains every repository that is visible from the
+                        // current one, so an apparent name outside of it is unknown, unless the
+                        // repository happens to exist anyway.
+                        repository.is_canonical
+                            || repo_mapping.is_empty()
+                            || BUILTIN_REPOSITORY_NAMES.contains(&repository.name.as_str())
+                            || repo_mapping.contains_key(&repository.name)
+                            || path.exists()
+                    })
+                    .map(Cow::Owned)
+                {
+                    Some(remote_repository_root)
+                } else {
+                    return Err(ResolveLoadError::UnknownRepository(
+                        label.clone(),
+                        repository.name.clone(),
+                        workspace.map_or(false, |ws| ws.bzlmod_enabled),
+                    )
+                    .into());
+                }
+            }
+        };
+
+        if let Some(package) = &label.package {
+            // Resolve from the root of the repository.
+            match resolve_root {
+                Some(resolve_root) => Ok(resolve_root.join(package)),
+                None => Err(ResolveLoadError::MissingWorkspaceRoot(label.clone()).into()),
+            }
+        } else {
+            // If we don't have a package, this is relative to the current file,
+            // so resolve relative paths from the current file.
+            match current_file {
+                LspUrl::File(current_file_path) => {
+                    let current_file_dir = current_file_path.parent();
+                    match current_file_dir {
+                        Some(current_file_dir) => Ok(current_file_dir.to_owned()),
+                        None => Err(ResolveLoadError::MissingCurrentFilePath(label.clone()).into()),
+                    }
+                }
+                _ => Err(ResolveLoadError::NotOnDisk(label.clone(), current_file.clone()).into()),
+            }
+        }
+    }
+
+    fn get_filesystem_entries(
+        &self,
+        from: FilesystemCompletionRoot,
+        current_file: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+        options: &FilesystemCompletionOptions,
+        results: &mut Vec<BazelCompletion>,
+    ) -> anyhow::Result<()> {
+        // Find the actual folder on disk we're looking at.
+        let (from_path, render_base) = match from {
+            FilesystemCompletionRoot::Path(path) => (path.to_owned(), ""),
+            FilesystemCompletionRoot::String(str) => {
+                let label = Label::parse(str)?;
+                (self.resolve_folder(&label, current_file, workspace)?, str)
+            }
+        };
+
+        // `read_dir` returns entries in no particular order, so they are collected and sorted
+        // before being added to `results`.
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(from_path)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = FileType::from_path(&path);
+
+            // NOTE: Safe to `unwrap()` here, because we know that `path` is a file system path. And
+            // since it's an entry in a directory, it must have a file name.
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            // Hidden files are rarely sources, and directories in `.bazelignore` can't contain
+            // packages, so neither are offered. Ignored directories are skipped even when hidden
+            // files are included.
+            if file_name.starts_with('.') && !self.options.include_hidden_files {
+                continue;
+            }
+            if let Some(workspace) = workspace {
+                if let Ok(relative_path) = path.strip_prefix(&workspace.root) {
+                    if workspace
+                        .ignored_directories
+                        .iter()
+                        .any(|ignored| relative_path == ignored)
+                    {
+                        continue;
+                    }
+                }
+            }
+
+            if path.is_dir() && options.directories {
+                entries.push(BazelCompletion {
+                    category: CompletionCategory::Directory,
+                    value: file_name.to_string(),
+                    insert_text: Some(format!(
+                        "{}{}",
+                        if render_base.ends_with('/') || render_base.is_empty() {
+                            ""
+                        } else {
+                            "/"
+                        },
+                        file_name
+                    )),
+                    insert_text_offset: render_base.len(),
+                    detail: None,
+                });
+            } else if path.is_file() {
+                // BUILD file templates are offered like other files, e.g. for `build_file`.
+                if file_type == FileType::Build && !FileType::is_build_file_template(&path) {
+                    if options.targets {
+                        // NOTE: Safe to `unwrap()` here, since `path` is a file in a directory.
+                        let package_dir = path.parent().unwrap();
+                        let module = format!(
+                            "{render_base}{}",
+                            if render_base.ends_with(':') { "" } else { ":" }
+                        );
+
+                        if let Some(targets) =
+                            self.query_buildable_targets(package_dir, &module, workspace)
+                        {
+                            let exporting_targets = if options.annotate_exports {
+                                self.query_exporting_targets(package_dir, &module, workspace)
+                                    .unwrap_or_default()
+                            } else {
+                                Vec::new()
+                            };
+
+                            entries.extend(targets.into_iter().map(|target| {
+                                let exports = exporting_targets.contains(&target.name);
+                                BazelCompletion {
+                                    category: CompletionCategory::Target(
+                                        target
+                                            .rule
+                                            .as_deref()
+                                            .map_or(TargetKind::Other, TargetKind::from_label_kind),
+                                    ),
+                                    detail: match (&target.rule, exports) {
+                                        (Some(rule), true) => {
+                                            Some(format!("`{rule}`, also provides its `exports`"))
+                                        }
+                                        (Some(rule), false) => Some(format!("`{rule}`")),
+                                        (None, true) => {
+                                            Some("Also provides its `exports`".to_owned())
+                                        }
+                                        (None, false) => None,
+                                    },
+                                    insert_text: Some(format!(
+                                        "{}{}",
+                                        if render_base.ends_with(':') { "" } else { ":" },
+                                        target.name
+                                    )),
+                                    value: target.name,
+                                    insert_text_offset: render_base.len(),
+                                }
+                            }));
+                        }
+                    }
+                    if options.files == FilesystemFileCompletionOptions::OnlyLoadable
+                        && render_base.ends_with(':')
+                    {
+                        entries.extend(self.bzl_library_completions(&path, render_base));
+                    }
+                    continue;
+                } else if options.files != FilesystemFileCompletionOptions::None {
+                    // Check if it's in the list of allowed extensions. If we have a list, and it
+                    // doesn't contain the extension, or the file has no extension, skip this file.
+                    if options.files == FilesystemFileCompletionOptions::OnlyLoadable {
+                        if file_type != FileType::Library {
+                            continue;
+                        }
+                    }
+
+                    if let FilesystemFileCompletionOptions::WithExtensions(extensions) =
+                        options.files
+                    {
+                        let has_extension = path
+                            .extension()
+                            .map(|extension| extensions.iter().any(|ext| *ext == extension))
+                            .unwrap_or(false);
+                        if !has_extension {
+                            continue;
+                        }
+                    }
+
+                    // Relative loads need a `:`, even without a package, e.g. `:defs.bzl`.
+                    let needs_separator = if render_base.is_empty() {
+                        options.files == FilesystemFileCompletionOptions::OnlyLoadable
+                    } else {
+                        !render_base.ends_with(':')
+                    };
+                    entries.push(BazelCompletion {
+                        category: CompletionCategory::File,
+                        value: file_name.to_string(),
+                        insert_text: Some(format!(
+                            "{}{}",
+                            if needs_separator { ":" } else { "" },
+                            file_name
+                        )),
+                        insert_text_offset: render_base.len(),
+                        detail: None,
+                    });
+                }
+            }
+        }
+
+        // The best matches come first. Equally good matches are files with a preferred
+        // extension or loadable files next to the completion root, then directories, then other
+        // files, then targets, each sorted by name.
+        let is_preferred = |entry: &BazelCompletion| match options.files {
+            FilesystemFileCompletionOptions::OnlyLoadable => {
+                entry.category == CompletionCategory::File && !entry.value.contains('/')
+            }
+            FilesystemFileCompletionOptions::PreferExtensions(extensions) => {
+                entry.category == CompletionCategory::File
+                    && Path::new(&entry.value)
+                        .extension()
+                        .map_or(false, |extension| {
+                            extensions.iter().any(|ext| *ext == extension)
+                        })
+            }
+            _ => false,
+        };
+        let mut entries: Vec<(u32, bool, BazelCompletion)> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                Some((
+                    completion::fuzzy_score(options.partial_name, &entry.value)?,
+                    is_preferred(&entry),
+                    entry,
+                ))
+            })
+            .collect();
+        entries.sort_by(|(a_score, a_preferred, a), (b_score, b_preferred, b)| {
+            (Reverse(a_score), Reverse(a_preferred), a.category, &a.value).cmp(&(
+                Reverse(b_score),
+                Reverse(b_preferred),
+                b.category,
+                &b.value,
+            ))
+        });
+        let mut entries: Vec<BazelCompletion> =
+            entries.into_iter().map(|(_, _, entry)| entry).collect();
+        if let Some(max) = self.options.max_filesystem_completions {
+            self.truncate_completions(&mut entries, max);
+        }
+        results.append(&mut entries);
+
+        Ok(())
+    }
+
+    /// Completes the files of the `bzl_library` targets in a BUILD file that are in
+    /// subdirectories of the package, which aren't offered when listing the package directory.
+    fn bzl_library_completions(
+        &self,
+        build_file: &Path,
+        render_base: &str,
+    ) -> Vec<BazelCompletion> {
+        let uri = LspUrl::File(build_file.to_owned());
+        let ast = match self.document_ast(&uri).or_else(|| {
+            AstModule::parse(
+                &build_file.to_string_lossy(),
+                fs::read_to_string(build_file).ok()?,
+                &Dialect::Extended,
+            )
+            .ok()
+        }) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+
+        ast::bzl_library_srcs(&ast)
+            .into_iter()
+            .filter(|(_, src)| src.contains('/'))
+            .map(|(name, src)| BazelCompletion {
+                category: CompletionCategory::File,
+                value: src.to_owned(),
+                insert_text: Some(src.to_owned()),
+                insert_text_offset: render_base.len(),
+                detail: Some(format!("Part of `bzl_library` `{name}`")),
+            })
+            .collect()
+    }
+
+    /// Queries the targets of the package in `package_dir`, which is referred to as `module`,
+    /// along with their rules. Results are cached until the package is invalidated, see
+    /// [`Self::invalidate()`].
+    fn query_buildable_targets(
+        &self,
+        package_dir: &Path,
+        module: &str,
+        workspace: Option<&BazelWorkspace>,
+    ) -> Option<Vec<PackageTarget>> {
+        self.query_package_targets(package_dir, module, &format!("{module}*"), true, workspace)
+    }
+
+    /// Queries the targets of the package in `package_dir` that have a non-empty `exports`
+    /// attribute, i.e. which also provide other targets to their dependents.
+    fn query_exporting_targets(
+        &self,
+        package_dir: &Path,
+        module: &str,
+        workspace: Option<&BazelWorkspace>,
+    ) -> Option<Vec<String>> {
+        let targets = self.query_package_targets(
+            package_dir,
+            module,
+            &format!("attr(exports, \"\\[.+\\]\", {module}*)"),
+            false,
+            workspace,
+        )?;
+
+        Some(targets.into_iter().map(|target| target.name).collect())
+    }
+
+    /// Runs a query for targets of the package in `package_dir`, returning the targets, and
+    /// their rules if `label_kind` is set. Results are cached until the package is invalidated.
+    fn query_package_targets(
+        &self,
+        package_dir: &Path,
+        module: &str,
+        query: &str,
+        label_kind: bool,
+        workspace: Option<&BazelWorkspace>,
+    ) -> Option<Vec<PackageTarget>> {
+        let key = if label_kind {
+            format!("--output=label_kind {query}")
+        } else {
+            query.to_owned()
+        };
+        self.check_package_targets(package_dir);
+        if let Some(targets) = self
+            .package_targets
+            .borrow()
+            .get(package_dir)
+            .and_then(|targets| targets.get(&key))
+        {
+            return Some(targets.clone());
+        }
+
+        let workspace = workspace?;
+        let targets: Vec<PackageTarget> = if label_kind {
+            // Lines look like `cc_binary rule //foo:bar` or `source file //foo:bar.cc`.
+            self.query_label_kind_keep_going(workspace, query)?
+                .lines()
+                .filter_map(|line| {
+                    let (kind, label) = line.rsplit_once(' ')?;
+                    Some(PackageTarget {
+                        name: label.strip_prefix(module)?.to_owned(),
+                        rule: kind.strip_suffix(" rule").map(str::to_owned),
+                    })
+                })
+                .collect()
+        } else {
+            self.query_keep_going(workspace, query)?
+                .lines()
+                .filter_map(|line| {
+                    Some(PackageTarget {
+                        name: line.strip_prefix(module)?.to_owned(),
+                        rule: None,
+                    })
+                })
+                .collect()
+        };
+
+        self.package_targets
+            .borrow_mut()
+            .entry(package_dir.to_owned())
+            .or_default()
+            .insert(key, targets.clone());
+
+        Some(targets)
+    }
+
+    /// Clears the cached targets of a package if its BUILD file on disk changed since they were
+    /// queried, e.g. by `git checkout`, which the client doesn't report as a saved document.
+    fn check_package_targets(&self, package_dir: &Path) {
+        let hash = FileType::BUILD_FILE_NAMES
+            .iter()
+            .find_map(|name| fs::read(package_dir.join(name)).ok())
+            .map(|contents| {
+                let mut hasher = DefaultHasher::new();
+                contents.hash(&mut hasher);
+                hasher.finish()
+            });
+
+        let mut hashes = self.build_file_hashes.borrow_mut();
+        if hashes.get(package_dir) == hash.as_ref() {
+            return;
+        }
+        self.package_targets.borrow_mut().remove(package_dir);
+        self.testonly_targets.borrow_mut().remove(package_dir);
+        match hash {
+            Some(hash) => hashes.insert(package_dir.to_owned(), hash),
+            None => hashes.remove(package_dir),
+        };
+    }
+
+    /// Runs a query, returning its output even if it only partially succeeded, see
+    /// [`PartialQueryError`].
+    fn query_keep_going(&self, workspace: &BazelWorkspace, query: &str) -> Option<String> {
+        self.keep_going(
+            query,
+            self.run_query(query, |client| client.query(workspace, query)),
+        )
+    }
+
+    /// Like [`Self::query_keep_going()`], but with `--output=label_kind`.
+    fn query_label_kind_keep_going(
+        &self,
+        workspace: &BazelWorkspace,
+        query: &str,
+    ) -> Option<String> {
+        self.keep_going(
+            query,
+            self.run_query(&format!("--output=label_kind {query}"), |client| {
+                client.query_label_kind(workspace, query)
+            }),
+        )
+    }
+
+    /// Runs a query with the client, unless Bazel must not be run, or the query with the
+    /// same arguments failed less than [`FAILED_QUERY_RETRY_DELAY`] ago, e.g. because it timed
+    /// out. Queries that partially succeeded aren't considered failed.
+    fn run_query(
+        &self,
+        arguments: &str,
+        query: impl FnOnce(&Client) -> anyhow::Result<String>,
+    ) -> anyhow::Result<String> {
+        if let Some(failed_at) = self.failed_queries.borrow().get(arguments) {
+            if failed_at.elapsed() < FAILED_QUERY_RETRY_DELAY {
+                return Err(anyhow!("Query `{}` failed recently", arguments));
+            }
+        }
+
+        let result = query(self.online_client()?);
+        match &result {
+            Err(error) if !error.is::<PartialQueryError>() => {
+                self.failed_queries
+                    .borrow_mut()
+                    .insert(arguments.to_owned(), Instant::now());
+            }
+            _ => {
+                self.failed_queries.borrow_mut().remove(arguments);
+            }
+        }
+        result
+    }
+
+    /// Returns the output of a query, even if it only partially succeeded.
+    fn keep_going(&self, query: &str, result: anyhow::Result<String>) -> Option<String> {
+        match result {
+            Ok(output) => Some(output),
+            Err(error) => match error.downcast::<PartialQueryError>() {
+                Ok(error) => {
+                    eprintln!("Query `{}` partially failed: {}", query, error.stderr);
+                    Some(error.stdout)
+                }
+                Err(_) => None,
+            },
+        }
+    }
+
+    /// Queries targets across a workspace, e.g. all `config_setting`s. Results are cached until
+    /// any package is invalidated.
+    fn query_workspace_targets(
+        &self,
+        workspace: &BazelWorkspace,
+        query: &str,
+    ) -> Option<Vec<String>> {
+        if let Some(targets) = self
+            .workspace_targets
+            .borrow()
+            .get(&workspace.root)
+            .and_then(|queries| queries.get(query))
+        {
+            return Some(targets.clone());
+        }
+
+        let output = self.query_keep_going(workspace, query)?;
+        let targets: Vec<String> = output.lines().map(str::to_owned).collect();
+
+        self.workspace_targets
+            .borrow_mut()
+            .entry(workspace.root.clone())
+            .or_default()
+            .insert(query.to_owned(), targets.clone());
+
+        Some(targets)
+    }
+
+    /// Completes a condition of `select()`. `//conditions:default` is always offered.
+    fn select_condition_completions(
+        &self,
+        workspace: Option<&BazelWorkspace>,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let default = BazelCompletion {
+            category: CompletionCategory::Value,
+            value: "//conditions:default".to_owned(),
+            insert_text: Some("//conditions:default".to_owned()),
+            insert_text_offset: 0,
+            detail: Some("Matches if no other condition does".to_owned()),
+        };
+        let conditions = workspace
+            .and_then(|workspace| {
+                self.query_workspace_targets(
+                    workspace,
+                    "kind(\"config_setting|constraint_value\", //...)",
+                )
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|condition| BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                insert_text: Some(condition.clone()),
+                value: condition,
+                insert_text_offset: 0,
+                detail: None,
+            });
+
+        std::iter::once(default)
+            .chain(conditions)
+            .filter(|completion| completion.value.starts_with(current_value))
+            .collect()
+    }
+
+    /// Completes the target of a visibility label in a package, e.g. `//foo:__pkg__`, or in the
+    /// package of the document, e.g. `:__pkg__`. Besides the special `__pkg__` and
+    /// `__subpackages__` targets, the `package_group`s of the package are offered. The special
+    /// `//visibility` package only has `public` and `private`.
+    fn visibility_completions(
+        &self,
+        document_uri: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let (package, partial_name) = match current_value.split_once(':') {
+            Some(split) => split,
+            None => return Vec::new(),
+        };
+        let package_groups = match package {
+            "//visibility" => Vec::new(),
+            _ => self.package_group_targets(document_uri, workspace, package),
+        };
+        let package_groups = package_groups.into_iter().map(|name| BazelCompletion {
+            category: CompletionCategory::Target(TargetKind::Other),
+            insert_text: Some(name.clone()),
+            value: name,
+            insert_text_offset: package.len() + 1,
+            detail: Some("`package_group`".to_owned()),
+        });
+
+        completion::visibility_suffix_completions(package)
+            .into_iter()
+            .chain(package_groups)
+            .filter(|completion| completion.value.starts_with(partial_name))
+            .collect()
+    }
+
+    /// Queries the names of the `package_group`s of a package, e.g. `//foo`, or of the package
+    /// of the document if `package` is empty. Only the package is queried, and the results are
+    /// cached along with its other targets, see [`Self::query_package_targets()`].
+    fn package_group_targets(
+        &self,
+        document_uri: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+        package: &str,
+    ) -> Vec<String> {
+        let workspace = match workspace {
+            Some(workspace) => workspace,
+            None => return Vec::new(),
+        };
+        let module = if package.is_empty() {
+            match document_uri
+                .path()
+                .parent()
+                .and_then(|dir| dir.strip_prefix(&workspace.root).ok())
+            {
+                Some(package_dir) => format!("//{}:", label::label_path(package_dir)),
+                None => return Vec::new(),
+            }
+        } else {
+            format!("{package}:")
+        };
+        let package_dir = match Label::parse(&module)
+            .and_then(|label| self.resolve_folder(&label, document_uri, Some(workspace)))
+        {
+            Ok(package_dir) => package_dir,
+            Err(_) => return Vec::new(),
+        };
+
+        self.query_package_targets(
+            &package_dir,
+            &module,
+            &format!("kind(package_group, {module}*)"),
+            false,
+            Some(workspace),
+        )
+        .unwrap_or_default()
+        .into_iter()
+        .map(|target| target.name)
+        .collect()
+    }
+
+    /// Completes a package specification of `package_group.packages`, i.e. the directories
+    /// of a package path, the `...` wildcard for its subpackages, or `public` and `private`.
+    /// Specifications starting with `-` exclude packages, and are completed the same way.
+    fn package_completions(
+        &self,
+        document_uri: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let (negation, value) = match current_value.strip_prefix('-') {
+            Some(value) => ("-", value),
+            None => ("", current_value),
+        };
+
+        let mut completions = match value.rfind('/') {
+            Some(pos) if value.starts_with("//") => {
+                let root = &value[..pos + 1];
+                let mut directories = Vec::new();
+                match workspace {
+                    Some(workspace) if self.options.query_only_completions => {
+                        directories = self.query_label_completions(
+                            document_uri,
+                            workspace,
+                            StringCompletionType::String,
+                            value,
+                            true,
+                        );
+                    }
+                    _ => {
+                        let _ = self.get_filesystem_entries(
+                            FilesystemCompletionRoot::String(root),
+                            document_uri,
+                            workspace,
+                            &FilesystemCompletionOptions {
+                                partial_name: &value[root.len()..],
+                                directories: true,
+                                files: FilesystemFileCompletionOptions::None,
+                                targets: false,
+                                annotate_exports: false,
+                            },
+                            &mut directories,
+                        );
+                    }
+                }
+                directories.extend(completion::target_pattern_completions(value, true));
+                directories
+            }
+            Some(_) => Vec::new(),
+            None if negation.is_empty() => completion::package_specification_completions(value),
+            None => Vec::new(),
+        };
+
+        for completion in &mut completions {
+            completion.insert_text_offset += negation.len();
+        }
+        completions
+    }
+
+    /// Completes a `package_group` label, e.g. for `package_group.includes`. Until the package
+    /// is typed, i.e. up to the `:`, its directories are offered, so that only the
+    /// `package_group`s of a single package are queried.
+    fn package_group_completions(
+        &self,
+        document_uri: &LspUrl,
+        workspace: Option<&BazelWorkspace>,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let (package, partial_name) = match current_value.split_once(':') {
+            Some(split) => split,
+            None if current_value.starts_with("//") => {
+                return self
+                    .package_completions(document_uri, workspace, current_value)
+                    .into_iter()
+                    .filter(|completion| completion.value != "...")
+                    .collect();
+            }
+            None => return Vec::new(),
+        };
+
+        self.package_group_targets(document_uri, workspace, package)
+            .into_iter()
+            .filter(|name| name.starts_with(partial_name))
+            .map(|name| BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                insert_text: Some(name.clone()),
+                value: name,
+                insert_text_offset: package.len() + 1,
+                detail: Some("`package_group`".to_owned()),
+            })
+            .collect()
+    }
+
+    /// Clears cached information that depends on the contents of a file, so that changes to
+    /// the file are picked up. Changes to a BUILD file only affect its own package, while
+    /// changes to a `.bzl` file may affect the targets of any package.
+    ///
+    /// This is called when a document is saved, since Bazel only sees the contents on disk.
+    pub(crate) fn invalidate(&self, uri: &LspUrl) {
+        self.loaded_modules.borrow_mut().remove(uri);
+
+        match FileType::from_lsp_url(uri) {
+            FileType::Build => {
+                if let Some(package_dir) = uri.path().parent() {
+                    self.package_targets.borrow_mut().remove(package_dir);
+                    self.testonly_targets.borrow_mut().remove(package_dir);
+                }
+                let build_file = match uri {
+                    LspUrl::File(path) => Some(path.as_path()),
+                    _ => None,
+                };
+                self.invalidate_workspace_targets(build_file);
+                self.failed_queries.borrow_mut().clear();
+            }
+            FileType::Library | FileType::Configuration => {
+                if let LspUrl::File(path) = uri {
+                    for index in self.export_indexes.borrow_mut().values_mut() {
+                        index.update_file(path);
+                    }
+                }
+                self.package_targets.borrow_mut().clear();
+                self.testonly_targets.borrow_mut().clear();
+                self.invalidate_workspace_targets(None);
+                self.failed_queries.borrow_mut().clear();
+            }
+            FileType::Bazelrc | FileType::Unknown => {
+                if uri.path().file_name() == Some(OsStr::new("MODULE.bazel")) {
+                    self.repo_mappings.borrow_mut().clear();
+                    self.module_dependencies.borrow_mut().clear();
+                }
+            }
+        }
+    }
+
+    /// Associates an in-memory module with a path on disk, so that labels in it are resolved as
+    /// if it were the file at that path, e.g. relative labels from its package.
+    pub(crate) fn associate_path(&self, uri: &LspUrl, path: PathBuf) {
+        self.virtual_paths.borrow_mut().insert(uri.clone(), path);
+    }
+
+    /// Returns the file that a module stands for, which is the module itself unless it is an
+    /// in-memory module with an associated path, see [`Self::associate_path()`].
+    fn file_url<'a>(&self, uri: &'a LspUrl) -> Cow<'a, LspUrl> {
+        match uri {
+            LspUrl::Starlark(_) => match self.virtual_paths.borrow().get(uri) {
+                Some(path) => Cow::Owned(LspUrl::File(path.clone())),
+                None => Cow::Borrowed(uri),
+            },
+            _ => Cow::Borrowed(uri),
+        }
+    }
+
+    /// Clears all cached information, e.g. after BUILD files were changed outside of the
+    /// editor by `git pull`. Workspaces are re-created with fresh `bazel info`, and packages
+    /// are re-queried and workspaces re-indexed when they are needed next. The contents of open
+    /// documents are kept.
+    pub(crate) fn refresh(&self) {
+        self.workspaces.borrow_mut().clear();
+        self.loaded_modules.borrow_mut().clear();
+        self.package_targets.borrow_mut().clear();
+        self.testonly_targets.borrow_mut().clear();
+        self.build_file_hashes.borrow_mut().clear();
+        self.repo_mappings.borrow_mut().clear();
+        self.module_dependencies.borrow_mut().clear();
+        self.failed_queries.borrow_mut().clear();
+        self.workspace_targets.borrow_mut().clear();
+        self.export_indexes.borrow_mut().clear();
+        self.bazel_globals.borrow_mut().clear();
+        self.globals_names.borrow_mut().clear();
+        self.deprecations.borrow_mut().clear();
+    }
+
+    /// Finds the statement that declares a repository, i.e. its `bazel_dep` in `MODULE.bazel`
+    /// or its repository rule in `WORKSPACE`.
+    fn resolve_repository_declaration(
+        &self,
+        repository: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Option<ResolvedStringLiteral>> {
+        let workspace = self.workspace(workspace_root, current_file)?;
+        let root = match (&workspace, workspace_root) {
+            (Some(workspace), _) => workspace.root.clone(),
+            (None, Some(workspace_root)) => workspace_root.to_owned(),
+            (None, None) => return Ok(None),
+        };
+
+        for file_name in REPOSITORY_DECLARATION_FILE_NAMES {
+            let path = root.join(file_name);
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+
+            let declared = AstModule::parse(file_name, contents, &Dialect::Extended)
+                .ok()
+                .and_then(|ast| ast::find_repository_declaration(&ast, repository))
+                .is_some();
+
+            if declared {
+                return Ok(Some(ResolvedStringLiteral {
+                    url: Url::from_file_path(path).unwrap().try_into()?,
+                    location: Some(StringLiteralLocation::RepositoryDeclaration(
+                        repository.to_owned(),
+                    )),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds the `MODULE.bazel` file of a module, if `literal` is the name of a `bazel_dep` in the
+    /// `MODULE.bazel` file of the workspace.
+    fn resolve_module_dependency(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self
+            .module_dependency(literal, current_file, workspace_root)?
+            .and_then(|(_, path)| path)
+            .map(|path| path.join("MODULE.bazel"))
+            .filter(|path| path.is_file()))
+    }
+
+    /// Renders hover content for the name of a `bazel_dep`, showing the resolved version of
+    /// the module, how the root module depends on it, and where its sources are.
+    pub(crate) fn module_dependency_hover(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> Option<String> {
+        let (dependency, path) = self
+            .module_dependency(literal, current_file, workspace_root)
+            .ok()??;
+        let workspace = self.workspace(workspace_root, current_file).ok().flatten();
+
+        // Like Bazel, overridden modules without a version are shown as `name@_`.
+        let version = match dependency.version.as_str() {
+            "" => "_",
+            version => version,
+        };
+        let mut hover = format!(
+            "**{}** `{}`\n\nDependency path: `<root>` → `{}@{}`",
+            dependency.name, version, dependency.name, version
+        );
+        match path {
+            Some(path) => hover.push_str(&format!(
+                "\n\nSources in `{}`",
+                self.display_path(&path, workspace.as_deref())
+            )),
+            None => hover.push_str("\n\nThe module hasn't been fetched yet"),
+        }
+
+        Some(hover)
+    }
+
+    /// Makes a label absolute, so that it can be passed to bazel regardless of the package it
+    /// was written in.
+    fn absolute_label(
+        &self,
+        label: &Label,
+        current_file: &LspUrl,
+        workspace: &BazelWorkspace,
+    ) -> Option<Label> {
+        let current_path = match current_file {
+            LspUrl::File(path) => Some(path.as_path()),
+            _ => None,
+        };
+        let current_repository =
+            current_path.and_then(|path| workspace.get_repository_for_path(path));
+
+        let repo = label.repo.clone().or_else(|| {
+            current_repository.as_ref().map(|(name, _)| LabelRepo {
+                name: name.to_string(),
+                is_canonical: true,
+            })
+        });
+
+        let package = match &label.package {
+            Some(package) => package.clone(),
+            None => {
+                let package_path = match &current_repository {
+                    Some((_, path)) => path.parent()?,
+                    None => current_path?.parent()?.strip_prefix(&workspace.root).ok()?,
+                };
+                label::label_path(package_path)
+            }
+        };
+
+        Some(Label {
+            repo,
+            package: Some(package),
+            name: label.name.clone(),
+        })
+    }
+
+    /// Completes the command or option at a position in a `.bazelrc`, see
+    /// [`completion::bazelrc_completions()`].
+    pub(crate) fn bazelrc_completions(
+        &self,
+        uri: &LspUrl,
+        position: ResolvedPos,
+    ) -> Vec<BazelCompletion> {
+        if FileType::from_lsp_url(uri) != FileType::Bazelrc {
+            return Vec::new();
+        }
+        let documents = self.documents.borrow();
+        let line = match documents
+            .get(uri)
+            .and_then(|contents| contents.lines().nth(position.line))
+        {
+            Some(line) => line,
+            None => return Vec::new(),
+        };
+        let line_prefix: String = line.chars().take(position.column).collect();
+
+        completion::bazelrc_completions(&line_prefix)
+    }
+
+    /// Builds the target under the cursor: the label at `position` in `uri`, or otherwise the
+    /// target whose definition encloses `position` in a BUILD file. Errors and warnings in the
+    /// output of the build are parsed into diagnostics where possible.
+    pub(crate) fn build_target_at(
+        &self,
+        uri: &LspUrl,
+        position: ResolvedPos,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<BuildReport> {
+        let ast = self
+            .document_ast(uri)
+            .ok_or_else(|| anyhow!("Cannot parse `{}`", uri))?;
+        let target = match ast::find_string_literal_at(&ast, position).filter(|literal| {
+            literal.starts_with(':') || literal.starts_with("//") || literal.starts_with('@')
+        }) {
+            Some(label) => label,
+            None if FileType::from_lsp_url(uri) == FileType::Build => {
+                ast::find_enclosing_target(&ast, position)
+                    .map(|name| format!(":{}", name))
+                    .ok_or_else(|| anyhow!("There is no target under the cursor"))?
+            }
+            None => return Err(anyhow!("There is no target under the cursor")),
+        };
+
+        let workspace = self
+            .workspace(workspace_root, uri)?
+            .ok_or_else(|| anyhow!("Cannot build `{}` outside of a workspace", target))?;
+        let label = self
+            .absolute_label(&Label::parse(&target)?, uri, &workspace)
+            .ok_or_else(|| anyhow!("Cannot find the package of `{}`", target))?
+            .to_string();
+
+        let output = self.online_client()?.build(&workspace, &label)?;
+        Ok(BuildReport {
+            diagnostics: build_diagnostics(&output.stderr, &workspace.root),
+            label,
+            success: output.success,
+            output: output.stderr,
+        })
+    }
+
+    /// Resolves a label written in `current_file` to its canonical form, e.g.
+    /// `@rules_rust//rust:defs.bzl` to `@@rules_rust~0.36.2//rust:defs.bzl`, by applying the
+    /// repo mapping of the current repository. Relative labels are made absolute first.
+    pub(crate) fn canonicalize_label(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<String> {
+        let workspace = self
+            .workspace(workspace_root, current_file)?
+            .ok_or_else(|| anyhow!("Cannot canonicalize `{}` outside of a workspace", literal))?;
+        let mut label = self
+            .absolute_label(&Label::parse(literal)?, current_file, &workspace)
+            .ok_or_else(|| anyhow!("Cannot find the package of `{}`", literal))?;
+
+        let canonical_name = match &label.repo {
+            // Labels without a repository are in the main repository.
+            None => String::new(),
+            Some(repository) if repository.is_canonical => repository.name.clone(),
+            Some(repository) => {
+                let repo_mapping = self
+                    .repo_mapping_for_file(&workspace, current_file)
+                    .unwrap_or_default();
+
+                match repo_mapping.get(&repository.name) {
+                    Some(canonical_name) => canonical_name.clone(),
+                    None if workspace.workspace_name.as_ref() == Some(&repository.name) => {
+                        String::new()
+                    }
+                    // Without a repo mapping, apparent names are the canonical names.
+                    None if repo_mapping.is_empty()
+                        || BUILTIN_REPOSITORY_NAMES.contains(&repository.name.as_str()) =>
+                    {
+                        repository.name.clone()
+                    }
+                    None => {
+                        return Err(ResolveLoadError::UnknownRepository(
+                            label.clone(),
+                            repository.name.clone(),
+                            workspace.bzlmod_enabled,
+                        )
+                        .into())
+                    }
+                }
+            }
+        };
+
+        label.repo = Some(LabelRepo {
+            name: canonical_name,
+            is_canonical: true,
+        });
+
+        Ok(label.to_string())
+    }
+
+    /// Renders hover content for a label. Labels of files show the path of the file, while
+    /// labels of targets show a summary of the target, see [`Self::target_hover()`], and the
+    /// BUILD file that defines it. Labels that resolve to neither show the path they would have,
+    /// and that it doesn't exist.
+    pub(crate) fn label_hover(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> Option<String> {
+        let label = Label::parse(literal).ok()?;
+        if label.is_target_pattern() {
+            let package = label::target_pattern_package(literal)?;
+            let package = if package.is_empty() {
+                "this package".to_owned()
+            } else {
+                format!("`{}`", package)
+            };
+            return Some(if literal.contains("...") {
+                format!("All targets in {} and the packages below it", package)
+            } else {
+                format!("All targets in {}", package)
+            });
+        }
+        let workspace = self.workspace(workspace_root, current_file).ok().flatten();
+
+        let folder = self
+            .resolve_folder(&label, current_file, workspace.as_deref())
+            .ok()?;
+        let path = folder.join(&label.name);
+        if path.is_file() {
+            return Some(format!(
+                "`{}`",
+                self.display_path(&path, workspace.as_deref())
+            ));
+        }
+
+        let build_file = FileType::BUILD_FILE_NAMES
+            .iter()
+            .map(|name| folder.join(name))
+            .find(|build_file| build_file.is_file());
+        match build_file {
+            Some(build_file) => {
+                let defined_in = format!(
+                    "Defined in `{}`",
+                    self.display_path(&build_file, workspace.as_deref())
+                );
+                Some(
+                    match self.target_hover(literal, current_file, workspace_root) {
+                        Some(summary) => format!("{}\n\n{}", summary.trim_end(), defined_in),
+                        None => defined_in,
+                    },
+                )
+            }
+            None => Some(format!(
+                "`{}` (doesn't exist)",
+                self.display_path(&path, workspace.as_deref())
+            )),
+        }
+    }
+
+    /// Formats a path to show to the user. With [`ContextOptions::relative_paths`], paths in
+    /// the workspace are shown relative to its root, and paths in external repositories
+    /// relative to the output base.
+    fn display_path(&self, path: &Path, workspace: Option<&BazelWorkspace>) -> String {
+        if self.options.relative_paths {
+            if let Some(workspace) = workspace {
+                if let Ok(relative) = path.strip_prefix(&workspace.root) {
+                    return relative.to_string_lossy().into_owned();
+                }
+                if let Ok(relative) = path.strip_prefix(&workspace.external_output_base) {
+                    return Path::new("external")
+                        .join(relative)
+                        .to_string_lossy()
+                        .into_owned();
+                }
+            }
+        }
+
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Returns the string literal at a position in a document, e.g. a label.
+    pub(crate) fn label_at(&self, uri: &LspUrl, position: Position) -> Option<String> {
+        let ast = self.document_ast(uri)?;
+
+        ast::find_string_literal_at(
+            &ast,
+            ResolvedPos {
+                line: position.line as usize,
+                column: position.character as usize,
+            },
+        )
+    }
+
+    /// Renders the hover for a position in a document, if it's on a label or a `select()`.
+    /// Returns `None` otherwise, so that the server falls back to the hover of `starlark_lsp`.
+    pub(crate) fn hover(&self, uri: &LspUrl, position: Position) -> Option<Hover> {
+        let ast = self.document_ast(uri)?;
+        let position = ResolvedPos {
+            line: position.line as usize,
+            column: position.character as usize,
+        };
+
+        let value = ast::find_string_literal_at(&ast, position)
+            .and_then(|literal| {
+                self.module_dependency_hover(&literal, uri, None)
+                    .or_else(|| self.label_hover(&literal, uri, None))
+            })
+            .or_else(|| self.select_hover(uri, position, None))?;
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+
+    /// Renders hover content for a label that refers to a target rather than a file, showing
+    /// the target's rule kind and a summary of its attributes. Returns `None` if the label
+    /// refers to a file, or if the target can't be queried.
+    pub(crate) fn target_hover(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> Option<String> {
+        let label = Label::parse(literal).ok()?;
+        let workspace = self.workspace(workspace_root, current_file).ok()??;
+
+        let folder = self
+            .resolve_folder(&label, current_file, Some(&workspace))
+            .ok()?;
+        if folder.join(&label.name).exists() {
+            return None;
+        }
+
+        let label = self
+            .absolute_label(&label, current_file, &workspace)?
+            .to_string();
+        let output = self
+            .run_query(&format!("--output=build {label}"), |client| {
+                client.query_build(&workspace, &label)
+            })
+            .ok()?;
+
+        Some(BuildTarget::parse(&output)?.to_markdown(&label))
+    }
+
+    /// Renders hover content for the `select()` at a position, showing the value that the
+    /// attribute it's in has in the configuration of [`ContextOptions::cquery_flags`]. Returns
+    /// `None` if there is no `select()` at the position, or if the target can't be queried.
+    pub(crate) fn select_hover(
+        &self,
+        uri: &LspUrl,
+        position: ResolvedPos,
+        workspace_root: Option<&Path>,
+    ) -> Option<String> {
+        let ast = self.document_ast(uri)?;
+        let (target, attribute) = ast::find_select_at(&ast, position)?;
+        let workspace = self.workspace(workspace_root, uri).ok()??;
+
+        let label = self
+            .absolute_label(
+                &Label::parse(&format!(":{}", target)).ok()?,
+                uri,
+                &workspace,
+            )?
+            .to_string();
+        let output = self
+            .online_client()
+            .ok()?
+            .cquery_build(&workspace, &label, &self.options.cquery_flags)
+            .ok()?;
+        let (_, value) = BuildTarget::parse(&output)?
+            .attributes
+            .into_iter()
+            .find(|(name, _)| name == attribute)?;
+
+        let configuration = if self.options.cquery_flags.is_empty() {
+            "the default configuration".to_owned()
+        } else {
+            format!("`{}`", self.options.cquery_flags.join(" "))
+        };
+        Some(format!(
+            "`{}` of `{}` resolves to `{}` in {}",
+            attribute, label, value, configuration
+        ))
+    }
+
+    /// Completes a symbol of a `load` statement with the public top-level bindings of the
+    /// loaded module that aren't loaded yet. Returns `None` if the string being completed isn't
+    /// a symbol of a `load` statement.
+    fn load_symbol_completions(
+        &self,
+        document_uri: &LspUrl,
+        current_value: &str,
+    ) -> Option<Vec<BazelCompletion>> {
+        let ast = self.document_ast(document_uri)?;
+        let load = ast::loads(&ast).into_iter().find(|load| {
+            load.symbols
+                .iter()
+                .any(|symbol| symbol.their == current_value)
+        })?;
+
+        let symbols = match self.loaded_module_symbols(load.module, document_uri) {
+            Some(symbols) => symbols,
+            None => return Some(Vec::new()),
+        };
+        let mut symbols: Vec<&String> = symbols
+            .iter()
+            .filter(|symbol| {
+                symbol.starts_with(current_value)
+                    && !symbol.starts_with('_')
+                    && !load.symbols.iter().any(|loaded| {
+                        loaded.their != current_value && loaded.their == symbol.as_str()
+                    })
+            })
+            .collect();
+        symbols.sort();
+
+        Some(
+            symbols
+                .into_iter()
+                .map(|symbol| BazelCompletion {
+                    category: CompletionCategory::Value,
+                    value: symbol.clone(),
+                    insert_text: None,
+                    insert_text_offset: 0,
+                    detail: Some(format!("Exported by `{}`", load.module)),
+                })
+                .collect(),
+        )
+    }
+
+    /// Completes the `name` of a target with the conventional names: the name of the package
+    /// directory, followed by the stems of the source files in the package, e.g. `foo` for
+    /// `foo.cc`.
+    fn target_name_completions(
+        &self,
+        document_uri: &LspUrl,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let package_dir = match document_uri.path().parent() {
+            Some(package_dir) => package_dir,
+            None => return Vec::new(),
+        };
+
+        let mut names = Vec::new();
+        if let Some(package_name) = package_dir.file_name() {
+            names.push((
+                package_name.to_string_lossy().into_owned(),
+                "The name of the package".to_owned(),
+            ));
+        }
+
+        let mut stems: Vec<(String, String)> = fs::read_dir(package_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && FileType::from_path(path) != FileType::Build)
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_string_lossy().into_owned();
+                let file_name = path.file_name()?.to_string_lossy().into_owned();
+
+                Some((stem, format!("Named after `{}`", file_name)))
+            })
+            .collect();
+        stems.sort();
+        names.extend(stems);
+
+        let mut seen = HashSet::new();
+        names
+            .into_iter()
+            .filter(|(name, _)| {
+                name.starts_with(current_value)
+                    && !name.starts_with('.')
+                    && seen.insert(name.clone())
+            })
+            .map(|(name, detail)| BazelCompletion {
+                category: CompletionCategory::Value,
+                value: name,
+                insert_text: None,
+                insert_text_offset: 0,
+                detail: Some(detail),
+            })
+            .collect()
+    }
+
+    /// Completes the packages and targets of a label from `bazel query`, rather than from the
+    /// filesystem like [`Self::get_filesystem_entries()`]. Packages are found from their BUILD
+    /// files, see [`Self::query_build_files()`], and relative labels are completed in the
+    /// package of `document_uri`.
+    fn query_label_completions(
+        &self,
+        document_uri: &LspUrl,
+        workspace: &BazelWorkspace,
+        kind: StringCompletionType,
+        current_value: &str,
+        directories: bool,
+    ) -> Vec<BazelCompletion> {
+        let mut completions = Vec::new();
+        // The length of the part of the value that is kept, e.g. `//foo:` for `//foo:ba`.
+        let mut base_len = 0;
+
+        if directories && current_value.contains("//") {
+            // NOTE: Safe to `unwrap()` here, since the value contains a `/`.
+            let base = &current_value[..current_value.rfind('/').unwrap() + 1];
+            base_len = base.len();
+            // Listing the packages of a repository root queries the whole repository, so that
+            // waits until the user starts typing a package, and the client is told to ask again.
+            if base.ends_with("//") && current_value.len() == base.len() {
+                self.last_string_completions.borrow_mut().is_incomplete = true;
+                return Vec::new();
+            }
+            let packages = self.query_build_files(workspace, base);
+
+            let mut names: Vec<&str> = packages
+                .iter()
+                .filter_map(|build_file| {
+                    let (package, name) = build_file.strip_prefix(base)?.split_once(':')?;
+                    FileType::BUILD_FILE_NAMES
+                        .contains(&name)
+                        .then_some(package)
+                })
+                .filter_map(|package| package.split('/').next())
+                .filter(|name| !name.is_empty())
+                .collect();
+            names.sort();
+            names.dedup();
+
+            completions.extend(names.into_iter().map(|name| BazelCompletion {
+                category: CompletionCategory::Directory,
+                value: name.to_owned(),
+                insert_text: Some(name.to_owned()),
+                insert_text_offset: base.len(),
+                detail: None,
+            }));
+        } else if !directories || !current_value.contains('/') {
+            // Relative labels are queried in the package of the document.
+            let base = current_value
+                .rfind(':')
+                .map_or("", |pos| &current_value[..pos + 1]);
+            base_len = base.len();
+            let module = if base.is_empty() || base == ":" {
+                let package_dir = match document_uri
+                    .path()
+                    .parent()
+                    .and_then(|dir| dir.strip_prefix(&workspace.root).ok())
+                {
+                    Some(package_dir) => package_dir,
+                    None => return Vec::new(),
+                };
+                format!("//{}:", label::label_path(package_dir))
+            } else {
+                base.to_owned()
+            };
+            let package_dir = match Label::parse(&module)
+                .and_then(|label| self.resolve_folder(&label, document_uri, Some(workspace)))
+            {
+                Ok(package_dir) => package_dir,
+                Err(_) => return Vec::new(),
+            };
+
+            let targets = self
+                .query_buildable_targets(&package_dir, &module, Some(workspace))
+                .unwrap_or_default();
+            completions.extend(targets.into_iter().filter_map(|target| {
+                // Files have no rule, and only `.bzl` files can be loaded.
+                let category = match (&target.rule, kind) {
+                    (Some(rule), StringCompletionType::String) => {
+                        CompletionCategory::Target(TargetKind::from_label_kind(rule))
+                    }
+                    (None, StringCompletionType::String) => CompletionCategory::File,
+                    (None, StringCompletionType::LoadPath)
+                        if FileType::from_path(&target.name) == FileType::Library =>
+                    {
+                        CompletionCategory::File
+                    }
+                    _ => return None,
+                };
+                let separator = if base.is_empty()
+                    && (category != CompletionCategory::File
+                        || kind == StringCompletionType::LoadPath)
+                {
+                    ":"
+                } else {
+                    ""
+                };
+
+                Some(BazelCompletion {
+                    category,
+                    insert_text: Some(format!("{}{}", separator, target.name)),
+                    value: target.name,
+                    insert_text_offset: base.len(),
+                    detail: target.rule.map(|rule| format!("`{rule}`")),
+                })
+            }));
+        }
+
+        let partial_name = &current_value[base_len..];
+        let mut completions: Vec<(u32, BazelCompletion)> = completions
+            .into_iter()
+            .filter_map(|completion| {
+                Some((
+                    completion::fuzzy_score(partial_name, &completion.value)?,
+                    completion,
+                ))
+            })
+            .collect();
+        completions.sort_by(|(a_score, a), (b_score, b)| {
+            (Reverse(a_score), a.category, &a.value).cmp(&(Reverse(b_score), b.category, &b.value))
+        });
+        let mut completions: Vec<BazelCompletion> = completions
+            .into_iter()
+            .map(|(_, completion)| completion)
+            .collect();
+        if let Some(max) = self.options.max_filesystem_completions {
+            self.truncate_completions(&mut completions, max);
+        }
+
+        completions
+    }
+
+    /// Lists the BUILD files of the packages below `base`, e.g. `//foo/`, with `buildfiles()`.
+    /// If the packages below a directory containing `base` were already listed, they are
+    /// filtered instead of running another query.
+    fn query_build_files(&self, workspace: &BazelWorkspace, base: &str) -> Vec<String> {
+        let cached = self
+            .workspace_targets
+            .borrow()
+            .get(&workspace.root)
+            .and_then(|queries| {
+                queries.iter().find_map(|(query, build_files)| {
+                    let scope = query.strip_prefix("buildfiles(")?.strip_suffix("...)")?;
+                    base.starts_with(scope).then(|| {
+                        build_files
+                            .iter()
+                            .filter(|build_file| build_file.starts_with(base))
+                            .cloned()
+                            .collect()
+                    })
+                })
+            });
+
+        cached.unwrap_or_else(|| {
+            self.query_workspace_targets(workspace, &format!("buildfiles({base}...)"))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Clears the cached targets of workspaces, see [`Self::query_workspace_targets()`].
+    /// Listings of packages, see [`Self::query_build_files()`], are kept unless `build_file`
+    /// is a BUILD file they don't contain yet, since editing a package doesn't add or remove
+    /// packages.
+    fn invalidate_workspace_targets(&self, build_file: Option<&Path>) {
+        for (root, queries) in self.workspace_targets.borrow_mut().iter_mut() {
+            let label = build_file
+                .and_then(|build_file| build_file.strip_prefix(root).ok())
+                .and_then(|relative| {
+                    Some(format!(
+                        "//{}:{}",
+                        label::label_path(relative.parent()?),
+                        relative.file_name()?.to_str()?
+                    ))
+                });
+            queries.retain(|query, targets| {
+                query.starts_with("buildfiles(")
+                    && label.as_ref().map_or(true, |label| targets.contains(label))
+            });
+        }
+    }
+
+    /// Leaves out the completions after the first `max`, and remembers that the completions
+    /// are incomplete, see [`StringCompletions::is_incomplete`].
+    fn truncate_completions(&self, completions: &mut Vec<BazelCompletion>, max: usize) {
+        if completions.len() > max {
+            completions.truncate(max);
+            self.last_string_completions.borrow_mut().is_incomplete = true;
+        }
+    }
+
+    /// Completes the directory part of a path relative to the package of `document_uri`, i.e.
+    /// everything up to the last `/` of `prefix`. The `suffix` is kept after the completed
+    /// directory, e.g. for the rest of a glob pattern. Directories containing another package
+    /// are skipped, since paths can't refer to files in other packages.
+    fn package_directory_completions(
+        &self,
+        document_uri: &LspUrl,
+        prefix: &str,
+        suffix: &str,
+    ) -> Vec<BazelCompletion> {
+        let package_dir = match document_uri.path().parent() {
+            Some(package_dir) => package_dir,
+            None => return Vec::new(),
+        };
+
+        let directory = prefix
+            .rfind('/')
+            .map(|pos| &prefix[..pos + 1])
+            .unwrap_or("");
+        let directory_path = package_dir.join(directory);
+
+        let mut results = Vec::new();
+        if self
+            .get_filesystem_entries(
+                FilesystemCompletionRoot::Path(&directory_path),
+                document_uri,
+                None,
+                &FilesystemCompletionOptions {
+                    partial_name: "",
+                    directories: true,
+                    files: FilesystemFileCompletionOptions::None,
+                    targets: false,
+                    annotate_exports: false,
+                },
+                &mut results,
+            )
+            .is_err()
+        {
+            return Vec::new();
+        }
+
+        results.retain(|completion| {
+            let path = directory_path.join(&completion.value);
+            !FileType::BUILD_FILE_NAMES
+                .iter()
+                .any(|build_file_name| path.join(build_file_name).exists())
+        });
+        for completion in &mut results {
+            completion.insert_text = Some(format!("{}/{}", completion.value, suffix));
+            completion.insert_text_offset = directory.len();
+        }
+        results.sort_by(|a, b| a.value.cmp(&b.value));
+
+        results
+    }
+
+    /// Finds the completions for a string, categorized by what they complete. This backs
+    /// [`LspContext::get_string_completion_options()`].
+    pub(crate) fn string_completions(
+        &self,
+        document_uri: &LspUrl,
+        kind: StringCompletionType,
+        current_value: &str,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Vec<BazelCompletion>> {
+        if kind == StringCompletionType::String {
+            if let Some(completions) = self.load_symbol_completions(document_uri, current_value) {
+                return Ok(completions);
+            }
+        }
+
+        let literal_context = match kind {
+            StringCompletionType::String => {
+                self.string_literal_context(document_uri, current_value)
+            }
+            StringCompletionType::LoadPath => None,
+        };
+        let rule_attribute = literal_context
+            .as_ref()
+            .and_then(|context| context.rule_attribute());
+
+        if let Some((_, "tags")) = rule_attribute {
+            return Ok(completion::tag_completions(current_value));
+        }
+
+        if let Some(("genrule", attribute)) = rule_attribute {
+            if completion::COMMAND_ATTRIBUTES.contains(&attribute) {
+                if let Some(completions) = completion::make_variable_completions(current_value) {
+                    return Ok(completions);
+                }
+            }
+        }
+
+        if let Some((_, "name")) = rule_attribute {
+            if FileType::from_lsp_url(document_uri) == FileType::Build {
+                return Ok(self.target_name_completions(document_uri, current_value));
+            }
+        }
+
+        if literal_context.as_ref().map_or(false, |context| {
+            context.is_dict_key && context.innermost_function() == Some("select")
+        }) {
+            let workspace = self.workspace(workspace_root, document_uri)?;
+            return Ok(self.select_condition_completions(workspace.as_deref(), current_value));
+        }
+
+        if let Some((_, attribute)) = rule_attribute {
+            if completion::VISIBILITY_ATTRIBUTES.contains(&attribute)
+                && (current_value.starts_with("//") || current_value.starts_with(':'))
+                && current_value.contains(':')
+            {
+                let workspace = self.workspace(workspace_root, document_uri)?;
+                return Ok(self.visibility_completions(
+                    document_uri,
+                    workspace.as_deref(),
+                    current_value,
+                ));
+            }
+        }
+
+        // The `packages` of a `package_group` are packages rather than labels, e.g. `//foo` or
+        // `//foo/...`, and its `includes` are other `package_group`s.
+        if let Some(("package_group", attribute @ ("packages" | "includes"))) = rule_attribute {
+            let workspace = self.workspace(workspace_root, document_uri)?;
+            return Ok(if attribute == "packages" {
+                self.package_completions(document_uri, workspace.as_deref(), current_value)
+            } else {
+                self.package_group_completions(document_uri, workspace.as_deref(), current_value)
+            });
+        }
+
+        // Outputs aren't labels, but paths relative to the package, e.g. `genrule.outs`. Since
+        // they are usually new files, only directories are offered.
+        if let Some((rule, attribute)) = rule_attribute {
+            if completion::is_output_attribute(rule, attribute) {
+                return Ok(self.package_directory_completions(document_uri, current_value, ""));
+            }
+        }
+
+        // Glob patterns are paths relative to the package as well. Only the part before the
+        // first wildcard is completed, and the rest of the pattern is kept intact.
+        if literal_context
+            .as_ref()
+            .and_then(|context| context.innermost_function())
+            == Some("glob")
+        {
+            let (prefix, suffix) = current_value.split_at(
+                current_value
+                    .find(|c| c == '*' || c == '?' || c == '[')
+                    .unwrap_or(current_value.len()),
+            );
+
+            return Ok(self.package_directory_completions(document_uri, prefix, suffix));
+        }
+
+        let workspace = self.workspace(workspace_root, document_uri)?;
+
+        let offer_repository_names = current_value.is_empty()
+            || current_value == "@"
+            || (current_value.starts_with('@') && !current_value.contains('/'))
+            || (!current_value.contains('/') && !current_value.contains(':'));
+
+        // With Bzlmod, the repositories that are visible from the current file are the ones in
+        // its repo mapping. In `WORKSPACE` mode, they are found in the output base instead.
+        let repo_mapping = workspace
+            .as_deref()
+            .filter(|ws| ws.bzlmod_enabled)
+            .and_then(|ws| self.repo_mapping_for_file(ws, document_uri).ok());
+
+        let mut names = if offer_repository_names {
+            if let Some(workspace) = &workspace {
+                let repo_names = match &repo_mapping {
+                    Some(repo_mappings) => {
+                        let mut names: Vec<Cow<str>> = repo_mappings
+                            .keys()
+                            .filter(|key| *key != "")
+                            .map(|key| Cow::Borrowed(key.deref()))
+                            .collect();
+                        // Repositories declared in `WORKSPACE` aren't part of the repo mapping.
+                        for name in workspace
+                            .declared_repositories
+                            .iter()
+                            .map(String::as_str)
+                            .chain(BUILTIN_REPOSITORY_NAMES)
+                        {
+                            if !repo_mappings.contains_key(name)
+                                && !names.contains(&Cow::Borrowed(name))
+                            {
+                                names.push(Cow::Borrowed(name));
+                            }
+                        }
+                        names
+                    }
+                    None => workspace.get_repository_names(),
+                };
+
+                repo_names
+                    .into_iter()
+                    .map(|name| {
+                        let name_with_at = format!("@{}", name);
+                        let insert_text = format!("{}//", &name_with_at);
+
+                        BazelCompletion {
+                            category: CompletionCategory::Repository,
+                            value: name_with_at,
+                            insert_text: Some(insert_text),
+                            insert_text_offset: 0,
+                            detail: None,
+                        }
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            }
+        } else {
+            Vec::new()
+        };
+
+        // Complete filenames if we're not in the middle of typing a repository name:
+        // "@foo" -> don't complete filenames (still typing repository)
+        // "@foo/" -> don't complete filenames (need two separating slashes)
+        // "@foo//", "@foo//bar -> complete directories (from `@foo//`)
+        // "@foo//bar/baz" -> complete directories (from `@foo//bar`)
+        // "@foo//bar:baz" -> complete filenames (from `@foo//bar`), and target names if `kind` is `String`
+        // "foo" -> complete directories and filenames (ambiguous, might be a relative path or a repository)
+        let complete_directories = (!current_value.starts_with('@')
+            || current_value.contains("//"))
+            && !current_value.contains(':');
+        let complete_filenames =
+            // Still typing repository
+            (!current_value.starts_with('@') || current_value.contains("//")) &&
+            // Explicitly typing directory
+            (!current_value.contains('/') || current_value.contains(':'));
+        let complete_targets = kind == StringCompletionType::String && complete_filenames;
+
+        // Some attributes only accept particular kinds of files, e.g. `proto_library.srcs`.
+        let extension_preference = rule_attribute
+            .and_then(|(rule, attribute)| completion::extension_preference(rule, attribute));
+
+        // Well-known repositories can be completed even if they haven't been fetched.
+        let known_completions = match kind {
+            StringCompletionType::String => completion::platforms_completions(current_value),
+            StringCompletionType::LoadPath => Vec::new(),
+        };
+
+        if (complete_directories || complete_filenames || complete_targets)
+            && self.options.query_only_completions
+        {
+            if let Some(workspace) = workspace.as_deref() {
+                names.extend(self.query_label_completions(
+                    document_uri,
+                    workspace,
+                    kind,
+                    current_value,
+                    complete_directories,
+                ));
+            }
+        } else if complete_directories || complete_filenames || complete_targets {
+            if let Some(completion_root) = if complete_directories && complete_filenames {
+                // This must mean we don't have a `/` or `:` separator, so we're completing a relative path.
+                // Use the document URI's directory as the base.
+                document_uri
+                    .path()
+                    .parent()
+                    .map(FilesystemCompletionRoot::Path)
+            } else {
+                // Complete from the last `:` or `/` in the current value.
+                current_value
+                    // NOTE: Can't use `rsplit_once` as we need the value _including_ the value
+                    // we're splitting on.
+                    .rfind(if complete_directories { '/' } else { ':' })
+                    .map(|pos| &current_value[..pos + 1])
+                    .map(FilesystemCompletionRoot::String)
+            } {
+                let partial_name = match completion_root {
+                    FilesystemCompletionRoot::Path(_) => current_value,
+                    FilesystemCompletionRoot::String(root) => &current_value[root.len()..],
+                };
+                let result = self.get_filesystem_entries(
+                    completion_root,
+                    document_uri,
+                    workspace.as_deref(),
+                    &FilesystemCompletionOptions {
+                        partial_name,
+                        directories: complete_directories,
+                        files: match (kind, complete_filenames) {
+                            (StringCompletionType::LoadPath, _) => {
+                                FilesystemFileCompletionOptions::OnlyLoadable
+                            }
+                            (StringCompletionType::String, true) => match extension_preference {
+                                Some(preference) if preference.exclusive => {
+                                    FilesystemFileCompletionOptions::WithExtensions(
+                                        preference.extensions,
+                                    )
+                                }
+                                Some(preference) => {
+                                    FilesystemFileCompletionOptions::PreferExtensions(
+                                        preference.extensions,
+                                    )
+                                }
+                                None => FilesystemFileCompletionOptions::All,
+                            },
+                            (StringCompletionType::String, false) => {
+                                FilesystemFileCompletionOptions::None
+                            }
+                        },
+                        targets: complete_targets,
+                        annotate_exports: rule_attribute.map_or(false, |(_, attribute)| {
+                            lint::DEPENDENCY_ATTRIBUTES.contains(&attribute)
+                        }),
+                    },
+                    &mut names,
+                );
+                if known_completions.is_empty() {
+                    result?;
+                }
+            }
+        }
+
+        for completion in known_completions {
+            if !names.iter().any(|name| name.value == completion.value) {
+                names.push(completion);
+            }
+        }
+
+        if kind == StringCompletionType::String {
+            let accepts_patterns = literal_context
+                .as_ref()
+                .and_then(|context| context.innermost_function())
+                .map_or(false, |function| {
+                    completion::TARGET_PATTERN_FUNCTIONS.contains(&function)
+                });
+            names.extend(completion::target_pattern_completions(
+                current_value,
+                accepts_patterns,
+            ));
+        }
+
+        // Sources are often aggregated by a `filegroup` in the same package, so these are
+        // offered before anything else.
+        if let Some((_, "srcs")) = rule_attribute {
+            let mut filegroups = self.sibling_filegroup_completions(document_uri, current_value);
+            names.retain(|name| {
+                !matches!(name.category, CompletionCategory::Target(_))
+                    || !filegroups
+                        .iter()
+                        .any(|filegroup| filegroup.value == name.value)
+            });
+            filegroups.append(&mut names);
+            names = filegroups;
+        }
+
+        Ok(names)
+    }
+
+    /// Completes the `filegroup`s defined in the same BUILD file as a relative label, except
+    /// for the one the literal being completed is passed to.
+    fn sibling_filegroup_completions(
+        &self,
+        document_uri: &LspUrl,
+        current_value: &str,
+    ) -> Vec<BazelCompletion> {
+        let partial_name = match current_value.strip_prefix(':') {
+            Some(partial_name) => partial_name,
+            None if current_value.is_empty() => current_value,
+            None => return Vec::new(),
+        };
+        let ast = match self.document_ast(document_uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+
+        let mut literal_span = None;
+        ast::visit_string_literals(&ast, |literal, span, _| {
+            if literal_span.is_none() && literal == current_value {
+                literal_span = Some(span);
+            }
+        });
+
+        ast::named_calls(&ast, "filegroup")
+            .into_iter()
+            .filter(|(name, span)| {
+                name.starts_with(partial_name)
+                    && !literal_span.map_or(false, |literal| span.contains(literal.begin()))
+            })
+            .map(|(name, _)| BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                value: name.to_owned(),
+                insert_text: Some(format!(":{}", name)),
+                insert_text_offset: 0,
+                detail: Some("`filegroup` in this package".to_owned()),
+            })
+            .collect()
+    }
+
+    fn get_build_language_proto(&self, uri: &LspUrl) -> anyhow::Result<Vec<u8>> {
+        let workspace = self
+            .workspace::<PathBuf>(None, uri)?
+            .ok_or_else(|| anyhow!("Cannot find workspace"))?;
+
+        self.online_client()?.build_language(&workspace)
+    }
+
+    /// Finds the major version of Bazel used for a file. This falls back to `.bazelversion` if
+    /// Bazel can't be reached, which is when the version is needed the most.
+    fn bazel_major_version(&self, uri: &LspUrl) -> Option<u32> {
+        let version = match self.workspace::<PathBuf>(None, uri) {
+            Ok(Some(workspace)) => workspace.bazel_version,
+            _ => find_workspace_root(uri)
+                .and_then(|root| BazelVersion::from_bazelversion_file(&root)),
+        };
+
+        version.map(|version| version.major)
+    }
+
+    /// Returns protos for bazel globals (like int, str, dir; but also e.g. cc_library, alias,
+    /// test_suite etc.), which are cached per workspace until [`Self::refresh()`].
+    fn get_bazel_globals(
+        &self,
+        uri: &LspUrl,
+    ) -> (Rc<builtin::BuildLanguage>, Rc<builtin::Builtins>) {
+        let workspace_root = find_workspace_root(uri);
+        if let Some(globals) = self.bazel_globals.borrow().get(&workspace_root) {
+            return globals.clone();
+        }
+
+        let language_proto = self.get_build_language_proto(uri);
+
+        let language_proto = language_proto
+            .as_deref()
+            .unwrap_or_else(|_| builtin::default_build_language(self.bazel_major_version(uri)));
+
+        let language = builtin::BuildLanguage::decode(&language_proto[..]).unwrap();
+
+        // TODO: builtins are also dependent on bazel version, but there is no way to obtain those,
+        // see https://github.com/bazel-contrib/vscode-bazel/issues/1.
+        let builtins_proto = include_bytes!(env!("BUILTIN_PB"));
+        let builtins = builtin::Builtins::decode(&builtins_proto[..]).unwrap();
+
+        let globals = (Rc::new(language), Rc::new(builtins));
+        self.bazel_globals
+            .borrow_mut()
+            .insert(workspace_root, globals.clone());
+        globals
+    }
+
+    fn try_get_environment(&self, uri: &LspUrl) -> anyhow::Result<DocModule> {
+        let file_type = FileType::from_lsp_url(uri);
+        let (language, builtins) = self.get_bazel_globals(uri);
+
+        // Rules can't be called from `.scl` files.
+        let rules = match file_type {
+            FileType::Configuration => None,
+            _ => Some(builtin::build_language_to_doc_members(&language)),
+        };
+
+        // `package()` is documented without its attributes, so it's replaced by a version that
+        // has them.
+        let package = match file_type {
+            FileType::Build => Some(("package".to_owned(), builtin::package_doc_member())),
+            _ => None,
+        };
+
+        let members: SmallMap<_, _> = rules
+            .into_iter()
+            .flatten()
+            .chain(builtin::builtins_to_doc_members(&builtins, file_type))
+            .chain(package)
+            .map(|(name, member)| (name, DocItem::Member(member)))
+            .collect();
+
+        Ok(DocModule {
+            docs: None,
+            members,
+        })
+    }
+
+    /// Provides signature help for the call at `position` in a document, by resolving the called
+    /// rule or function to its documentation. Calls of builtins, rules, and functions that
+    /// are defined in or loaded by the document are supported.
+    pub(crate) fn signature_help(&self, uri: &LspUrl, position: Position) -> Option<SignatureHelp> {
+        let contents = self.documents.borrow().get(uri)?.clone();
+        let dialect = FileType::from_lsp_url(uri).dialect();
+        let parse = |contents: String| {
+            AstModule::parse(&uri.path().to_string_lossy(), contents, &dialect).ok()
+        };
+
+        // The call is usually incomplete while typing its arguments, so if the document doesn't
+        // parse, try closing the call at the cursor.
+        let ast = parse(contents.clone()).or_else(|| {
+            let mut lines: Vec<String> = contents.split('\n').map(str::to_owned).collect();
+            let line = lines.get_mut(position.line as usize)?;
+            let offset = line
+                .char_indices()
+                .nth(position.character as usize)
+                .map(|(offset, _)| offset)
+                .unwrap_or(line.len());
+            line.insert(offset, ')');
+            parse(lines.join("\n"))
+        })?;
+
+        let call = ast::find_active_call(
+            &ast,
+            ResolvedPos {
+                line: position.line as usize,
+                column: position.character as usize,
+            },
+        )?;
+
+        let function = self.called_function_doc(uri, &ast, call.function)?;
+
+        Some(signature::signature_help(&function, &call))
+    }
+
+    /// Renames the local variable, function, or loaded symbol at `position` in a document. Only
+    /// the document itself is edited, unless a target is renamed, see [`Self::rename_target()`].
+    pub(crate) fn rename(
+        &self,
+        uri: &LspUrl,
+        position: Position,
+        new_name: &str,
+    ) -> anyhow::Result<WorkspaceEdit> {
+        let url = match uri {
+            LspUrl::File(path) => Url::from_file_path(path)
+                .map_err(|_| anyhow!("Cannot convert `{}` to a URL", path.display()))?,
+            _ => return Err(anyhow!("Cannot rename symbols in `{}`", uri)),
+        };
+        let ast = self
+            .document_ast(uri)
+            .ok_or_else(|| anyhow!("Cannot parse `{}`", uri))?;
+        let position = ResolvedPos {
+            line: position.line as usize,
+            column: position.character as usize,
+        };
+
+        if FileType::from_lsp_url(uri) == FileType::Build {
+            if let Some((name, range)) = rename::find_target_name(&ast, position) {
+                return self.rename_target(uri, &ast, &name, range, new_name);
+            }
+        }
+
+        let edits = rename::rename(&ast, position, new_name)?;
+
+        Ok(WorkspaceEdit {
+            changes: Some(HashMap::from([(url, edits)])),
+            ..Default::default()
+        })
+    }
+
+    /// Renames the target `name` defined in a BUILD file, whose `name` attribute is at `range`.
+    /// Labels referring to the target are updated in its own BUILD file, and in the BUILD files
+    /// of the packages that depend on it according to `rdeps()`. Since the latter can touch many
+    /// files, their edits are annotated with [`TARGET_REFERENCES_ANNOTATION`], which asks the
+    /// user for confirmation.
+    fn rename_target(
+        &self,
+        uri: &LspUrl,
+        ast: &AstModule,
+        name: &str,
+        range: Range,
+        new_name: &str,
+    ) -> anyhow::Result<WorkspaceEdit> {
+        if new_name.is_empty() || new_name.contains(':') {
+            return Err(rename::RenameError::InvalidName(new_name.to_owned()).into());
+        }
+
+        let workspace = self
+            .workspace::<PathBuf>(None, uri)?
+            .ok_or_else(|| anyhow!("Cannot find workspace"))?;
+        let label = self
+            .absolute_label(&Label::parse(name)?, uri, &workspace)
+            .ok_or_else(|| anyhow!("Cannot find the package of `{}`", uri))?;
+        let package = label.package.clone().unwrap_or_default();
+
+        let mut edits = vec![TextEdit {
+            range,
+            new_text: new_name.to_owned(),
+        }];
+        edits.extend(rename::rename_target_references(
+            ast, &package, &package, name, new_name,
+        ));
+        let mut document_changes = vec![text_document_edit(
+            uri,
+            edits.into_iter().map(OneOf::Left).collect(),
+        )?];
+
+        // Targets in external repositories can't be referenced from the workspace.
+        let dependents = match &label.repo {
+            Some(_) => String::new(),
+            None => self
+                .query_keep_going(&workspace, &format!("rdeps(//..., {}, 1)", label))
+                .unwrap_or_else(|| {
+                    eprintln!("Cannot find the dependents of `{}`", label);
+                    String::new()
+                }),
+        };
+        let mut dependent_packages: Vec<String> = dependents
+            .lines()
+            .filter_map(|dependent| Label::parse(dependent).ok()?.package)
+            .filter(|dependent_package| *dependent_package != package)
+            .collect();
+        dependent_packages.sort();
+        dependent_packages.dedup();
+
+        for dependent_package in dependent_packages {
+            let package_dir = workspace.root.join(&dependent_package);
+            let build_file = match FileType::BUILD_FILE_NAMES
+                .iter()
+                .map(|build_file_name| package_dir.join(build_file_name))
+                .find(|build_file| build_file.is_file())
+            {
+                Some(build_file) => LspUrl::File(build_file),
+                None => continue,
+            };
+            let ast = match self.document_ast(&build_file).or_else(|| {
+                AstModule::parse(
+                    &build_file.path().to_string_lossy(),
+                    fs::read_to_string(build_file.path()).ok()?,
+                    &Dialect::Extended,
+                )
+                .ok()
+            }) {
+                Some(ast) => ast,
+                None => continue,
+            };
+
+            let edits: Vec<_> = rename::rename_target_references(
+                &ast,
+                &dependent_package,
+                &package,
+                name,
+                new_name,
+            )
+            .into_iter()
+            .map(|text_edit| {
+                OneOf::Right(AnnotatedTextEdit {
+                    text_edit,
+                    annotation_id: TARGET_REFERENCES_ANNOTATION.to_owned(),
+                })
+            })
+            .collect();
+            if !edits.is_empty() {
+                document_changes.push(text_document_edit(&build_file, edits)?);
+            }
+        }
+
+        let has_references = document_changes.len() > 1;
+        Ok(WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(document_changes)),
+            change_annotations: has_references.then(|| {
+                HashMap::from([(
+                    TARGET_REFERENCES_ANNOTATION.to_owned(),
+                    ChangeAnnotation {
+                        label: format!("Update references to `{}` in other packages", label),
+                        needs_confirmation: Some(true),
+                        description: None,
+                    },
+                )])
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// Highlights the occurrences of the local variable, function, or loaded symbol at
+    /// `position` in a document, see [`rename::highlights()`].
+    pub(crate) fn document_highlights(
+        &self,
+        uri: &LspUrl,
+        position: Position,
+    ) -> Vec<DocumentHighlight> {
+        self.document_ast(uri)
+            .map(|ast| {
+                rename::highlights(
+                    &ast,
+                    ResolvedPos {
+                        line: position.line as usize,
+                        column: position.character as usize,
+                    },
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// Provides the folding ranges of a document, see [`folding::folding_ranges()`].
+    pub(crate) fn folding_ranges(&self, uri: &LspUrl) -> Vec<FoldingRange> {
+        self.document_ast(uri)
+            .map(|ast| folding::folding_ranges(&ast))
+            .unwrap_or_default()
+    }
+
+    /// Provides the selection range at each of `positions` in a document, see
+    /// [`selection::selection_range()`].
+    pub(crate) fn selection_ranges(
+        &self,
+        uri: &LspUrl,
+        positions: &[Position],
+    ) -> Vec<SelectionRange> {
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+
+        positions
+            .iter()
+            .map(|position| {
+                selection::selection_range(
+                    &ast,
+                    ResolvedPos {
+                        line: position.line as usize,
+                        column: position.character as usize,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Provides the semantic tokens of a document, see [`semantic_tokens::semantic_tokens()`].
+    pub(crate) fn semantic_tokens(&self, uri: &LspUrl) -> Option<SemanticTokens> {
+        let ast = self.document_ast(uri)?;
+        let (language, _) = self.get_bazel_globals(uri);
+        let rules: HashSet<String> = language.rule.iter().map(|rule| rule.name.clone()).collect();
+
+        Some(SemanticTokens {
+            result_id: None,
+            data: semantic_tokens::semantic_tokens(&ast, &rules),
+        })
+    }
+
+    /// Provides a link for every label in a document, e.g. `"//foo:bar.bzl"`. The targets of
+    /// the links are only resolved by [`Self::resolve_document_link()`], since resolving every
+    /// label of a large BUILD file up front may need many queries.
+    pub(crate) fn document_links(&self, uri: &LspUrl) -> Vec<DocumentLink> {
+        let url = match uri {
+            LspUrl::File(path) => match Url::from_file_path(path) {
+                Ok(url) => url,
+                Err(_) => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        let ast = match self.document_ast(uri) {
+            Some(ast) => ast,
+            None => return Vec::new(),
+        };
+
+        ast::label_literals(&ast)
+            .into_iter()
+            .filter(|(label, _)| {
+                label::looks_like_label(label, false) && Label::parse(label).is_ok()
+            })
+            .filter_map(|(label, span)| {
+                // The link covers the contents of the literal, but not its quotes.
+                let (line, start, end) = ast::string_literal_value_columns(&ast, span)?;
+                Some(DocumentLink {
+                    range: Range::new(
+                        Position::new(line as u32, start as u32),
+                        Position::new(line as u32, end as u32),
+                    ),
+                    target: None,
+                    tooltip: None,
+                    data: Some(serde_json::json!({
+                        "uri": url.as_str(),
+                        "label": label,
+                    })),
+                })
+            })
+            .collect()
+    }
+
+    /// Resolves the target of a link from [`Self::document_links()`] to the file its label
+    /// refers to. Labels that can't be resolved are returned without a target.
+    pub(crate) fn resolve_document_link(&self, mut link: DocumentLink) -> DocumentLink {
+        let data = link.data.as_ref();
+        let uri = data
+            .and_then(|data| data.get("uri")?.as_str())
+            .and_then(|uri| Url::parse(uri).ok())
+            .and_then(|url| LspUrl::try_from(url).ok());
+        let label = data.and_then(|data| data.get("label")?.as_str());
+        if let (Some(uri), Some(label)) = (uri, label) {
+            let workspace_root = self.lint_workspace_root(&uri);
+            link.target = match self.resolve_string_literal(label, &uri, workspace_root.as_deref())
+            {
+                Ok(Some(StringLiteralResult {
+                    url: LspUrl::File(path),
+                    ..
+                })) => Url::from_file_path(path).ok(),
+                _ => None,
+            };
+        }
+        link
+    }
+
+    /// Finds the declaration of the provider at `position` in a document, e.g. of `FooInfo` in
+    /// `dep[FooInfo]`. Providers declared in the document or in the module they are loaded from
+    /// resolve to their `provider()` assignment, and builtin providers to their documentation.
+    /// Providers that the loaded module itself loads from elsewhere aren't followed.
+    pub(crate) fn provider_type_definition(
+        &self,
+        uri: &LspUrl,
+        position: Position,
+    ) -> Option<Location> {
+        let ast = self.document_ast(uri)?;
+        let name = ast::find_identifier_at(
+            &ast,
+            ResolvedPos {
+                line: position.line as usize,
+                column: position.character as usize,
+            },
+        )?;
+        let location = |url: &LspUrl, span: ResolvedSpan| {
+            Some(Location {
+                uri: Url::from_file_path(url.path()).ok()?,
+                range: Range::new(
+                    Position::new(span.begin.line as u32, span.begin.column as u32),
+                    Position::new(span.end.line as u32, span.end.column as u32),
+                ),
+            })
+        };
+
+        if let Some(span) = ast::find_provider_declaration(&ast, name) {
+            return location(uri, span);
+        }
+
+        if let Some((module, symbol)) = ast::loads(&ast).iter().find_map(|load| {
+            load.symbols
+                .iter()
+                .find(|symbol| symbol.local == name)
+                .map(|symbol| (load.module, symbol.their))
+        }) {
+            let url = self
+                .resolve_load(module, uri, self.lint_workspace_root(uri).as_deref())
+                .ok()?;
+            let contents = self.get_load_contents(&url).ok()??;
+            let loaded_ast = AstModule::parse(
+                &url.path().to_string_lossy(),
+                contents,
+                &FileType::from_lsp_url(&url).dialect(),
+            )
+            .ok()?;
+
+            return location(&url, ast::find_provider_declaration(&loaded_ast, symbol)?);
+        }
+
+        // Builtin providers are types of the builtins, which are conventionally named `*Info`.
+        let (_, builtins) = self.get_bazel_globals(uri);
+        if name.ends_with("Info") && builtins.r#type.iter().any(|typ| typ.name == name) {
+            return Some(Location {
+                uri: Url::parse(&format!("https://bazel.build/rules/lib/providers/{}", name))
+                    .ok()?,
+                range: Range::default(),
+            });
+        }
+
+        None
+    }
+
+    /// Finds the documentation of a function called from a document.
+    fn called_function_doc(
+        &self,
+        uri: &LspUrl,
+        ast: &AstModule,
+        function: &str,
+    ) -> Option<DocFunction> {
+        if let Some(def) = ast::find_def(ast, function) {
+            return Some(signature::def_to_doc_function(ast, def));
+        }
+
+        if let Some((load, symbol)) = ast::loads(ast).iter().find_map(|load| {
+            load.symbols
+                .iter()
+                .find(|symbol| symbol.local == function)
+                .map(|symbol| (load.module.to_owned(), symbol.their.to_owned()))
+        }) {
+            let url = self
+                .resolve_load(&load, uri, self.lint_workspace_root(uri).as_deref())
+                .ok()?;
+            let contents = self.get_load_contents(&url).ok()??;
+            let loaded_ast = AstModule::parse(
+                &url.path().to_string_lossy(),
+                contents,
+                &FileType::from_lsp_url(&url).dialect(),
+            )
+            .ok()?;
+
+            return ast::find_def(&loaded_ast, &symbol)
+                .map(|def| signature::def_to_doc_function(&loaded_ast, def));
+        }
+
+        match self.get_environment(uri).members.get(function)? {
+            DocItem::Member(DocMember::Function(function)) => Some(function.clone()),
+            _ => None,
+        }
+    }
+
+    /// Takes the completions of the last call of
+    /// [`LspContext::get_string_completion_options()`], so that the server can add their
+    /// details to the completions of `starlark_lsp`, and mark them as incomplete.
+    pub(crate) fn take_string_completions(&self) -> StringCompletions {
+        self.last_string_completions.take()
+    }
+
+    /// Returns the text before a position on its line in a document.
+    pub(crate) fn line_prefix(&self, uri: &LspUrl, position: Position) -> Option<String> {
+        let documents = self.documents.borrow();
+        let line = documents
+            .get(uri)?
+            .split('\n')
+            .nth(position.line as usize)?;
+
+        Some(line.chars().take(position.character as usize).collect())
+    }
+
+    /// Completes the fields of a provider that is accessed at the end of `line_prefix`, e.g.
+    /// `dep[CcInfo].`, using the fields of builtin providers.
+    pub(crate) fn provider_field_completions(
+        &self,
+        uri: &LspUrl,
+        line_prefix: &str,
+    ) -> Vec<BazelCompletion> {
+        let access = match completion::provider_field_access(line_prefix) {
+            Some(access) => access,
+            None => return Vec::new(),
+        };
+
+        let (_, builtins) = self.get_bazel_globals(uri);
+
+        builtin::type_fields(&builtins, access.provider)
+            .iter()
+            // Methods of providers are fields as well, but aren't completed here.
+            .filter(|field| field.callable.is_none())
+            .filter(|field| field.name.starts_with(access.partial_field))
+            .map(|field| BazelCompletion {
+                category: CompletionCategory::Field,
+                value: field.name.clone(),
+                insert_text: None,
+                insert_text_offset: 0,
+                detail: None,
+            })
+            .collect()
+    }
+
+    /// Completes the members of a value whose type is known, at the end of `line_prefix`. The
+    /// type of the accessed identifier is taken from [`completion::CONVENTIONAL_PARAMETER_TYPES`],
+    /// e.g. `ctx.actions.` completes the members of the `actions` type.
+    pub(crate) fn member_completions(
+        &self,
+        uri: &LspUrl,
+        line_prefix: &str,
+    ) -> Vec<BazelCompletion> {
+        let access = match completion::member_access(line_prefix) {
+            Some(access) => access,
+            None => return Vec::new(),
+        };
+        let root_type = match completion::CONVENTIONAL_PARAMETER_TYPES
+            .iter()
+            .find(|(parameter, _)| *parameter == access.root)
+        {
+            Some((_, root_type)) => root_type.to_string(),
+            None => return Vec::new(),
+        };
+
+        let (_, builtins) = self.get_bazel_globals(uri);
+
+        let mut current_type = root_type;
+        for member in access.path {
+            current_type = match builtin::type_fields(&builtins, &current_type)
+                .iter()
+                .find(|field| field.name == member)
+            {
+                Some(field) => field.r#type.clone(),
+                None => return Vec::new(),
+            };
+        }
+
+        builtin::type_fields(&builtins, &current_type)
+            .iter()
+            .filter(|field| field.name.starts_with(access.partial_member))
+            .map(|field| BazelCompletion {
+                category: if field.callable.is_some() {
+                    CompletionCategory::Method
+                } else {
+                    CompletionCategory::Field
+                },
+                value: field.name.clone(),
+                insert_text: None,
+                insert_text_offset: 0,
+                detail: (!field.r#type.is_empty()).then(|| format!("`{}`", field.r#type)),
+            })
+            .collect()
+    }
+
+    /// Completes the names of rules at the start of a top-level statement in a BUILD file, as
+    /// snippets that call the rule with its mandatory attributes, see
+    /// [`completion::rule_snippet()`]. Commonly used rules are listed first.
+    pub(crate) fn rule_completions(&self, uri: &LspUrl, line_prefix: &str) -> Vec<CompletionItem> {
+        if FileType::from_lsp_url(uri) != FileType::Build
+            || !completion::is_statement_start(line_prefix)
+        {
+            return Vec::new();
+        }
+
+        let (language, _) = self.get_bazel_globals(uri);
+
+        let mut completions: Vec<CompletionItem> = language
+            .rule
+            .iter()
+            .filter(|rule| rule.name.starts_with(line_prefix))
+            .map(|rule| CompletionItem {
+                label: rule.name.clone(),
+                kind: Some(CompletionCategory::Rule.completion_item_kind()),
+                sort_text: Some(completion::rule_sort_text(&rule.name)),
+                insert_text: Some(completion::rule_snippet(rule)),
+                insert_text_format: Some(InsertTextFormat::SNIPPET),
+                ..Default::default()
+            })
+            .collect();
+        completions.sort_by(|a, b| a.sort_text.cmp(&b.sort_text));
+
+        completions
+    }
+
+    /// Returns the names of the globals of a document, which are cached per workspace until
+    /// [`Self::refresh()`], except for the few globals of `.scl` files.
+    fn get_bazel_globals_names(&self, uri: &LspUrl) -> Rc<HashSet<String>> {
+        if FileType::from_lsp_url(uri) == FileType::Configuration {
+            return Rc::new(self.get_environment(uri).members.keys().cloned().collect());
+        }
+
+        let workspace_root = find_workspace_root(uri);
+        if let Some(names) = self.globals_names.borrow().get(&workspace_root) {
+            return names.clone();
+        }
+
+        let (language, builtins) = self.get_bazel_globals(uri);
+        let names: Rc<HashSet<String>> = Rc::new(
+            language
+                .rule
+                .iter()
+                .map(|rule| rule.name.clone())
+                .chain(builtins.global.iter().map(|global| global.name.clone()))
+                .chain(
+                    builtin::MISSING_GLOBALS
+                        .iter()
+                        .map(|missing| missing.to_string()),
+                )
+                .collect(),
+        );
+        self.globals_names
+            .borrow_mut()
+            .insert(workspace_root.clone(), names.clone());
+        self.deprecations.borrow_mut().insert(
+            workspace_root,
+            Rc::new(builtin::Deprecations::from_build_language(&language)),
+        );
+
+        names
+    }
+
+    /// Returns the deprecated rules and attributes of the build language for a document, which
+    /// are cached along with [`Self::get_bazel_globals_names()`].
+    fn get_deprecations(&self, uri: &LspUrl) -> Rc<builtin::Deprecations> {
+        let workspace_root = find_workspace_root(uri);
+        if self.deprecations.borrow().get(&workspace_root).is_none() {
+            self.get_bazel_globals_names(uri);
+        }
+
+        self.deprecations
+            .borrow()
+            .get(&workspace_root)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Resolves a string literal to the file it refers to, and where in that file, see
+    /// [`LspContext::resolve_string_literal()`].
+    pub(crate) fn resolve_string_literal_location(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Option<ResolvedStringLiteral>> {
+        // A `$(location //foo:bar)` reference, e.g. in the `cmd` of a genrule, navigates to the
+        // referenced target. Of several references, the one under the cursor is resolved, or
+        // the first one if the cursor isn't known.
+        let references = label::location_references(literal);
+        if !references.is_empty() {
+            let offset = self.cursor_offset(current_file, literal);
+            let (_, label) = offset
+                .and_then(|offset| {
+                    references
+                        .iter()
+                        .find(|(range, _)| range.start <= offset && offset <= range.end)
+                })
+                .unwrap_or(&references[0]);
+            return self.resolve_string_literal_location(label, current_file, workspace_root);
+        }
+
+        // Free text like a docstring is never resolved, so it doesn't cause any queries. File
+        // names are only resolved where labels are expected, i.e. in the value of an attribute.
+        let in_attribute = self
+            .string_literal_context(current_file, literal)
+            .and_then(|context| context.calls.last().cloned())
+            .map_or(false, |call| call.argument.is_some());
+        if !label::looks_like_label(literal, in_attribute) {
+            return Ok(None);
+        }
+
+        // The name of a `bazel_dep` navigates to the `MODULE.bazel` file of the module.
+        if let Some(module_file) =
+            self.resolve_module_dependency(literal, current_file, workspace_root)?
+        {
+            return Ok(Some(ResolvedStringLiteral {
+                url: Url::from_file_path(module_file).unwrap().try_into()?,
+                location: None,
+            }));
+        }
+
+        // A bare repository name like `@rules_rust` navigates to where the repository is
+        // declared.
+        if !literal.contains("//") {
+            if let Some(repository) = Label::parse(literal).ok().and_then(|label| label.repo) {
+                if let Some(result) = self.resolve_repository_declaration(
+                    &repository.name,
+                    current_file,
+                    workspace_root,
+                )? {
+                    return Ok(Some(result));
+                }
+            }
+        }
+
+        // Target patterns, e.g. `//tools/...` in `register_toolchains()`, navigate to the BUILD
+        // file of the package they start from. They're never resolved as files.
+        if Label::parse(literal).map_or(false, |label| label.is_target_pattern()) {
+            return Ok(label::target_pattern_package(literal)
+                .map(|package| format!("{}:{}", package, FileType::BUILD_FILE_NAMES[0]))
+                .and_then(|build_file| {
+                    self.resolve_load(&build_file, current_file, workspace_root)
+                        .ok()
+                })
+                .map(|url| ResolvedStringLiteral {
+                    url,
+                    location: None,
+                }));
+        }
+
+        self.resolve_load(literal, current_file, workspace_root)
+            .map(|url| {
+                let original_target_name = Path::new(literal).file_name();
+                let path_file_name = url.path().file_name();
+                let same_filename = original_target_name == path_file_name;
+
+                Some(ResolvedStringLiteral {
+                    url: url.clone(),
+                    // If the target name is the same as the original target name, we don't need to
+                    // do anything. Otherwise, we need to find the function call in the target file
+                    // that has a `name` parameter with the same value as the original target name.
+                    location: if same_filename {
+                        None
+                    } else {
+                        Label::parse(literal)
+                            .ok()
+                            .map(|label| StringLiteralLocation::TargetName(label.name))
+                    },
+                })
+            })
+    }
+}
+
+impl<Client: BazelClient> LspContext for BazelContext<Client> {
+    fn parse_file_with_contents(&self, uri: &LspUrl, content: String) -> LspEvalResult {
+        match uri {
+            LspUrl::File(path) => {
+                self.documents
+                    .borrow_mut()
+                    .insert(uri.clone(), content.clone());
+
+                // `.bazelrc` files aren't Starlark, so they would only have parse errors.
+                if FileType::from_path(path) == FileType::Bazelrc {
+                    return LspEvalResult::default();
+                }
+
+                match AstModule::parse(
+                    &path.to_string_lossy(),
+                    content.clone(),
+                    &FileType::from_path(path).dialect(),
+                ) {
+                    Ok(ast) => {
+                        let mut diagnostics = self
+                            .lint_module(uri, &ast)
+                            .into_map(eval_message_to_lsp_diagnostic);
+                        if self
+                            .options
+                            .enabled_lints
+                            .contains("redundant-label-separator")
+                        {
+                            diagnostics.extend(lint::redundant_label_separators(&ast));
+                        }
+                        diagnostics.extend(lint::malformed_label_paths(&ast));
+                        diagnostics.extend(lint::conflicting_visibilities(&ast));
+                        if FileType::from_path(path) == FileType::Build {
+                            diagnostics
+                                .extend(lint::deprecated_usages(&ast, &self.get_deprecations(uri)));
+                            if self.options.enabled_lints.contains("empty-build-file") {
+                                diagnostics.extend(lint::empty_build_file(&ast));
+                            }
+                            if self.options.enabled_lints.contains("test-name-mismatch") {
+                                diagnostics.extend(lint::test_name_mismatches(&ast));
+                            }
+                        }
+                        if self.options.enabled_lints.contains("malformed-label") {
+                            diagnostics.extend(lint::malformed_labels(&ast));
+                        }
+                        if FileType::from_path(path) != FileType::Unknown
+                            && self.options.enabled_lints.contains("load-not-at-top")
+                        {
+                            diagnostics.extend(lint::loads_not_at_top(&ast));
+                        }
+                        LspEvalResult {
+                            diagnostics,
+                            ast: Some(ast),
+                        }
+                    }
+                    Err(e) => {
+                        let diagnostics = vec![parse_error_diagnostic(path, &content, &e)];
+                        LspEvalResult {
+                            diagnostics,
+                            ast: None,
+                        }
+                    }
+                }
+            }
+            _ => LspEvalResult::default(),
+        }
+    }
+
+    fn resolve_load(
+        &self,
+        path: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<LspUrl> {
+        let current_file = self.file_url(current_file);
+        let current_file = current_file.as_ref();
+        let label = Label::parse(path)?;
+        let workspace = self.workspace(workspace_root, current_file)?;
+
+        let folder = self.resolve_folder(&label, current_file, workspace.as_deref())?;
+
+        // Try the presumed filename first, and check if it exists.
+        let presumed_path = folder.join(label.name);
+        if presumed_path.exists() {
+            return Ok(Url::from_file_path(presumed_path).unwrap().try_into()?);
+        }
+
+        // Generated files aren't in the source tree, but are output to `bazel-bin` once they
+        // have been built.
+        if let Some(generated_path) = workspace
+            .as_deref()
+            .and_then(|workspace| workspace.generated_path(&presumed_path))
+        {
+            if generated_path.is_file() {
+                return Ok(Url::from_file_path(generated_path).unwrap().try_into()?);
+            }
+        }
+
+        // If the presumed filename doesn't exist, try to find a build file from the build system
+        // and use that instead.
+        for build_file_name in FileType::BUILD_FILE_NAMES {
+            let path = folder.join(build_file_name);
+            if path.exists() {
+                return Ok(Url::from_file_path(path).unwrap().try_into()?);
+            }
+        }
+
+        Err(ResolveLoadError::TargetNotFound(path.to_owned()).into())
+    }
+
+    fn render_as_load(
+        &self,
+        target: &LspUrl,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<String> {
+        let workspace = self.workspace(workspace_root, current_file)?;
+
+        match (target, current_file) {
+            // Check whether the target and the current file are in the same package.
+            (LspUrl::File(target_path), LspUrl::File(current_file_path)) if matches!((target_path.parent(), current_file_path.parent()), (Some(a), Some(b)) if a == b) =>
+            {
+                // Then just return a relative path.
+                let target_filename = target_path.file_name();
+                match target_filename {
+                    Some(filename) => Ok(format!(":{}", filename.to_string_lossy())),
+                    None => Err(RenderLoadError::MissingTargetFilename(target_path.clone()).into()),
+                }
+            }
+            (LspUrl::File(target_path), _) => {
+                // Try to find a repository that contains the target, as well as the path to the
+                // target relative to the repository root. If we can't find a repository, we'll
+                // try to resolve the target relative to the workspace root. If we don't have a
+                // workspace root, we'll just use the target path as-is.
+                let (repository, target_path) = &workspace
+                    .as_deref()
+                    .and_then(|ws| ws.get_repository_for_path(target_path))
+                    .map(|(repository, target_path)| (Some(repository), target_path))
+                    .or_else(|| {
+                        workspace_root
+                            .and_then(|root| target_path.strip_prefix(root).ok())
+                            .map(|path| (None, path))
+                    })
+                    .unwrap_or((None, target_path));
+
+                let target_filename = target_path.file_name();
+                match target_filename {
+                    Some(filename) => Ok(format!(
+                        "@{}//{}:{}",
+                        repository.as_ref().unwrap_or(&Cow::Borrowed("")),
+                        target_path
+                            .parent()
+                            .map(label::label_path)
+                            .unwrap_or_default(),
+                        filename.to_string_lossy()
+                    )),
+                    None => Err(
+                        RenderLoadError::MissingTargetFilename(target_path.to_path_buf()).into(),
+                    ),
+                }
+            }
+            _ => Err(RenderLoadError::WrongScheme(
+                "file://".to_owned(),
+                target.clone(),
+                current_file.clone(),
+            )
+            .into()),
+        }
+    }
+
+    fn resolve_string_literal(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Option<StringLiteralResult>> {
+        Ok(self
+            .resolve_string_literal_location(literal, current_file, workspace_root)?
+            .map(ResolvedStringLiteral::into_result))
+    }
+
+    fn get_load_contents(&self, uri: &LspUrl) -> anyhow::Result<Option<String>> {
+        match uri {
+            LspUrl::File(path) => match path.is_absolute() {
+                true => match fs::read_to_string(path) {
+                    Ok(contents) => Ok(Some(contents)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                },
+                false => Err(ContextError::NotAbsolute(uri.clone()).into()),
+            },
+            LspUrl::Starlark(_) => Ok(None),
+            _ => Err(ContextError::WrongScheme("file://".to_owned(), uri.clone()).into()),
+        }
+    }
+
+    fn get_environment(&self, uri: &LspUrl) -> DocModule {
+        self.try_get_environment(uri).unwrap_or_default()
+    }
+
+    fn get_url_for_global_symbol(
+        &self,
+        _current_file: &LspUrl,
+        _symbol: &str,
+    ) -> anyhow::Result<Option<LspUrl>> {
+        Ok(None)
+    }
+
+    fn get_string_completion_options(
+        &self,
+        document_uri: &LspUrl,
+        kind: StringCompletionType,
+        current_value: &str,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Vec<StringCompletionResult>> {
+        self.last_string_completions.take();
+        let completions =
+            self.string_completions(document_uri, kind, current_value, workspace_root)?;
+        self.last_string_completions.borrow_mut().completions = completions.clone();
+
+        Ok(completions
+            .into_iter()
+            .map(StringCompletionResult::from)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Diagnostic, DiagnosticSeverity, DocumentChanges, NumberOrString, OneOf, Url};
+    use std::{
+        collections::{HashMap, HashSet},
+        fs,
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
+
+    use lsp_types::CompletionItemKind;
+    use serde_json::json;
+    use starlark::{
+        codemap::ResolvedPos,
+        docs::{DocFunction, DocItem, DocMember, DocModule, DocParam, DocString},
+        syntax::{AstModule, Dialect},
+        typing::Ty,
+    };
+    use starlark_lsp::{
+        completion::{StringCompletionResult, StringCompletionType},
+        server::{LspContext, LspUrl, StringLiteralResult},
+    };
+
+    use crate::{
+        bazel::{
+            build_diagnostics, BazelContext, ContextOptions, ResolveLoadError,
+            ResolvedStringLiteral, StringLiteralLocation,
+        },
+        builtin::{AttributeDefinition, BuildLanguage, RuleDefinition},
+        client::{BazelClient, MockBazel, PanickingBazel, ProfilingClient},
+        completion::{BazelCompletion, CompletionCategory, TargetKind},
+        file_type::FileType,
+        label::Label,
+        lint,
+        test_fixture::TestFixture,
+        workspace::BazelVersion,
+    };
+
+    #[test]
+    fn relative_resolve_load_in_external_repository() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let url = context.resolve_load(
+            "//:foo.bzl",
+            &LspUrl::File(fixture.external_dir("foo").join("BUILD")),
+            None,
+        )?;
+
+        assert_eq!(
+            url,
+            Url::from_file_path(fixture.external_dir("foo").join("foo.bzl"))
+                .unwrap()
+                .try_into()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn absolute_resolve_load_in_external_repository() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let url = context.resolve_load(
+            "@bar//:bar.bzl",
+            &LspUrl::File(fixture.external_dir("foo").join("BUILD")),
+            None,
+        )?;
+
+        assert_eq!(
+            url,
+            Url::from_file_path(fixture.external_dir("bar").join("bar.bzl"))
+                .unwrap()
+                .try_into()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn external_resolve_load_in_root_workspace() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let url = context.resolve_load(
+            "@foo//:foo.bzl",
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            url,
+            Url::from_file_path(fixture.external_dir("foo").join("foo.bzl"))
+                .unwrap()
+                .try_into()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_load_with_repo_mapping_of_local_repository() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "local_lib": "local_lib~",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .repo_mapping_json(
+                "local_lib~",
+                json!({
+                    "": "",
+                    "local_lib": "local_lib~",
+                    "main": "",
+                    "rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        let local_lib = fixture
+            .workspace_root()
+            .join("third_party")
+            .join("local_lib");
+        let resolve = |path: &str, current_file: &Path| {
+            context.resolve_load(
+                path,
+                &LspUrl::File(current_file.to_owned()),
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        assert_eq!(
+            resolve("@rust//rust:defs.bzl", &local_lib.join("defs.bzl"))?,
+            Url::from_file_path(
+                fixture
+                    .external_dir("rules_rust~0.36.2")
+                    .join("rust")
+                    .join("defs.bzl")
+            )
+            .unwrap()
+            .try_into()?
+        );
+        // Labels without a repository refer to the repository of the current file.
+        assert_eq!(
+            resolve("//:defs.bzl", &local_lib.join("BUILD"))?,
+            Url::from_file_path(local_lib.join("defs.bzl"))
+                .unwrap()
+                .try_into()?
+        );
+        assert_eq!(
+            resolve("@main//:main.rs", &local_lib.join("BUILD"))?,
+            Url::from_file_path(fixture.workspace_root().join("main.rs"))
+                .unwrap()
+                .try_into()?
+        );
+        // The apparent name is only known within `@local_lib`.
+        assert!(resolve(
+            "@rust//rust:defs.bzl",
+            &fixture.workspace_root().join("BUILD")
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_load_in_nested_workspace() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        // Offline, the root of a workspace is where its marker file is, rather than what Bazel
+        // reports for any directory in it.
+        let context = BazelContext::new(
+            PanickingBazel,
+            None,
+            ContextOptions {
+                offline: true,
+                ..Default::default()
+            },
+        )?;
+        let root = fixture.workspace_root();
+        let nested = root.join("nested");
+
+        // Files in the nested workspace resolve labels from its root, even if the outer
+        // workspace was given.
+        assert_eq!(
+            context.resolve_load(
+                "//:defs.bzl",
+                &LspUrl::File(nested.join("pkg").join("BUILD")),
+                Some(&root)
+            )?,
+            LspUrl::File(nested.join("defs.bzl"))
+        );
+        // Files in the outer workspace still resolve labels from the outer root.
+        assert_eq!(
+            context.resolve_load(
+                "//nested:defs.bzl",
+                &LspUrl::File(root.join("BUILD")),
+                Some(&root)
+            )?,
+            LspUrl::File(nested.join("defs.bzl"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_load_in_workspace_folders() -> anyhow::Result<()> {
+        let simple = TestFixture::new("simple")?;
+        let bzlmod = TestFixture::new("bzlmod")?;
+        let context = simple
+            .context_builder()?
+            .options(ContextOptions {
+                offline: true,
+                ..Default::default()
+            })
+            .build()?;
+        context.set_workspace_folders(vec![simple.workspace_root(), bzlmod.workspace_root()]);
+
+        // The server passes the same root for files of both folders.
+        let resolve = |fixture: &TestFixture| {
+            context.resolve_load(
+                "//:BUILD",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&simple.workspace_root()),
+            )
+        };
+
+        assert_eq!(
+            resolve(&simple)?,
+            LspUrl::File(simple.workspace_root().join("BUILD"))
+        );
+        assert_eq!(
+            resolve(&bzlmod)?,
+            LspUrl::File(bzlmod.workspace_root().join("BUILD"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_load_in_virtual_module() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let virtual_module = LspUrl::Starlark(PathBuf::from("untitled.bzl"));
+        // Without a path, relative labels can't be resolved.
+        let error = context
+            .resolve_load(":main.cc", &virtual_module, Some(&fixture.workspace_root()))
+            .unwrap_err();
+        assert!(error.to_string().contains("Use an absolute label"));
+
+        context.associate_path(
+            &virtual_module,
+            fixture.workspace_root().join("foo").join("untitled.bzl"),
+        );
+        assert_eq!(
+            context.resolve_load("//lib:defs.bzl", &virtual_module, None)?,
+            LspUrl::File(fixture.workspace_root().join("lib").join("defs.bzl"))
+        );
+        assert_eq!(
+            context.resolve_load(":main.cc", &virtual_module, None)?,
+            LspUrl::File(fixture.workspace_root().join("foo").join("main.cc"))
+        );
+
+        Ok(())
+    }
+
+    /// Parses the contents of a document, and returns its semantic diagnostics, see
+    /// [`BazelContext::semantic_diagnostics()`].
+    fn semantic_diagnostics<Client: BazelClient>(
+        context: &BazelContext<Client>,
+        uri: &LspUrl,
+        contents: String,
+    ) -> Vec<Diagnostic> {
+        context.parse_file_with_contents(uri, contents);
+        context.semantic_diagnostics(uri)
+    }
+
+    #[test]
+    fn test_offline_mode() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = BazelContext::new(
+            PanickingBazel,
+            None,
+            ContextOptions {
+                offline: true,
+                ..Default::default()
+            },
+        )?;
+        let root = fixture.workspace_root();
+        let build_file = LspUrl::File(root.join("foo").join("BUILD"));
+
+        assert_eq!(
+            context.resolve_load("//lib:defs.bzl", &build_file, None)?,
+            LspUrl::File(root.join("lib").join("defs.bzl"))
+        );
+
+        let completions = context.get_string_completion_options(
+            &build_file,
+            StringCompletionType::String,
+            "//",
+            Some(&root),
+        )?;
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "lib"));
+
+        // The bundled build language is used for diagnostics.
+        let result = context.parse_file_with_contents(
+            &build_file,
+            "cc_library(name = 'foo')\nunknown_rule(name = 'bar')".to_owned(),
+        );
+        assert_eq!(
+            result
+                .diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.message.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Use of undefined variable `unknown_rule`"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_as_load_nested_package() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let target = fixture
+            .workspace_root()
+            .join("lib")
+            .join("ignored")
+            .join("defs.bzl");
+        assert_eq!(
+            context.render_as_load(
+                &LspUrl::File(target),
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?,
+            "@//lib/ignored:defs.bzl"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalize_label() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let canonicalize = |literal: &str| {
+            context.canonicalize_label(literal, &current_file, Some(&fixture.workspace_root()))
+        };
+
+        assert_eq!(
+            canonicalize("@rules_rust//rust:defs.bzl")?,
+            "@@rules_rust~0.36.2//rust:defs.bzl"
+        );
+        assert_eq!(canonicalize(":main")?, "@@//:main");
+        assert_eq!(canonicalize("@@other//foo")?, "@@other//foo:foo");
+        assert!(canonicalize("@unknown//foo").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn external_resolve_load_in_bzlmod_workspace() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        let url = context.resolve_load(
+            "@rules_rust//rust:defs.bzl",
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            url,
+            Url::from_file_path(
+                fixture
+                    .external_dir("rules_rust~0.36.2")
+                    .join("rust")
+                    .join("defs.bzl")
+            )
+            .unwrap()
+            .try_into()?
+        );
+
+        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn workspace_info_is_cached() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let root_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let external_file = LspUrl::File(fixture.external_dir("foo").join("BUILD"));
+
+        context.resolve_load("//:foo.bzl", &root_file, Some(&fixture.workspace_root()))?;
+        context.resolve_load("//:foo.bzl", &external_file, None)?;
+        context.get_string_completion_options(
+            &root_file,
+            StringCompletionType::String,
+            "//foo:",
+            Some(&fixture.workspace_root()),
+        )?;
+        context.parse_file_with_contents(&root_file, "".into());
+
+        assert_eq!(context.client.profile.borrow().info, 1);
+
+        // Directories within a known workspace share its `BazelWorkspace`, without running
+        // `bazel info` again.
+        let workspace = context
+            .workspace(Some(fixture.workspace_root()), &root_file)?
+            .unwrap();
+        let subdirectory_workspace = context
+            .workspace(Some(fixture.workspace_root().join("foo")), &root_file)?
+            .unwrap();
+        assert!(Rc::ptr_eq(&workspace, &subdirectory_workspace));
+        assert_eq!(context.client.profile.borrow().info, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_load_in_local_path_override() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "local_lib": "local_lib~override",
+                }),
+            )?
+            .build()?;
+
+        let url = context.resolve_load(
+            "@local_lib//:defs.bzl",
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            url,
+            Url::from_file_path(
+                fixture
+                    .workspace_root()
+                    .join("third_party")
+                    .join("local_lib")
+                    .join("defs.bzl")
+            )
+            .unwrap()
+            .try_into()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn detects_bazel_version() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .release("release 8.0.1")
+            .build()?;
+        let workspace = context
+            .workspace(
+                Some(fixture.workspace_root()),
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            )?
+            .unwrap();
+
+        // `.bazelversion` takes precedence over the release reported by bazel.
+        assert_eq!(
+            workspace.bazel_version,
+            Some(BazelVersion {
+                major: 7,
+                minor: 4,
+                patch: 1
+            })
+        );
+
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .release("release 8.0.1")
+            .build()?;
+        let workspace = context
+            .workspace(
+                Some(fixture.workspace_root()),
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            )?
+            .unwrap();
+
+        assert_eq!(
+            workspace.bazel_version,
+            Some(BazelVersion {
+                major: 8,
+                minor: 0,
+                patch: 1
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_generated_file_to_its_rule() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context
+            .resolve_string_literal(
+                "//gen:header.h",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?
+            .unwrap();
+
+        let build_file = fixture.workspace_root().join("gen").join("BUILD");
+        assert_eq!(
+            result.url,
+            Url::from_file_path(&build_file).unwrap().try_into()?
+        );
+
+        let ast =
+            AstModule::parse("BUILD", fs::read_to_string(build_file)?, &Dialect::Extended).unwrap();
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_string_literal_location_of_target() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context
+            .resolve_string_literal_location(
+                "//gen:header.h",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?
+            .unwrap();
+
+        assert_eq!(
+            result,
+            ResolvedStringLiteral {
+                url: Url::from_file_path(fixture.workspace_root().join("gen").join("BUILD"))
+                    .unwrap()
+                    .try_into()?,
+                location: Some(StringLiteralLocation::TargetName("header.h".to_owned())),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_build_file_template() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context
+            .resolve_string_literal(
+                "//lib:zlib.BUILD",
+                &LspUrl::File(fixture.workspace_root().join("WORKSPACE")),
+                Some(&fixture.workspace_root()),
+            )?
+            .unwrap();
+
+        let template = fixture.workspace_root().join("lib").join("zlib.BUILD");
+        assert_eq!(result.url, LspUrl::File(template.clone()));
+        // Templates are parsed as BUILD files, but aren't the BUILD file of their package.
+        assert!(FileType::from_path(&template) == FileType::Build);
+        assert!(FileType::from_path("BUILD.zlib.bazel") == FileType::Build);
+        assert!(!FileType::is_build_file_template("BUILD.bazel"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_compatible_with_to_its_constraint() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let resolve = |literal: &str| -> anyhow::Result<StringLiteralResult> {
+            Ok(context
+                .resolve_string_literal(
+                    literal,
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    Some(&fixture.workspace_root()),
+                )?
+                .unwrap())
+        };
+
+        let build_file = fixture.workspace_root().join("foo").join("BUILD");
+        let ast = AstModule::parse(
+            "BUILD",
+            r#"constraint_setting(name = "os")
+
+constraint_value(
+    name = "linux",
+    constraint_setting = ":os",
+)
+"#
+            .to_owned(),
+            &Dialect::Extended,
+        )
+        .unwrap();
+
+        // An entry of `target_compatible_with` navigates to its `constraint_value`.
+        let result = resolve("//foo:linux")?;
+        assert_eq!(
+            result.url,
+            Url::from_file_path(&build_file).unwrap().try_into()?
+        );
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 2);
+
+        // The `constraint_setting` of a value navigates to the setting.
+        let result = resolve("//foo:os")?;
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_toolchain_labels() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let resolve = |literal: &str| -> anyhow::Result<Option<StringLiteralResult>> {
+            context.resolve_string_literal(
+                literal,
+                &LspUrl::File(fixture.workspace_root().join("WORKSPACE")),
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        let build_file: LspUrl =
+            Url::from_file_path(fixture.workspace_root().join("foo").join("BUILD"))
+                .unwrap()
+                .try_into()?;
+        let ast = AstModule::parse(
+            "BUILD",
+            r#"toolchain_type(name = "toolchain_type")
+
+toolchain(
+    name = "linux_toolchain",
+    toolchain = ":linux",
+    toolchain_type = ":toolchain_type",
+)
+"#
+            .to_owned(),
+            &Dialect::Extended,
+        )
+        .unwrap();
+
+        // An entry of `register_toolchains()` navigates to its `toolchain`.
+        let result = resolve("//foo:linux_toolchain")?.unwrap();
+        assert_eq!(result.url, build_file);
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 2);
+
+        // Target patterns navigate to the BUILD file of their package.
+        for pattern in ["//foo:all", "//foo/...", "//foo/...:*"] {
+            let result = resolve(pattern)?.unwrap();
+            assert_eq!(result.url, build_file);
+            assert!(result.location_finder.is_none());
+        }
+        assert!(resolve("//does_not_exist/...")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_location_reference_in_cmd() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context
+            .resolve_string_literal(
+                "$(location //gen:header.h) > $@",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?
+            .unwrap();
+
+        let build_file = fixture.workspace_root().join("gen").join("BUILD");
+        assert_eq!(
+            result.url,
+            Url::from_file_path(&build_file).unwrap().try_into()?
+        );
+
+        let ast =
+            AstModule::parse("BUILD", fs::read_to_string(build_file)?, &Dialect::Extended).unwrap();
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 7);
+
+        // Of several references, the one under the cursor is resolved.
+        let document = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let cmd = "$(location //gen:header.h) $(location //foo:main.cc)";
+        context.parse_file_with_contents(&document, format!("genrule(cmd = {:?})", cmd));
+        let resolve_at = |character| -> anyhow::Result<LspUrl> {
+            context.set_cursor(Some((
+                document.clone(),
+                lsp_types::Position::new(0, character),
+            )));
+            Ok(context
+                .resolve_string_literal(cmd, &document, Some(&fixture.workspace_root()))?
+                .unwrap()
+                .url)
+        };
+        assert_eq!(
+            resolve_at(55)?,
+            LspUrl::File(fixture.workspace_root().join("foo").join("main.cc"))
+        );
+        assert_eq!(
+            resolve_at(30)?,
+            LspUrl::File(fixture.workspace_root().join("gen").join("BUILD"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_plain_text_without_queries() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        assert!(context
+            .resolve_string_literal(
+                "Builds the main binary.",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?
+            .is_none());
+        // The workspace isn't even looked up.
+        assert_eq!(context.client.profile.borrow().info, 0);
+        assert_eq!(context.client.profile.borrow().query, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_repository_to_bazel_dep() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture.context()?;
+
+        let result = context
+            .resolve_string_literal(
+                "@rules_rust",
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                Some(&fixture.workspace_root()),
+            )?
+            .unwrap();
+
+        let module_file = fixture.workspace_root().join("MODULE.bazel");
+        assert_eq!(
+            result.url,
+            Url::from_file_path(&module_file).unwrap().try_into()?
+        );
+
+        let ast = AstModule::parse(
+            "MODULE.bazel",
+            fs::read_to_string(module_file)?,
+            &Dialect::Extended,
+        )
+        .unwrap();
+        let span = (result.location_finder.unwrap())(&ast)?.unwrap();
+        assert_eq!(span.begin.line, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_module_dependency() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .mod_deps_json(json!({
+                "key": "<root>",
+                "name": "",
+                "version": "",
+                "apparentName": "",
+                "dependencies": [
+                    {
+                        "key": "rules_rust@0.36.2",
+                        "name": "rules_rust",
+                        "version": "0.36.2",
+                        "apparentName": "rules_rust",
+                        "dependencies": [],
+                    },
+                    {
+                        "key": "local_lib@_",
+                        "name": "local_lib",
+                        "version": "1.0",
+                        "apparentName": "local_lib",
+                        "dependencies": [],
+                    },
+                ],
+            }))
+            .build()?;
+
+        let module_file = LspUrl::File(fixture.workspace_root().join("MODULE.bazel"));
+        context.parse_file_with_contents(
+            &module_file,
+            fs::read_to_string(fixture.workspace_root().join("MODULE.bazel"))?,
+        );
+        // The dependencies aren't listed until a `bazel_dep` is looked up.
+        assert_eq!(context.client.profile.borrow().mod_deps, 0);
+        let resolve = |literal: &str| -> anyhow::Result<Option<LspUrl>> {
+            Ok(context
+                .resolve_string_literal(literal, &module_file, Some(&fixture.workspace_root()))?
+                .map(|result| result.url))
+        };
+
+        assert_eq!(
+            resolve("rules_rust")?,
+            Some(LspUrl::File(
+                fixture
+                    .external_dir("rules_rust~0.36.2")
+                    .join("MODULE.bazel")
+            ))
+        );
+        // Overridden modules resolve to their local sources.
+        assert_eq!(
+            resolve("local_lib")?,
+            Some(LspUrl::File(
+                fixture
+                    .workspace_root()
+                    .join("third_party")
+                    .join("local_lib")
+                    .join("MODULE.bazel")
+            ))
+        );
+
+        // Hovering the name of a dependency shows its version and where it comes from.
+        let hover = context
+            .module_dependency_hover("rules_rust", &module_file, Some(&fixture.workspace_root()))
+            .unwrap();
+        assert!(hover.starts_with(
+            "**rules_rust** `0.36.2`\n\nDependency path: `<root>` → `rules_rust@0.36.2`"
+        ));
+        assert_eq!(context.client.profile.borrow().mod_deps, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_repositories_declared_in_workspace_file() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let repositories: Vec<String> = context
+            .string_completions(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::LoadPath,
+                "@",
+                Some(&fixture.workspace_root()),
+            )?
+            .into_iter()
+            .map(|completion| completion.value)
+            .collect();
+
+        // `@rules_foo` and `@rules_bar` haven't been fetched, and `@foo` is only offered once.
+        for name in ["@foo", "@rules_foo", "@rules_bar"] {
+            assert!(repositories.contains(&name.to_owned()));
+        }
+        assert_eq!(
+            repositories
+                .iter()
+                .filter(|repository| *repository == "@foo")
+                .count(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_builtin_repositories() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<BazelCompletion>> {
+            context.string_completions(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::LoadPath,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        let repositories = completions("@")?;
+        for name in ["@bazel_tools", "@platforms"] {
+            assert!(repositories
+                .iter()
+                .any(|completion| completion.value == name));
+        }
+
+        // `@bazel_tools` is resolved to the tools embedded in Bazel.
+        assert!(completions("@bazel_tools//")?
+            .iter()
+            .any(|completion| completion.value == "tools"
+                && completion.category == CompletionCategory::Directory));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_repositories_in_root_workspace_with_bzlmod() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::String,
+            "@rules_ru",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            completions[0],
+            StringCompletionResult {
+                value: "@rules_rust".into(),
+                insert_text: Some("@rules_rust//".into()),
+                insert_text_offset: 0,
+                kind: CompletionItemKind::MODULE,
+            }
+        );
+
+        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_packages_in_root_workspace_with_bzlmod() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::LoadPath,
+            "@rules_rust//",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            completions[0],
+            StringCompletionResult {
+                value: "rust".into(),
+                insert_text: Some("rust".into()),
+                insert_text_offset: "@rules_rust//".len(),
+                kind: CompletionItemKind::FOLDER,
+            }
+        );
+
+        assert_eq!(context.client.profile.borrow().query, 0);
+        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_bzl_library_srcs() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = context.string_completions(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::LoadPath,
+            "//lib:",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        // Files in subdirectories of the package are offered if a `bzl_library` lists them.
+        let private = completions
+            .iter()
+            .find(|completion| completion.value == "private/impl.bzl")
+            .unwrap();
+        assert_eq!(private.insert_text.as_deref(), Some("private/impl.bzl"));
+        assert_eq!(
+            private.detail.as_deref(),
+            Some("Part of `bzl_library` `rules`")
+        );
+        // Files of the package directory are only offered once.
+        assert_eq!(
+            completions
+                .iter()
+                .filter(|completion| completion.value == "rules.bzl")
+                .count(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_relative_loads_prefers_sibling_files() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<BazelCompletion>> {
+            context.string_completions(
+                &LspUrl::File(fixture.workspace_root().join("lib").join("BUILD")),
+                StringCompletionType::LoadPath,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        // Sibling `.bzl` files come before the files of subdirectories.
+        let values: Vec<String> = completions(":")?
+            .into_iter()
+            .map(|completion| completion.value)
+            .collect();
+        let rules = values
+            .iter()
+            .position(|value| value == "rules.bzl")
+            .unwrap();
+        let private = values
+            .iter()
+            .position(|value| value == "private/impl.bzl")
+            .unwrap();
+        assert!(rules < private);
+
+        // Without a `:`, sibling `.bzl` files still come before subdirectories, and are inserted
+        // as relative labels.
+        let completions: Vec<BazelCompletion> = completions("")?
+            .into_iter()
+            .filter(|completion| completion.category != CompletionCategory::Repository)
+            .collect();
+        assert_eq!(completions[0].category, CompletionCategory::File);
+        assert_eq!(completions[0].value, "cycle_a.bzl");
+        assert_eq!(completions[0].insert_text.as_deref(), Some(":cycle_a.bzl"));
+        assert!(completions
+            .iter()
+            .any(|completion| completion.category == CompletionCategory::Directory));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_loads_offers_star_files() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::LoadPath,
+            "//lib:",
+            Some(&fixture.workspace_root()),
+        )?;
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "macros.star"));
+
+        // `.star` files are libraries, like `.bzl` files.
+        let module = context.get_environment(&LspUrl::File(
+            fixture.workspace_root().join("lib").join("macros.star"),
+        ));
+        assert!(module.members.iter().any(|(member, _)| member == "range"));
+        assert!(!module.members.iter().any(|(member, _)| member == "glob"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_only_from_queries() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                query_only_completions: true,
+                ..Default::default()
+            })
+            .query(
+                "buildfiles(//...)",
+                "//foo:BUILD\n//lib:defs.bzl\n//remote:BUILD\n//remote/nested:BUILD.bazel\n",
+            )
+            .query(
+                "--output=label_kind //remote:*",
+                "cc_library rule //remote:lib\nsource file //remote:lib.cc\n",
+            )
+            .build()?;
+
+        // The package isn't on disk, so completing from the filesystem would fail to read it.
+        let document = LspUrl::File(fixture.workspace_root().join("remote").join("BUILD"));
+        let completions = |current_value: &str| -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .string_completions(
+                    &document,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        // Packages are found from their BUILD files, once a package is typed.
+        assert_eq!(completions("//")?, Vec::<String>::new());
+        assert_eq!(context.client.profile.borrow().query, 0);
+        assert_eq!(completions("//f")?, vec!["foo"]);
+        assert_eq!(completions(":")?, vec!["lib.cc", "lib"]);
+        assert_eq!(completions("//remote:lib.")?, vec!["lib.cc"]);
+        // Both queries are cached, and subpackages are found from the listing of the root.
+        assert_eq!(completions("//r")?, vec!["remote"]);
+        assert_eq!(completions("//remote/")?, vec!["nested"]);
+        assert_eq!(context.client.profile.borrow().query, 2);
+
+        // Saving a BUILD file only lists the packages again if the package is new.
+        context.invalidate(&LspUrl::File(
+            fixture.workspace_root().join("foo").join("BUILD"),
+        ));
+        assert_eq!(completions("//f")?, vec!["foo"]);
+        assert_eq!(context.client.profile.borrow().query, 2);
+        context.invalidate(&LspUrl::File(
+            fixture.workspace_root().join("new").join("BUILD"),
+        ));
+        assert_eq!(completions("//f")?, vec!["foo"]);
+        assert_eq!(context.client.profile.borrow().query, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_repositories_depends_on_bzlmod_mode() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let completions = |context: &BazelContext<ProfilingClient<MockBazel>>,
+                           current_value: &str|
+         -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .get_string_completion_options(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::LoadPath,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        // Bazel 7 enables Bzlmod by default, so repositories are found in the repo mapping.
+        let context = fixture
+            .context_builder()?
+            .release("release 7.4.1")
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+        assert!(completions(&context, "@rules_ru")?.contains(&"@rules_rust".to_owned()));
+        assert_eq!(completions(&context, "@rules_rust//")?, vec!["rust"]);
+        assert!(context.client.profile.borrow().dump_repo_mapping > 0);
+
+        // Before Bazel 7, the same workspace uses `WORKSPACE`, where repositories are found in
+        // the output base under their canonical names.
+        let context = fixture
+            .context_builder()?
+            .release("release 6.5.0")
+            .build()?;
+        assert!(completions(&context, "@rules_ru")?.contains(&"@rules_rust~0.36.2".to_owned()));
+        assert_eq!(completions(&context, "@rules_rust~0.36.2//")?, vec!["rust"]);
+        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_bare_targets() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::String,
+            "",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        let completion = completions
+            .iter()
+            .find(|completion| completion.value == "main.cc")
+            .unwrap();
+
+        assert_eq!(
+            *completion,
+            StringCompletionResult {
+                value: "main.cc".into(),
+                insert_text: Some("main.cc".into()),
+                insert_text_offset: 0,
+                kind: CompletionItemKind::FILE,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_order_and_limit() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let completions = |context: &BazelContext<ProfilingClient<MockBazel>>,
+                           current_value: &str|
+         -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .string_completions(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\nsource file //foo:BUILD\n",
+            )
+            .build()?;
+        assert_eq!(
+            completions(&context, "//")?,
+            vec!["foo", "gen", "lib", "proto"]
+        );
+        // Files come before targets.
+        assert_eq!(
+            completions(&context, "//foo:")?,
+            vec!["main.cc", "BUILD", "main"]
+        );
+
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                max_filesystem_completions: Some(2),
+                ..Default::default()
+            })
+            .build()?;
+        assert_eq!(completions(&context, "//")?, vec!["foo", "gen"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_skips_hidden_and_ignored_entries() -> anyhow::Result<()> {
+        let fixture = TestFixture::copy("simple")?;
+        let completions = |context: &BazelContext<ProfilingClient<MockBazel>>,
+                           current_value: &str|
+         -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .string_completions(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        let git_dir = fixture.workspace_root().join(".git");
+        fs::create_dir_all(&git_dir)?;
+
+        let context = fixture.context()?;
+        let root_entries = completions(&context, "//")?;
+        let files = completions(&context, "")?;
+        let lib_entries = completions(&context, "//lib/")?;
+
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                include_hidden_files: true,
+                ..Default::default()
+            })
+            .build()?;
+        let hidden_root_entries = completions(&context, "//")?;
+        let hidden_files = completions(&context, "")?;
+        let hidden_lib_entries = completions(&context, "//lib/")?;
+
+        assert!(!root_entries.contains(&".git".to_owned()));
+        assert!(!files.contains(&".bazelrc".to_owned()));
+        assert!(files.contains(&"main.cc".to_owned()));
+        // Directories in `.bazelignore` are skipped.
+        assert!(!lib_entries.contains(&"ignored".to_owned()));
+
+        assert!(hidden_root_entries.contains(&".git".to_owned()));
+        assert!(hidden_files.contains(&".bazelrc".to_owned()));
+        assert!(!hidden_lib_entries.contains(&"ignored".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_files_in_package() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::String,
+            "//foo:",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        let completion = completions
+            .iter()
+            .find(|completion| completion.value == "main.cc")
+            .unwrap();
+
+        assert_eq!(
+            *completion,
+            StringCompletionResult {
+                value: "main.cc".into(),
+                insert_text: Some("main.cc".into()),
+                insert_text_offset: "//foo:".len(),
+                kind: CompletionItemKind::FILE,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_targets_in_package() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let completions = context.get_string_completion_options(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::String,
+            "//foo:",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        let completion = completions
+            .iter()
+            .find(|completion| completion.value == "main")
+            .unwrap();
+
+        assert_eq!(
+            *completion,
+            StringCompletionResult {
+                value: "main".into(),
+                insert_text: Some("main".into()),
+                insert_text_offset: "//foo:".len(),
+                kind: CompletionItemKind::CLASS,
+            }
+        );
+
+        assert_eq!(context.client.profile.borrow().query, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_with_fuzzy_matches() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:cmain\ncc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .string_completions(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        assert_eq!(completions("//foo:mncc")?, vec!["main.cc"]);
+        // Values starting with the typed text rank above values that only contain it.
+        assert_eq!(completions("//foo:main")?, vec!["main.cc", "main", "cmain"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_targets_with_kinds() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\ncc_binary rule //foo:tool\ncc_test rule //foo:main_test\n",
+            )
+            .build()?;
+
+        let completions: HashMap<String, (CompletionCategory, Option<String>)> = context
+            .string_completions(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::String,
+                "//foo:",
+                Some(&fixture.workspace_root()),
+            )?
+            .into_iter()
+            .map(|completion| (completion.value, (completion.category, completion.detail)))
+            .collect();
+
+        assert_eq!(
+            completions.get("tool"),
+            Some(&(
+                CompletionCategory::Target(TargetKind::Binary),
+                Some("`cc_binary`".to_owned())
+            ))
+        );
+        assert_eq!(
+            completions.get("main_test").map(|(category, _)| *category),
+            Some(CompletionCategory::Target(TargetKind::Test))
+        );
+        assert_eq!(
+            CompletionCategory::Target(TargetKind::Binary).completion_item_kind(),
+            CompletionItemKind::CONSTRUCTOR
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_categories() -> anyhow::Result<()> {
+        fn categories(
+            context: &BazelContext<ProfilingClient<MockBazel>>,
+            fixture: &TestFixture,
+            current_value: &str,
+        ) -> anyhow::Result<HashMap<String, CompletionCategory>> {
+            Ok(context
+                .string_completions(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| (completion.value, completion.category))
+                .collect())
+        }
+
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let package_completions = categories(&context, &fixture, "//foo:")?;
+        assert_eq!(
+            package_completions.get("main.cc"),
+            Some(&CompletionCategory::File)
+        );
+        assert_eq!(
+            package_completions.get("main"),
+            Some(&CompletionCategory::Target(TargetKind::Library))
+        );
+        assert_eq!(
+            categories(&context, &fixture, "//")?.get("foo"),
+            Some(&CompletionCategory::Directory)
+        );
+
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "rules_rust": "rules_rust~0.36.2",
+                }),
+            )?
+            .build()?;
+
+        assert_eq!(
+            categories(&context, &fixture, "@rules_ru")?.get("@rules_rust"),
+            Some(&CompletionCategory::Repository)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_deps_with_exports() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\njava_library rule //foo:api\n",
+            )
+            .query("attr(exports, \"\\[.+\\]\", //foo:*)", "//foo:api\n")
+            .build()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        context.parse_file_with_contents(
+            &document_uri,
+            "java_library(name = 'lib', deps = ['//foo:'])".into(),
+        );
+
+        let details: HashMap<String, Option<String>> = context
+            .string_completions(
+                &document_uri,
+                StringCompletionType::String,
+                "//foo:",
+                Some(&fixture.workspace_root()),
+            )?
+            .into_iter()
+            .filter(|completion| matches!(completion.category, CompletionCategory::Target(_)))
+            .map(|completion| (completion.value, completion.detail))
+            .collect();
+
+        assert_eq!(
+            details.get("api"),
+            Some(&Some(
+                "`java_library`, also provides its `exports`".to_owned()
+            ))
+        );
+        assert_eq!(details.get("main"), Some(&Some("`cc_library`".to_owned())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_targets_of_partially_failing_query() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .partial_query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let completions = context.string_completions(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            StringCompletionType::String,
+            "//foo:",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "main"
+                && matches!(completion.category, CompletionCategory::Target(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_queries_are_not_retried_right_away() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let complete = || {
+            context.get_string_completion_options(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::String,
+                "//foo:",
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        // The query isn't registered, so it fails.
+        complete()?;
+        complete()?;
+        assert_eq!(context.client.profile.borrow().query, 1);
+
+        context.refresh();
+        complete()?;
+        assert_eq!(context.client.profile.borrow().query, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_targets_after_refresh() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let complete = || {
+            context.get_string_completion_options(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::String,
+                "//foo:",
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        complete()?;
+        complete()?;
+        assert_eq!(context.client.profile.borrow().query, 1);
+        assert_eq!(context.client.profile.borrow().info, 1);
+
+        // Everything is recomputed after a refresh.
+        context.refresh();
+        complete()?;
+        assert_eq!(context.client.profile.borrow().query, 2);
+        assert_eq!(context.client.profile.borrow().info, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn caches_bazel_globals_until_refresh() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+
+        context.get_environment(&uri);
+        context.rule_completions(&uri, "cc_");
+        assert_eq!(context.client.profile.borrow().build_language, 1);
+
+        context.refresh();
+        context.get_environment(&uri);
+        assert_eq!(context.client.profile.borrow().build_language, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_targets_after_invalidation() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let mut context = fixture
+            .context_builder()?
+            .query(
+                "--output=label_kind //foo:*",
+                "cc_library rule //foo:main\n",
+            )
+            .build()?;
+
+        let target_completions = |context: &BazelContext<ProfilingClient<MockBazel>>| {
+            context
+                .get_string_completion_options(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    StringCompletionType::String,
+                    "//foo:",
+                    Some(&fixture.workspace_root()),
+                )
+                .map(|completions| {
+                    completions
+                        .into_iter()
+                        .filter(|completion| completion.kind != CompletionItemKind::FILE)
+                        .map(|completion| completion.value)
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(target_completions(&context)?, vec!["main"]);
+        assert_eq!(target_completions(&context)?, vec!["main"]);
+        assert_eq!(context.client.profile.borrow().query, 1);
+
+        // Add a new target to the package.
+        context.client.inner_mut().queries.insert(
+            "--output=label_kind //foo:*".into(),
+            "cc_library rule //foo:main\ncc_test rule //foo:test\n".into(),
+        );
+
+        // Unrelated packages don't invalidate the targets.
+        context.invalidate(&LspUrl::File(fixture.workspace_root().join("BUILD")));
+        assert_eq!(target_completions(&context)?, vec!["main"]);
+
+        context.invalidate(&LspUrl::File(
+            fixture.workspace_root().join("foo").join("BUILD"),
+        ));
+        assert_eq!(target_completions(&context)?, vec!["main", "test"]);
+        assert_eq!(context.client.profile.borrow().query, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_genrule_outs() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let build_file = fixture.workspace_root().join("gen").join("BUILD");
+        let document_uri = LspUrl::File(build_file.clone());
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<StringCompletionResult>> {
+            context.parse_file_with_contents(
+                &document_uri,
+                fs::read_to_string(&build_file)?.replace("\"\"", &format!("{:?}", current_value)),
+            );
+            context.get_string_completion_options(
+                &document_uri,
+                StringCompletionType::String,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        // Subpackages and files aren't offered.
+        assert_eq!(
+            completions("")?,
+            vec![StringCompletionResult {
+                value: "templates".into(),
+                insert_text: Some("templates/".into()),
+                insert_text_offset: 0,
+                kind: CompletionItemKind::FOLDER,
+            }]
+        );
+        assert_eq!(
+            completions("templates/")?,
+            vec![StringCompletionResult {
+                value: "nested".into(),
+                insert_text: Some("nested/".into()),
+                insert_text_offset: "templates/".len(),
+                kind: CompletionItemKind::FOLDER,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_target_names() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        context.parse_file_with_contents(&document_uri, "cc_library(name = '')".into());
+
+        let completions = context.string_completions(
+            &document_uri,
+            StringCompletionType::String,
+            "",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert_eq!(
+            completions
+                .iter()
+                .map(|completion| (completion.value.as_str(), completion.detail.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("foo", Some("The name of the package")),
+                ("main", Some("Named after `main.cc`")),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_load_symbols() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let completions = |contents: &str, current_value: &str| -> anyhow::Result<Vec<String>> {
+            context.parse_file_with_contents(&document_uri, contents.into());
+
+            Ok(context
+                .string_completions(
+                    &document_uri,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        assert_eq!(
+            completions("load('//lib:rules.bzl', '')", "")?,
+            vec!["FancyInfo", "fancy_library"]
+        );
+        // Symbols that are already loaded aren't offered again.
+        assert_eq!(
+            completions("load('//lib:rules.bzl', 'fancy_library', 'F')", "F")?,
+            vec!["FancyInfo"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_tags() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        context.parse_file_with_contents(
+            &document_uri,
+            "cc_test(name = 'test', tags = ['no-'])".into(),
+        );
+
+        let completions = context.string_completions(
+            &document_uri,
+            StringCompletionType::String,
+            "no-",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert!(completions.iter().all(|completion| completion.category
+            == CompletionCategory::Value
+            && completion.value.starts_with("no-")
+            && completion.detail.is_some()));
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "no-remote"));
+        // Files aren't offered for tags.
+        assert!(!completions
+            .iter()
+            .any(|completion| completion.value == "main.cc"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_make_variables() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        context.parse_file_with_contents(
+            &document_uri,
+            "genrule(name = 'gen', outs = ['a.txt'], cmd = 'cat $(')".into(),
+        );
+
+        let completions = context.string_completions(
+            &document_uri,
+            StringCompletionType::String,
+            "cat $(",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        let location = completions
+            .iter()
+            .find(|completion| completion.value == "$(location )")
+            .unwrap();
+        assert_eq!(location.insert_text.as_deref(), Some("$(location )"));
+        assert_eq!(location.insert_text_offset, 4);
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "$(OUTS)"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_sibling_filegroups() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        context.parse_file_with_contents(
+            &document_uri,
+            "
+filegroup(name = 'headers', srcs = glob(['*.h']))
+filegroup(name = 'sources', srcs = [':'])
+cc_library(name = 'lib', srcs = [''])
+"
+            .into(),
+        );
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<BazelCompletion>> {
+            context.string_completions(
+                &document_uri,
+                StringCompletionType::String,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        let completions_for_lib = completions("")?;
+        assert_eq!(
+            completions_for_lib[0],
+            BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                value: "headers".into(),
+                insert_text: Some(":headers".into()),
+                insert_text_offset: 0,
+                detail: Some("`filegroup` in this package".into()),
+            }
+        );
+        assert_eq!(completions_for_lib[1].value, "sources");
+        // Files are still offered after the filegroups.
+        assert!(completions_for_lib
+            .iter()
+            .any(|completion| completion.value == "main.cc"));
+
+        // A filegroup isn't offered within itself.
+        let completions_for_sources = completions(":")?;
+        assert_eq!(completions_for_sources[0].value, "headers");
+        assert!(!completions_for_sources
+            .iter()
+            .any(|completion| completion.value == "sources"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_visibility_labels() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query("kind(package_group, //foo:*)", "//foo:friends\n")
+            .query("kind(package_group, //foo/bar:*)", "//foo/bar:others\n")
+            .query("kind(package_group, //:*)", "//:team\n")
+            .build()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let completions = |current_value: &str| -> anyhow::Result<Vec<(String, usize)>> {
+            context.parse_file_with_contents(
+                &document_uri,
+                format!(
+                    "cc_library(name = 'lib', visibility = [{:?}])",
+                    current_value
+                ),
+            );
+
+            Ok(context
+                .string_completions(
+                    &document_uri,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| (completion.value, completion.insert_text_offset))
+                .collect())
+        };
+
+        assert_eq!(
+            completions("//foo:")?,
+            vec![
+                ("__pkg__".to_owned(), 6),
+                ("__subpackages__".to_owned(), 6),
+                ("friends".to_owned(), 6),
+            ]
+        );
+        assert_eq!(
+            completions("//foo:__s")?,
+            vec![("__subpackages__".to_owned(), 6)]
+        );
+        assert_eq!(
+            completions("//foo/bar:")?,
+            vec![
+                ("__pkg__".to_owned(), 10),
+                ("__subpackages__".to_owned(), 10),
+                ("others".to_owned(), 10),
+            ]
+        );
+        // Relative labels refer to the package of the document.
+        assert_eq!(
+            completions(":")?,
+            vec![
+                ("__pkg__".to_owned(), 1),
+                ("__subpackages__".to_owned(), 1),
+                ("team".to_owned(), 1),
+            ]
+        );
+        // The `//visibility` package only has `public` and `private`.
+        assert_eq!(
+            completions("//visibility:")?,
+            vec![("public".to_owned(), 13), ("private".to_owned(), 13)]
+        );
+        assert_eq!(
+            completions("//visibility:pu")?,
+            vec![("public".to_owned(), 13)]
+        );
+        // Only the packages that were typed are queried.
+        assert_eq!(context.client.profile.borrow().query, 3);
+
+        // The default visibility of a package is completed the same way.
+        context.parse_file_with_contents(
+            &document_uri,
+            "package(default_visibility = ['//foo:__p'])".to_owned(),
+        );
+        assert_eq!(
+            context
+                .string_completions(
+                    &document_uri,
+                    StringCompletionType::String,
+                    "//foo:__p",
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect::<Vec<_>>(),
+            vec!["__pkg__"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_package_group_packages() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query("kind(package_group, //lib:*)", "//lib:team\n")
+            .build()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        let completions =
+            |attribute: &str, current_value: &str| -> anyhow::Result<Vec<(String, usize)>> {
+                context.parse_file_with_contents(
+                    &document_uri,
+                    format!(
+                        "package_group(name = 'group', {} = [{:?}])",
+                        attribute, current_value
+                    ),
+                );
+
+                let mut completions: Vec<_> = context
+                    .string_completions(
+                        &document_uri,
+                        StringCompletionType::String,
+                        current_value,
+                        Some(&fixture.workspace_root()),
+                    )?
+                    .into_iter()
+                    .map(|completion| (completion.value, completion.insert_text_offset))
+                    .collect();
+                completions.sort();
+                Ok(completions)
+            };
+
+        // Packages are offered as directories, along with the wildcard for their subpackages.
+        let packages = completions("packages", "//")?;
+        for expected in ["...", "foo", "lib"] {
+            assert!(packages.contains(&(expected.to_owned(), 2)), "{packages:?}");
+        }
+        // Packages can be excluded.
+        assert!(completions("packages", "-//")?.contains(&("foo".to_owned(), 3)));
+        assert_eq!(
+            completions("packages", "pu")?,
+            vec![("public".to_owned(), 0)]
+        );
+
+        // The packages of `package_group`s are offered as directories, and then their
+        // `package_group`s.
+        assert_eq!(completions("includes", "//l")?, vec![("lib".to_owned(), 2)]);
+        assert_eq!(
+            completions("includes", "//lib:")?,
+            vec![("team".to_owned(), 6)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_select_conditions() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query(
+                "kind(\"config_setting|constraint_value\", //...)",
+                "//foo:linux\n//foo:opt\n",
+            )
+            .build()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let completions = |current_value: &str| -> anyhow::Result<Vec<String>> {
+            context.parse_file_with_contents(
+                &document_uri,
+                format!(
+                    "cc_library(name = 'lib', srcs = select({{{:?}: []}}))",
+                    current_value
+                ),
+            );
+
+            Ok(context
+                .string_completions(
+                    &document_uri,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        assert_eq!(
+            completions("//")?,
+            vec!["//conditions:default", "//foo:linux", "//foo:opt"]
+        );
+        assert_eq!(completions("//foo:o")?, vec!["//foo:opt"]);
+        // Editing the BUILD file doesn't change what Bazel sees until it is saved.
+        assert_eq!(context.client.profile.borrow().query, 1);
+
+        context.invalidate(&document_uri);
+        context.string_completions(
+            &document_uri,
+            StringCompletionType::String,
+            "//foo:o",
+            Some(&fixture.workspace_root()),
+        )?;
+        assert_eq!(context.client.profile.borrow().query, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_platforms() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("bzlmod")?;
+        let context = fixture
+            .context_builder()?
+            .repo_mapping_json(
+                "",
+                json!({
+                    "": "",
+                    "platforms": "platforms",
+                }),
+            )?
+            .build()?;
+
+        let completions = |current_value: &str| {
+            context.string_completions(
+                &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                StringCompletionType::String,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        // The repository isn't fetched in the fixture.
+        let os_completions = completions("@platforms//os:")?;
+        for os in ["linux", "macos", "windows"] {
+            assert!(os_completions.contains(&BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                value: os.into(),
+                insert_text: Some(os.into()),
+                insert_text_offset: "@platforms//os:".len(),
+                detail: None,
+            }));
+        }
+
+        let packages = completions("@platforms//")?
+            .into_iter()
+            .map(|completion| completion.value)
+            .collect::<Vec<_>>();
+        assert!(packages.contains(&"os".to_owned()));
+        assert!(packages.contains(&"cpu".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_glob_patterns() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("gen").join("BUILD"));
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<StringCompletionResult>> {
+            context.parse_file_with_contents(
+                &document_uri,
+                format!(
+                    "filegroup(name = 'templates', srcs = glob([{:?}]))",
+                    current_value
+                ),
+            );
+            context.get_string_completion_options(
+                &document_uri,
+                StringCompletionType::String,
+                current_value,
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        assert_eq!(
+            completions("")?,
+            vec![StringCompletionResult {
+                value: "templates".into(),
+                insert_text: Some("templates/".into()),
+                insert_text_offset: 0,
+                kind: CompletionItemKind::FOLDER,
+            }]
+        );
+        assert_eq!(
+            completions("templates/**/*.tmpl")?,
+            vec![StringCompletionResult {
+                value: "nested".into(),
+                insert_text: Some("nested/**/*.tmpl".into()),
+                insert_text_offset: "templates/".len(),
+                kind: CompletionItemKind::FOLDER,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_proto_library_srcs() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let build_file = fixture.workspace_root().join("proto").join("BUILD");
+        let document_uri = LspUrl::File(build_file.clone());
+        context.parse_file_with_contents(&document_uri, fs::read_to_string(build_file)?);
+
+        let completions = context.get_string_completion_options(
+            &document_uri,
+            StringCompletionType::String,
+            "",
+            Some(&fixture.workspace_root()),
+        )?;
+
+        assert!(completions
+            .iter()
+            .any(|completion| completion.value == "foo.proto"));
+        assert!(!completions
+            .iter()
+            .any(|completion| completion.value == "notes.txt"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_ranks_files_by_extension() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let document_uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        context.parse_file_with_contents(
+            &document_uri,
+            "cc_library(name = 'lib', hdrs = ['main.'], srcs = ['main'])".into(),
+        );
+        let completions = |current_value: &str| -> anyhow::Result<Vec<String>> {
+            Ok(context
+                .string_completions(
+                    &document_uri,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect())
+        };
+
+        // Headers come first in `hdrs`, and sources in `srcs`, but other files are still offered.
+        assert_eq!(completions("main.")?, vec!["main.h", "main.cc"]);
+        assert_eq!(completions("main")?, vec!["main.cc", "main.h"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_testonly_dependencies_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query_build(
+                "//foo:main",
+                "cc_library(\n  name = \"main\",\n  testonly = True,\n)\n",
+            )
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["testonly-dependency".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let reported = |contents: &str| {
+            semantic_diagnostics(&context, &uri, contents.to_owned())
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("testonly-dependency".into()))
+                })
+                .map(|diagnostic| diagnostic.range.start.line)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            reported(
+                "cc_binary(name = 'bin', deps = ['//foo:main', '//foo:main.cc'])
+cc_test(name = 'test', deps = ['//foo:main'])"
+            ),
+            vec![0]
+        );
+        assert_eq!(
+            reported(
+                "package(default_testonly = True)
+cc_binary(name = 'bin', deps = ['//foo:main'])"
+            ),
+            Vec::<u32>::new()
+        );
+        // The target is only queried once, since its package didn't change.
+        assert_eq!(context.client.profile.borrow().query_build, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_for_target() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query_build(
+                "//foo:main",
+                "cc_library(\n  name = \"main\",\n  srcs = [\"//foo:main.cc\"],\n)\n",
+            )
+            .build()?;
+
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+
+        assert_eq!(
+            context.target_hover("//foo:main", &current_file, Some(&fixture.workspace_root())),
+            Some("**cc_library** `//foo:main`\n\n- `srcs`: `[\"//foo:main.cc\"]`".into())
+        );
+
+        // Relative labels are made absolute before querying.
+        let foo_build_file = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        assert!(context
+            .target_hover(":main", &foo_build_file, Some(&fixture.workspace_root()))
+            .is_some());
+
+        // Failing queries result in no hover, and files aren't queried at all.
+        assert_eq!(
+            context.target_hover(
+                "//foo:unknown",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            None
+        );
+        assert_eq!(
+            context.target_hover(
+                "//foo:main.cc",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            None
+        );
+        assert_eq!(context.client.profile.borrow().query_build, 2);
+
+        // Hovering a label in a document shows the same summary.
+        context.parse_file_with_contents(
+            &current_file,
+            "cc_binary(name = 'bin', deps = ['//foo:main'])".to_owned(),
+        );
+        let hover = context.hover(&current_file, lsp_types::Position::new(0, 35));
+        assert!(matches!(
+            hover.map(|hover| hover.contents),
+            Some(lsp_types::HoverContents::Markup(content)) if content.value.starts_with("**cc_library**")
+                && content.value.contains("Defined in `")
+        ));
+        assert_eq!(
+            context.hover(&current_file, lsp_types::Position::new(0, 3)),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_for_select() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .cquery_build(
+                "//foo:main",
+                "# /workspace/foo/BUILD:1:11\ncc_library(\n  name = \"main\",\n  srcs = [\"//foo:linux.cc\"],\n)\n",
+            )
+            .build()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        context.parse_file_with_contents(
+            &uri,
+            r#"cc_library(
+    name = "main",
+    srcs = select({
+        "//conditions:default": ["linux.cc"],
+        "//foo:windows": ["windows.cc"],
+    }),
+)
+"#
+            .to_owned(),
+        );
+        let hover = |line, column| {
+            context.select_hover(
+                &uri,
+                ResolvedPos { line, column },
+                Some(&fixture.workspace_root()),
+            )
+        };
+
+        assert_eq!(
+            hover(2, 12),
+            Some(
+                "`srcs` of `//foo:main` resolves to `[\"//foo:linux.cc\"]` in the default configuration"
+                    .to_owned()
+            )
+        );
+        // There's no hover away from the `select`.
+        assert_eq!(hover(1, 12), None);
+        assert_eq!(context.client.profile.borrow().cquery_build, 1);
+
+        // Hovering the `select` in the document shows the same.
+        assert!(context
+            .hover(&uri, lsp_types::Position::new(2, 12))
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_provider_fields() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("foo.bzl"));
+        let fields = |line_prefix: &str| {
+            context
+                .provider_field_completions(&uri, line_prefix)
+                .into_iter()
+                .map(|completion| {
+                    assert_eq!(completion.category, CompletionCategory::Field);
+                    completion.value
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let cc_info_fields = fields("    cc_info = dep[CcInfo].");
+        assert!(cc_info_fields.contains(&"linking_context".to_string()));
+        assert!(cc_info_fields.contains(&"compilation_context".to_string()));
+
+        assert_eq!(
+            fields("    cc_info = dep[CcInfo].link"),
+            vec!["linking_context"]
+        );
+        assert!(fields("    cc_info = dep.").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_rule_snippets() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+
+        let completions = context.rule_completions(&uri, "cc_");
+        let cc_library = completions
+            .iter()
+            .find(|completion| completion.label == "cc_library")
+            .unwrap();
+        assert!(
+            cc_library
+                .insert_text
+                .as_ref()
+                .unwrap()
+                .starts_with("cc_library(\n    name = \"$1\",\n    srcs = [$2],\n"),
+            "{:?}",
+            cc_library.insert_text
+        );
+        assert_eq!(
+            cc_library.insert_text_format,
+            Some(lsp_types::InsertTextFormat::SNIPPET)
+        );
+        assert!(completions
+            .iter()
+            .all(|completion| completion.label.starts_with("cc_")));
+        // Common rules come first.
+        assert_eq!(completions[0].label, "cc_library");
+
+        // Rules are only completed where a statement starts.
+        assert!(context.rule_completions(&uri, "x = cc_").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_for_ctx_members() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("foo.bzl"));
+        let members = |line_prefix: &str| {
+            context
+                .member_completions(&uri, line_prefix)
+                .into_iter()
+                .map(|completion| (completion.value, completion.category))
+                .collect::<Vec<_>>()
+        };
+
+        let ctx_members = members("    ctx.");
+        for member in ["actions", "attr", "file"] {
+            assert!(ctx_members.contains(&(member.to_owned(), CompletionCategory::Field)));
+        }
+        assert!(members("    ctx.act")
+            .iter()
+            .all(|(member, _)| member.starts_with("act")));
+        assert!(
+            members("    ctx.actions.").contains(&("run".to_owned(), CompletionCategory::Method))
+        );
+        assert!(members("    other.").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let build_file = fixture.workspace_root().join("BUILD");
+        let uri = LspUrl::File(build_file.clone());
+        context.parse_file_with_contents(
+            &uri,
+            "load('//lib:defs.bzl', my_rule = 'exported')
+my_rule(name = 'foo')"
+                .to_owned(),
+        );
+
+        let edit = context.rename(&uri, lsp_types::Position::new(1, 2), "other_rule")?;
+        let changes = edit.changes.unwrap();
+        assert_eq!(
+            changes[&Url::from_file_path(build_file).unwrap()]
+                .iter()
+                .map(|edit| (edit.range.start.line, edit.new_text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(0, "other_rule"), (1, "other_rule")]
+        );
+
+        // Builtin rules can't be renamed.
+        context.parse_file_with_contents(&uri, "cc_library(name = 'foo')".to_owned());
+        assert!(context
+            .rename(&uri, lsp_types::Position::new(0, 2), "my_library")
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_in_bazelrc() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join(".bazelrc"));
+        let result = context
+            .parse_file_with_contents(&uri, "common --noenable_bzlmod\nbuild --no\n".to_string());
+        assert!(result.diagnostics.is_empty());
+
+        let completions: Vec<String> = context
+            .bazelrc_completions(
+                &uri,
+                ResolvedPos {
+                    line: 1,
+                    column: 10,
+                },
+            )
+            .into_iter()
+            .map(|completion| completion.value)
+            .collect();
+        assert!(completions.contains(&"--noincompatible_strict_action_env".to_owned()));
+        assert!(completions.iter().all(|flag| flag.starts_with("--no")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_target_under_cursor() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let root = fixture.workspace_root();
+        let build_file = root.join("foo").join("BUILD");
+        let context = fixture
+            .context_builder()?
+            .build_output(
+                "//foo:main",
+                false,
+                &format!(
+                    "ERROR: {}:1:11: Compiling foo/main.cc failed: (Exit 1)\nfoo/main.cc:3:5: error: expected ';'\nINFO: Elapsed time: 1.2s\n",
+                    build_file.display()
+                ),
+            )
+            .build_output("//lib:rules", true, "INFO: Build completed successfully\n")
+            .build()?;
+
+        let uri = LspUrl::File(build_file.clone());
+        context.parse_file_with_contents(
+            &uri,
+            "cc_library(\n    name = \"main\",\n    srcs = [\"main.cc\"],\n    deps = [\"//lib:rules\"],\n)\n"
+                .into(),
+        );
+        let build =
+            |line, column| context.build_target_at(&uri, ResolvedPos { line, column }, Some(&root));
+
+        // Within a target, but not on a label, the target itself is built.
+        let report = build(2, 5)?;
+        assert_eq!(report.label, "//foo:main");
+        assert!(!report.success);
+        assert_eq!(
+            report
+                .diagnostics
+                .iter()
+                .map(|(path, diagnostic)| (
+                    path.clone(),
+                    diagnostic.range.start.line,
+                    diagnostic.range.start.character,
+                    diagnostic.message.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    build_file.clone(),
+                    0,
+                    10,
+                    "Compiling foo/main.cc failed: (Exit 1)"
+                ),
+                (root.join("foo").join("main.cc"), 2, 4, "expected ';'"),
+            ]
+        );
+
+        // Labels are built instead of the target they are passed to.
+        let report = build(3, 15)?;
+        assert_eq!(report.label, "//lib:rules");
+        assert!(report.success);
+        assert!(report.diagnostics.is_empty());
+
+        // Outside of any target, nothing is built.
+        assert!(build(5, 0).is_err());
+        assert_eq!(context.client.profile.borrow().build, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_build_diagnostics() {
+        let diagnostics = build_diagnostics(
+            "INFO: Analyzed target //foo:main.
+ERROR: C:\\src\\foo\\BUILD:3:10: no such target '//bar:baz': target 'baz' not declared
+foo/main.cc:7:2: warning: unused variable 'x'
+",
+            Path::new("/workspace"),
+        );
+
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|(path, diagnostic)| (
+                    path.clone(),
+                    diagnostic.range.start,
+                    diagnostic.severity,
+                    diagnostic.message.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Path::new("/workspace").join("C:\\src\\foo\\BUILD"),
+                    lsp_types::Position::new(2, 9),
+                    Some(DiagnosticSeverity::ERROR),
+                    "no such target '//bar:baz': target 'baz' not declared"
+                ),
+                (
+                    Path::new("/workspace").join("foo/main.cc"),
+                    lsp_types::Position::new(6, 1),
+                    Some(DiagnosticSeverity::WARNING),
+                    "unused variable 'x'"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_target() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query("rdeps(//..., //foo:main, 1)", "//foo:main\n//:foo\n")
+            .build()?;
+
+        let build_file = fixture.workspace_root().join("foo").join("BUILD");
+        let uri = LspUrl::File(build_file.clone());
+        context.parse_file_with_contents(&uri, fs::read_to_string(&build_file)?);
+
+        let edit = context.rename(&uri, lsp_types::Position::new(1, 13), "core")?;
+        let document_changes = match edit.document_changes.unwrap() {
+            DocumentChanges::Edits(edits) => edits,
+            DocumentChanges::Operations(_) => panic!("Expected only edits"),
+        };
+        let edits: Vec<_> = document_changes
+            .iter()
+            .flat_map(|change| {
+                change.edits.iter().map(|edit| match edit {
+                    OneOf::Left(edit) => (&change.text_document.uri, edit, None),
+                    OneOf::Right(annotated) => (
+                        &change.text_document.uri,
+                        &annotated.text_edit,
+                        Some(annotated.annotation_id.as_str()),
+                    ),
+                })
+            })
+            .map(|(uri, edit, annotation)| {
+                (
+                    uri.to_file_path().unwrap(),
+                    edit.range.start.line,
+                    edit.new_text.as_str(),
+                    annotation,
+                )
+            })
+            .collect();
+
+        // The referencing package is only edited after confirmation.
+        assert_eq!(
+            edits,
+            vec![
+                (build_file, 1, "core", None),
+                (
+                    fixture.workspace_root().join("BUILD"),
+                    6,
+                    "//foo:core",
+                    Some("target-references")
+                ),
+            ]
+        );
+        assert_eq!(
+            edit.change_annotations.unwrap()["target-references"].needs_confirmation,
+            Some(true)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_target_with_partially_failed_rdeps() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .partial_query("rdeps(//..., //foo:main, 1)", "//foo:main\n//:foo\n")
+            .build()?;
+
+        let build_file = fixture.workspace_root().join("foo").join("BUILD");
+        let uri = LspUrl::File(build_file.clone());
+        context.parse_file_with_contents(&uri, fs::read_to_string(&build_file)?);
+
+        // The dependents that were found are renamed, even if other packages are broken.
+        let edit = context.rename(&uri, lsp_types::Position::new(1, 13), "core")?;
+        let edited_files: Vec<_> = match edit.document_changes.unwrap() {
+            DocumentChanges::Edits(edits) => edits
+                .into_iter()
+                .map(|change| change.text_document.uri.to_file_path().unwrap())
+                .collect(),
+            DocumentChanges::Operations(_) => panic!("Expected only edits"),
+        };
+        assert_eq!(
+            edited_files,
+            vec![build_file, fixture.workspace_root().join("BUILD")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature_help() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let signature_help = |contents: &str, line, character| {
+            context.parse_file_with_contents(&uri, contents.to_owned());
+            context
+                .signature_help(&uri, lsp_types::Position::new(line, character))
+                .map(|help| (help.signatures[0].label.clone(), help.active_parameter))
+        };
+
+        // Loaded functions, with an incomplete call.
+        assert_eq!(
+            signature_help(
+                "load('//lib:defs.bzl', 'exported')\nexported('foo', ",
+                1,
+                16
+            ),
+            Some(("exported(name, srcs=[], **kwargs)".into(), Some(1)))
+        );
+
+        // Rules, which only take named arguments.
+        let help = signature_help("cc_library(name = 'foo', srcs = [])", 0, 34);
+        let (label, active_parameter) = help.unwrap();
+        assert!(label.starts_with("cc_library("));
+        assert!(active_parameter.is_some());
+
+        // Unknown functions have no signature.
+        assert_eq!(signature_help("unknown(1)", 0, 8), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_for_file() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+
+        let context = fixture.context()?;
+        assert_eq!(
+            context.label_hover(
+                "//foo:main.cc",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            Some(format!(
+                "`{}`",
+                fixture
+                    .workspace_root()
+                    .join("foo")
+                    .join("main.cc")
+                    .display()
+            ))
+        );
+
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                relative_paths: true,
+                ..Default::default()
+            })
+            .build()?;
+        assert_eq!(
+            context.label_hover(
+                "//foo:main.cc",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            Some("`foo/main.cc`".into())
+        );
+        assert_eq!(
+            context.label_hover(
+                "@foo//:foo.bzl",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            Some("`external/foo/foo.bzl`".into())
+        );
+
+        // Files in packages that don't exist are marked as such.
+        assert_eq!(
+            context.label_hover(
+                "//missing:file.txt",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            Some("`missing/file.txt` (doesn't exist)".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_and_completion_for_target_patterns() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let workspace_file = LspUrl::File(fixture.workspace_root().join("WORKSPACE"));
+
+        let hover = |literal: &str| {
+            context.label_hover(literal, &workspace_file, Some(&fixture.workspace_root()))
+        };
+        assert_eq!(
+            hover("//foo/..."),
+            Some("All targets in `//foo` and the packages below it".into())
+        );
+        assert_eq!(hover("//foo:all"), Some("All targets in `//foo`".into()));
+        assert_eq!(hover("//foo:*"), Some("All targets in `//foo`".into()));
+        assert_eq!(
+            hover("//foo/...:all"),
+            Some("All targets in `//foo` and the packages below it".into())
+        );
+        // A `...` after the colon is the name of a target, not a pattern.
+        assert!(!hover("//foo:...").map_or(false, |hover| hover.starts_with("All targets")));
+
+        // Patterns navigate to the BUILD file of their package.
+        let foo_build_file: LspUrl =
+            Url::from_file_path(fixture.workspace_root().join("foo").join("BUILD"))
+                .unwrap()
+                .try_into()?;
+        for pattern in ["//foo/...", "//foo:all", "//foo:*", "//foo/...:all"] {
+            let result = context
+                .resolve_string_literal_location(
+                    pattern,
+                    &workspace_file,
+                    Some(&fixture.workspace_root()),
+                )?
+                .unwrap();
+            assert_eq!(result.url, foo_build_file, "{pattern}");
+        }
+
+        let completions = |current_value: &str| -> anyhow::Result<Vec<(String, Option<String>)>> {
+            context.parse_file_with_contents(
+                &workspace_file,
+                format!("register_toolchains({:?})", current_value),
+            );
+
+            Ok(context
+                .string_completions(
+                    &workspace_file,
+                    StringCompletionType::String,
+                    current_value,
+                    Some(&fixture.workspace_root()),
+                )?
+                .into_iter()
+                .map(|completion| (completion.value, completion.insert_text))
+                .collect())
+        };
+
+        // `register_toolchains()` takes patterns, so they are offered after a package.
+        assert!(completions("//foo:")?.contains(&("all".to_owned(), Some("all".to_owned()))));
+        assert!(completions("//")?.contains(&("...".to_owned(), Some("...".to_owned()))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_for_target_shows_build_file() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .query_build("//foo:main", "cc_library(\n  name = \"main\",\n)\n")
+            .build()?;
+
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let build_file = fixture.workspace_root().join("foo").join("BUILD");
+
+        assert_eq!(
+            context.label_hover("//foo:main", &current_file, Some(&fixture.workspace_root())),
+            Some(format!(
+                "**cc_library** `//foo:main`\n\nDefined in `{}`",
+                build_file.display()
+            ))
+        );
+        // Targets that can't be queried still show their BUILD file.
+        assert_eq!(
+            context.label_hover(
+                "//foo:unknown",
+                &current_file,
+                Some(&fixture.workspace_root())
+            ),
+            Some(format!("Defined in `{}`", build_file.display()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_builtins() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        fn module_contains(module: &DocModule, value: &str) -> bool {
+            module.members.iter().any(|(member, _)| member == value)
+        }
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar.bzl")));
+
+        assert!(!module_contains(&module, "glob"));
+        assert!(module_contains(&module, "range"));
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/BUILD")));
+
+        assert!(module_contains(&module, "glob"));
+        assert!(module_contains(&module, "range"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_rules() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        fn module_contains(module: &DocModule, value: &str) -> bool {
+            module.members.iter().any(|(member, _)| member == value)
+        }
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar.bzl")));
+
+        assert!(module_contains(&module, "cc_library"));
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/BUILD")));
+
+        assert!(module_contains(&module, "cc_library"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_configuration_files() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("lib").join("flags.scl"));
+
+        fn module_contains(module: &DocModule, value: &str) -> bool {
+            module.members.iter().any(|(member, _)| member == value)
+        }
+
+        let result = context.parse_file_with_contents(
+            &uri,
+            "load(':other.scl', 'x')\nload(':defs.bzl', 'exported')\nflags = [x, len(exported)]\n"
+                .to_owned(),
+        );
+        assert!(result.ast.is_some());
+        let mismatches = context
+            .semantic_diagnostics(&uri)
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("file-type-mismatch".into()))
+            })
+            .map(|diagnostic| diagnostic.range.start.line)
+            .collect::<Vec<_>>();
+        assert_eq!(mismatches, vec![1]);
+
+        let module = context.get_environment(&uri);
+
+        assert!(module_contains(&module, "range"));
+        assert!(!module_contains(&module, "cc_library"));
+        assert!(!module_contains(&module, "glob"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_documents_in_the_dialect_of_their_file_type() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let contents = "def double(x: int) -> int:\n    return 2 * x\n";
+
+        // Type annotations are only supported outside of `.scl` files.
+        let bzl = LspUrl::File(fixture.workspace_root().join("lib").join("double.bzl"));
+        context.parse_file_with_contents(&bzl, contents.to_owned());
+        assert!(context.document_ast(&bzl).is_some());
+
+        let scl = LspUrl::File(fixture.workspace_root().join("lib").join("double.scl"));
+        context.parse_file_with_contents(&scl, contents.to_owned());
+        assert!(context.document_ast(&scl).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_package() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/BUILD")));
+        let params = match module.members.get("package") {
+            Some(DocItem::Member(DocMember::Function(function))) => function
+                .params
+                .named_only
+                .iter()
+                .map(|param| param.name.as_str())
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        assert!(params.contains(&"default_visibility"));
+        assert!(params.contains(&"default_testonly"));
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar.bzl")));
+        assert!(module.members.get("package").is_none());
+
+        Ok(())
+    }
+
+    fn get_function_doc(file_path: &str, function_name: &str) -> DocFunction {
+        let fixture = TestFixture::new("simple").unwrap();
+        let context = fixture.context().unwrap();
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from(file_path)));
+
+        let (_, function) = module
+            .members
+            .iter()
+            .find(|(member, _)| *member == function_name)
+            .unwrap();
+
+        match function {
+            DocItem::Member(DocMember::Function(f)) => f.clone(),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_function_doc_code_block() -> anyhow::Result<()> {
+        let doc = get_function_doc("/foo/bar/sample.bzl", "hasattr");
+
+        assert_eq!(
+            doc.docs.clone().unwrap().summary,
+            "Returns True if the object `x` has an attribute or method of the given `name`, otherwise False. Example:  \n```python\nhasattr(ctx.attr, \"myattr\")\n```"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_doc_links() -> anyhow::Result<()> {
+        // select doc contains both page relative links (#) and absolute links (/).
+        let select_doc = get_function_doc("sample.bzl", "select");
+        assert_eq!(
+            select_doc.docs.clone().unwrap().summary,
+            "`select()` is the helper function that makes a rule attribute configurable. See [build encyclopedia](https://bazel.build/reference/be/functions#select) for details."
+        );
+
+        assert_eq!(
+            select_doc.params.pos_or_named,
+            vec![
+                DocParam {
+                    name: "x".into(),
+                    default_value: None,
+                    docs: Some(DocString {
+                      summary: "A dict that maps configuration conditions to values. Each key is a [Label](https://bazel.build/rules/lib/builtins/Label.html) or a label string that identifies a config\\_setting or constraint\\_value instance. See the [documentation on macros](https://bazel.build/rules/macros#label-resolution) for when to use a Label instead of a string.".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+                DocParam {
+                    name: "no_match_error".into(),
+                    default_value: Some("''".into()),
+                    docs: Some(DocString {
+                        summary: "Optional custom error to report if no condition matches.".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+            ]
+        );
+
+        // aspect contains absolute link.
+        let aspect_doc = get_function_doc("sample.bzl", "aspect");
+        assert_eq!(
+            aspect_doc.docs.clone().unwrap().summary,
+            "Creates a new aspect. The result of this function must be stored in a global value. Please see the [introduction to Aspects](https://bazel.build/rules/aspects) for more details."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_doc_params() -> anyhow::Result<()> {
+        let glob_doc = get_function_doc("/foo/bar/BUILD", "glob");
+
+        assert_eq!(
+            glob_doc.params.pos_or_named,
+            vec![
+                DocParam {
+                    name: "include".into(),
+                    default_value: Some("[]".into()),
+                    docs: Some(DocString {
+                        summary: "The list of glob patterns to include.".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+                DocParam {
+                    name: "exclude".into(),
+                    default_value: Some("[]".into()),
+                    docs: Some(DocString {
+                        summary: "The list of glob patterns to exclude.".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+                DocParam {
+                    name: "exclude_directories".into(),
+                    default_value: Some("1".into()),
+                    docs: Some(DocString {
+                        summary: "A flag whether to exclude directories or not.".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+                DocParam {
+                    name: "allow_empty".into(),
+                    // TODO: Fix this
+                    default_value: Some("unbound".into()),
+                    docs: Some(DocString {
+                        summary: "Whether we allow glob patterns to match nothing. If `allow_empty` is False, each individual include pattern must match something and also the final result must be non-empty (after the matches of the `exclude` patterns are excluded).".into(),
+                        details: None,
+                    }),
+                    typ: Ty::any(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_doc_args_kwargs() -> anyhow::Result<()> {
+        assert_eq!(
+            get_function_doc("BUILD", "max").params.args,
+            Some(DocParam {
+                name: "args".into(),
+                default_value: None,
+                docs: Some(DocString {
+                    summary: "The elements to be checked.".into(),
+                    details: None,
+                }),
+                typ: Ty::any(),
+            })
+        );
+
+        assert_eq!(
+            get_function_doc("BUILD", "dict").params.kwargs,
+            Some(DocParam {
+                name: "kwargs".into(),
+                default_value: None,
+                docs: Some(DocString {
+                    summary: "Dictionary of additional entries.".into(),
+                    details: None,
+                }),
+                typ: Ty::any(),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Empty summary in DocString strings break starlark-rust. See #41. Here
+    /// we ensure that instead of generating an empty summary, the whole
+    /// DocString is None.
+    fn no_empty_documentation_is_produced() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/defs.bzl")));
+
+        fn validate_doc_item(item: &DocItem) {
+            match item {
+                DocItem::Module(module) => {
+                    validate_doc_string(module.docs.as_ref());
+                    for member in module.members.values() {
+                        validate_doc_item(member)
+                    }
+                }
+                DocItem::Type(r#type) => {
+                    validate_doc_string(r#type.docs.as_ref());
+                    for member in r#type.members.values() {
+                        validate_doc_member(member);
+                    }
+                }
+                DocItem::Member(member) => {
+                    validate_doc_member(&member);
+                }
+            }
+        }
+
+        fn validate_doc_member(member: &DocMember) {
+            match member {
+                DocMember::Function(function) => {
+                    validate_doc_string(function.docs.as_ref());
+                    for param in &function.params.pos_or_named {
+                        validate_doc_string(param.get_doc_string());
+                    }
+                }
+                DocMember::Property(property) => {
+                    validate_doc_string(property.docs.as_ref());
+                }
+            }
+        }
+
+        fn validate_doc_string(doc: Option<&DocString>) {
+            if let Some(doc) = doc {
+                assert!(!doc.summary.trim().is_empty());
+            }
+        }
+
+        for item in module.members.values() {
+            validate_doc_item(item)
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_undefined_global_symbols() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context.parse_file_with_contents(
+            &LspUrl::File(PathBuf::from("/foo.bzl")),
+            "
+test_suite(name='my_test_suite');
+
+unknown_global_function(42);
+
+a=int(7);
+
+register_toolchains([':my_toolchain']);
+"
+            .to_string(),
+        );
+
+        assert_eq!(1, result.diagnostics.len());
+        assert_eq!(
+            "Use of undefined variable `unknown_global_function`",
+            result.diagnostics[0].message
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_unterminated_lists_from_the_opening_bracket() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let result = context.parse_file_with_contents(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            "cc_library(name = 'lib')\n\nSRCS = [\n    'a.cc',  # ]\n    ')',\n".to_string(),
+        );
+
+        assert!(result.ast.is_none());
+        assert_eq!(1, result.diagnostics.len());
+        let range = result.diagnostics[0].range;
+        assert_eq!((range.start.line, range.start.character), (2, 7));
+        assert!(range.end.line >= 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn suggests_missing_loads() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("defs.bzl"));
+        let diagnostics = semantic_diagnostics(
+            &context,
+            &uri,
+            "load('//lib:defs.bzl', 'exported')
+
+def my_macro(name):
+    fancy_library(name = name)
+    return FancyInfo(), unknown_function()
+"
+            .to_owned(),
+        );
+
+        let mut messages: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            vec![
+                "`FancyInfo` can be loaded from `//lib:rules.bzl`",
+                "`fancy_library` can be loaded from `//lib:rules.bzl`",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn offers_code_actions_for_missing_loads() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("defs.bzl"));
+
+        let code_actions = |contents: &str| {
+            let result = context.parse_file_with_contents(&uri, contents.to_owned());
+            let url = Url::from_file_path(uri.path()).unwrap();
+
+            context
+                .missing_load_code_actions(&uri, &result.diagnostics)
+                .into_iter()
+                .flat_map(|action| {
+                    let edits = action.edit.unwrap().changes.unwrap().remove(&url).unwrap();
+                    edits
+                        .into_iter()
+                        .map(move |edit| (action.title.clone(), edit.range.start, edit.new_text))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Without loads, the new load is added at the top of the file.
+        assert_eq!(
+            code_actions(
+                "def my_macro(name):
+    fancy_library(name = name)
+"
+            ),
+            vec![(
+                "Load `fancy_library` from `@//lib:rules.bzl`".to_owned(),
+                lsp_types::Position::new(0, 0),
+                "load(\"@//lib:rules.bzl\", \"fancy_library\")\n".to_owned()
+            )]
+        );
+
+        // A docstring stays the first statement of the module.
+        assert_eq!(
+            code_actions(
+                "\"\"\"Macros for fancy libraries.\"\"\"
+
+def my_macro(name):
+    fancy_library(name = name)
+"
+            ),
+            vec![(
+                "Load `fancy_library` from `@//lib:rules.bzl`".to_owned(),
+                lsp_types::Position::new(1, 0),
+                "load(\"@//lib:rules.bzl\", \"fancy_library\")\n".to_owned()
+            )]
+        );
+
+        // A load of the same module is extended, even if it is spelled differently.
+        assert_eq!(
+            code_actions(
+                "load('//lib:defs.bzl', 'exported')
+load('//lib:rules.bzl', 'FancyInfo')
+
+def my_macro(name):
+    fancy_library(name = name)
+"
+            ),
+            vec![(
+                "Add `fancy_library` to the load of `//lib:rules.bzl`".to_owned(),
+                lsp_types::Position::new(1, 35),
+                ", \"fancy_library\"".to_owned()
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn offers_code_actions_wrapping_strings_in_lists() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        let url = Url::from_file_path(uri.path()).unwrap();
+
+        let code_actions = |contents: &str, position: lsp_types::Position| {
+            context.parse_file_with_contents(&uri, contents.to_owned());
+
+            context
+                .wrap_in_list_code_actions(&uri, lsp_types::Range::new(position, position))
+                .into_iter()
+                .map(|action| {
+                    let edits = action.edit.unwrap().changes.unwrap().remove(&url).unwrap();
+                    (
+                        action.title,
+                        edits
+                            .into_iter()
+                            .map(|edit| (edit.range.start, edit.new_text))
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            code_actions(
+                r#"cc_library(name = "foo", deps = "//foo")"#,
+                lsp_types::Position::new(0, 34)
+            ),
+            vec![(
+                "Wrap `deps` in a list".to_owned(),
+                vec![
+                    (lsp_types::Position::new(0, 32), "[".to_owned()),
+                    (lsp_types::Position::new(0, 39), "]".to_owned())
+                ]
+            )]
+        );
+        // Attributes that aren't lists, and strings elsewhere in the file, aren't wrapped.
+        assert!(code_actions(
+            r#"cc_library(name = "foo", deps = "//foo")"#,
+            lsp_types::Position::new(0, 20)
+        )
+        .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn links_labels_to_their_files() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+
+        context.parse_file_with_contents(
+            &uri,
+            r#"cc_library(name = "main", srcs = ["main.cc"], data = ["//lib:defs.bzl", r'''//lib:defs.bzl'''])"#
+                .to_owned(),
+        );
+
+        // Only labels are linked, and they aren't resolved until needed.
+        let links = context.document_links(&uri);
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[0].range,
+            lsp_types::Range::new(
+                lsp_types::Position::new(0, 55),
+                lsp_types::Position::new(0, 69)
+            )
+        );
+        // The prefix and quotes of raw and triple-quoted literals aren't linked either.
+        assert_eq!(
+            links[1].range,
+            lsp_types::Range::new(
+                lsp_types::Position::new(0, 76),
+                lsp_types::Position::new(0, 90)
+            )
+        );
+        assert_eq!(links[0].target, None);
+
+        let link = context.resolve_document_link(links[0].clone());
+        assert_eq!(
+            link.target,
+            Some(
+                Url::from_file_path(fixture.workspace_root().join("lib").join("defs.bzl")).unwrap()
+            )
+        );
+
+        Ok(())
+    }
 
     #[test]
-    fn relative_resolve_load_in_external_repository() -> anyhow::Result<()> {
+    fn reports_and_fixes_malformed_label_paths() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        let url = Url::from_file_path(uri.path()).unwrap();
 
-        let url = context.resolve_load(
-            "//:foo.bzl",
-            &LspUrl::File(fixture.external_dir("foo").join("BUILD")),
-            None,
-        )?;
+        let result = context.parse_file_with_contents(
+            &uri,
+            r#"cc_library(name = "foo", deps = ["//foo//bar:baz", "//foo\\bar:qux"])"#.to_owned(),
+        );
+        let messages = result
+            .diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("malformed-label-path".into()))
+            })
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "Malformed path in `//foo//bar:baz`, use `//foo/bar:baz` instead",
+                "Malformed path in `//foo\\bar:qux`, use `//foo/bar:qux` instead",
+            ]
+        );
 
+        let code_actions = |position: lsp_types::Position| {
+            context
+                .normalize_label_path_code_actions(&uri, lsp_types::Range::new(position, position))
+                .into_iter()
+                .flat_map(|action| action.edit.unwrap().changes.unwrap().remove(&url).unwrap())
+                .map(|edit| (edit.range, edit.new_text))
+                .collect::<Vec<_>>()
+        };
+        // The contents of the literal are replaced, but not its quotes.
         assert_eq!(
-            url,
-            Url::from_file_path(fixture.external_dir("foo").join("foo.bzl"))
-                .unwrap()
-                .try_into()?
+            code_actions(lsp_types::Position::new(0, 40)),
+            vec![(
+                lsp_types::Range::new(
+                    lsp_types::Position::new(0, 34),
+                    lsp_types::Position::new(0, 48)
+                ),
+                "//foo/bar:baz".to_owned()
+            )]
         );
+        assert_eq!(
+            code_actions(lsp_types::Position::new(0, 55)),
+            vec![(
+                lsp_types::Range::new(
+                    lsp_types::Position::new(0, 52),
+                    lsp_types::Position::new(0, 66)
+                ),
+                "//foo/bar:qux".to_owned()
+            )]
+        );
+        assert!(code_actions(lsp_types::Position::new(0, 20)).is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn absolute_resolve_load_in_external_repository() -> anyhow::Result<()> {
+    fn finds_type_definitions_of_providers() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("defs.bzl"));
+        context.parse_file_with_contents(
+            &uri,
+            r#"load("//lib:rules.bzl", "FancyInfo")
 
-        let url = context.resolve_load(
-            "@bar//:bar.bzl",
-            &LspUrl::File(fixture.external_dir("foo").join("BUILD")),
-            None,
-        )?;
+LocalInfo = provider()
+
+def _impl(ctx):
+    return [FancyInfo(), LocalInfo(), ctx.attr.dep[CcInfo]]
+"#
+            .to_owned(),
+        );
+        let definition = |character| {
+            context
+                .provider_type_definition(&uri, lsp_types::Position::new(5, character))
+                .map(|location| (location.uri.to_string(), location.range))
+        };
 
+        // User-defined providers resolve to their declaration in the loaded module.
         assert_eq!(
-            url,
-            Url::from_file_path(fixture.external_dir("bar").join("bar.bzl"))
-                .unwrap()
-                .try_into()?
+            definition(13),
+            Some((
+                Url::from_file_path(fixture.workspace_root().join("lib").join("rules.bzl"))
+                    .unwrap()
+                    .to_string(),
+                lsp_types::Range::new(
+                    lsp_types::Position::new(0, 0),
+                    lsp_types::Position::new(0, 22)
+                )
+            ))
         );
+        assert_eq!(definition(26).map(|(_, range)| range.start.line), Some(2));
+        // Builtin providers resolve to their documentation.
+        assert_eq!(
+            definition(52).map(|(uri, _)| uri),
+            Some("https://bazel.build/rules/lib/providers/CcInfo".to_owned())
+        );
+        assert_eq!(definition(40), None);
 
         Ok(())
     }
 
     #[test]
-    fn external_resolve_load_in_root_workspace() -> anyhow::Result<()> {
+    fn reports_unknown_load_symbols() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
 
-        let url = context.resolve_load(
-            "@foo//:foo.bzl",
+        let diagnostics = semantic_diagnostics(
+            &context,
             &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            Some(&fixture.workspace_root()),
-        )?;
+            "
+load('//lib:defs.bzl', 'exported', 'does_not_exist', '_private')
+"
+            .to_string(),
+        );
+
+        let diagnostics = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("load-symbol-not-found".into()))
+            })
+            .collect::<Vec<_>>();
 
         assert_eq!(
-            url,
-            Url::from_file_path(fixture.external_dir("foo").join("foo.bzl"))
-                .unwrap()
-                .try_into()?
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.message.as_str())
+                .collect::<Vec<_>>(),
+            vec!["`does_not_exist` is not defined in `//lib:defs.bzl`"]
         );
 
         Ok(())
     }
 
     #[test]
-    fn external_resolve_load_in_bzlmod_workspace() -> anyhow::Result<()> {
-        let fixture = TestFixture::new("bzlmod")?;
-        let context = fixture
-            .context_builder()?
-            .repo_mapping_json(
-                "",
-                json!({
-                    "": "",
-                    "rules_rust": "rules_rust~0.36.2",
-                }),
-            )?
-            .build()?;
+    fn reports_load_cycles() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
 
-        let url = context.resolve_load(
-            "@rules_rust//rust:defs.bzl",
-            &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            Some(&fixture.workspace_root()),
-        )?;
+        let cycles = |path: &Path, contents: String| {
+            semantic_diagnostics(&context, &LspUrl::File(path.to_owned()), contents)
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("load-cycle".into()))
+                })
+                .map(|diagnostic| (diagnostic.range.start.line, diagnostic.message))
+                .collect::<Vec<_>>()
+        };
 
+        let lib = fixture.workspace_root().join("lib");
         assert_eq!(
-            url,
-            Url::from_file_path(
-                fixture
-                    .external_dir("rules_rust~0.36.2")
-                    .join("rust")
-                    .join("defs.bzl")
-            )
-            .unwrap()
-            .try_into()?
+            cycles(
+                &lib.join("cycle_a.bzl"),
+                fs::read_to_string(lib.join("cycle_a.bzl"))?
+            ),
+            vec![(
+                0,
+                "Load cycle: this file -> `:cycle_b.bzl` -> this file".to_owned()
+            )]
         );
-
-        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 1);
+        // Files which load a cycle aren't part of it.
+        assert!(cycles(&lib.join("BUILD"), "load(':cycle_a.bzl', 'a')".into()).is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_completion_for_repositories_in_root_workspace_with_bzlmod() -> anyhow::Result<()> {
-        let fixture = TestFixture::new("bzlmod")?;
-        let context = fixture
-            .context_builder()?
-            .repo_mapping_json(
-                "",
-                json!({
-                    "": "",
-                    "rules_rust": "rules_rust~0.36.2",
-                }),
-            )?
-            .build()?;
+    fn reports_loads_of_private_symbols() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture.context()?;
 
-        let completions = context.get_string_completion_options(
-            &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            StringCompletionType::String,
-            "@rules_ru",
-            Some(&fixture.workspace_root()),
-        )?;
+        let private_loads = |path: &Path, contents: &str| {
+            semantic_diagnostics(
+                &context,
+                &LspUrl::File(path.to_owned()),
+                contents.to_string(),
+            )
+            .into_iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("load-private-symbol".into()))
+            })
+            .map(|diagnostic| diagnostic.message)
+            .collect::<Vec<_>>()
+        };
 
         assert_eq!(
-            completions[0],
-            StringCompletionResult {
-                value: "@rules_rust".into(),
-                insert_text: Some("@rules_rust//".into()),
-                insert_text_offset: 0,
-                kind: CompletionItemKind::MODULE,
-            }
+            private_loads(
+                &fixture.workspace_root().join("BUILD"),
+                "load('//lib:defs.bzl', 'exported', '_private')"
+            ),
+            vec!["`_private` is private to `//lib:defs.bzl`"]
+        );
+        // Modules that can't be resolved are reported too, as are modules in the same package.
+        assert_eq!(
+            private_loads(
+                &fixture.workspace_root().join("BUILD"),
+                "load('@unknown//:defs.bzl', '_private')"
+            )
+            .len(),
+            1
+        );
+        assert_eq!(
+            private_loads(
+                &fixture.workspace_root().join("lib").join("BUILD"),
+                "load(':defs.bzl', '_private')"
+            )
+            .len(),
+            1
         );
-
-        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 1);
 
         Ok(())
     }
 
     #[test]
-    fn test_completion_for_packages_in_root_workspace_with_bzlmod() -> anyhow::Result<()> {
+    fn reports_unknown_repositories() -> anyhow::Result<()> {
         let fixture = TestFixture::new("bzlmod")?;
         let context = fixture
             .context_builder()?
@@ -942,284 +8807,423 @@ mod tests {
             )?
             .build()?;
 
-        let completions = context.get_string_completion_options(
+        let diagnostics = semantic_diagnostics(
+            &context,
             &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            StringCompletionType::LoadPath,
-            "@rules_rust//",
-            Some(&fixture.workspace_root()),
-        )?;
-
-        assert_eq!(
-            completions[0],
-            StringCompletionResult {
-                value: "rust".into(),
-                insert_text: Some("rust".into()),
-                insert_text_offset: "@rules_rust//".len(),
-                kind: CompletionItemKind::FOLDER,
-            }
+            "
+load('@rules_rust//rust:defs.bzl', 'rust_library')
+load('@rules_rutt//rust:defs.bzl', 'rust_binary')
+"
+            .to_string(),
         );
 
-        assert_eq!(context.client.profile.borrow().query, 0);
-        // TODO: Avoid duplicate dump_repo_mapping calls
-        assert_eq!(context.client.profile.borrow().dump_repo_mapping, 2);
+        let diagnostics = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("unknown-repository".into()))
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 2);
+        assert!(diagnostics[0].message.contains("`@rules_rutt`"));
+        assert!(diagnostics[0].message.contains("bazel mod deps"));
+
+        // Without Bzlmod, repositories are declared in `WORKSPACE` instead.
+        let error =
+            ResolveLoadError::UnknownRepository(Label::parse("@foo//:bar")?, "foo".into(), false);
+        assert!(error
+            .to_string()
+            .ends_with("declared in the WORKSPACE file"));
 
         Ok(())
     }
 
     #[test]
-    fn test_completion_for_bare_targets() -> anyhow::Result<()> {
+    fn reports_file_type_mismatches() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
 
-        let completions = context.get_string_completion_options(
+        let diagnostics = semantic_diagnostics(
+            &context,
             &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            StringCompletionType::String,
-            "",
-            Some(&fixture.workspace_root()),
-        )?;
+            "
+load('//lib:BUILD', 'exported')
 
-        let completion = completions
+cc_library(
+    name = 'foo',
+    srcs = ['//lib:defs.bzl'],
+    deps = ['//lib:defs.bzl', '//lib/defs.bzl:bar', '//foo:main'],
+)
+"
+            .to_string(),
+        );
+
+        let messages = diagnostics
             .iter()
-            .find(|completion| completion.value == "main.cc")
-            .unwrap();
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("file-type-mismatch".into()))
+            })
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
 
         assert_eq!(
-            *completion,
-            StringCompletionResult {
-                value: "main.cc".into(),
-                insert_text: Some("main.cc".into()),
-                insert_text_offset: 0,
-                kind: CompletionItemKind::FILE,
-            }
+            messages,
+            vec![
+                "`//lib:BUILD` is a BUILD file, which can't be loaded",
+                "`//lib:defs.bzl` is a .bzl file, which can't be depended on",
+                "The package of `//lib/defs.bzl:bar` is a file, not a directory containing a BUILD file",
+            ]
         );
 
+        // The BUILD file is only reported once, rather than also reporting unknown symbols.
+        assert!(!diagnostics.iter().any(|diagnostic| {
+            diagnostic.code == Some(NumberOrString::String("load-symbol-not-found".into()))
+        }));
+
         Ok(())
     }
 
     #[test]
-    fn test_completion_for_files_in_package() -> anyhow::Result<()> {
-        let fixture = TestFixture::new("simple")?;
+    fn resolve_load_of_generated_file() -> anyhow::Result<()> {
+        let fixture = TestFixture::copy("simple")?;
         let context = fixture.context()?;
 
-        let completions = context.get_string_completion_options(
-            &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            StringCompletionType::String,
-            "//foo:",
-            Some(&fixture.workspace_root()),
-        )?;
-
-        let completion = completions
-            .iter()
-            .find(|completion| completion.value == "main.cc")
-            .unwrap();
+        let generated_dir = fixture.bazel_bin().join("lib");
+        let generated_file = generated_dir.join("generated.bzl");
+        fs::create_dir_all(&generated_dir)?;
+        fs::write(&generated_file, "generated = 1")?;
 
-        assert_eq!(
-            *completion,
-            StringCompletionResult {
-                value: "main.cc".into(),
-                insert_text: Some("main.cc".into()),
-                insert_text_offset: "//foo:".len(),
-                kind: CompletionItemKind::FILE,
-            }
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let result = context.resolve_load(
+            "//lib:generated.bzl",
+            &current_file,
+            Some(&fixture.workspace_root()),
         );
 
+        assert_eq!(result?, LspUrl::File(generated_file));
+
         Ok(())
     }
 
     #[test]
-    fn test_completion_for_targets_in_package() -> anyhow::Result<()> {
+    fn resolve_load_with_redundant_separator() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture
             .context_builder()?
-            .query("//foo:*", "//foo:main\n")
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["redundant-label-separator".to_owned()]),
+                ..Default::default()
+            })
             .build()?;
 
-        let completions = context.get_string_completion_options(
-            &LspUrl::File(fixture.workspace_root().join("BUILD")),
-            StringCompletionType::String,
-            "//foo:",
-            Some(&fixture.workspace_root()),
-        )?;
+        let current_file = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        assert_eq!(
+            context.resolve_load(
+                "//lib/:defs.bzl",
+                &current_file,
+                Some(&fixture.workspace_root())
+            )?,
+            context.resolve_load(
+                "//lib:defs.bzl",
+                &current_file,
+                Some(&fixture.workspace_root())
+            )?
+        );
 
-        let completion = completions
+        let result = context.parse_file_with_contents(
+            &current_file,
+            "load('//lib/:defs.bzl', 'exported')".to_string(),
+        );
+        let diagnostics = result
+            .diagnostics
             .iter()
-            .find(|completion| completion.value == "main")
-            .unwrap();
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("redundant-label-separator".into()))
+            })
+            .collect::<Vec<_>>();
 
+        assert_eq!(diagnostics.len(), 1);
         assert_eq!(
-            *completion,
-            StringCompletionResult {
-                value: "main".into(),
-                insert_text: Some("main".into()),
-                insert_text_offset: "//foo:".len(),
-                kind: CompletionItemKind::PROPERTY,
-            }
+            diagnostics[0].message,
+            "Redundant `/` in `//lib/:defs.bzl`, use `//lib:defs.bzl` instead"
         );
 
-        assert_eq!(context.client.profile.borrow().query, 1);
-
         Ok(())
     }
 
     #[test]
-    fn test_environment_builtins() -> anyhow::Result<()> {
+    fn reports_ineffective_glob_excludes() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
 
-        fn module_contains(module: &DocModule, value: &str) -> bool {
-            module.members.iter().any(|(member, _)| member == value)
-        }
+        let diagnostics = semantic_diagnostics(
+            &context,
+            &LspUrl::File(fixture.workspace_root().join("gen").join("BUILD")),
+            "
+filegroup(
+    name = 'templates',
+    srcs = glob(['**/*.tmpl'], exclude = ['templates/nested/**', '**/*.cc']),
+)
+"
+            .to_string(),
+        );
 
-        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar.bzl")));
+        let messages = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("ineffective-glob-exclude".into()))
+            })
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
 
-        assert!(!module_contains(&module, "glob"));
-        assert!(module_contains(&module, "range"));
+        assert_eq!(
+            messages,
+            vec!["`**/*.cc` doesn't exclude any files matched by the glob"]
+        );
 
-        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/BUILD")));
+        Ok(())
+    }
 
-        assert!(module_contains(&module, "glob"));
-        assert!(module_contains(&module, "range"));
+    #[test]
+    fn reports_missing_source_files_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["missing-source-file".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+
+        let diagnostics = semantic_diagnostics(
+            &context,
+            &LspUrl::File(fixture.workspace_root().join("foo").join("BUILD")),
+            "
+genrule(name = 'gen', outs = ['generated.h'], cmd = '')
+cc_library(
+    name = 'main',
+    srcs = ['main.cc', 'missing.cc', 'generated.h', ':gen', '//lib:defs.bzl'],
+    data = glob(['*.txt']),
+)
+"
+            .to_string(),
+        );
+
+        let messages = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("missing-source-file".into()))
+            })
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            messages,
+            vec![
+                "`missing.cc` doesn't exist in the package, and isn't generated by a target in it"
+            ]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_environment_rules() -> anyhow::Result<()> {
+    fn reports_conflicting_visibility() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
 
-        fn module_contains(module: &DocModule, value: &str) -> bool {
-            module.members.iter().any(|(member, _)| member == value)
-        }
-
-        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar.bzl")));
-
-        assert!(module_contains(&module, "cc_library"));
-
-        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/BUILD")));
+        let conflicts = |contents: &str| {
+            context
+                .parse_file_with_contents(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    contents.to_string(),
+                )
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("conflicting-visibility".into()))
+                })
+                .map(|diagnostic| diagnostic.message)
+                .collect::<Vec<_>>()
+        };
 
-        assert!(module_contains(&module, "cc_library"));
+        assert_eq!(
+            conflicts(
+                "filegroup(name = 'foo', visibility = ['//visibility:public', '//foo:__pkg__'])"
+            ),
+            vec!["`//visibility:public` can't be combined with other visibility labels"]
+        );
+        assert!(conflicts(
+            "filegroup(name = 'foo', visibility = ['//foo:__pkg__', '//bar:__subpackages__'])"
+        )
+        .is_empty());
+        assert!(
+            conflicts("filegroup(name = 'foo', visibility = ['//visibility:private'])").is_empty()
+        );
 
         Ok(())
     }
 
-    fn get_function_doc(file_path: &str, function_name: &str) -> DocFunction {
-        let fixture = TestFixture::new("simple").unwrap();
-        let context = fixture.context().unwrap();
+    #[test]
+    fn reports_empty_build_files_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let uri = LspUrl::File(fixture.workspace_root().join("BUILD"));
+        let is_reported = |context: &BazelContext<ProfilingClient<MockBazel>>, contents: &str| {
+            context
+                .parse_file_with_contents(&uri, contents.to_owned())
+                .diagnostics
+                .iter()
+                .any(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("empty-build-file".into()))
+                })
+        };
 
-        let module = context.get_environment(&LspUrl::File(PathBuf::from(file_path)));
+        let context = fixture.context()?;
+        assert!(!is_reported(&context, "# Nothing here\n"));
 
-        let (_, function) = module
-            .members
-            .iter()
-            .find(|(member, _)| *member == function_name)
-            .unwrap();
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["empty-build-file".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+        assert!(is_reported(
+            &context,
+            "load('//lib:defs.bzl', 'exported')\npackage(default_visibility = ['//visibility:public'])\n"
+        ));
+        assert!(!is_reported(&context, "filegroup(name = 'foo')\n"));
 
-        match function {
-            DocItem::Member(DocMember::Function(f)) => f.clone(),
-            _ => panic!(),
-        }
+        Ok(())
     }
 
     #[test]
-    fn test_function_doc_code_block() -> anyhow::Result<()> {
-        let doc = get_function_doc("/foo/bar/sample.bzl", "hasattr");
+    fn reports_loads_not_at_top_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let uri = LspUrl::File(fixture.workspace_root().join("lib").join("more.bzl"));
+        let reported = |context: &BazelContext<ProfilingClient<MockBazel>>, contents: &str| {
+            context
+                .parse_file_with_contents(&uri, contents.to_owned())
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("load-not-at-top".into()))
+                })
+                .map(|diagnostic| diagnostic.range.start.line)
+                .collect::<Vec<_>>()
+        };
+        let interleaved =
+            "load(':defs.bzl', 'exported')\nx = 1\nload(':defs.bzl', y = 'exported')\n";
+
+        let context = fixture.context()?;
+        assert_eq!(reported(&context, interleaved), Vec::<u32>::new());
 
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["load-not-at-top".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+        assert_eq!(reported(&context, interleaved), vec![2]);
+        // Loads may follow the docstring of the module.
         assert_eq!(
-            doc.docs.clone().unwrap().summary,
-            "Returns True if the object `x` has an attribute or method of the given `name`, otherwise False. Example:  \n```python\nhasattr(ctx.attr, \"myattr\")\n```"
+            reported(
+                &context,
+                "\"\"\"Docs.\"\"\"\n\nload(':defs.bzl', 'exported')\nload(':defs.bzl', y = 'exported')\n"
+            ),
+            Vec::<u32>::new()
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_function_doc_links() -> anyhow::Result<()> {
-        // select doc contains both page relative links (#) and absolute links (/).
-        let select_doc = get_function_doc("sample.bzl", "select");
-        assert_eq!(
-            select_doc.docs.clone().unwrap().summary,
-            "`select()` is the helper function that makes a rule attribute configurable. See [build encyclopedia](https://bazel.build/reference/be/functions#select) for details."
-        );
+    fn reports_test_name_mismatches_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        let reported = |context: &BazelContext<ProfilingClient<MockBazel>>| {
+            context
+                .parse_file_with_contents(
+                    &uri,
+                    r#"cc_test(name = "parser_test", srcs = ["parser_test.cc"])
+cc_test(name = "lexer", srcs = ["lexer_test.cc"])
+cc_test(name = "printer_test", srcs = ["formatter_test.cc"])
+py_test(name = "cli_test", srcs = ["tests/main.py"])
+cc_test(name = "combined_test", srcs = ["a_test.cc", "b_test.cc"])
+cc_test(name = "generated_test", srcs = [":gen"])
+cc_library(name = "lib", srcs = ["other.cc"])
+"#
+                    .to_owned(),
+                )
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("test-name-mismatch".into()))
+                })
+                .map(|diagnostic| diagnostic.range.start.line)
+                .collect::<Vec<_>>()
+        };
 
-        assert_eq!(
-            select_doc.params.pos_or_named,
-            vec![
-                DocParam {
-                    name: "x".into(),
-                    default_value: None,
-                    docs: Some(DocString {
-                      summary: "A dict that maps configuration conditions to values. Each key is a [Label](https://bazel.build/rules/lib/builtins/Label.html) or a label string that identifies a config\\_setting or constraint\\_value instance. See the [documentation on macros](https://bazel.build/rules/macros#label-resolution) for when to use a Label instead of a string.".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
-                DocParam {
-                    name: "no_match_error".into(),
-                    default_value: Some("''".into()),
-                    docs: Some(DocString {
-                        summary: "Optional custom error to report if no condition matches.".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
-            ]
-        );
+        let context = fixture.context()?;
+        assert_eq!(reported(&context), Vec::<u32>::new());
 
-        // aspect contains absolute link.
-        let aspect_doc = get_function_doc("sample.bzl", "aspect");
-        assert_eq!(
-            aspect_doc.docs.clone().unwrap().summary,
-            "Creates a new aspect. The result of this function must be stored in a global value. Please see the [introduction to Aspects](https://bazel.build/rules/aspects) for more details."
-        );
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["test-name-mismatch".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+        // Tests with several sources, and sources that are labels, aren't checked.
+        assert_eq!(reported(&context), vec![2, 3]);
 
         Ok(())
     }
 
     #[test]
-    fn test_function_doc_params() -> anyhow::Result<()> {
-        let glob_doc = get_function_doc("/foo/bar/BUILD", "glob");
+    fn reports_malformed_labels_when_enabled() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
+        let reported = |context: &BazelContext<ProfilingClient<MockBazel>>| {
+            context
+                .parse_file_with_contents(
+                    &uri,
+                    r#"cc_library(
+    name = "main",
+    deps = ["//foo::bar", "@foo!bar//:baz", "//lib:rules", "//foo//bar:baz"],
+    cmd = "cp $(location //foo::bar) $@",
+)
+"#
+                    .to_owned(),
+                )
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("malformed-label".into()))
+                })
+                .map(|diagnostic| diagnostic.message)
+                .collect::<Vec<_>>()
+        };
+
+        let context = fixture.context()?;
+        assert_eq!(reported(&context), Vec::<String>::new());
 
+        let context = fixture
+            .context_builder()?
+            .options(ContextOptions {
+                enabled_lints: HashSet::from(["malformed-label".to_owned()]),
+                ..Default::default()
+            })
+            .build()?;
+        // Strings that don't look like labels, e.g. commands, aren't reported, and malformed
+        // paths are only reported as `malformed-label-path`.
         assert_eq!(
-            glob_doc.params.pos_or_named,
+            reported(&context),
             vec![
-                DocParam {
-                    name: "include".into(),
-                    default_value: Some("[]".into()),
-                    docs: Some(DocString {
-                        summary: "The list of glob patterns to include.".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
-                DocParam {
-                    name: "exclude".into(),
-                    default_value: Some("[]".into()),
-                    docs: Some(DocString {
-                        summary: "The list of glob patterns to exclude.".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
-                DocParam {
-                    name: "exclude_directories".into(),
-                    default_value: Some("1".into()),
-                    docs: Some(DocString {
-                        summary: "A flag whether to exclude directories or not.".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
-                DocParam {
-                    name: "allow_empty".into(),
-                    // TODO: Fix this
-                    default_value: Some("unbound".into()),
-                    docs: Some(DocString {
-                        summary: "Whether we allow glob patterns to match nothing. If `allow_empty` is False, each individual include pattern must match something and also the final result must be non-empty (after the matches of the `exclude` patterns are excluded).".into(),
-                        details: None,
-                    }),
-                    typ: Ty::any(),
-                },
+                "Unable to parse the label `//foo::bar`: only one `:` may separate the package from the name",
+                "Unable to parse the label `@foo!bar//:baz`: the repository name contains the invalid character `!`",
             ]
         );
 
@@ -1227,117 +9231,132 @@ mod tests {
     }
 
     #[test]
-    fn test_function_doc_args_kwargs() -> anyhow::Result<()> {
-        assert_eq!(
-            get_function_doc("BUILD", "max").params.args,
-            Some(DocParam {
-                name: "args".into(),
-                default_value: None,
-                docs: Some(DocString {
-                    summary: "The elements to be checked.".into(),
-                    details: None,
-                }),
-                typ: Ty::any(),
+    fn reports_deprecated_rules_and_attributes() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let attribute = |name: &str, documentation: &str| AttributeDefinition {
+            name: name.to_owned(),
+            documentation: Some(documentation.to_owned()),
+            ..Default::default()
+        };
+        let context = fixture
+            .context_builder()?
+            .build_language(BuildLanguage {
+                rule: vec![
+                    RuleDefinition {
+                        name: "fancy_library".to_owned(),
+                        attribute: vec![
+                            attribute("name", "The name of the target."),
+                            attribute("copts", "<b>Deprecated:</b> Use <code>cxxopts</code>."),
+                            attribute("licenses", "The licenses of the target."),
+                        ],
+                        ..Default::default()
+                    },
+                    RuleDefinition {
+                        name: "old_library".to_owned(),
+                        documentation: Some("Deprecated.".to_owned()),
+                        attribute: vec![attribute("name", "The name of the target.")],
+                        ..Default::default()
+                    },
+                ],
             })
+            .build()?;
+
+        let result = context.parse_file_with_contents(
+            &LspUrl::File(fixture.workspace_root().join("foo").join("BUILD")),
+            r#"fancy_library(
+    name = "foo",
+    copts = ["-O2"],
+    licenses = ["notice"],
+)
+
+old_library(name = "bar")
+"#
+            .to_owned(),
         );
+        let messages: Vec<&str> = result
+            .diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.code == Some(NumberOrString::String("deprecated-usage".into()))
+            })
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
 
+        // Attributes are deprecated by their documentation, or by the bundled list.
         assert_eq!(
-            get_function_doc("BUILD", "dict").params.kwargs,
-            Some(DocParam {
-                name: "kwargs".into(),
-                default_value: None,
-                docs: Some(DocString {
-                    summary: "Dictionary of additional entries.".into(),
-                    details: None,
-                }),
-                typ: Ty::any(),
-            })
+            messages,
+            vec![
+                "`fancy_library.copts` is deprecated: Use `cxxopts`.",
+                "`fancy_library.licenses` is deprecated: Use `package_metadata` or `applicable_licenses` from rules_license instead.",
+                "`old_library` is deprecated",
+            ]
         );
 
         Ok(())
     }
 
     #[test]
-    /// Empty summary in DocString strings break starlark-rust. See #41. Here
-    /// we ensure that instead of generating an empty summary, the whole
-    /// DocString is None.
-    fn no_empty_documentation_is_produced() -> anyhow::Result<()> {
+    fn caches_globals_between_edits() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
         let context = fixture.context()?;
+        let uri = LspUrl::File(fixture.workspace_root().join("foo").join("BUILD"));
 
-        let module = context.get_environment(&LspUrl::File(PathBuf::from("/foo/bar/defs.bzl")));
-
-        fn validate_doc_item(item: &DocItem) {
-            match item {
-                DocItem::Module(module) => {
-                    validate_doc_string(module.docs.as_ref());
-                    for member in module.members.values() {
-                        validate_doc_item(member)
-                    }
-                }
-                DocItem::Type(r#type) => {
-                    validate_doc_string(r#type.docs.as_ref());
-                    for member in r#type.members.values() {
-                        validate_doc_member(member);
-                    }
-                }
-                DocItem::Member(member) => {
-                    validate_doc_member(&member);
-                }
-            }
-        }
-
-        fn validate_doc_member(member: &DocMember) {
-            match member {
-                DocMember::Function(function) => {
-                    validate_doc_string(function.docs.as_ref());
-                    for param in &function.params.pos_or_named {
-                        validate_doc_string(param.get_doc_string());
-                    }
-                }
-                DocMember::Property(property) => {
-                    validate_doc_string(property.docs.as_ref());
-                }
-            }
-        }
+        context.parse_file_with_contents(&uri, "cc_library(name = \"foo\")\n".to_owned());
+        assert_eq!(context.client.profile.borrow().build_language, 1);
 
-        fn validate_doc_string(doc: Option<&DocString>) {
-            if let Some(doc) = doc {
-                assert!(!doc.summary.trim().is_empty());
-            }
-        }
+        // Editing the document, or another document of the workspace, reuses the globals.
+        context.parse_file_with_contents(&uri, "cc_library(name = \"foo2\")\n".to_owned());
+        context.parse_file_with_contents(
+            &LspUrl::File(fixture.workspace_root().join("BUILD")),
+            "cc_binary(name = \"main\")\n".to_owned(),
+        );
+        assert_eq!(context.client.profile.borrow().build_language, 1);
 
-        for item in module.members.values() {
-            validate_doc_item(item)
-        }
+        // Refreshing fetches the build language again, e.g. after Bazel was upgraded.
+        context.refresh();
+        context.parse_file_with_contents(&uri, "cc_library(name = \"foo\")\n".to_owned());
+        assert_eq!(context.client.profile.borrow().build_language, 2);
 
         Ok(())
     }
 
     #[test]
-    fn reports_undefined_global_symbols() -> anyhow::Result<()> {
+    fn overrides_lint_severities() -> anyhow::Result<()> {
         let fixture = TestFixture::new("simple")?;
-        let context = fixture.context()?;
-
-        let result = context.parse_file_with_contents(
-            &LspUrl::File(PathBuf::from("/foo.bzl")),
-            "
-test_suite(name='my_test_suite');
-
-unknown_global_function(42);
-
-a=int(7);
-
-register_toolchains([':my_toolchain']);
-"
-            .to_string(),
-        );
+        let misplaced_loads = |severity: &str| -> anyhow::Result<Vec<Option<DiagnosticSeverity>>> {
+            let context = fixture
+                .context_builder()?
+                .options(ContextOptions {
+                    lint_severities: HashMap::from([lint::parse_lint_severity(&format!(
+                        "misplaced-load={}",
+                        severity
+                    ))
+                    .unwrap()]),
+                    ..Default::default()
+                })
+                .build()?;
+
+            Ok(context
+                .parse_file_with_contents(
+                    &LspUrl::File(fixture.workspace_root().join("BUILD")),
+                    "test_suite(name = 'my_test_suite')\n\nload('foo.bzl', 'bar')\n".to_owned(),
+                )
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("misplaced-load".into()))
+                })
+                .map(|diagnostic| diagnostic.severity)
+                .collect())
+        };
 
-        assert_eq!(1, result.diagnostics.len());
         assert_eq!(
-            "Use of undefined variable `unknown_global_function`",
-            result.diagnostics[0].message
+            misplaced_loads("error")?,
+            vec![Some(DiagnosticSeverity::ERROR)]
         );
+        assert!(misplaced_loads("ignore")?.is_empty());
+        assert!(lint::parse_lint_severity("misplaced-load=fatal").is_err());
+        assert!(lint::parse_lint_severity("misplaced-load").is_err());
 
         Ok(())
     }
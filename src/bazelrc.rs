@@ -0,0 +1,378 @@
+//! Parsing of `.bazelrc` files, for the startup options that affect how Bazel is invoked, the
+//! options that affect the results of queries, overrides of repositories, and whether Bzlmod is
+//! enabled.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The maximum depth of nested `import`s, to guard against import cycles.
+const MAX_IMPORT_DEPTH: usize = 8;
+
+/// Options of `common` lines that are passed on to queries, without their `--` or `--no`
+/// prefix. Bazel ignores `common` options that a command doesn't support when they come from an
+/// rc file, but rejects them on the command line, so only options that are known to be
+/// supported by `bazel query` are passed on.
+const COMMON_QUERY_OPTIONS: &[&str] = &[
+    "enable_bzlmod",
+    "enable_workspace",
+    "implicit_deps",
+    "tool_deps",
+    "host_deps",
+    "registry",
+    "override_repository",
+    "override_module",
+    "repo_env",
+    "lockfile_mode",
+];
+
+/// Finds the `--output_base` startup option in the `.bazelrc` of a workspace, including the
+/// files it imports. If the option is given several times, the last one wins, like in Bazel.
+pub(crate) fn startup_output_base(workspace_root: &Path) -> Option<PathBuf> {
+    find_output_base(workspace_root, &|path| fs::read_to_string(path).ok())
+}
+
+fn find_output_base(
+    workspace_root: &Path,
+    read: &dyn Fn(&Path) -> Option<String>,
+) -> Option<PathBuf> {
+    let mut output_base = None;
+    visit_command_lines(
+        &workspace_root.join(".bazelrc"),
+        workspace_root,
+        read,
+        0,
+        &mut |command, options| {
+            if command == "startup" {
+                for (option, value) in option_values(options) {
+                    if option == "--output_base" {
+                        if let Some(value) = value {
+                            output_base = Some(PathBuf::from(value));
+                        }
+                    }
+                }
+            }
+        },
+    );
+
+    output_base
+}
+
+/// Commands whose options apply to the repositories that queries see.
+const REPOSITORY_COMMANDS: &[&str] = &["common", "build", "query", "fetch", "sync"];
+
+/// Finds the repositories that are pointed to a local directory by `--override_repository` and
+/// `--override_module` options in the `.bazelrc` of a workspace, including the files it imports.
+/// Repositories are keyed by their name, and modules by their module name. If a repository is
+/// overridden several times, the last override wins, like in Bazel.
+pub(crate) fn repository_overrides(workspace_root: &Path) -> HashMap<String, PathBuf> {
+    find_repository_overrides(workspace_root, &|path| fs::read_to_string(path).ok())
+}
+
+fn find_repository_overrides(
+    workspace_root: &Path,
+    read: &dyn Fn(&Path) -> Option<String>,
+) -> HashMap<String, PathBuf> {
+    let mut overrides = HashMap::new();
+    visit_command_lines(
+        &workspace_root.join(".bazelrc"),
+        workspace_root,
+        read,
+        0,
+        &mut |command, options| {
+            if !REPOSITORY_COMMANDS.contains(&command) {
+                return;
+            }
+            for (option, value) in option_values(options) {
+                if option != "--override_repository" && option != "--override_module" {
+                    continue;
+                }
+                if let Some((name, path)) = value.and_then(|value| value.split_once('=')) {
+                    let path = path.replace("%workspace%", &workspace_root.to_string_lossy());
+                    overrides.insert(name.to_owned(), workspace_root.join(path));
+                }
+            }
+        },
+    );
+
+    overrides
+}
+
+/// Finds whether Bzlmod is turned on or off by `--enable_bzlmod` or `--noenable_bzlmod` in the
+/// `.bazelrc` of a workspace, including the files it imports. Returns `None` if neither is
+/// given, in which case the default of the Bazel version applies.
+pub(crate) fn bzlmod_enabled(workspace_root: &Path) -> Option<bool> {
+    find_bzlmod_enabled(workspace_root, &|path| fs::read_to_string(path).ok())
+}
+
+fn find_bzlmod_enabled(
+    workspace_root: &Path,
+    read: &dyn Fn(&Path) -> Option<String>,
+) -> Option<bool> {
+    let mut enabled = None;
+    visit_command_lines(
+        &workspace_root.join(".bazelrc"),
+        workspace_root,
+        read,
+        0,
+        &mut |command, options| {
+            if !REPOSITORY_COMMANDS.contains(&command) {
+                return;
+            }
+            for (option, value) in option_values(options) {
+                match option {
+                    "--enable_bzlmod" => {
+                        enabled = Some(!matches!(value, Some("false" | "0" | "no")));
+                    }
+                    "--noenable_bzlmod" => enabled = Some(false),
+                    _ => {}
+                }
+            }
+        },
+    );
+
+    enabled
+}
+
+/// Finds the options of `query` lines in the `.bazelrc` of a workspace, including the files it
+/// imports, along with the options of `common` lines in [`COMMON_QUERY_OPTIONS`]. Lines for a
+/// `--config` are skipped, since queries don't select one.
+pub(crate) fn query_flags(workspace_root: &Path) -> Vec<String> {
+    find_query_flags(workspace_root, &|path| fs::read_to_string(path).ok())
+}
+
+fn find_query_flags(workspace_root: &Path, read: &dyn Fn(&Path) -> Option<String>) -> Vec<String> {
+    let mut flags = Vec::new();
+    visit_command_lines(
+        &workspace_root.join(".bazelrc"),
+        workspace_root,
+        read,
+        0,
+        &mut |command, options| {
+            for (option, value) in option_values(options) {
+                let name = option.trim_start_matches("--");
+                let name = name.strip_prefix("no").unwrap_or(name);
+                if command == "query"
+                    || (command == "common" && COMMON_QUERY_OPTIONS.contains(&name))
+                {
+                    flags.push(option.to_owned());
+                    flags.extend(value.map(str::to_owned));
+                }
+            }
+        },
+    );
+
+    flags
+}
+
+/// Pairs options with their values, e.g. `--output_base=/base`, or `--output_base /base` as
+/// two words. Options that are directly followed by another option have no value.
+fn option_values(words: &[String]) -> Vec<(&str, Option<&str>)> {
+    let mut options = Vec::new();
+    let mut words = words.iter().peekable();
+    while let Some(word) = words.next() {
+        match word.split_once('=') {
+            Some((option, value)) => options.push((option, Some(value))),
+            None => {
+                let value = words.next_if(|value| !value.starts_with('-'));
+                options.push((word.as_str(), value.map(String::as_str)));
+            }
+        }
+    }
+
+    options
+}
+
+/// Splits the lines of a `.bazelrc` into words, joining lines that end in `\` and dropping
+/// comments. Quotes are removed, but not otherwise interpreted.
+fn rc_lines(contents: &str) -> Vec<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for line in contents.lines() {
+        if let Some(continued) = line.strip_suffix('\\') {
+            current.push_str(continued);
+            current.push(' ');
+            continue;
+        }
+        current.push_str(line);
+
+        let line = std::mem::take(&mut current);
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let words: Vec<String> = line
+            .split_whitespace()
+            .map(|word| word.replace(['"', '\''], ""))
+            .collect();
+        if !words.is_empty() {
+            lines.push(words);
+        }
+    }
+
+    lines
+}
+
+/// Calls `f` with the command and the options of every line in an rc file and the files it
+/// imports, e.g. `startup` and `["--output_base=/base"]`.
+fn visit_command_lines(
+    path: &Path,
+    workspace_root: &Path,
+    read: &dyn Fn(&Path) -> Option<String>,
+    depth: usize,
+    f: &mut dyn FnMut(&str, &[String]),
+) {
+    if depth > MAX_IMPORT_DEPTH {
+        return;
+    }
+    let contents = match read(path) {
+        Some(contents) => contents,
+        None => return,
+    };
+
+    for words in rc_lines(&contents) {
+        match words[0].as_str() {
+            "import" | "try-import" => {
+                if let Some(import) = words.get(1) {
+                    let import = import.replace("%workspace%", &workspace_root.to_string_lossy());
+                    visit_command_lines(
+                        &workspace_root.join(import),
+                        workspace_root,
+                        read,
+                        depth + 1,
+                        f,
+                    );
+                }
+            }
+            command => f(command, &words[1..]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use super::{
+        find_bzlmod_enabled, find_output_base, find_query_flags, find_repository_overrides,
+    };
+
+    fn output_base(files: &[(&str, &str)]) -> Option<PathBuf> {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|(path, contents)| (PathBuf::from(path), contents.to_string()))
+            .collect();
+
+        find_output_base(Path::new("/workspace"), &|path| files.get(path).cloned())
+    }
+
+    fn query_flags(contents: &str) -> Vec<String> {
+        find_query_flags(Path::new("/workspace"), &|path| {
+            (path == Path::new("/workspace/.bazelrc")).then(|| contents.to_owned())
+        })
+    }
+
+    #[test]
+    fn finds_output_base() {
+        assert_eq!(
+            output_base(&[(
+                "/workspace/.bazelrc",
+                "build --output_base=/wrong\n# startup --output_base=/commented\nstartup --host_jvm_args=-Xmx2g --output_base=/cache/base"
+            )]),
+            Some(PathBuf::from("/cache/base"))
+        );
+        assert_eq!(
+            output_base(&[(
+                "/workspace/.bazelrc",
+                "startup \\\n  --output_base \"/cache/base\""
+            )]),
+            Some(PathBuf::from("/cache/base"))
+        );
+        assert_eq!(
+            output_base(&[("/workspace/.bazelrc", "build --config=ci")]),
+            None
+        );
+    }
+
+    #[test]
+    fn follows_imports() {
+        assert_eq!(
+            output_base(&[
+                (
+                    "/workspace/.bazelrc",
+                    "startup --output_base=/first\nimport %workspace%/tools/bazel.rc\ntry-import user.bazelrc"
+                ),
+                ("/workspace/tools/bazel.rc", "startup --output_base=/second"),
+            ]),
+            Some(PathBuf::from("/second"))
+        );
+        // Imports of the file itself don't recurse forever.
+        assert_eq!(
+            output_base(&[(
+                "/workspace/.bazelrc",
+                "import %workspace%/.bazelrc\nstartup --output_base=/base"
+            )]),
+            Some(PathBuf::from("/base"))
+        );
+    }
+
+    #[test]
+    fn finds_query_flags() {
+        assert_eq!(
+            query_flags(
+                "common --noenable_bzlmod --remote_cache=grpc://cache\nquery --noimplicit_deps\nquery:ci --keep_going\nbuild --notool_deps\ncommon --registry https://bcr.example.com"
+            ),
+            vec![
+                "--noenable_bzlmod",
+                "--noimplicit_deps",
+                "--registry",
+                "https://bcr.example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_bzlmod_setting() {
+        let find = |contents: &'static str| {
+            find_bzlmod_enabled(Path::new("/workspace"), &|path| {
+                (path == Path::new("/workspace/.bazelrc")).then(|| contents.to_owned())
+            })
+        };
+
+        assert_eq!(find("build --jobs=4"), None);
+        assert_eq!(find("common --noenable_bzlmod"), Some(false));
+        assert_eq!(
+            find("common --noenable_bzlmod\nbuild --enable_bzlmod"),
+            Some(true)
+        );
+        assert_eq!(find("common --enable_bzlmod=false"), Some(false));
+        // Options of other commands don't affect how repositories are resolved.
+        assert_eq!(find("test --noenable_bzlmod"), None);
+    }
+
+    #[test]
+    fn finds_repository_overrides() {
+        let overrides = find_repository_overrides(Path::new("/workspace"), &|path| {
+            (path == Path::new("/workspace/.bazelrc")).then(|| {
+                "common --override_repository=foo=/src/foo\nbuild --override_module rules_x=%workspace%/third_party/rules_x\ntest --override_repository=bar=/src/bar\ncommon --override_repository=foo=/src/new_foo".to_owned()
+            })
+        });
+
+        assert_eq!(
+            overrides,
+            HashMap::from([
+                ("foo".to_owned(), PathBuf::from("/src/new_foo")),
+                (
+                    "rules_x".to_owned(),
+                    PathBuf::from("/workspace/third_party/rules_x")
+                ),
+            ])
+        );
+    }
+}
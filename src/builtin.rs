@@ -1,4 +1,4 @@
-use std::sync::LazyLock;
+use std::{collections::HashMap, sync::LazyLock};
 
 pub use build_proto::blaze_query::*;
 pub use builtin_proto::builtin::*;
@@ -49,6 +49,170 @@ pub static MISSING_GLOBALS: &'static [&'static str] = &[
     "distribs",
 ];
 
+/// The attributes of `package()`, along with a short description. `package` is neither a rule
+/// in the build language nor documented with its parameters in the builtins, so these are
+/// taken from https://bazel.build/reference/be/functions#package.
+pub static PACKAGE_ATTRIBUTES: &'static [(&'static str, &'static str)] = &[
+    (
+        "default_applicable_licenses",
+        "The licenses of the targets of the package, unless overridden by `applicable_licenses`.",
+    ),
+    (
+        "default_visibility",
+        "The visibility of the targets of the package, unless overridden by `visibility`.",
+    ),
+    (
+        "default_deprecation",
+        "The deprecation warning of the targets of the package, unless overridden by `deprecation`.",
+    ),
+    (
+        "default_package_metadata",
+        "The metadata of the targets of the package, unless overridden by `package_metadata`.",
+    ),
+    (
+        "default_testonly",
+        "Whether the targets of the package are `testonly`, unless overridden.",
+    ),
+    ("features", "Features that affect the semantics of the BUILD file."),
+];
+
+/// Documents `package()` with its attributes, see [`PACKAGE_ATTRIBUTES`].
+pub fn package_doc_member() -> DocMember {
+    DocMember::Function(DocFunction {
+        docs: create_docstring("Declares metadata that applies to every target in the package."),
+        params: DocParams {
+            named_only: PACKAGE_ATTRIBUTES
+                .iter()
+                .map(|(name, doc)| DocParam {
+                    name: name.to_string(),
+                    docs: create_docstring(doc),
+                    typ: Ty::any(),
+                    default_value: None,
+                })
+                .collect(),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+}
+
+/// Attributes that are deprecated, along with what to do instead, since the build language
+/// has no way to mark them. A rule of `*` stands for every rule that has the attribute.
+pub static DEPRECATED_ATTRIBUTES: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("*", "distribs", "It has no effect."),
+    (
+        "*",
+        "licenses",
+        "Use `package_metadata` or `applicable_licenses` from rules_license instead.",
+    ),
+];
+
+/// The rules and attributes of the build language that are deprecated, with a message on what
+/// to do instead, which may be empty.
+#[derive(Debug, Default)]
+pub struct Deprecations {
+    rules: HashMap<String, String>,
+    attributes: HashMap<(String, String), String>,
+}
+
+impl Deprecations {
+    /// Finds the rules and attributes whose documentation starts with "Deprecated", and the
+    /// attributes in [`DEPRECATED_ATTRIBUTES`].
+    pub fn from_build_language(language: &BuildLanguage) -> Self {
+        let mut deprecations = Deprecations::default();
+
+        for rule in &language.rule {
+            if let Some(message) = rule.documentation.as_deref().and_then(deprecation_message) {
+                deprecations.rules.insert(rule.name.clone(), message);
+            }
+
+            for attribute in &rule.attribute {
+                let message = attribute
+                    .documentation
+                    .as_deref()
+                    .and_then(deprecation_message)
+                    .or_else(|| {
+                        DEPRECATED_ATTRIBUTES
+                            .iter()
+                            .find(|(deprecated_rule, deprecated_attribute, _)| {
+                                (*deprecated_rule == "*" || *deprecated_rule == rule.name)
+                                    && *deprecated_attribute == attribute.name
+                            })
+                            .map(|(_, _, message)| message.to_string())
+                    });
+                if let Some(message) = message {
+                    deprecations
+                        .attributes
+                        .insert((rule.name.clone(), attribute.name.clone()), message);
+                }
+            }
+        }
+
+        deprecations
+    }
+
+    /// Returns the deprecation message of a rule, if it is deprecated.
+    pub fn rule(&self, rule: &str) -> Option<&str> {
+        self.rules.get(rule).map(String::as_str)
+    }
+
+    /// Returns the deprecation message of an attribute of a rule, if it is deprecated.
+    pub fn attribute(&self, rule: &str, attribute: &str) -> Option<&str> {
+        self.attributes
+            .get(&(rule.to_owned(), attribute.to_owned()))
+            .map(String::as_str)
+    }
+}
+
+/// If documentation starts with "Deprecated", returns the rest of its first paragraph, e.g.
+/// `Use bar instead.` for `<b>Deprecated:</b> Use bar instead.`.
+fn deprecation_message(documentation: &str) -> Option<String> {
+    let docs = create_docstring_for_possible_html(documentation)?;
+    let summary = docs.summary.trim_start_matches(['*', '_']);
+    if !summary
+        .get(.."deprecated".len())?
+        .eq_ignore_ascii_case("deprecated")
+    {
+        return None;
+    }
+
+    let message = summary["deprecated".len()..]
+        .trim_start_matches(['*', '_', ':', '.', ','])
+        .split("\n\n")
+        .next()
+        .unwrap_or_default()
+        .trim();
+    Some(message.to_owned())
+}
+
+/// Build language protos bundled with the server, keyed by the major version of Bazel they were
+/// generated with. These are used when the build language can't be obtained from Bazel.
+pub static DEFAULT_BUILD_LANGUAGES: &'static [(u32, &'static [u8])] =
+    &[(8, include_bytes!(env!("DEFAULT_BUILD_LANGUAGE_PB")))];
+
+/// Picks the bundled build language proto for a major version of Bazel, see
+/// [`DEFAULT_BUILD_LANGUAGES`].
+pub fn default_build_language(major_version: Option<u32>) -> &'static [u8] {
+    closest_version(DEFAULT_BUILD_LANGUAGES, major_version)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Finds the entry for the version closest to `version`, preferring newer versions on ties. If
+/// the version is unknown, the newest entry is used.
+fn closest_version<T>(entries: &[(u32, T)], version: Option<u32>) -> Option<&T> {
+    entries
+        .iter()
+        .max_by_key(|(entry_version, _)| match version {
+            Some(version) => (
+                std::cmp::Reverse(entry_version.abs_diff(version)),
+                *entry_version,
+            ),
+            None => (std::cmp::Reverse(0), *entry_version),
+        })
+        .map(|(_, entry)| entry)
+}
+
 static HTML_CONVERTER: LazyLock<htmd::HtmlToMarkdown> = LazyLock::new(|| {
     HtmlToMarkdown::builder()
         .add_handler(vec!["pre"], |element: Element| {
@@ -98,11 +262,23 @@ pub fn build_language_to_doc_members<'a>(
 }
 
 pub fn rule_to_doc_member(rule: &RuleDefinition) -> DocMember {
+    let docs = match rule
+        .documentation
+        .as_ref()
+        .and_then(|doc| create_docstring(doc))
+    {
+        Some(docs) => DocString {
+            details: attributes_summary(rule),
+            ..docs
+        },
+        None => DocString {
+            summary: attributes_summary(rule).unwrap_or_default(),
+            details: None,
+        },
+    };
+
     DocMember::Function(DocFunction {
-        docs: rule
-            .documentation
-            .as_ref()
-            .and_then(|doc| create_docstring(doc)),
+        docs: (!docs.summary.is_empty()).then_some(docs),
         params: DocParams {
             named_only: rule
                 .attribute
@@ -139,6 +315,51 @@ pub fn builtins_to_doc_members<'a>(
     })
 }
 
+/// Lists the mandatory attributes of a rule separately from the optional ones, as markdown,
+/// so that hovers show which attributes have to be given.
+fn attributes_summary(rule: &RuleDefinition) -> Option<String> {
+    let list = |mandatory: bool| {
+        rule.attribute
+            .iter()
+            .filter(|attribute| attribute.mandatory() == mandatory)
+            .map(|attribute| format!("`{}`", attribute.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let lines: Vec<String> = [
+        ("Mandatory attributes", list(true)),
+        ("Optional attributes", list(false)),
+    ]
+    .into_iter()
+    .filter(|(_, attributes)| !attributes.is_empty())
+    .map(|(title, attributes)| format!("**{}:** {}", title, attributes))
+    .collect();
+
+    (!lines.is_empty()).then(|| lines.join("\n\n"))
+}
+
+/// Whether the values of a rule attribute are lists, e.g. `deps` or `srcs`.
+pub fn is_list_attribute(attribute: &AttributeDefinition) -> bool {
+    matches!(
+        attribute.r#type(),
+        attribute::Discriminator::StringList
+            | attribute::Discriminator::LabelList
+            | attribute::Discriminator::OutputList
+            | attribute::Discriminator::IntegerList
+    )
+}
+
+/// Finds the fields of a builtin type, e.g. the fields of the `CcInfo` provider.
+pub fn type_fields<'a>(builtins: &'a Builtins, type_name: &str) -> &'a [Value] {
+    builtins
+        .r#type
+        .iter()
+        .find(|typ| typ.name == type_name)
+        .map(|typ| typ.field.as_slice())
+        .unwrap_or_default()
+}
+
 fn value_to_doc_member(value: &Value) -> DocMember {
     let docs = create_docstring_for_possible_html(&value.doc);
 
@@ -220,3 +441,66 @@ fn create_docstring_for_possible_html(html: &str) -> Option<DocString> {
 
     create_docstring(&markdown)
 }
+
+#[cfg(test)]
+mod tests {
+    use starlark::docs::{DocFunction, DocMember};
+
+    use super::{
+        closest_version, deprecation_message, rule_to_doc_member, AttributeDefinition,
+        RuleDefinition,
+    };
+
+    #[test]
+    fn test_closest_version() {
+        let entries = [(6, "six"), (7, "seven"), (9, "nine")];
+
+        assert_eq!(closest_version(&entries, Some(7)), Some(&"seven"));
+        assert_eq!(closest_version(&entries, Some(5)), Some(&"six"));
+        // Ties prefer the newer version.
+        assert_eq!(closest_version(&entries, Some(8)), Some(&"nine"));
+        assert_eq!(closest_version(&entries, None), Some(&"nine"));
+        assert_eq!(closest_version::<&str>(&[], Some(8)), None);
+    }
+
+    #[test]
+    fn test_deprecation_message() {
+        assert_eq!(
+            deprecation_message(
+                "<p><b>Deprecated:</b> Use <code>bar</code> instead.</p><p>More.</p>"
+            ),
+            Some("Use `bar` instead.".to_owned())
+        );
+        assert_eq!(deprecation_message("DEPRECATED"), Some("".to_owned()));
+        assert_eq!(deprecation_message("Not deprecated, really."), None);
+    }
+
+    #[test]
+    fn test_rule_docs_list_mandatory_attributes() {
+        let attribute = |name: &str, mandatory: bool| AttributeDefinition {
+            name: name.to_owned(),
+            mandatory: Some(mandatory),
+            ..Default::default()
+        };
+        let rule = RuleDefinition {
+            name: "cc_library".to_owned(),
+            documentation: Some("Builds a library.".to_owned()),
+            attribute: vec![
+                attribute("name", true),
+                attribute("deps", false),
+                attribute("srcs", false),
+            ],
+            ..Default::default()
+        };
+
+        let docs = match rule_to_doc_member(&rule) {
+            DocMember::Function(DocFunction { docs, .. }) => docs.unwrap(),
+            _ => panic!("Rules are documented as functions"),
+        };
+        assert_eq!(docs.summary, "Builds a library.");
+        assert_eq!(
+            docs.details.as_deref(),
+            Some("**Mandatory attributes:** `name`\n\n**Optional attributes:** `deps`, `srcs`")
+        );
+    }
+}
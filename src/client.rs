@@ -1,7 +1,10 @@
 use std::{
     collections::HashMap,
+    io::Read,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
@@ -13,6 +16,118 @@ pub(crate) struct BazelInfo {
     pub(crate) execution_root: String,
     pub(crate) output_base: String,
     pub(crate) workspace: String,
+    /// The version of Bazel, e.g. `release 7.1.0`. Development builds have no release.
+    pub(crate) release: Option<String>,
+    /// The directory Bazel is extracted to, which contains the embedded `@bazel_tools`.
+    pub(crate) install_base: Option<String>,
+    /// The directory generated files are output to, in the configuration of the `.bazelrc`.
+    pub(crate) bazel_bin: Option<String>,
+}
+
+/// The outcome of building a target, see [`BazelClient::build()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BuildOutput {
+    pub(crate) success: bool,
+    /// What Bazel printed to stderr, which is where it reports progress and errors.
+    pub(crate) stderr: String,
+}
+
+/// A command that was killed because it didn't finish in time, see
+/// [`BazelCli::query_timeout`].
+#[derive(thiserror::Error, Debug)]
+#[error("Timed out after {:?}", .0)]
+pub(crate) struct TimeoutError(Duration);
+
+/// How often a command with a timeout is checked for whether it has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs a command like [`Command::output()`], but kills it if it hasn't finished after
+/// `timeout`, returning a [`TimeoutError`].
+fn output_with_timeout(command: &mut Command, timeout: Duration) -> anyhow::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The pipes are read while waiting, so that the command doesn't block on a full pipe.
+    let read_pipe = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut contents = Vec::new();
+            pipe.read_to_end(&mut contents).map(|_| contents)
+        })
+    };
+    let stdout = read_pipe(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_pipe(Box::new(child.stderr.take().unwrap()));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(TimeoutError(timeout).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let join = |reader: thread::JoinHandle<std::io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .map_err(|_| anyhow!("Cannot read the output of the command"))?
+            .map_err(anyhow::Error::from)
+    };
+
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// A query that only partially succeeded when run with `--keep_going`, e.g. because of a broken
+/// BUILD file in another package. The targets that could be queried are still in `stdout`.
+#[derive(thiserror::Error, Debug)]
+#[error("Query partially failed: {}", .stderr)]
+pub(crate) struct PartialQueryError {
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+}
+
+/// A direct dependency of the root module, as reported by `bazel mod deps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ModuleDependency {
+    /// The name of the module, e.g. `rules_rust`.
+    pub(crate) name: String,
+    /// The name the root module refers to the dependency by, which is its `repo_name`.
+    pub(crate) apparent_name: String,
+    /// The resolved version of the module, e.g. `0.36.2`. Overridden modules may have none.
+    pub(crate) version: String,
+}
+
+/// Parses the output of `bazel mod deps --output=json`, returning the direct dependencies of
+/// the root module.
+pub(crate) fn parse_mod_deps(json: &[u8]) -> anyhow::Result<Vec<ModuleDependency>> {
+    let root: serde_json::Value = serde_json::from_slice(json)?;
+    let dependencies = root["dependencies"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Cannot find dependencies of the root module"))?;
+
+    dependencies
+        .iter()
+        .map(|dependency| {
+            let field = |name: &str| dependency[name].as_str().map(str::to_owned);
+            let name = field("name").ok_or_else(|| anyhow!("Cannot find module name"))?;
+
+            Ok(ModuleDependency {
+                apparent_name: field("apparentName").unwrap_or_else(|| name.clone()),
+                version: field("version").unwrap_or_default(),
+                name,
+            })
+        })
+        .collect()
 }
 
 /// A client for interacting with the build system. This is used for testing,
@@ -26,32 +141,71 @@ pub(crate) trait BazelClient {
         repo: &str,
     ) -> anyhow::Result<HashMap<String, String>>;
     fn query(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String>;
+    /// Like [`Self::query()`], but outputs the kind of each target before its label, e.g.
+    /// `cc_binary rule //foo:bar` or `source file //foo:bar.cc`.
+    fn query_label_kind(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String>;
+    /// Lists the direct dependencies of the root module, see [`parse_mod_deps()`].
+    fn mod_deps(&self, workspace: &BazelWorkspace) -> anyhow::Result<Vec<ModuleDependency>>;
+    /// Queries a single target, returning its definition as output by `--output=build`.
+    fn query_build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<String>;
+    /// Like [`Self::query_build()`], but with `bazel cquery`, so that the values of attributes
+    /// are those of the configuration selected by `flags`, e.g. `--platforms=//:arm`, with
+    /// `select()`s resolved.
+    fn cquery_build(
+        &self,
+        workspace: &BazelWorkspace,
+        label: &str,
+        flags: &[String],
+    ) -> anyhow::Result<String>;
     fn build_language(&self, workspace: &BazelWorkspace) -> anyhow::Result<Vec<u8>>;
+    /// Builds a target. Failed builds aren't errors, since their output is what the user wants
+    /// to see.
+    fn build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<BuildOutput>;
 }
 
 #[derive(Debug)]
 pub(crate) struct BazelCli {
     bazel: PathBuf,
+    /// How long queries may run before they are killed, so that a slow query doesn't block
+    /// the server. If `None`, queries run until they finish.
+    query_timeout: Option<Duration>,
+    /// Startup options passed to every command before the command name, e.g.
+    /// `--host_jvm_args=-Xmx2g`.
+    startup_flags: Vec<String>,
 }
 
 impl BazelCli {
-    pub fn new<P: AsRef<Path>>(bazel: P) -> Self {
+    pub fn new<P: AsRef<Path>>(
+        bazel: P,
+        query_timeout: Option<Duration>,
+        startup_flags: Vec<String>,
+    ) -> Self {
         Self {
             bazel: bazel.as_ref().to_owned(),
+            query_timeout,
+            startup_flags,
         }
     }
 
+    fn command(&self, output_base: Option<&Path>, workspace_root: &Path, args: &[&str]) -> Command {
+        let mut command = Command::new(&self.bazel);
+        command.args(&self.startup_flags);
+        // This comes after the configured flags, so that queries still get their own output base.
+        if let Some(output_base) = output_base {
+            command.arg("--output_base").arg(output_base);
+        }
+        command.args(args).current_dir(workspace_root);
+
+        command
+    }
+
     fn execute_bazel(
         &self,
         output_base: Option<&Path>,
         workspace_root: &Path,
         args: &[&str],
     ) -> anyhow::Result<std::process::Output> {
-        let mut command = &mut Command::new(&self.bazel);
-        if let Some(output_base) = output_base {
-            command = command.arg("--output_base").arg(output_base);
-        }
-        command = command.args(args).current_dir(&workspace_root);
+        let mut command = self.command(output_base, workspace_root, args);
 
         let output = command.output()?;
 
@@ -63,6 +217,50 @@ impl BazelCli {
         }
     }
 
+    /// Runs a query, killing it if it takes longer than [`Self::query_timeout`].
+    fn execute_query(&self, workspace: &BazelWorkspace, args: &[&str]) -> anyhow::Result<Output> {
+        let mut command = self.command(
+            workspace.query_output_base.as_deref(),
+            &workspace.root,
+            args,
+        );
+
+        match self.query_timeout {
+            Some(timeout) => output_with_timeout(&mut command, timeout)
+                .map_err(|error| error.context(format!("Command `bazel {}`", args.join(" ")))),
+            None => Ok(command.output()?),
+        }
+    }
+
+    /// Runs `bazel query --keep_going` with additional `flags`, failing with a
+    /// [`PartialQueryError`] if the query only partially succeeded.
+    fn execute_keep_going_query(
+        &self,
+        workspace: &BazelWorkspace,
+        flags: &[&str],
+        query: &str,
+    ) -> anyhow::Result<String> {
+        // The flags of the workspace come first, so that the flags needed to parse the output win.
+        let mut args = vec!["query"];
+        args.extend(workspace.query_flags.iter().map(String::as_str));
+        args.push("--keep_going");
+        args.extend_from_slice(flags);
+        args.push(query);
+        let output = self.execute_query(workspace, &args)?;
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        match output.status.code() {
+            Some(0) => Ok(stdout),
+            // Bazel exits with 3 if the query succeeded only partially.
+            Some(3) => Err(PartialQueryError { stdout, stderr }.into()),
+            _ => {
+                eprintln!("Query `{}` failed: {}", query, stderr);
+                Err(anyhow!("Command `bazel query {}` failed", query))
+            }
+        }
+    }
+
     fn execute_bazel_get_stdout(
         &self,
         workspace: &BazelWorkspace,
@@ -87,12 +285,18 @@ impl BazelClient for BazelCli {
         let mut execution_root = None;
         let mut output_base = None;
         let mut workspace = None;
+        let mut release = None;
+        let mut install_base = None;
+        let mut bazel_bin = None;
         for line in output.lines() {
             if let Some((key, value)) = line.split_once(": ") {
                 match key {
                     "execution_root" => execution_root = Some(value),
                     "output_base" => output_base = Some(value),
                     "workspace" => workspace = Some(value),
+                    "release" => release = Some(value),
+                    "install_base" => install_base = Some(value),
+                    "bazel-bin" => bazel_bin = Some(value),
                     _ => {}
                 }
             }
@@ -108,6 +312,9 @@ impl BazelClient for BazelCli {
             workspace: workspace
                 .ok_or_else(|| anyhow!("Cannot find workspace info"))?
                 .into(),
+            release: release.map(|release| release.into()),
+            install_base: install_base.map(|install_base| install_base.into()),
+            bazel_bin: bazel_bin.map(|bazel_bin| bazel_bin.into()),
         })
     }
 
@@ -123,11 +330,62 @@ impl BazelClient for BazelCli {
         Ok(serde_json::from_slice(&stdout)?)
     }
 
+    #[tracing::instrument]
+    fn mod_deps(&self, workspace: &BazelWorkspace) -> anyhow::Result<Vec<ModuleDependency>> {
+        let stdout = self.execute_bazel_get_stdout(workspace, &["mod", "deps", "--output=json"])?;
+
+        parse_mod_deps(&stdout)
+    }
+
     #[tracing::instrument]
     fn query(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String> {
-        let stdout = self.execute_bazel_get_stdout(workspace, &["query", query])?;
+        self.execute_keep_going_query(workspace, &[], query)
+    }
+
+    #[tracing::instrument]
+    fn query_label_kind(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String> {
+        self.execute_keep_going_query(workspace, &["--output=label_kind"], query)
+    }
+
+    #[tracing::instrument]
+    fn query_build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<String> {
+        let mut args = vec!["query"];
+        args.extend(workspace.query_flags.iter().map(String::as_str));
+        args.extend(["--output=build", label]);
+        let output = self.execute_query(workspace, &args)?;
+
+        if !output.status.success() {
+            eprintln!("Query of `{}` failed: {:?}", label, output);
+            return Err(anyhow!(
+                "Command `bazel query --output=build {}` failed",
+                label
+            ));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    #[tracing::instrument]
+    fn cquery_build(
+        &self,
+        workspace: &BazelWorkspace,
+        label: &str,
+        flags: &[String],
+    ) -> anyhow::Result<String> {
+        let mut args = vec!["cquery"];
+        args.extend(flags.iter().map(String::as_str));
+        args.extend(["--output=build", label]);
+        let output = self.execute_query(workspace, &args)?;
 
-        Ok(String::from_utf8(stdout)?)
+        if !output.status.success() {
+            eprintln!("Configured query of `{}` failed: {:?}", label, output);
+            return Err(anyhow!(
+                "Command `bazel cquery --output=build {}` failed",
+                label
+            ));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
     }
 
     #[tracing::instrument]
@@ -136,6 +394,20 @@ impl BazelClient for BazelCli {
 
         Ok(stdout)
     }
+
+    #[tracing::instrument]
+    fn build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<BuildOutput> {
+        // Builds use the output base of the workspace rather than the one for queries, so that
+        // they share their outputs with the builds of the user.
+        let output = self
+            .command(None, &workspace.root, &["build", label])
+            .output()?;
+
+        Ok(BuildOutput {
+            success: output.status.success(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
 }
 
 #[derive(Default)]
@@ -144,7 +416,11 @@ pub struct Profile {
     pub info: u16,
     pub dump_repo_mapping: u16,
     pub query: u16,
+    pub mod_deps: u16,
+    pub query_build: u16,
+    pub cquery_build: u16,
     pub build_language: u16,
+    pub build: u16,
 }
 
 /// A wrapper client that records the number of invocations to the inner client.
@@ -163,6 +439,10 @@ impl<InnerClient> ProfilingClient<InnerClient> {
             profile: Default::default(),
         }
     }
+
+    pub fn inner_mut(&mut self) -> &mut InnerClient {
+        &mut self.inner
+    }
 }
 
 #[cfg(test)]
@@ -189,18 +469,69 @@ impl<InnerClient: BazelClient> BazelClient for ProfilingClient<InnerClient> {
         self.inner.query(workspace, query)
     }
 
+    fn query_label_kind(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String> {
+        // These are counted as queries, since they only differ in their output.
+        self.profile.borrow_mut().query += 1;
+
+        self.inner.query_label_kind(workspace, query)
+    }
+
+    fn mod_deps(&self, workspace: &BazelWorkspace) -> anyhow::Result<Vec<ModuleDependency>> {
+        self.profile.borrow_mut().mod_deps += 1;
+
+        self.inner.mod_deps(workspace)
+    }
+
+    fn query_build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<String> {
+        self.profile.borrow_mut().query_build += 1;
+
+        self.inner.query_build(workspace, label)
+    }
+
+    fn cquery_build(
+        &self,
+        workspace: &BazelWorkspace,
+        label: &str,
+        flags: &[String],
+    ) -> anyhow::Result<String> {
+        self.profile.borrow_mut().cquery_build += 1;
+
+        self.inner.cquery_build(workspace, label, flags)
+    }
+
     fn build_language(&self, workspace: &BazelWorkspace) -> anyhow::Result<Vec<u8>> {
         self.profile.borrow_mut().build_language += 1;
 
         self.inner.build_language(workspace)
     }
+
+    fn build(&self, workspace: &BazelWorkspace, label: &str) -> anyhow::Result<BuildOutput> {
+        self.profile.borrow_mut().build += 1;
+
+        self.inner.build(workspace, label)
+    }
 }
 
 #[cfg(test)]
 pub(crate) struct MockBazel {
     pub(crate) info: BazelInfo,
     pub(crate) repo_mappings: HashMap<String, HashMap<String, String>>,
+    /// The output of queries. Queries with `--output=label_kind` are registered with the flag
+    /// before the query, e.g. `--output=label_kind //foo:*`.
     pub(crate) queries: HashMap<String, String>,
+    /// The output of queries that partially fail, see [`PartialQueryError`].
+    pub(crate) partial_queries: HashMap<String, String>,
+    /// The `--output=build` results for targets, keyed by label.
+    pub(crate) target_definitions: HashMap<String, String>,
+    /// The `--output=build` results of `bazel cquery` for targets, keyed by label. The flags
+    /// selecting the configuration are ignored.
+    pub(crate) configured_target_definitions: HashMap<String, String>,
+    /// The output of `bazel mod deps --output=json`, if the workspace uses modules.
+    pub(crate) mod_deps: Option<String>,
+    /// The outcome of builds, keyed by the label of the target.
+    pub(crate) builds: HashMap<String, BuildOutput>,
+    /// The encoded `bazel info build-language`. Without it, the bundled build language is used.
+    pub(crate) build_language: Option<Vec<u8>>,
 }
 
 #[cfg(test)]
@@ -222,13 +553,227 @@ impl BazelClient for MockBazel {
     }
 
     fn query(&self, _workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String> {
+        if let Some(stdout) = self.partial_queries.get(query) {
+            return Err(PartialQueryError {
+                stdout: stdout.clone(),
+                stderr: "ERROR: no such package".into(),
+            }
+            .into());
+        }
+
         self.queries
             .get(query)
             .map(|result| result.clone())
             .ok_or_else(|| anyhow!("Query {} not registered in mock", query))
     }
 
+    fn query_label_kind(&self, workspace: &BazelWorkspace, query: &str) -> anyhow::Result<String> {
+        self.query(workspace, &format!("--output=label_kind {}", query))
+    }
+
+    fn mod_deps(&self, _workspace: &BazelWorkspace) -> anyhow::Result<Vec<ModuleDependency>> {
+        parse_mod_deps(
+            self.mod_deps
+                .as_ref()
+                .ok_or_else(|| anyhow!("Module dependencies not registered in mock"))?
+                .as_bytes(),
+        )
+    }
+
+    fn query_build(&self, _workspace: &BazelWorkspace, label: &str) -> anyhow::Result<String> {
+        self.target_definitions
+            .get(label)
+            .map(|result| result.clone())
+            .ok_or_else(|| anyhow!("Target {} not registered in mock", label))
+    }
+
+    fn cquery_build(
+        &self,
+        _workspace: &BazelWorkspace,
+        label: &str,
+        _flags: &[String],
+    ) -> anyhow::Result<String> {
+        self.configured_target_definitions
+            .get(label)
+            .cloned()
+            .ok_or_else(|| anyhow!("Configured target {} not registered in mock", label))
+    }
+
+    fn build_language(&self, _workspace: &BazelWorkspace) -> anyhow::Result<Vec<u8>> {
+        self.build_language
+            .clone()
+            .ok_or_else(|| anyhow!("Cannot get test build language"))
+    }
+
+    fn build(&self, _workspace: &BazelWorkspace, label: &str) -> anyhow::Result<BuildOutput> {
+        self.builds
+            .get(label)
+            .cloned()
+            .ok_or_else(|| anyhow!("Build of {} not registered in mock", label))
+    }
+}
+
+/// A client that panics whenever Bazel would be run, for testing that it isn't, e.g. with
+/// [`crate::bazel::ContextOptions::offline`].
+#[cfg(test)]
+pub(crate) struct PanickingBazel;
+
+#[cfg(test)]
+impl BazelClient for PanickingBazel {
+    fn info(&self, _workspace_root: &Path) -> anyhow::Result<BazelInfo> {
+        panic!("Unexpected `bazel info`")
+    }
+
+    fn dump_repo_mapping(
+        &self,
+        _workspace: &BazelWorkspace,
+        _repo: &str,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        panic!("Unexpected `bazel mod dump_repo_mapping`")
+    }
+
+    fn query(&self, _workspace: &BazelWorkspace, _query: &str) -> anyhow::Result<String> {
+        panic!("Unexpected `bazel query`")
+    }
+
+    fn query_label_kind(
+        &self,
+        _workspace: &BazelWorkspace,
+        _query: &str,
+    ) -> anyhow::Result<String> {
+        panic!("Unexpected `bazel query --output=label_kind`")
+    }
+
+    fn mod_deps(&self, _workspace: &BazelWorkspace) -> anyhow::Result<Vec<ModuleDependency>> {
+        panic!("Unexpected `bazel mod deps`")
+    }
+
+    fn query_build(&self, _workspace: &BazelWorkspace, _label: &str) -> anyhow::Result<String> {
+        panic!("Unexpected `bazel query --output=build`")
+    }
+
+    fn cquery_build(
+        &self,
+        _workspace: &BazelWorkspace,
+        _label: &str,
+        _flags: &[String],
+    ) -> anyhow::Result<String> {
+        panic!("Unexpected `bazel cquery --output=build`")
+    }
+
     fn build_language(&self, _workspace: &BazelWorkspace) -> anyhow::Result<Vec<u8>> {
-        Err(anyhow!("Cannot get test build language"))
+        panic!("Unexpected `bazel info build-language`")
+    }
+
+    fn build(&self, _workspace: &BazelWorkspace, _label: &str) -> anyhow::Result<BuildOutput> {
+        panic!("Unexpected `bazel build`")
+    }
+}
+
+// The tests run a fake `bazel` shell script.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        env, fs,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use crate::{test_fixture::TestFixture, workspace::BazelWorkspace};
+
+    use super::{BazelCli, BazelClient, BazelInfo, TimeoutError};
+
+    #[test]
+    fn queries_time_out() -> anyhow::Result<()> {
+        // A fake `bazel` that takes much longer than the timeout.
+        let bazel = env::temp_dir().join(format!("bazel-lsp-slow-bazel-{}", std::process::id()));
+        fs::write(&bazel, "#!/bin/sh\nsleep 10\n")?;
+        fs::set_permissions(&bazel, fs::Permissions::from_mode(0o755))?;
+
+        let fixture = TestFixture::new("simple")?;
+        let workspace = BazelWorkspace::from_bazel_info(
+            BazelInfo {
+                execution_root: fixture.workspace_root().to_string_lossy().into_owned(),
+                output_base: fixture.output_base().to_string_lossy().into_owned(),
+                workspace: fixture.workspace_root().to_string_lossy().into_owned(),
+                release: None,
+                install_base: None,
+                bazel_bin: None,
+            },
+            None::<&str>,
+        )?;
+
+        let client = BazelCli::new(&bazel, Some(Duration::from_millis(100)), Vec::new());
+        let start = Instant::now();
+        let result = client.query(&workspace, "//...");
+        let elapsed = start.elapsed();
+        fs::remove_file(&bazel)?;
+
+        assert!(result.unwrap_err().downcast_ref::<TimeoutError>().is_some());
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn queries_use_workspace_query_flags() -> anyhow::Result<()> {
+        // A fake `bazel` that outputs its arguments.
+        let bazel = env::temp_dir().join(format!("bazel-lsp-echo-bazel-{}", std::process::id()));
+        fs::write(&bazel, "#!/bin/sh\necho \"$@\"\n")?;
+        fs::set_permissions(&bazel, fs::Permissions::from_mode(0o755))?;
+
+        let fixture = TestFixture::new("simple")?;
+        let workspace = BazelWorkspace::from_bazel_info(
+            BazelInfo {
+                execution_root: fixture.workspace_root().to_string_lossy().into_owned(),
+                output_base: fixture.output_base().to_string_lossy().into_owned(),
+                workspace: fixture.workspace_root().to_string_lossy().into_owned(),
+                release: None,
+                install_base: None,
+                bazel_bin: None,
+            },
+            None::<&str>,
+        )?;
+
+        let client = BazelCli::new(&bazel, None, Vec::new());
+        let result = client.query_label_kind(&workspace, "//foo:*");
+        fs::remove_file(&bazel)?;
+
+        // The fixture's `.bazelrc` has `common --noenable_bzlmod`.
+        assert_eq!(
+            result?,
+            "query --noenable_bzlmod --keep_going --output=label_kind //foo:*\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn commands_use_configured_binary_and_startup_flags() {
+        let client = BazelCli::new(
+            "/usr/local/bin/bazelisk",
+            None,
+            vec!["--host_jvm_args=-Xmx2g".to_owned()],
+        );
+
+        let command = client.command(
+            Some(Path::new("/tmp/output_base")),
+            Path::new("/workspace"),
+            &["query", "//..."],
+        );
+
+        assert_eq!(command.get_program(), "/usr/local/bin/bazelisk");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec![
+                "--host_jvm_args=-Xmx2g",
+                "--output_base",
+                "/tmp/output_base",
+                "query",
+                "//...",
+            ]
+        );
+        assert_eq!(command.get_current_dir(), Some(Path::new("/workspace")));
     }
 }
@@ -0,0 +1,1059 @@
+//! Bazel-specific knowledge used to tailor completions to the attribute being edited.
+
+use lsp_types::{CompletionItem, CompletionItemKind};
+use starlark_lsp::completion::StringCompletionResult;
+
+use crate::builtin::{attribute::Discriminator, RuleDefinition};
+
+/// File extensions that are expected by an attribute of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExtensionPreference {
+    /// The name of the rule, e.g. `proto_library`, or a prefix of the names of rules followed
+    /// by `*`, e.g. `cc_*`.
+    pub(crate) rule: &'static str,
+    /// The name of the attribute, e.g. `srcs`.
+    pub(crate) attribute: &'static str,
+    /// The extensions expected by the attribute, without the leading `.`.
+    pub(crate) extensions: &'static [&'static str],
+    /// Whether the attribute only accepts files with these extensions. Otherwise, other files
+    /// are offered as well, after the ones with these extensions.
+    pub(crate) exclusive: bool,
+}
+
+/// The extensions of C and C++ headers.
+const CC_HEADER_EXTENSIONS: &[&str] = &["h", "hh", "hpp", "hxx", "inc", "inl", "ipp"];
+
+/// Attributes that expect files with particular extensions. When completing files for one of
+/// these attributes, files with a matching extension are offered first, or exclusively. The
+/// first matching entry is used, so specific rules come before prefixes.
+pub(crate) static EXTENSION_PREFERENCES: &[ExtensionPreference] = &[
+    ExtensionPreference {
+        rule: "proto_library",
+        attribute: "srcs",
+        extensions: &["proto"],
+        exclusive: true,
+    },
+    ExtensionPreference {
+        rule: "cc_*",
+        attribute: "hdrs",
+        extensions: CC_HEADER_EXTENSIONS,
+        exclusive: false,
+    },
+    ExtensionPreference {
+        rule: "cc_*",
+        attribute: "textual_hdrs",
+        extensions: CC_HEADER_EXTENSIONS,
+        exclusive: false,
+    },
+    ExtensionPreference {
+        rule: "cc_*",
+        attribute: "srcs",
+        extensions: &["c", "cc", "cpp", "cxx", "c++", "C", "S"],
+        exclusive: false,
+    },
+    ExtensionPreference {
+        rule: "py_*",
+        attribute: "srcs",
+        extensions: &["py"],
+        exclusive: false,
+    },
+    ExtensionPreference {
+        rule: "java_*",
+        attribute: "srcs",
+        extensions: &["java"],
+        exclusive: false,
+    },
+];
+
+/// Finds the extension preference for an attribute of a rule, if there is one.
+pub(crate) fn extension_preference(
+    rule: &str,
+    attribute: &str,
+) -> Option<&'static ExtensionPreference> {
+    EXTENSION_PREFERENCES.iter().find(|preference| {
+        let matches_rule = match preference.rule.strip_suffix('*') {
+            Some(prefix) => rule.starts_with(prefix),
+            None => preference.rule == rule,
+        };
+        matches_rule && preference.attribute == attribute
+    })
+}
+
+/// Attributes that declare outputs, which are paths relative to the package rather than labels.
+pub(crate) static OUTPUT_ATTRIBUTES: &[(&str, &str)] = &[("genrule", "outs")];
+
+/// Whether an attribute of a rule declares outputs, see [`OUTPUT_ATTRIBUTES`].
+pub(crate) fn is_output_attribute(rule: &str, attribute: &str) -> bool {
+    OUTPUT_ATTRIBUTES
+        .iter()
+        .any(|(output_rule, output_attribute)| {
+            *output_rule == rule && *output_attribute == attribute
+        })
+}
+
+/// Conventional values of the `tags` attribute, which are understood by Bazel, along with a
+/// short description. See https://bazel.build/reference/be/common-definitions#common.tags.
+pub(crate) static KNOWN_TAGS: &[(&str, &str)] = &[
+    (
+        "manual",
+        "Excludes the target from wildcard target patterns like `//...`.",
+    ),
+    (
+        "exclusive",
+        "Runs the test without any other tests running at the same time.",
+    ),
+    (
+        "external",
+        "Forces the test to be unconditionally executed, without caching.",
+    ),
+    (
+        "local",
+        "Runs the action locally, without sandboxing or remote execution.",
+    ),
+    (
+        "no-cache",
+        "Doesn't cache the results of the action, locally or remotely.",
+    ),
+    (
+        "no-remote",
+        "Doesn't execute the action remotely or cache its results remotely.",
+    ),
+    ("no-remote-exec", "Doesn't execute the action remotely."),
+    (
+        "no-remote-cache",
+        "Doesn't cache the results of the action remotely.",
+    ),
+    ("no-sandbox", "Runs the action without sandboxing."),
+    (
+        "requires-network",
+        "Allows network access from within the sandbox.",
+    ),
+    ("flaky", "Retries the test up to three times if it fails."),
+];
+
+/// Completes a value of the `tags` attribute, see [`KNOWN_TAGS`].
+pub(crate) fn tag_completions(current_value: &str) -> Vec<BazelCompletion> {
+    KNOWN_TAGS
+        .iter()
+        .filter(|(tag, _)| tag.starts_with(current_value))
+        .map(|(tag, description)| BazelCompletion {
+            category: CompletionCategory::Value,
+            value: tag.to_string(),
+            insert_text: Some(tag.to_string()),
+            insert_text_offset: 0,
+            detail: Some(description.to_string()),
+        })
+        .collect()
+}
+
+/// The score of values that start with the text being typed, which always rank above values
+/// that only match it as a subsequence, see [`fuzzy_score()`].
+const PREFIX_SCORE: u32 = 1000;
+
+/// Scores how well a value matches the partial text being typed, or returns `None` if the
+/// characters of `partial` don't appear in `value` in order, ignoring case. For example, `mncc`
+/// matches `main.cc`. Values that start with `partial` score highest. Otherwise, consecutive
+/// characters and characters at the start of a word raise the score.
+pub(crate) fn fuzzy_score(partial: &str, value: &str) -> Option<u32> {
+    if value.starts_with(partial) {
+        return Some(PREFIX_SCORE);
+    }
+
+    // The remaining characters are matched greedily, from each occurrence of the first one.
+    let value: Vec<char> = value.chars().collect();
+    let partial: Vec<char> = partial.chars().collect();
+    (0..value.len())
+        .filter(|&start| value[start].eq_ignore_ascii_case(&partial[0]))
+        .filter_map(|start| subsequence_score(&value, start, &partial[1..]))
+        .max()
+        .map(|score| score.min(PREFIX_SCORE - 1))
+}
+
+/// Scores the match of `partial` in `value` after its first character matched at `start`, see
+/// [`fuzzy_score()`].
+fn subsequence_score(value: &[char], start: usize, partial: &[char]) -> Option<u32> {
+    let is_word_start =
+        |index: usize| index == 0 || matches!(value[index - 1], '_' | '-' | '.' | '/');
+
+    let mut score = if is_word_start(start) { 3 } else { 1 };
+    let mut previous = start;
+    for c in partial {
+        let index =
+            (previous + 1..value.len()).find(|&index| value[index].eq_ignore_ascii_case(c))?;
+        score += 1;
+        if index == previous + 1 {
+            score += 1;
+        }
+        if is_word_start(index) {
+            score += 2;
+        }
+        previous = index;
+    }
+
+    Some(score)
+}
+
+/// Attributes of a `genrule` which are shell commands, in which make variables are expanded.
+pub(crate) static COMMAND_ATTRIBUTES: &[&str] = &["cmd", "cmd_bash", "cmd_ps", "cmd_bat"];
+
+/// Make variables that can be used in the command of a `genrule`, along with whether they take a
+/// label and a short description. See https://bazel.build/reference/be/make-variables.
+pub(crate) static MAKE_VARIABLES: &[(&str, bool, &str)] = &[
+    ("location", true, "The path of the single file of a label."),
+    ("locations", true, "The paths of the files of a label."),
+    (
+        "execpath",
+        true,
+        "The path of the single file of a label, relative to the execution root.",
+    ),
+    (
+        "execpaths",
+        true,
+        "The paths of the files of a label, relative to the execution root.",
+    ),
+    (
+        "rootpath",
+        true,
+        "The path of the single file of a label, relative to the runfiles root.",
+    ),
+    (
+        "rootpaths",
+        true,
+        "The paths of the files of a label, relative to the runfiles root.",
+    ),
+    (
+        "rlocationpath",
+        true,
+        "The path of the single file of a label, for looking it up in the runfiles.",
+    ),
+    (
+        "rlocationpaths",
+        true,
+        "The paths of the files of a label, for looking them up in the runfiles.",
+    ),
+    ("SRCS", false, "The paths of the files in `srcs`."),
+    ("OUTS", false, "The paths of the files in `outs`."),
+    ("RULEDIR", false, "The output directory of the package."),
+    ("@D", false, "The output directory of the target."),
+    (
+        "BINDIR",
+        false,
+        "The output directory of the target configuration.",
+    ),
+    ("GENDIR", false, "The output directory of generated files."),
+    ("CC", false, "The path of the C++ compiler."),
+    ("CC_FLAGS", false, "The flags of the C++ compiler."),
+    (
+        "AR",
+        false,
+        "The path of the archiver of the C++ toolchain.",
+    ),
+    ("NM", false, "The path of `nm` of the C++ toolchain."),
+    ("JAVA", false, "The path of the Java runtime."),
+    ("JAVABASE", false, "The root directory of the Java runtime."),
+    ("TARGET_CPU", false, "The CPU of the target configuration."),
+    (
+        "COMPILATION_MODE",
+        false,
+        "The compilation mode, e.g. `opt`.",
+    ),
+];
+
+/// Completes a make variable in a command, e.g. `$(location )`, if the cursor is right after an
+/// unclosed `$(`. See [`MAKE_VARIABLES`].
+///
+/// Completions can't contain snippets, so the label of variables like `$(location )` is left
+/// empty for the user to type.
+pub(crate) fn make_variable_completions(current_value: &str) -> Option<Vec<BazelCompletion>> {
+    let start = current_value.rfind("$(")?;
+    let partial_variable = &current_value[start + 2..];
+    if partial_variable.contains(')') {
+        return None;
+    }
+
+    Some(
+        MAKE_VARIABLES
+            .iter()
+            .filter(|(variable, _, _)| variable.starts_with(partial_variable))
+            .map(|(variable, takes_label, description)| {
+                let value = if *takes_label {
+                    format!("$({} )", variable)
+                } else {
+                    format!("$({})", variable)
+                };
+                BazelCompletion {
+                    category: CompletionCategory::Value,
+                    insert_text: Some(value.clone()),
+                    value,
+                    insert_text_offset: start,
+                    detail: Some(description.to_string()),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// The commands that lines of a `.bazelrc` can apply to, besides `import` and `try-import`.
+pub(crate) static BAZELRC_COMMANDS: &[&str] = &[
+    "startup", "common", "always", "build", "test", "run", "query", "cquery", "aquery", "coverage",
+    "fetch", "sync", "mod", "info", "clean",
+];
+
+/// Well-known options of Bazel commands, along with whether they are boolean options, which
+/// can also be negated with a `no` prefix, e.g. `--nokeep_going`.
+pub(crate) static BAZELRC_FLAGS: &[(&str, bool)] = &[
+    ("announce_rc", true),
+    ("build_tests_only", true),
+    ("compilation_mode", false),
+    ("config", false),
+    ("copt", false),
+    ("cxxopt", false),
+    ("define", false),
+    ("disk_cache", false),
+    ("enable_bzlmod", true),
+    ("enable_platform_specific_config", true),
+    ("enable_workspace", true),
+    ("experimental_remote_cache_compression", true),
+    ("extra_toolchains", false),
+    ("host_platform", false),
+    ("incompatible_disallow_empty_glob", true),
+    ("incompatible_enable_cc_toolchain_resolution", true),
+    ("incompatible_strict_action_env", true),
+    ("java_language_version", false),
+    ("java_runtime_version", false),
+    ("jobs", false),
+    ("keep_going", true),
+    ("linkopt", false),
+    ("lockfile_mode", false),
+    ("override_module", false),
+    ("override_repository", false),
+    ("platforms", false),
+    ("registry", false),
+    ("remote_cache", false),
+    ("remote_download_outputs", false),
+    ("remote_executor", false),
+    ("repo_env", false),
+    ("sandbox_debug", true),
+    ("show_timestamps", true),
+    ("stamp", true),
+    ("test_env", false),
+    ("test_output", false),
+    ("test_summary", false),
+    ("test_tag_filters", false),
+    ("test_timeout", false),
+    ("verbose_failures", true),
+    ("workspace_status_command", false),
+];
+
+/// Well-known startup options, which are only valid on `startup` lines.
+pub(crate) static BAZELRC_STARTUP_FLAGS: &[(&str, bool)] = &[
+    ("batch", true),
+    ("host_jvm_args", false),
+    ("max_idle_secs", false),
+    ("output_base", false),
+    ("output_user_root", false),
+    ("windows_enable_symlinks", true),
+];
+
+/// Completes the command or an option of a line in a `.bazelrc`, given the text of the line
+/// before the cursor. Commands may be followed by a config, e.g. `build:ci`. See
+/// [`BAZELRC_FLAGS`].
+pub(crate) fn bazelrc_completions(line_prefix: &str) -> Vec<BazelCompletion> {
+    let start = line_prefix
+        .rfind(char::is_whitespace)
+        .map_or(0, |index| index + 1);
+    let partial = &line_prefix[start..];
+
+    let command = match line_prefix.split_whitespace().next() {
+        Some(command) if start > 0 => command,
+        _ => {
+            return BAZELRC_COMMANDS
+                .iter()
+                .chain(&["import", "try-import"])
+                .filter(|command| command.starts_with(partial))
+                .map(|command| BazelCompletion {
+                    category: CompletionCategory::Value,
+                    value: command.to_string(),
+                    insert_text: Some(command.to_string()),
+                    insert_text_offset: start,
+                    detail: None,
+                })
+                .collect();
+        }
+    };
+    let flags = match command.split(':').next() {
+        Some("startup") => BAZELRC_STARTUP_FLAGS,
+        Some(command) if BAZELRC_COMMANDS.contains(&command) => BAZELRC_FLAGS,
+        _ => return Vec::new(),
+    };
+    if !(partial.is_empty() || partial.starts_with('-')) {
+        return Vec::new();
+    }
+
+    let mut completions: Vec<BazelCompletion> = flags
+        .iter()
+        .flat_map(|(flag, is_boolean)| {
+            let negated = is_boolean.then(|| format!("--no{}", flag));
+            std::iter::once(format!("--{}", flag)).chain(negated)
+        })
+        .filter(|flag| flag.starts_with(partial))
+        .map(|flag| BazelCompletion {
+            category: CompletionCategory::Value,
+            insert_text: Some(flag.clone()),
+            value: flag,
+            insert_text_offset: start,
+            detail: None,
+        })
+        .collect();
+    completions.sort_by(|a, b| a.value.cmp(&b.value));
+
+    completions
+}
+
+/// Attributes that take visibility labels, e.g. `["//foo:__pkg__"]`.
+pub(crate) static VISIBILITY_ATTRIBUTES: &[&str] = &["visibility", "default_visibility"];
+
+/// The special targets of visibility labels, along with a short description. See
+/// https://bazel.build/concepts/visibility#visibility-specifications.
+pub(crate) static VISIBILITY_SUFFIXES: &[(&str, &str)] = &[
+    (
+        "__pkg__",
+        "Grants access to the package, but not its subpackages.",
+    ),
+    (
+        "__subpackages__",
+        "Grants access to the package and all of its subpackages.",
+    ),
+];
+
+/// The targets of the special `//visibility` package, along with a short description.
+pub(crate) static VISIBILITY_PACKAGE_TARGETS: &[(&str, &str)] = &[
+    ("public", "Grants access to every package."),
+    ("private", "Grants access to the package only."),
+];
+
+/// Completes the special targets of a visibility label in `package`, see
+/// [`VISIBILITY_SUFFIXES`], or the targets of `//visibility`, see
+/// [`VISIBILITY_PACKAGE_TARGETS`].
+pub(crate) fn visibility_suffix_completions(package: &str) -> Vec<BazelCompletion> {
+    let suffixes = match package {
+        "//visibility" => VISIBILITY_PACKAGE_TARGETS,
+        _ => VISIBILITY_SUFFIXES,
+    };
+    suffixes
+        .iter()
+        .map(|(suffix, description)| BazelCompletion {
+            category: CompletionCategory::Value,
+            value: suffix.to_string(),
+            insert_text: Some(suffix.to_string()),
+            insert_text_offset: package.len() + 1,
+            detail: Some(description.to_string()),
+        })
+        .collect()
+}
+
+/// The package specifications of `package_group.packages` that aren't paths, along with a short
+/// description. See https://bazel.build/reference/be/functions#package_group.
+pub(crate) static PACKAGE_SPECIFICATIONS: &[(&str, &str)] =
+    &[("public", "Every package."), ("private", "No package.")];
+
+/// Completes the package specifications of `package_group.packages` that aren't paths, see
+/// [`PACKAGE_SPECIFICATIONS`].
+pub(crate) fn package_specification_completions(current_value: &str) -> Vec<BazelCompletion> {
+    PACKAGE_SPECIFICATIONS
+        .iter()
+        .filter(|(specification, _)| specification.starts_with(current_value))
+        .map(|(specification, description)| BazelCompletion {
+            category: CompletionCategory::Value,
+            value: specification.to_string(),
+            insert_text: Some(specification.to_string()),
+            insert_text_offset: 0,
+            detail: Some(description.to_string()),
+        })
+        .collect()
+}
+
+/// Constraint values defined by the `@platforms` repository, keyed by their package. These are
+/// offered even if the repository hasn't been fetched yet.
+pub(crate) static PLATFORMS_CONSTRAINT_VALUES: &[(&str, &[&str])] = &[
+    (
+        "os",
+        &[
+            "android",
+            "chromiumos",
+            "emscripten",
+            "freebsd",
+            "fuchsia",
+            "haiku",
+            "ios",
+            "linux",
+            "macos",
+            "netbsd",
+            "nixos",
+            "none",
+            "openbsd",
+            "qnx",
+            "tvos",
+            "uefi",
+            "visionos",
+            "vxworks",
+            "wasi",
+            "watchos",
+            "windows",
+        ],
+    ),
+    (
+        "cpu",
+        &[
+            "aarch32", "aarch64", "arm64_32", "arm64e", "armv6-m", "armv7", "armv7e-m", "armv7k",
+            "i386", "mips64", "ppc", "ppc32", "ppc64le", "riscv32", "riscv64", "s390x", "wasm32",
+            "wasm64", "x86_32", "x86_64",
+        ],
+    ),
+];
+
+/// Completes the packages and constraint values of the `@platforms` repository, see
+/// [`PLATFORMS_CONSTRAINT_VALUES`].
+pub(crate) fn platforms_completions(current_value: &str) -> Vec<BazelCompletion> {
+    let rest = match ["@platforms//", "@@platforms//"]
+        .iter()
+        .find_map(|prefix| current_value.strip_prefix(prefix))
+    {
+        Some(rest) => rest,
+        None => return Vec::new(),
+    };
+    let base_len = current_value.len() - rest.len();
+
+    match rest.split_once(':') {
+        Some((package, _)) => PLATFORMS_CONSTRAINT_VALUES
+            .iter()
+            .filter(|(constraint_package, _)| *constraint_package == package)
+            .flat_map(|(_, values)| values.iter())
+            .map(|value| BazelCompletion {
+                category: CompletionCategory::Target(TargetKind::Other),
+                value: value.to_string(),
+                insert_text: Some(value.to_string()),
+                insert_text_offset: base_len + package.len() + 1,
+                detail: None,
+            })
+            .collect(),
+        None if !rest.contains('/') => PLATFORMS_CONSTRAINT_VALUES
+            .iter()
+            .map(|(package, _)| BazelCompletion {
+                category: CompletionCategory::Directory,
+                value: package.to_string(),
+                insert_text: Some(package.to_string()),
+                insert_text_offset: base_len,
+                detail: None,
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Functions whose arguments are target patterns rather than labels, so that
+/// [`target_pattern_completions()`] are offered before they are typed.
+pub(crate) const TARGET_PATTERN_FUNCTIONS: &[&str] =
+    &["register_execution_platforms", "register_toolchains"];
+
+/// Completes the target patterns that match all targets of a package, i.e. `all` after the `:`
+/// of a package, and `...` after a `/` for all packages below a directory, e.g. `//foo:all`
+/// and `//foo/...`. Unless `include_untyped` is set, they are only offered once their first
+/// character is typed, since most attributes don't accept them.
+pub(crate) fn target_pattern_completions(
+    current_value: &str,
+    include_untyped: bool,
+) -> Vec<BazelCompletion> {
+    let package_start = match current_value.find("//") {
+        Some(index) => index + 2,
+        None => return Vec::new(),
+    };
+    let rest = &current_value[package_start..];
+    let is_offered = |partial: &str, pattern: &str| {
+        (include_untyped || !partial.is_empty()) && pattern.starts_with(partial)
+    };
+
+    match rest.split_once(':') {
+        Some((package, partial)) if is_offered(partial, "all") => vec![BazelCompletion {
+            category: CompletionCategory::Target(TargetKind::Other),
+            value: "all".to_owned(),
+            insert_text: Some("all".to_owned()),
+            insert_text_offset: package_start + package.len() + 1,
+            detail: Some("All targets of the package".to_owned()),
+        }],
+        Some(_) => Vec::new(),
+        None => {
+            let directory_len = rest.rfind('/').map_or(0, |index| index + 1);
+            if is_offered(&rest[directory_len..], "...") {
+                vec![BazelCompletion {
+                    category: CompletionCategory::Directory,
+                    value: "...".to_owned(),
+                    insert_text: Some("...".to_owned()),
+                    insert_text_offset: package_start + directory_len,
+                    detail: Some("All packages below this directory".to_owned()),
+                }]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// An access of a provider field that is being typed, e.g. `dep[CcInfo].link`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProviderFieldAccess<'a> {
+    /// The name of the provider, e.g. `CcInfo`.
+    pub(crate) provider: &'a str,
+    /// The part of the field name that is already typed, e.g. `link`.
+    pub(crate) partial_field: &'a str,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Detects whether the text before the cursor ends in a provider field access, i.e. an
+/// identifier indexed by a provider name, followed by a `.` and a possibly partial field name.
+pub(crate) fn provider_field_access(line_prefix: &str) -> Option<ProviderFieldAccess<'_>> {
+    let field_start = line_prefix.trim_end_matches(is_identifier_char).len();
+    let partial_field = &line_prefix[field_start..];
+
+    let before_field = line_prefix[..field_start].strip_suffix('.')?;
+    let before_bracket = before_field.strip_suffix(']')?;
+
+    let provider_start = before_bracket.trim_end_matches(is_identifier_char).len();
+    let provider = &before_bracket[provider_start..];
+    let before_provider = before_bracket[..provider_start].strip_suffix('[')?;
+
+    // The indexed value must be an identifier (e.g. `dep`) or a call result.
+    if provider.is_empty()
+        || !before_provider.ends_with(|c: char| is_identifier_char(c) || c == ')' || c == ']')
+    {
+        return None;
+    }
+
+    Some(ProviderFieldAccess {
+        provider,
+        partial_field,
+    })
+}
+
+/// Parameters of implementation functions whose type is known by convention, e.g. `ctx` of a
+/// rule implementation, along with the name of their builtin type.
+pub(crate) static CONVENTIONAL_PARAMETER_TYPES: &[(&str, &str)] = &[
+    ("ctx", "ctx"),
+    ("repository_ctx", "repository_ctx"),
+    ("rctx", "repository_ctx"),
+    ("module_ctx", "module_ctx"),
+    ("mctx", "module_ctx"),
+];
+
+/// An access of a member that is being typed, e.g. `ctx.actions.run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MemberAccess<'a> {
+    /// The identifier the members are accessed on, e.g. `ctx`.
+    pub(crate) root: &'a str,
+    /// The members accessed before the one being typed, e.g. `["actions"]`.
+    pub(crate) path: Vec<&'a str>,
+    /// The part of the member name that is already typed, e.g. `run`.
+    pub(crate) partial_member: &'a str,
+}
+
+/// Detects whether the text before the cursor ends in a chain of member accesses on an
+/// identifier, e.g. `ctx.actions.` or `ctx.att`.
+pub(crate) fn member_access(line_prefix: &str) -> Option<MemberAccess<'_>> {
+    let chain_start = line_prefix
+        .trim_end_matches(|c: char| is_identifier_char(c) || c == '.')
+        .len();
+    let chain = &line_prefix[chain_start..];
+
+    let mut components: Vec<&str> = chain.split('.').collect();
+    let partial_member = components.pop()?;
+    if components.is_empty() || components.iter().any(|component| component.is_empty()) {
+        return None;
+    }
+    let root = components.remove(0);
+    // The chain must start with an identifier, not e.g. a number.
+    if root.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(MemberAccess {
+        root,
+        path: components,
+        partial_member,
+    })
+}
+
+/// Rules that are used in most workspaces, in the order they are offered when completing rule
+/// names. Other rules are offered after these, sorted by name.
+pub(crate) static COMMON_RULES: &[&str] = &[
+    "filegroup",
+    "genrule",
+    "cc_library",
+    "cc_binary",
+    "cc_test",
+    "java_library",
+    "java_binary",
+    "java_test",
+    "py_library",
+    "py_binary",
+    "py_test",
+    "sh_binary",
+    "sh_test",
+    "proto_library",
+    "test_suite",
+    "alias",
+    "config_setting",
+];
+
+/// Attributes that are filled in by rule snippets in addition to the mandatory ones, since
+/// almost every target sets them.
+static SNIPPET_ATTRIBUTES: &[&str] = &["srcs"];
+
+/// Whether the text before the cursor is a possibly partial identifier at the start of a
+/// top-level statement, where a rule call can be inserted.
+pub(crate) fn is_statement_start(line_prefix: &str) -> bool {
+    line_prefix.chars().all(is_identifier_char)
+        && !line_prefix.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Builds a snippet that calls a rule with its `name`, its other mandatory attributes, and the
+/// attributes in [`SNIPPET_ATTRIBUTES`], with a tab stop for the value of each attribute.
+pub(crate) fn rule_snippet(rule: &RuleDefinition) -> String {
+    let attributes = rule
+        .attribute
+        .iter()
+        .filter(|attribute| attribute.name == "name")
+        .chain(rule.attribute.iter().filter(|attribute| {
+            attribute.name != "name"
+                && (attribute.mandatory() || SNIPPET_ATTRIBUTES.contains(&attribute.name.as_str()))
+        }));
+
+    let mut snippet = format!("{}(\n", rule.name);
+    for (index, attribute) in attributes.enumerate() {
+        let tab_stop = index + 1;
+        let value = match attribute.r#type() {
+            Discriminator::StringList
+            | Discriminator::LabelList
+            | Discriminator::OutputList
+            | Discriminator::IntegerList => format!("[${}]", tab_stop),
+            Discriminator::StringDict
+            | Discriminator::StringListDict
+            | Discriminator::LabelListDict
+            | Discriminator::LabelDictUnary
+            | Discriminator::LabelKeyedStringDict => format!("{{${}}}", tab_stop),
+            Discriminator::Boolean | Discriminator::Integer | Discriminator::Tristate => {
+                format!("${}", tab_stop)
+            }
+            _ => format!("\"${}\"", tab_stop),
+        };
+        snippet.push_str(&format!("    {} = {},\n", attribute.name, value));
+    }
+    snippet.push(')');
+
+    snippet
+}
+
+/// Sort text that orders [`COMMON_RULES`] first, in the order they are listed.
+pub(crate) fn rule_sort_text(rule: &str) -> String {
+    let rank = COMMON_RULES
+        .iter()
+        .position(|common| *common == rule)
+        .unwrap_or(COMMON_RULES.len());
+
+    format!("{:03}_{}", rank, rule)
+}
+
+/// What kind of rule a target is an instance of, which is shown to clients as the
+/// [`CompletionItemKind`] of its completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum TargetKind {
+    /// A target whose kind isn't known, or isn't one of the kinds below, e.g. a source file.
+    Other,
+    /// A library, e.g. a `cc_library`.
+    Library,
+    /// A binary that can be run, e.g. a `cc_binary`.
+    Binary,
+    /// A test, e.g. a `cc_test`, or a `test_suite`.
+    Test,
+}
+
+impl TargetKind {
+    /// Classifies a target by its kind as output by `bazel query --output=label_kind`, e.g.
+    /// `cc_binary` or `source file`.
+    pub(crate) fn from_label_kind(kind: &str) -> Self {
+        if kind.ends_with("_test") || kind == "test_suite" {
+            TargetKind::Test
+        } else if kind.ends_with("_binary") {
+            TargetKind::Binary
+        } else if kind.ends_with("_library") {
+            TargetKind::Library
+        } else {
+            TargetKind::Other
+        }
+    }
+}
+
+/// The category of a completion offered by the server. This is kept separately from the
+/// [`CompletionItemKind`] shown by clients, so that completions can be told apart reliably.
+/// Completions of different categories are sorted in the order the categories are declared in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum CompletionCategory {
+    /// A repository name, e.g. `@foo`.
+    Repository,
+    /// A directory within a repository, e.g. `//foo/bar`.
+    Directory,
+    /// A source file, e.g. `//foo:bar.cc`.
+    File,
+    /// A target defined in a package, e.g. `//foo:bar`.
+    Target(TargetKind),
+    /// The name of a rule, e.g. `cc_library`.
+    Rule,
+    /// A value of an attribute, e.g. `"//visibility:public"`.
+    Value,
+    /// A field of a provider, e.g. `linking_context` of `CcInfo`.
+    Field,
+    /// A method of a builtin type, e.g. `run` of `ctx.actions`.
+    Method,
+}
+
+impl CompletionCategory {
+    /// The kind of completion item that is shown to clients for this category.
+    pub(crate) fn completion_item_kind(self) -> CompletionItemKind {
+        match self {
+            CompletionCategory::Repository => CompletionItemKind::MODULE,
+            CompletionCategory::Directory => CompletionItemKind::FOLDER,
+            CompletionCategory::File => CompletionItemKind::FILE,
+            // Clients have no kinds for targets, so these are picked for how they are shown.
+            CompletionCategory::Target(TargetKind::Other) => CompletionItemKind::PROPERTY,
+            CompletionCategory::Target(TargetKind::Library) => CompletionItemKind::CLASS,
+            CompletionCategory::Target(TargetKind::Binary) => CompletionItemKind::CONSTRUCTOR,
+            CompletionCategory::Target(TargetKind::Test) => CompletionItemKind::EVENT,
+            CompletionCategory::Rule => CompletionItemKind::FUNCTION,
+            CompletionCategory::Value => CompletionItemKind::VALUE,
+            CompletionCategory::Field => CompletionItemKind::FIELD,
+            CompletionCategory::Method => CompletionItemKind::METHOD,
+        }
+    }
+}
+
+/// A completion offered by the server, before it's converted to the type expected by
+/// `starlark_lsp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BazelCompletion {
+    /// What is being completed.
+    pub(crate) category: CompletionCategory,
+    /// The value shown in the completion list.
+    pub(crate) value: String,
+    /// The text to insert, if it differs from `value`.
+    pub(crate) insert_text: Option<String>,
+    /// The offset within the current value at which `insert_text` is inserted.
+    pub(crate) insert_text_offset: usize,
+    /// Additional information about the completion, e.g. what a target provides.
+    pub(crate) detail: Option<String>,
+}
+
+// NOTE: `StringCompletionResult` has no detail, so the server adds it to the completion items
+// afterwards, see `BazelContext::take_string_completions()`.
+impl From<BazelCompletion> for StringCompletionResult {
+    fn from(completion: BazelCompletion) -> Self {
+        StringCompletionResult {
+            value: completion.value,
+            insert_text: completion.insert_text,
+            insert_text_offset: completion.insert_text_offset,
+            kind: completion.category.completion_item_kind(),
+        }
+    }
+}
+
+impl From<BazelCompletion> for CompletionItem {
+    fn from(completion: BazelCompletion) -> Self {
+        CompletionItem {
+            label: completion.value,
+            kind: Some(completion.category.completion_item_kind()),
+            detail: completion.detail,
+            insert_text: completion.insert_text,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bazelrc_completions, extension_preference, fuzzy_score, is_statement_start,
+        make_variable_completions, member_access, provider_field_access, rule_sort_text,
+        target_pattern_completions, MemberAccess, ProviderFieldAccess, PREFIX_SCORE,
+    };
+
+    #[test]
+    fn completes_target_patterns() {
+        let completions = |current_value, include_untyped| {
+            target_pattern_completions(current_value, include_untyped)
+                .into_iter()
+                .map(|completion| (completion.value, completion.insert_text_offset))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(completions("//foo:", true), vec![("all".to_owned(), 6)]);
+        assert_eq!(completions("//foo:a", false), vec![("all".to_owned(), 6)]);
+        assert_eq!(
+            completions("@repo//foo/", true),
+            vec![("...".to_owned(), 11)]
+        );
+        assert_eq!(completions("//foo/.", false), vec![("...".to_owned(), 6)]);
+        // Most attributes don't accept patterns, so they aren't offered before being typed.
+        assert!(completions("//foo:", false).is_empty());
+        assert!(completions("//foo:b", true).is_empty());
+        assert!(completions(":", true).is_empty());
+    }
+
+    #[test]
+    fn detects_provider_field_access() {
+        assert_eq!(
+            provider_field_access("    info = dep[CcInfo]."),
+            Some(ProviderFieldAccess {
+                provider: "CcInfo",
+                partial_field: "",
+            })
+        );
+        assert_eq!(
+            provider_field_access("ctx.attr.deps[0][CcInfo].link"),
+            Some(ProviderFieldAccess {
+                provider: "CcInfo",
+                partial_field: "link",
+            })
+        );
+
+        assert_eq!(provider_field_access("dep.link"), None);
+        assert_eq!(provider_field_access("[CcInfo]."), None);
+        assert_eq!(provider_field_access("dep[\"CcInfo\"]."), None);
+    }
+
+    #[test]
+    fn detects_member_access() {
+        assert_eq!(
+            member_access("    ctx.actions.r"),
+            Some(MemberAccess {
+                root: "ctx",
+                path: vec!["actions"],
+                partial_member: "r",
+            })
+        );
+        assert_eq!(
+            member_access("return ctx."),
+            Some(MemberAccess {
+                root: "ctx",
+                path: vec![],
+                partial_member: "",
+            })
+        );
+
+        assert_eq!(member_access("ctx"), None);
+        assert_eq!(member_access("ctx..attr"), None);
+        assert_eq!(member_access("x = 1."), None);
+    }
+
+    #[test]
+    fn detects_statement_start() {
+        assert!(is_statement_start(""));
+        assert!(is_statement_start("cc_"));
+
+        assert!(!is_statement_start("    cc_"));
+        assert!(!is_statement_start("x = cc_"));
+        assert!(!is_statement_start("1cc"));
+    }
+
+    #[test]
+    fn sorts_common_rules_first() {
+        let mut rules = vec!["aaa_library", "cc_test", "cc_library", "filegroup"];
+        rules.sort_by_key(|rule| rule_sort_text(rule));
+
+        assert_eq!(
+            rules,
+            vec!["filegroup", "cc_library", "cc_test", "aaa_library"]
+        );
+    }
+
+    #[test]
+    fn completes_make_variables() {
+        let values = |current_value| {
+            make_variable_completions(current_value).map(|completions| {
+                completions
+                    .into_iter()
+                    .map(|completion| (completion.value, completion.insert_text_offset))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            values("cat $(SRCS) > $(O"),
+            Some(vec![("$(OUTS)".to_owned(), 14)])
+        );
+        assert_eq!(
+            values("$(loc"),
+            Some(vec![
+                ("$(location )".to_owned(), 0),
+                ("$(locations )".to_owned(), 0)
+            ])
+        );
+        assert_eq!(values("cat $(SRCS) > "), None);
+    }
+
+    #[test]
+    fn finds_extension_preferences() {
+        let preference = extension_preference("proto_library", "srcs").unwrap();
+        assert!(preference.exclusive);
+
+        // Prefixes match all rules starting with them.
+        let preference = extension_preference("cc_library", "hdrs").unwrap();
+        assert!(!preference.exclusive);
+        assert!(preference.extensions.contains(&"h"));
+        assert_eq!(
+            extension_preference("cc_test", "hdrs").map(|preference| preference.extensions),
+            Some(preference.extensions)
+        );
+
+        assert_eq!(extension_preference("cc_library", "deps"), None);
+        assert_eq!(extension_preference("genrule", "srcs"), None);
+    }
+
+    #[test]
+    fn scores_fuzzy_matches() {
+        assert_eq!(fuzzy_score("", "main.cc"), Some(PREFIX_SCORE));
+        assert_eq!(fuzzy_score("mai", "main.cc"), Some(PREFIX_SCORE));
+        assert!(fuzzy_score("mncc", "main.cc").is_some());
+        assert_eq!(fuzzy_score("mncx", "main.cc"), None);
+        // Consecutive characters and word starts score higher.
+        assert!(fuzzy_score("MC", "main.cc") > fuzzy_score("mc", "ammcx"));
+        assert!(fuzzy_score("ma", "demo_main") > fuzzy_score("ma", "mxa"));
+    }
+
+    #[test]
+    fn completes_bazelrc_lines() {
+        let values = |line_prefix| {
+            bazelrc_completions(line_prefix)
+                .into_iter()
+                .map(|completion| completion.value)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(values("te"), vec!["test"]);
+        assert_eq!(
+            values("build:ci --noinc"),
+            vec![
+                "--noincompatible_disallow_empty_glob",
+                "--noincompatible_enable_cc_toolchain_resolution",
+                "--noincompatible_strict_action_env",
+            ]
+        );
+        // Options that aren't boolean can't be negated.
+        assert!(!values("common --no").contains(&"--nojobs".to_owned()));
+        assert_eq!(values("startup --output_b"), vec!["--output_base"]);
+        assert!(values("frobnicate --").is_empty());
+
+        let completion = &bazelrc_completions("build --verbose_f")[0];
+        assert_eq!(completion.insert_text_offset, "build ".len());
+    }
+}
@@ -0,0 +1,306 @@
+//! An index of the symbols exported by the `.bzl` files of a workspace, for suggesting where to
+//! load undefined symbols from.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use starlark::syntax::{AstModule, Dialect};
+
+use crate::{ast, file_type::FileType, label};
+
+/// The maximum number of `.bzl` files that are indexed per workspace, so that huge workspaces
+/// don't use up all memory.
+const MAX_INDEXED_FILES: usize = 10_000;
+
+/// The public symbols of a `.bzl` file.
+#[derive(Debug)]
+struct FileExports {
+    /// The label of the file in the main repository, e.g. `//foo:defs.bzl`.
+    label: String,
+    symbols: Vec<String>,
+}
+
+/// The symbols exported by the `.bzl` files of a workspace.
+///
+/// The workspace is scanned lazily by [`Self::scan()`], which can be interrupted and resumed
+/// later, so that indexing a large workspace doesn't block the server.
+#[derive(Debug)]
+pub(crate) struct ExportIndex {
+    root: PathBuf,
+    /// Directories relative to the root that are skipped, from the `.bazelignore` file.
+    ignored: Vec<PathBuf>,
+    /// Directories which still have to be scanned, with the package they are in.
+    pending: Vec<(PathBuf, Option<String>)>,
+    files: HashMap<PathBuf, FileExports>,
+}
+
+impl ExportIndex {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        let ignored = read_bazelignore(&root);
+
+        ExportIndex {
+            pending: vec![(root.clone(), None)],
+            root,
+            ignored,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Whether all `.bzl` files of the workspace have been indexed, or the maximum number of
+    /// files was reached.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Scans directories until the whole workspace has been indexed, or `should_stop` returns
+    /// true. The scan continues where it was stopped on the next call.
+    pub(crate) fn scan(&mut self, should_stop: &dyn Fn() -> bool) {
+        while let Some((dir, package)) = self.pending.pop() {
+            if should_stop() {
+                self.pending.push((dir, package));
+                return;
+            }
+            self.scan_dir(&dir, package);
+
+            if self.files.len() >= MAX_INDEXED_FILES {
+                self.pending.clear();
+            }
+        }
+    }
+
+    fn scan_dir(&mut self, dir: &Path, package: Option<String>) {
+        let relative_dir = dir.strip_prefix(&self.root).unwrap_or(dir);
+        if self.ignored.iter().any(|ignored| relative_dir == ignored) {
+            return;
+        }
+
+        let is_package = FileType::BUILD_FILE_NAMES
+            .iter()
+            .any(|name| dir.join(name).is_file());
+        let package = if is_package {
+            let package = label::label_path(relative_dir);
+            self.index_bzl_library_srcs(dir, &package);
+            Some(package)
+        } else {
+            package
+        };
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+
+            if path.is_dir() {
+                // Skip hidden directories and the convenience symlinks of Bazel.
+                if !file_name.starts_with('.') && !file_name.starts_with("bazel-") {
+                    self.pending.push((path, package.clone()));
+                }
+            } else if FileType::from_path(&path) == FileType::Library {
+                // Files outside of any package can't be loaded.
+                if let Some(package) = &package {
+                    self.index_file(path, package);
+                }
+            }
+        }
+    }
+
+    /// Indexes the files listed in the `bzl_library` targets of a package that the scan doesn't
+    /// find by their extension, e.g. `.scl` files.
+    fn index_bzl_library_srcs(&mut self, dir: &Path, package: &str) {
+        let build_file = match FileType::BUILD_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|build_file| build_file.is_file())
+        {
+            Some(build_file) => build_file,
+            None => return,
+        };
+        let ast = match fs::read_to_string(&build_file).ok().and_then(|contents| {
+            AstModule::parse(&build_file.to_string_lossy(), contents, &Dialect::Extended).ok()
+        }) {
+            Some(ast) => ast,
+            None => return,
+        };
+
+        for (_, src) in ast::bzl_library_srcs(&ast) {
+            let path = dir.join(src);
+            if FileType::from_path(&path) != FileType::Library && path.is_file() {
+                self.index_file(path, package);
+            }
+        }
+    }
+
+    fn index_file(&mut self, path: PathBuf, package: &str) {
+        let name = label::label_path(path.strip_prefix(self.root.join(package)).unwrap_or(&path));
+        let label = format!("//{}:{}", package, name);
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let symbols = exported_symbols(&path, contents);
+                self.files.insert(path, FileExports { label, symbols });
+            }
+            Err(_) => {
+                self.files.remove(&path);
+            }
+        }
+    }
+
+    /// Re-indexes a `.bzl` file after it has changed, been created, or been deleted.
+    pub(crate) fn update_file(&mut self, path: &Path) {
+        if FileType::from_path(path) != FileType::Library {
+            return;
+        }
+        let relative_path = match path.strip_prefix(&self.root) {
+            Ok(relative_path) => relative_path,
+            Err(_) => return,
+        };
+        if self
+            .ignored
+            .iter()
+            .any(|ignored| relative_path.starts_with(ignored))
+        {
+            return;
+        }
+
+        // The package is the closest directory with a BUILD file.
+        let package = relative_path.ancestors().skip(1).find(|dir| {
+            FileType::BUILD_FILE_NAMES
+                .iter()
+                .any(|name| self.root.join(dir).join(name).is_file())
+        });
+        match package {
+            Some(package) => {
+                let package = label::label_path(package);
+                self.index_file(path.to_owned(), &package);
+            }
+            None => {
+                self.files.remove(path);
+            }
+        }
+    }
+
+    /// Returns the `.bzl` files that export a symbol, sorted by their path.
+    pub(crate) fn find_exporting_files(&self, symbol: &str) -> Vec<&Path> {
+        let mut files: Vec<&Path> = self
+            .files
+            .iter()
+            .filter(|(_, exports)| exports.symbols.iter().any(|s| s == symbol))
+            .map(|(path, _)| path.as_path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Returns the label of an indexed `.bzl` file.
+    pub(crate) fn label(&self, path: &Path) -> Option<&str> {
+        self.files.get(path).map(|exports| exports.label.as_str())
+    }
+}
+
+/// Returns the top-level bindings of a module that don't start with `_`, which are the symbols
+/// that other modules can load.
+fn exported_symbols(path: &Path, contents: String) -> Vec<String> {
+    match AstModule::parse(&path.to_string_lossy(), contents, &Dialect::Extended) {
+        Ok(ast) => ast::top_level_bindings(&ast)
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| !name.starts_with('_'))
+            .map(|name| name.to_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Reads the directories that Bazel ignores from the `.bazelignore` file in a workspace root.
+pub(crate) fn read_bazelignore(root: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(root.join(".bazelignore"))
+        .map(|contents| parse_bazelignore(&contents))
+        .unwrap_or_default()
+}
+
+/// Parses a `.bazelignore` file, which lists one directory relative to the workspace root per
+/// line. Lines starting with `#` are comments.
+fn parse_bazelignore(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .map(|line| line.trim().trim_end_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, fs, path::PathBuf};
+
+    use crate::test_fixture::TestFixture;
+
+    use super::{parse_bazelignore, ExportIndex};
+
+    #[test]
+    fn finds_exporting_files() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let root = fixture.workspace_root();
+
+        // A scan that is stopped right away doesn't index anything, but can be resumed.
+        let mut index = ExportIndex::new(root.clone());
+        index.scan(&|| true);
+        assert!(!index.is_complete());
+        assert!(index.find_exporting_files("fancy_library").is_empty());
+
+        let scanned_dirs = Cell::new(0);
+        index.scan(&|| {
+            scanned_dirs.set(scanned_dirs.get() + 1);
+            false
+        });
+        assert!(index.is_complete());
+        assert!(scanned_dirs.get() > 1);
+
+        let rules = root.join("lib").join("rules.bzl");
+        assert_eq!(
+            index.find_exporting_files("fancy_library"),
+            vec![rules.as_path()]
+        );
+        assert_eq!(index.label(&rules), Some("//lib:rules.bzl"));
+        // Private symbols aren't exported.
+        assert!(index.find_exporting_files("_private").is_empty());
+        // Files listed by a `bzl_library` are indexed even if they aren't `.bzl` files.
+        let scl = root.join("lib").join("other.scl");
+        assert!(index.find_exporting_files("x").contains(&scl.as_path()));
+        assert_eq!(index.label(&scl), Some("//lib:other.scl"));
+        // Directories in `.bazelignore` are skipped.
+        assert!(index.find_exporting_files("ignored_library").is_empty());
+
+        // Changed files are re-indexed, and deleted files are dropped.
+        let new_file = root.join("lib").join("new.bzl");
+        fs::write(&new_file, "new_library = 1")?;
+        index.update_file(&new_file);
+        fs::remove_file(&new_file)?;
+        assert_eq!(
+            index.find_exporting_files("new_library"),
+            vec![new_file.as_path()]
+        );
+        assert_eq!(index.label(&new_file), Some("//lib:new.bzl"));
+        index.update_file(&new_file);
+        assert!(index.find_exporting_files("new_library").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_bazelignore() {
+        assert_eq!(
+            parse_bazelignore("# Generated files\nnode_modules\n\n  third_party/vendor/ \n"),
+            vec![
+                PathBuf::from("node_modules"),
+                PathBuf::from("third_party/vendor")
+            ]
+        );
+    }
+}
@@ -1,17 +1,32 @@
 use std::path::Path;
 
+use starlark::syntax::Dialect;
 use starlark_lsp::server::LspUrl;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum FileType {
     Build,
     Library,
+    /// A `.scl` file, written in the Starlark configuration language. These can only load other
+    /// `.scl` files, and have none of the Bazel-specific globals.
+    Configuration,
+    /// A `.bazelrc` file with options for Bazel commands. These aren't Starlark, so they are
+    /// only completed line by line, see [`crate::completion::bazelrc_completions()`].
+    Bazelrc,
     Unknown,
 }
 
 impl FileType {
     pub const BUILD_FILE_NAMES: [&'static str; 2] = ["BUILD", "BUILD.bazel"];
-    const LIBRARY_EXTENSIONS: [&'static str; 1] = ["bzl"];
+    /// BUILD files for external repositories, e.g. the `build_file` of an `http_archive`, are
+    /// often named e.g. `zlib.BUILD` or `BUILD.zlib.bazel`.
+    const BUILD_FILE_TEMPLATE_EXTENSIONS: [&'static str; 2] = [".BUILD", ".BUILD.bazel"];
+    /// Some projects name their Starlark files e.g. `defs.star` instead.
+    const LIBRARY_EXTENSIONS: [&'static str; 2] = ["bzl", "star"];
+    const CONFIGURATION_EXTENSIONS: [&'static str; 1] = ["scl"];
+    /// Besides files with these names, rc files are often named e.g. `ci.bazelrc`.
+    const BAZELRC_FILE_NAMES: [&'static str; 2] = [".bazelrc", "bazel.rc"];
+    const BAZELRC_EXTENSIONS: [&'static str; 1] = ["bazelrc"];
 
     pub fn from_lsp_url(url: &LspUrl) -> Self {
         if let LspUrl::File(path) = url {
@@ -23,17 +38,62 @@ impl FileType {
 
     pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
         if let Some(file_name) = path.as_ref().file_name() {
-            if Self::BUILD_FILE_NAMES.iter().any(|name| *name == file_name) {
+            if Self::BUILD_FILE_NAMES.iter().any(|name| *name == file_name)
+                || Self::is_build_file_template(path.as_ref())
+            {
                 return Self::Build;
             }
+            if Self::BAZELRC_FILE_NAMES
+                .iter()
+                .any(|name| *name == file_name)
+            {
+                return Self::Bazelrc;
+            }
         }
 
         if let Some(extension) = path.as_ref().extension() {
             if Self::LIBRARY_EXTENSIONS.iter().any(|ext| *ext == extension) {
                 return Self::Library;
             }
+            if Self::CONFIGURATION_EXTENSIONS
+                .iter()
+                .any(|ext| *ext == extension)
+            {
+                return Self::Configuration;
+            }
+            if Self::BAZELRC_EXTENSIONS.iter().any(|ext| *ext == extension) {
+                return Self::Bazelrc;
+            }
         }
 
         FileType::Unknown
     }
+
+    /// Whether a file is a BUILD file for an external repository rather than the BUILD file of
+    /// the package it is in, e.g. `zlib.BUILD`. These are [`Self::Build`] files too.
+    pub fn is_build_file_template<P: AsRef<Path>>(path: P) -> bool {
+        let file_name = match path.as_ref().file_name() {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => return false,
+        };
+        if Self::BUILD_FILE_NAMES.iter().any(|name| *name == file_name) {
+            return false;
+        }
+
+        Self::BUILD_FILE_TEMPLATE_EXTENSIONS
+            .iter()
+            .any(|extension| file_name.ends_with(extension))
+            || (file_name.starts_with("BUILD.") && file_name.ends_with(".bazel"))
+    }
+
+    /// The dialect files of this type are parsed with. `.scl` files only support standard
+    /// Starlark, without e.g. type annotations.
+    pub fn dialect(self) -> Dialect {
+        match self {
+            FileType::Configuration => Dialect::Standard,
+            FileType::Build | FileType::Library | FileType::Bazelrc | FileType::Unknown => {
+                Dialect::Extended
+            }
+        }
+    }
 }
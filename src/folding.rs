@@ -0,0 +1,122 @@
+//! Folding ranges, which allow clients to collapse rule calls, long lists, and blocks of loads.
+
+use lsp_types::{FoldingRange, FoldingRangeKind};
+use starlark::{codemap::Span, syntax::AstModule};
+use starlark_syntax::syntax::{
+    ast::{AstExpr, AstStmt, ExprP, StmtP},
+    module::AstModuleFields,
+};
+
+use crate::ast;
+
+/// Returns the folding ranges of a module: one for each top-level call, each list or dict
+/// literal, and each block of consecutive `load` statements, as long as they span multiple lines.
+pub(crate) fn folding_ranges(ast: &AstModule) -> Vec<FoldingRange> {
+    let mut spans = Vec::new();
+    let mut load_block: Option<(Span, Span)> = None;
+
+    for stmt in ast::top_level_statements(ast) {
+        match &stmt.node {
+            StmtP::Load(_) => {
+                load_block = match load_block {
+                    Some((first, _)) => Some((first, stmt.span)),
+                    None => Some((stmt.span, stmt.span)),
+                };
+                continue;
+            }
+            StmtP::Expression(expr) if matches!(expr.node, ExprP::Call(..)) => {
+                spans.push((expr.span, FoldingRangeKind::Region));
+            }
+            _ => {}
+        }
+
+        if let Some((first, last)) = load_block.take() {
+            spans.push((first.merge(last), FoldingRangeKind::Imports));
+        }
+    }
+    if let Some((first, last)) = load_block {
+        spans.push((first.merge(last), FoldingRangeKind::Imports));
+    }
+
+    visit_stmt_collections(ast.statement(), &mut |span| {
+        spans.push((span, FoldingRangeKind::Region))
+    });
+
+    let mut ranges: Vec<FoldingRange> = spans
+        .into_iter()
+        .filter_map(|(span, kind)| {
+            let span = ast.codemap().resolve_span(span);
+            if span.begin.line == span.end.line {
+                return None;
+            }
+
+            Some(FoldingRange {
+                start_line: span.begin.line as u32,
+                end_line: span.end.line as u32,
+                kind: Some(kind),
+                ..Default::default()
+            })
+        })
+        .collect();
+    ranges.sort_by_key(|range| (range.start_line, range.end_line));
+    ranges.dedup_by_key(|range| (range.start_line, range.end_line));
+    ranges
+}
+
+/// Calls `f` with the span of every list and dict literal in a statement.
+fn visit_stmt_collections(stmt: &AstStmt, f: &mut dyn FnMut(Span)) {
+    stmt.visit_expr(|expr| visit_expr_collections(expr, f));
+    stmt.visit_stmt(|stmt| visit_stmt_collections(stmt, f));
+}
+
+fn visit_expr_collections(expr: &AstExpr, f: &mut dyn FnMut(Span)) {
+    if matches!(expr.node, ExprP::List(_) | ExprP::Dict(_)) {
+        f(expr.span);
+    }
+
+    expr.visit_expr(|expr| visit_expr_collections(expr, f));
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::FoldingRangeKind;
+    use starlark::syntax::{AstModule, Dialect};
+
+    use super::folding_ranges;
+
+    fn ranges(contents: &str) -> Vec<(u32, u32, FoldingRangeKind)> {
+        let ast = AstModule::parse("BUILD", contents.to_owned(), &Dialect::Extended).unwrap();
+
+        folding_ranges(&ast)
+            .into_iter()
+            .map(|range| (range.start_line, range.end_line, range.kind.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn folds_calls_collections_and_loads() {
+        assert_eq!(
+            ranges(
+                r#"load("//a:a.bzl", "a")
+load("//b:b.bzl", "b")
+
+cc_library(
+    name = "foo",
+    srcs = [
+        "foo.cc",
+        "bar.cc",
+    ],
+    hdrs = ["foo.h"],
+)
+
+cc_library(name = "bar")
+"#
+            ),
+            vec![
+                (0, 1, FoldingRangeKind::Imports),
+                (3, 10, FoldingRangeKind::Region),
+                (5, 8, FoldingRangeKind::Region),
+            ]
+        );
+    }
+}
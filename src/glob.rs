@@ -0,0 +1,123 @@
+//! Matching of `glob()` patterns against the files of a package, following Bazel's semantics:
+//! `*` matches any part of a path segment, and a `**` segment matches any number of segments.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::file_type::FileType;
+
+/// Whether a path relative to the package, with `/` as separator, matches a glob pattern.
+pub(crate) fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+
+    matches_segments(&pattern, &path)
+}
+
+fn matches_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                matches_segment(segment.as_bytes(), name.as_bytes())
+                    && matches_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn matches_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_segment(rest, &name[skip..])),
+        Some((c, rest)) => name.first() == Some(c) && matches_segment(rest, &name[1..]),
+    }
+}
+
+/// Lists the files of the package in `package_dir`, relative to it. Directories which contain
+/// another package are skipped, since globs don't cross package boundaries, as are the
+/// `ignored_directories` of the workspace from its `.bazelignore`. Symbolic links to directories
+/// aren't followed, since they may lead out of the package, or back into it.
+pub(crate) fn package_files(package_dir: &Path, ignored_directories: &[PathBuf]) -> Vec<String> {
+    fn visit(dir: &Path, prefix: &str, ignored_directories: &[PathBuf], files: &mut Vec<String>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            let path = entry.path();
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+            if file_type.is_dir() {
+                let is_package = FileType::BUILD_FILE_NAMES
+                    .iter()
+                    .any(|build_file_name| path.join(build_file_name).exists());
+                if !is_package && !ignored_directories.contains(&path) {
+                    visit(&path, &format!("{}/", name), ignored_directories, files);
+                }
+            } else if !(file_type.is_symlink() && path.is_dir()) {
+                files.push(name);
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    visit(package_dir, "", ignored_directories, &mut files);
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{matches, package_files};
+
+    #[test]
+    fn test_matching_patterns() {
+        assert!(matches("*.cc", "foo.cc"));
+        assert!(!matches("*.cc", "foo/bar.cc"));
+        assert!(matches("**/*.cc", "foo.cc"));
+        assert!(matches("**/*.cc", "foo/bar/baz.cc"));
+        assert!(matches("foo/**", "foo/bar/baz.cc"));
+        assert!(matches("foo/*_test.cc", "foo/bar_test.cc"));
+        assert!(!matches("foo/*_test.cc", "foo/bar.cc"));
+        assert!(!matches("foo/**", "bar/baz.cc"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listing_package_files() -> std::io::Result<()> {
+        let package_dir =
+            env::temp_dir().join(format!("bazel-lsp-package-files-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&package_dir);
+        fs::create_dir_all(package_dir.join("src"))?;
+        fs::create_dir_all(package_dir.join("ignored"))?;
+        fs::create_dir_all(package_dir.join("nested"))?;
+        fs::write(package_dir.join("src").join("main.cc"), "")?;
+        fs::write(package_dir.join("ignored").join("main.cc"), "")?;
+        fs::write(package_dir.join("nested").join("BUILD"), "")?;
+        std::os::unix::fs::symlink(package_dir.join("src"), package_dir.join("linked"))?;
+        std::os::unix::fs::symlink(
+            package_dir.join("src").join("main.cc"),
+            package_dir.join("linked.cc"),
+        )?;
+
+        let files = package_files(&package_dir, &[package_dir.join("ignored")]);
+        fs::remove_dir_all(&package_dir)?;
+
+        // Symbolic links to files are files of the package, but links to directories aren't
+        // followed.
+        assert_eq!(files, vec!["linked.cc", "src/main.cc"]);
+        Ok(())
+    }
+}
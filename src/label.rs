@@ -1,6 +1,6 @@
 //! Module for parsing bazel labels
 
-use std::fmt;
+use std::{fmt, ops::Range, path::Path};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Label {
@@ -17,10 +17,26 @@ pub struct LabelRepo {
     pub is_canonical: bool,
 }
 
+/// What is wrong with a label that can't be parsed, see [`LabelParseError`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelErrorKind {
+    #[error("the repository must start with `@` or `@@`")]
+    MissingRepositoryPrefix,
+    #[error("the repository name contains the invalid character `{0}`")]
+    InvalidRepositoryCharacter(char),
+    #[error("the package contains an empty component")]
+    EmptyPackageComponent,
+    #[error("only one `:` may separate the package from the name")]
+    MultipleColons,
+}
+
 #[derive(thiserror::Error, Debug)]
-#[error("Unable to parse the label `{}`", .label)]
+#[error("Unable to parse the label `{}`: {}", .label, .kind)]
 pub struct LabelParseError {
     label: String,
+    /// The byte offset in the label where the problem is.
+    pub offset: usize,
+    pub kind: LabelErrorKind,
 }
 
 impl fmt::Display for Label {
@@ -51,25 +67,118 @@ impl fmt::Display for LabelRepo {
 }
 
 impl Label {
+    /// Whether a label has a redundant `/` between its package and name, e.g. `//foo/:bar`.
+    /// Such labels are accepted by [`Label::parse()`], which normalizes them.
+    pub fn has_redundant_separator(label: &str) -> bool {
+        match label.split_once(':') {
+            Some((package, _)) => {
+                package.contains("//") && package.ends_with('/') && !package.ends_with("//")
+            }
+            None => false,
+        }
+    }
+
+    /// Normalizes the separators of a label that Bazel doesn't accept, i.e. `\`, repeated `/`,
+    /// and `/` at the end of the package, e.g. `//foo//bar:baz` or `//foo\bar:baz` to
+    /// `//foo/bar:baz`. Returns `None` if the label is already normal.
+    pub fn normalize_path(label: &str) -> Option<String> {
+        let (prefix, path) = match label.find("//") {
+            Some(index) => label.split_at(index + 2),
+            None => ("", label),
+        };
+
+        let mut normalized = prefix.to_owned();
+        for c in path.chars() {
+            let c = if c == '\\' { '/' } else { c };
+            let follows_separator = normalized.ends_with('/');
+            if c == '/' && follows_separator {
+                continue;
+            }
+            if c == ':' && follows_separator && normalized.len() > prefix.len() {
+                normalized.pop();
+            }
+            normalized.push(c);
+        }
+        if normalized.ends_with('/') && normalized.len() > prefix.len() {
+            normalized.pop();
+        }
+
+        (normalized != label).then_some(normalized)
+    }
+
+    /// Whether a label is a target pattern matching several targets, e.g. `//foo:all` or
+    /// `//foo/...`, i.e. whether [`target_pattern_package()`] finds the package it starts from.
+    /// A `...` only matches packages, so `//foo:...` isn't a pattern.
+    pub fn is_target_pattern(&self) -> bool {
+        let is_recursive = self.package.as_deref().map_or(false, |package| {
+            package == "..." || package.ends_with("/...")
+        });
+
+        (is_recursive && self.name.ends_with("..."))
+            || TARGET_WILDCARDS
+                .iter()
+                .any(|wildcard| wildcard.strip_prefix(':') == Some(self.name.as_str()))
+    }
+
     pub fn parse(label: &str) -> Result<Self, LabelParseError> {
+        let error = |offset, kind| LabelParseError {
+            label: label.to_string(),
+            offset,
+            kind,
+        };
+
         match label.split_once("//") {
             Some((repo_part, rest)) => {
                 let repo = if repo_part == "" {
                     None
                 } else {
-                    Some(Self::parse_repo(repo_part).ok_or_else(|| LabelParseError {
-                        label: label.to_string(),
-                    })?)
+                    let repo = Self::parse_repo(repo_part)
+                        .ok_or_else(|| error(0, LabelErrorKind::MissingRepositoryPrefix))?;
+                    let name_offset = repo_part.len() - repo.name.len();
+                    if let Some((index, c)) = repo
+                        .name
+                        .char_indices()
+                        .find(|(_, c)| !is_repository_name_char(*c))
+                    {
+                        return Err(error(
+                            name_offset + index,
+                            LabelErrorKind::InvalidRepositoryCharacter(c),
+                        ));
+                    }
+                    Some(repo)
                 };
 
-                let (package, name) = rest.split_once(":").unwrap_or_else(|| {
-                    // Here the name is implicit, and comes from the last component of the package name
-                    if let Some((index, _)) = rest.rmatch_indices('/').last() {
-                        (rest, &rest[index + 1..])
-                    } else {
-                        (rest, rest)
+                let rest_offset = repo_part.len() + 2;
+                if let Some((index, _)) = rest.match_indices(':').nth(1) {
+                    return Err(error(rest_offset + index, LabelErrorKind::MultipleColons));
+                }
+
+                let (package, name) = match rest.split_once(":") {
+                    // Tolerate redundant separators before the name, e.g. `//foo/:bar`.
+                    Some((package, name)) => (package.trim_end_matches('/'), name),
+                    None => {
+                        // Here the name is implicit, and comes from the last component of the package name
+                        if let Some((index, _)) = rest.rmatch_indices('/').last() {
+                            (rest, &rest[index + 1..])
+                        } else {
+                            (rest, rest)
+                        }
                     }
-                });
+                };
+
+                // A trailing `/` is tolerated, since it is typed before the next component.
+                let components = package.trim_end_matches('/');
+                let empty_component = if components.starts_with('/') {
+                    Some(0)
+                } else {
+                    components.find("//").map(|index| index + 1)
+                };
+                if let Some(index) = empty_component {
+                    return Err(error(
+                        rest_offset + index,
+                        LabelErrorKind::EmptyPackageComponent,
+                    ));
+                }
 
                 Ok(Label {
                     name: name.to_string(),
@@ -86,6 +195,9 @@ impl Label {
                         package: Some("".to_string()),
                     })
                 } else {
+                    if let Some((index, _)) = label.match_indices(':').nth(1) {
+                        return Err(error(index, LabelErrorKind::MultipleColons));
+                    }
                     let name = label.strip_prefix(":").unwrap_or(label);
 
                     Ok(Label {
@@ -115,11 +227,195 @@ impl Label {
     }
 }
 
+/// Whether a character may appear in the name of a repository, which may be a canonical name
+/// such as `rules_rust~0.36.2` or `rules_rust+`.
+fn is_repository_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '~' | '+')
+}
+
+/// Converts a relative path to the package or name of a label, which always separate their
+/// components with `/`, whereas paths use `\` on Windows.
+pub fn label_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The make variables which take a label and expand to the paths of its files, e.g.
+/// `$(location //foo:bar)` in the `cmd` of a genrule.
+const LOCATION_VARIABLES: &[&str] = &["location", "locations", "rootpath"];
+
+/// Returns the labels referenced by location make variables in a string, with the byte range of
+/// each label in the string.
+pub fn location_references(value: &str) -> Vec<(Range<usize>, &str)> {
+    let mut references = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = value[offset..].find("$(") {
+        let start = offset + start + 2;
+        let end = match value[start..].find(')') {
+            Some(end) => start + end,
+            None => break,
+        };
+        offset = end;
+
+        let expression = &value[start..end];
+        if let Some((variable, argument)) = expression.split_once(char::is_whitespace) {
+            let label = argument.trim();
+            if LOCATION_VARIABLES.contains(&variable) && !label.is_empty() {
+                // The argument runs until the end of the expression.
+                let label_start = end - argument.trim_start().len();
+                references.push((label_start..label_start + label.len(), label));
+            }
+        }
+    }
+    references
+}
+
+/// The names of a target pattern that match all targets of a package, e.g. `//foo:all`.
+const TARGET_WILDCARDS: &[&str] = &[":all-targets", ":all", ":*"];
+
+/// Returns the package that a target pattern like `//foo/...`, `//foo:all`, or `//foo/...:*`
+/// starts from, e.g. `//foo`. Returns `None` for labels that aren't patterns.
+pub fn target_pattern_package(pattern: &str) -> Option<&str> {
+    let without_wildcard = TARGET_WILDCARDS
+        .iter()
+        .find_map(|wildcard| pattern.strip_suffix(wildcard));
+    let base = without_wildcard.unwrap_or(pattern);
+
+    match base.strip_suffix("...") {
+        // `//...` starts from the root package.
+        Some(prefix) if prefix.ends_with("//") => Some(prefix),
+        Some(prefix) => prefix.strip_suffix('/'),
+        None => without_wildcard,
+    }
+}
+
+/// Whether a string looks like it's meant to be a label, e.g. `//foo:bar`, `@repo`, or `:bar`,
+/// rather than free text like a docstring, a command, or a URL. A file name like `bar.cc` only
+/// counts with `allow_file_names`, e.g. in the value of an attribute. This is only a cheap check
+/// before parsing or resolving the label, which may need to look at the filesystem or run
+/// queries.
+pub fn looks_like_label(value: &str, allow_file_names: bool) -> bool {
+    let has_label_prefix =
+        value.starts_with("//") || value.starts_with('@') || value.starts_with(':');
+
+    (has_label_prefix || (allow_file_names && !value.contains("://")))
+        && !value.is_empty()
+        && !value.contains(char::is_whitespace)
+        && !value.contains("$(")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
+    use crate::label::label_path;
+    use crate::label::location_references;
+    use crate::label::looks_like_label;
+    use crate::label::target_pattern_package;
     use crate::label::Label;
+    use crate::label::LabelErrorKind;
     use crate::label::LabelRepo;
 
+    #[test]
+    fn test_label_path() {
+        // Paths are joined with the separator of the platform.
+        assert_eq!(
+            label_path(&Path::new("foo").join("bar").join("baz.bzl")),
+            "foo/bar/baz.bzl"
+        );
+        assert_eq!(label_path(Path::new("")), "");
+    }
+
+    #[test]
+    fn test_looks_like_label() {
+        for label in ["//foo:bar", "@repo", "@@repo//:baz", ":bar", "//foo::bar"] {
+            assert!(looks_like_label(label, false), "{label}");
+        }
+        for text in ["", "Builds the binary.", "cp $(location :bar) $@", "bar.cc"] {
+            assert!(!looks_like_label(text, false), "{text}");
+        }
+
+        // File names only count where labels are expected, but URLs never do.
+        assert!(looks_like_label("bar.cc", true));
+        assert!(!looks_like_label("https://example.com/foo.tar.gz", true));
+        assert!(!looks_like_label("Builds the binary.", true));
+    }
+
+    #[test]
+    fn test_location_references() {
+        let cmd = "$(location :tool) --in=$(locations  //foo:srcs ) $(execpath :bar) $@ $(rootpath @repo//:baz";
+        let references = location_references(cmd);
+        assert_eq!(
+            references
+                .iter()
+                .map(|(range, label)| (&cmd[range.clone()], *label))
+                .collect::<Vec<_>>(),
+            vec![(":tool", ":tool"), ("//foo:srcs", "//foo:srcs")]
+        );
+        assert!(location_references("cp $< $@").is_empty());
+    }
+
+    #[test]
+    fn test_target_pattern_package() {
+        assert_eq!(target_pattern_package("//foo/..."), Some("//foo"));
+        assert_eq!(target_pattern_package("//foo:all"), Some("//foo"));
+        assert_eq!(
+            target_pattern_package("//foo/...:all-targets"),
+            Some("//foo")
+        );
+        assert_eq!(target_pattern_package("@repo//..."), Some("@repo//"));
+        assert_eq!(target_pattern_package(":*"), Some(""));
+        assert_eq!(target_pattern_package("//foo:bar"), None);
+        assert_eq!(target_pattern_package("//foo:..."), None);
+    }
+
+    #[test]
+    fn target_patterns_have_a_package() {
+        for pattern in [
+            "//...",
+            "@repo//...",
+            "//foo/...",
+            "//foo/bar/...",
+            "//foo:all",
+            "//foo:*",
+            "//foo/...:all",
+            "//foo/...:*",
+            ":all",
+            ":*",
+        ] {
+            assert!(
+                Label::parse(pattern).unwrap().is_target_pattern(),
+                "{pattern}"
+            );
+            assert!(target_pattern_package(pattern).is_some(), "{pattern}");
+        }
+
+        for label in ["//foo:bar", "//foo:...", ":...", "//foo/...:bar"] {
+            assert!(!Label::parse(label).unwrap().is_target_pattern(), "{label}");
+            assert_eq!(target_pattern_package(label), None, "{label}");
+        }
+    }
+
+    #[test]
+    fn test_parsing_target_patterns() {
+        let label = Label::parse("//foo/...").unwrap();
+        assert_eq!(
+            label,
+            Label {
+                repo: None,
+                package: Some("foo/...".into()),
+                name: "...".into(),
+            }
+        );
+        assert!(label.is_target_pattern());
+        assert!(Label::parse("//...").unwrap().is_target_pattern());
+        assert!(Label::parse("//foo:all").unwrap().is_target_pattern());
+        assert!(Label::parse(":*").unwrap().is_target_pattern());
+        assert!(!Label::parse("//foo:bar").unwrap().is_target_pattern());
+    }
+
     #[test]
     fn test_parsing_repo_only_labels() {
         assert_eq!(
@@ -243,9 +539,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_labels_with_redundant_separator() {
+        assert_eq!(
+            Label::parse("//foo/:bar.bzl").unwrap(),
+            Label::parse("//foo:bar.bzl").unwrap()
+        );
+        assert_eq!(
+            Label::parse("@foo//bar/baz/:qux").unwrap(),
+            Label::parse("@foo//bar/baz:qux").unwrap()
+        );
+
+        assert!(Label::has_redundant_separator("//foo/:bar.bzl"));
+        assert!(!Label::has_redundant_separator("//foo:bar.bzl"));
+        assert!(!Label::has_redundant_separator("//:bar.bzl"));
+        assert!(!Label::has_redundant_separator("foo/:bar"));
+    }
+
+    #[test]
+    fn test_normalizing_label_paths() {
+        assert_eq!(
+            Label::normalize_path("//foo//bar:baz"),
+            Some("//foo/bar:baz".to_owned())
+        );
+        assert_eq!(
+            Label::normalize_path("@repo//foo\\bar/:baz"),
+            Some("@repo//foo/bar:baz".to_owned())
+        );
+        assert_eq!(Label::normalize_path("///foo/"), Some("//foo".to_owned()));
+        assert_eq!(Label::normalize_path("//:baz"), None);
+        assert_eq!(Label::normalize_path(":baz.h"), None);
+        assert_eq!(Label::normalize_path("//foo/bar:baz"), None);
+    }
+
     #[test]
     fn test_invalid_labels() {
-        assert!(Label::parse("foo//bar/baz").is_err());
+        let error = |label| {
+            let error = Label::parse(label).unwrap_err();
+            (error.kind, error.offset)
+        };
+
+        assert_eq!(
+            error("foo//bar/baz"),
+            (LabelErrorKind::MissingRepositoryPrefix, 0)
+        );
+        assert_eq!(
+            error("@foo bar//baz"),
+            (LabelErrorKind::InvalidRepositoryCharacter(' '), 4)
+        );
+        assert_eq!(
+            error("//foo//bar:baz"),
+            (LabelErrorKind::EmptyPackageComponent, 6)
+        );
+        assert_eq!(
+            error("///foo:bar"),
+            (LabelErrorKind::EmptyPackageComponent, 2)
+        );
+        assert_eq!(error("//foo:bar:baz"), (LabelErrorKind::MultipleColons, 9));
+        assert_eq!(error("::foo"), (LabelErrorKind::MultipleColons, 1));
+
+        assert_eq!(
+            Label::parse("//foo::bar").unwrap_err().to_string(),
+            "Unable to parse the label `//foo::bar`: only one `:` may separate the package from the name"
+        );
+        // Partial labels that are being typed are still accepted.
+        assert!(Label::parse("//foo/").is_ok());
+        assert!(Label::parse("@rules_rust~0.36.2//:").is_ok());
     }
 
     #[test]
@@ -0,0 +1,480 @@
+//! Bazel-specific lints, which are reported alongside the lints built into starlark-rust.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Position, Range};
+use starlark::{
+    codemap::{Pos, Span},
+    errors::EvalSeverity,
+    syntax::AstModule,
+};
+use starlark_syntax::syntax::{
+    ast::{ArgumentP, AstLiteral, ExprP, StmtP},
+    module::AstModuleFields,
+};
+
+use crate::{
+    ast,
+    builtin::Deprecations,
+    glob,
+    label::{self, Label},
+};
+
+/// Lints which are only reported when enabled with `--enable-lint`, since they are often
+/// intentional.
+pub(crate) const OPT_IN_LINTS: &[&str] = &[
+    "empty-build-file",
+    "load-not-at-top",
+    "malformed-label",
+    "missing-source-file",
+    "redundant-label-separator",
+    "test-name-mismatch",
+    "testonly-dependency",
+];
+
+/// Parses a severity override for a lint built into starlark-rust, given as
+/// `--lint-severity=<lint>=<severity>`, e.g. `unused-load=error`. The severity is one of
+/// `error`, `warning`, `info`, or `ignore`, which drops the lint.
+pub(crate) fn parse_lint_severity(value: &str) -> Result<(String, EvalSeverity), String> {
+    let (lint, severity) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected `<lint>=<severity>`, got `{}`", value))?;
+    let severity = match severity {
+        "error" => EvalSeverity::Error,
+        "warning" => EvalSeverity::Warning,
+        "info" => EvalSeverity::Advice,
+        "ignore" => EvalSeverity::Disabled,
+        _ => {
+            return Err(format!(
+                "Unknown severity `{}`, expected one of `error`, `warning`, `info`, or `ignore`",
+                severity
+            ))
+        }
+    };
+
+    Ok((lint.to_owned(), severity))
+}
+
+/// Whether an undefined symbol looks like it is defined in another `.bzl` file, i.e. a rule,
+/// macro, or provider, rather than a misspelled local variable.
+pub(crate) fn looks_loadable(symbol: &str) -> bool {
+    ["_library", "_binary", "_test"]
+        .iter()
+        .any(|suffix| symbol.ends_with(suffix))
+        || (symbol.starts_with(|c: char| c.is_ascii_uppercase())
+            && symbol.contains(|c: char| c.is_ascii_lowercase()))
+}
+
+/// Attributes that take labels of targets to depend on.
+pub(crate) const DEPENDENCY_ATTRIBUTES: &[&str] =
+    &["deps", "runtime_deps", "implementation_deps", "exports"];
+
+/// Creates a diagnostic for a span of a module. The `code` is the short name of the lint,
+/// e.g. `load-symbol-not-found`.
+pub(crate) fn diagnostic(
+    ast: &AstModule,
+    span: Span,
+    code: &str,
+    severity: DiagnosticSeverity,
+    message: String,
+) -> Diagnostic {
+    let span = ast.codemap().resolve_span(span);
+
+    Diagnostic {
+        range: Range::new(
+            Position::new(span.begin.line as u32, span.begin.column as u32),
+            Position::new(span.end.line as u32, span.end.column as u32),
+        ),
+        severity: Some(severity),
+        code: Some(NumberOrString::String(code.to_owned())),
+        message,
+        ..Default::default()
+    }
+}
+
+/// Reports labels with a redundant `/` before their name, e.g. `//foo/:bar`. These still
+/// resolve, but aren't accepted by Bazel.
+pub(crate) fn redundant_label_separators(ast: &AstModule) -> Vec<Diagnostic> {
+    ast::label_literals(ast)
+        .into_iter()
+        .filter(|(label, _)| Label::has_redundant_separator(label))
+        .filter_map(|(label, span)| {
+            let normalized = Label::parse(&label).ok()?;
+
+            Some(diagnostic(
+                ast,
+                span,
+                "redundant-label-separator",
+                DiagnosticSeverity::WARNING,
+                format!("Redundant `/` in `{}`, use `{}` instead", label, normalized),
+            ))
+        })
+        .collect()
+}
+
+/// Reports strings that look like labels, but can't be parsed, e.g. `//foo::bar`. Labels with
+/// malformed paths are reported by [`malformed_label_paths()`] instead.
+pub(crate) fn malformed_labels(ast: &AstModule) -> Vec<Diagnostic> {
+    ast::label_literals(ast)
+        .into_iter()
+        .filter(|(label, _)| label::looks_like_label(label, false))
+        // Malformed paths are reported by `malformed_label_paths()`, along with a fix.
+        .filter(|(label, _)| Label::normalize_path(label).is_none())
+        .filter_map(|(label, span)| {
+            let error = Label::parse(&label).err()?;
+
+            Some(diagnostic(
+                ast,
+                span,
+                "malformed-label",
+                DiagnosticSeverity::ERROR,
+                error.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Reports labels with separators that Bazel doesn't accept, e.g. `//foo//bar:baz` or
+/// `//foo\bar:baz`, see [`Label::normalize_path()`]. Labels whose only problem is a `/` before
+/// their name are reported by [`redundant_label_separators()`] instead.
+pub(crate) fn malformed_label_paths(ast: &AstModule) -> Vec<Diagnostic> {
+    ast::label_literals(ast)
+        .into_iter()
+        .filter(|(label, _)| label::looks_like_label(label, false))
+        .filter(|(label, _)| {
+            !Label::has_redundant_separator(label)
+                || Label::normalize_path(&label.replacen("/:", ":", 1)).is_some()
+        })
+        .filter_map(|(label, span)| {
+            let normalized = Label::normalize_path(&label)?;
+
+            Some(diagnostic(
+                ast,
+                span,
+                "malformed-label-path",
+                DiagnosticSeverity::WARNING,
+                format!(
+                    "Malformed path in `{}`, use `{}` instead",
+                    label, normalized
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Reports patterns in the `exclude` of a `glob()` which don't exclude any of the files matched
+/// by its `include`, given the directory of the package the module belongs to, and the
+/// directories that are ignored, see [`glob::package_files()`].
+pub(crate) fn ineffective_glob_excludes(
+    ast: &AstModule,
+    package_dir: &Path,
+    ignored_directories: &[PathBuf],
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut package_files = None;
+
+    ast::visit_calls(ast, |call| {
+        if call.function != Some("glob") {
+            return;
+        }
+
+        // Only globs with literal patterns can be evaluated.
+        let include = match call
+            .positional_argument(0)
+            .or_else(|| call.named_argument("include"))
+            .and_then(ast::string_list_literal)
+        {
+            Some(include) => include,
+            None => return,
+        };
+        let exclude = match call
+            .positional_argument(1)
+            .or_else(|| call.named_argument("exclude"))
+            .and_then(ast::string_list_literal)
+        {
+            Some(exclude) => exclude,
+            None => return,
+        };
+
+        let files = package_files
+            .get_or_insert_with(|| glob::package_files(package_dir, ignored_directories));
+        let included: Vec<&String> = files
+            .iter()
+            .filter(|file| {
+                include
+                    .iter()
+                    .any(|(pattern, _)| glob::matches(pattern, file))
+            })
+            .collect();
+
+        for (pattern, span) in exclude {
+            if !included.iter().any(|file| glob::matches(pattern, file)) {
+                diagnostics.push(diagnostic(
+                    ast,
+                    span,
+                    "ineffective-glob-exclude",
+                    DiagnosticSeverity::WARNING,
+                    format!(
+                        "`{}` doesn't exclude any files matched by the glob",
+                        pattern
+                    ),
+                ));
+            }
+        }
+    });
+
+    diagnostics
+}
+
+/// Attributes that take source files of the package, which are checked by
+/// [`missing_source_files()`].
+const SOURCE_ATTRIBUTES: &[&str] = &["srcs", "hdrs", "data"];
+
+/// Reports files in the `srcs`, `hdrs`, or `data` of a target which don't exist in the
+/// package directory, and aren't the name or an output of a target in the same package. Only
+/// list literals are checked, so files matched by a `glob()` are never reported.
+pub(crate) fn missing_source_files(ast: &AstModule, package_dir: &Path) -> Vec<Diagnostic> {
+    // Entries without a `:` may also refer to targets or generated files of the package.
+    let mut package_names = HashSet::new();
+    ast::visit_calls(ast, |call| {
+        package_names.extend(call.string_argument("name"));
+        package_names.extend(call.string_argument("out"));
+        if let Some(outs) = call
+            .named_argument("outs")
+            .and_then(ast::string_list_literal)
+        {
+            package_names.extend(outs.into_iter().map(|(out, _)| out));
+        }
+    });
+
+    let mut diagnostics = Vec::new();
+    ast::visit_calls(ast, |call| {
+        for attribute in SOURCE_ATTRIBUTES {
+            let files = match call
+                .named_argument(attribute)
+                .and_then(ast::string_list_literal)
+            {
+                Some(files) => files,
+                None => continue,
+            };
+
+            for (file, span) in files {
+                // Labels and make variables aren't plain files of the package.
+                if file.is_empty()
+                    || file.starts_with("//")
+                    || file.starts_with('@')
+                    || file.contains(':')
+                    || file.contains("$(")
+                    || package_names.contains(file)
+                    || package_dir.join(file).exists()
+                {
+                    continue;
+                }
+
+                diagnostics.push(diagnostic(
+                    ast,
+                    span,
+                    "missing-source-file",
+                    DiagnosticSeverity::ERROR,
+                    format!(
+                        "`{}` doesn't exist in the package, and isn't generated by a target in it",
+                        file
+                    ),
+                ));
+            }
+        }
+    });
+
+    diagnostics
+}
+
+/// Reports `*_test` targets with a single source file whose `name` doesn't correspond to the
+/// name of the file, e.g. `foo_test` with `bar_test.cc`. The name corresponds if it is the stem
+/// of the file, with or without a `_test` suffix. Sources that are labels aren't checked.
+pub(crate) fn test_name_mismatches(ast: &AstModule) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    ast::visit_calls(ast, |call| {
+        if !call.function.map_or(false, |rule| rule.ends_with("_test")) {
+            return;
+        }
+        let (name, srcs) = match (
+            call.string_argument("name"),
+            call.named_argument("srcs")
+                .and_then(ast::string_list_literal),
+        ) {
+            (Some(name), Some(srcs)) if srcs.len() == 1 => (name, srcs),
+            _ => return,
+        };
+
+        let (src, span) = srcs[0];
+        if src.contains(':') || src.contains("$(") {
+            return;
+        }
+        let stem = match Path::new(src).file_stem() {
+            Some(stem) => stem.to_string_lossy(),
+            None => return,
+        };
+        let name_stem = name.strip_suffix("_test").unwrap_or(name);
+        let src_stem = stem.strip_suffix("_test").unwrap_or(&stem);
+        if name_stem == src_stem {
+            return;
+        }
+
+        diagnostics.push(diagnostic(
+            ast,
+            span,
+            "test-name-mismatch",
+            DiagnosticSeverity::WARNING,
+            format!(
+                "The name of the test `{}` doesn't match its source `{}`",
+                name, src
+            ),
+        ));
+    });
+
+    diagnostics
+}
+
+/// Reports calls of deprecated rules of the build language and uses of their deprecated
+/// attributes, see [`Deprecations`]. Calls of loaded symbols are skipped, since these aren't the
+/// rules of the build language even if they have the same name.
+pub(crate) fn deprecated_usages(ast: &AstModule, deprecations: &Deprecations) -> Vec<Diagnostic> {
+    let loaded: HashSet<&str> = ast::loads(ast)
+        .into_iter()
+        .flat_map(|load| load.symbols.into_iter().map(|symbol| symbol.local))
+        .collect();
+    let deprecated = |name: &str, message: &str| {
+        if message.is_empty() {
+            format!("`{}` is deprecated", name)
+        } else {
+            format!("`{}` is deprecated: {}", name, message)
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    ast::visit_calls(ast, |call| {
+        let rule = match call.function {
+            Some(rule) if !loaded.contains(rule) => rule,
+            _ => return,
+        };
+
+        if let Some(message) = deprecations.rule(rule) {
+            diagnostics.push(diagnostic(
+                ast,
+                call.function_span,
+                "deprecated-usage",
+                DiagnosticSeverity::WARNING,
+                deprecated(rule, message),
+            ));
+        }
+        for arg in call.args {
+            if let ArgumentP::Named(name, _) = &arg.node {
+                if let Some(message) = deprecations.attribute(rule, &name.node) {
+                    diagnostics.push(diagnostic(
+                        ast,
+                        name.span,
+                        "deprecated-usage",
+                        DiagnosticSeverity::WARNING,
+                        deprecated(&format!("{}.{}", rule, name.node), message),
+                    ));
+                }
+            }
+        }
+    });
+
+    diagnostics
+}
+
+/// Visibility labels which can't be combined with other entries in a `visibility` list.
+const EXCLUSIVE_VISIBILITIES: &[&str] = &["//visibility:public", "//visibility:private"];
+
+/// Reports `visibility` lists which combine `//visibility:public` or `//visibility:private`
+/// with other entries, which makes the other entries meaningless.
+pub(crate) fn conflicting_visibilities(ast: &AstModule) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    ast::visit_calls(ast, |call| {
+        let visibility = match call
+            .named_argument("visibility")
+            .and_then(ast::string_list_literal)
+        {
+            Some(visibility) if visibility.len() > 1 => visibility,
+            _ => return,
+        };
+
+        for (label, span) in &visibility {
+            if EXCLUSIVE_VISIBILITIES.contains(label) {
+                diagnostics.push(diagnostic(
+                    ast,
+                    *span,
+                    "conflicting-visibility",
+                    DiagnosticSeverity::WARNING,
+                    format!("`{}` can't be combined with other visibility labels", label),
+                ));
+            }
+        }
+    });
+
+    diagnostics
+}
+
+/// Functions which can be called in a BUILD file without defining targets.
+const NON_TARGET_FUNCTIONS: &[&str] = &["package", "licenses"];
+
+/// Reports BUILD files which don't call any rules or macros, and thus define no targets.
+pub(crate) fn empty_build_file(ast: &AstModule) -> Option<Diagnostic> {
+    let has_targets = ast::top_level_statements(ast)
+        .iter()
+        .any(|stmt| match &stmt.node {
+            StmtP::Expression(expr) => match &expr.node {
+                ExprP::Call(function, _) => !ast::call_name(function)
+                    .map_or(false, |name| NON_TARGET_FUNCTIONS.contains(&name)),
+                _ => false,
+            },
+            _ => false,
+        });
+
+    if has_targets {
+        return None;
+    }
+
+    Some(diagnostic(
+        ast,
+        Span::new(Pos::new(0), Pos::new(0)),
+        "empty-build-file",
+        DiagnosticSeverity::INFORMATION,
+        "This BUILD file doesn't define any targets".to_owned(),
+    ))
+}
+
+/// Reports `load` statements that follow other statements, following the convention of
+/// buildifier that all loads come first. Only a docstring may precede them.
+pub(crate) fn loads_not_at_top(ast: &AstModule) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut seen_other_statement = false;
+
+    for (index, stmt) in ast::top_level_statements(ast).into_iter().enumerate() {
+        match &stmt.node {
+            StmtP::Load(load) if seen_other_statement => diagnostics.push(diagnostic(
+                ast,
+                stmt.span,
+                "load-not-at-top",
+                DiagnosticSeverity::WARNING,
+                format!(
+                    "Load of `{}` should be at the top of the file, before other statements",
+                    load.module.node
+                ),
+            )),
+            StmtP::Load(_) => {}
+            StmtP::Expression(expr)
+                if index == 0 && matches!(expr.node, ExprP::Literal(AstLiteral::String(_))) => {}
+            _ => seen_other_statement = true,
+        }
+    }
+
+    diagnostics
+}
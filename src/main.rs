@@ -1,23 +1,38 @@
+mod ast;
 mod bazel;
+mod bazelrc;
 mod builtin;
 mod client;
+mod completion;
+mod exports;
 mod file_type;
+mod folding;
+mod glob;
 mod label;
+mod lint;
+mod query;
+mod rename;
+mod selection;
+mod semantic_tokens;
+mod server;
+mod signature;
 #[cfg(test)]
 pub mod test_fixture;
 mod workspace;
 
-use std::{env, io, path::PathBuf};
+use std::{env, io, path::PathBuf, time::Duration};
 
-use bazel::BazelContext;
+use bazel::{BazelContext, ContextOptions};
 use clap::Parser;
 use client::BazelCli;
+use lsp_server::Connection;
+use starlark::errors::EvalSeverity;
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Location of the bazel binary
+    /// Location of the bazel binary, e.g. `bazelisk` or a wrapper script
     #[arg(long, default_value = "bazel")]
     bazel: PathBuf,
 
@@ -34,6 +49,72 @@ struct Args {
     /// temp directory.
     #[arg(long)]
     query_output_base: Option<PathBuf>,
+
+    /// Whether to display paths relative to the workspace root, rather than
+    /// absolute paths.
+    #[arg(long)]
+    relative_paths: bool,
+
+    /// Enables a lint that is disabled by default. Can be given multiple times.
+    #[arg(
+        long = "enable-lint",
+        value_name = "LINT",
+        value_parser = clap::builder::PossibleValuesParser::new(lint::OPT_IN_LINTS),
+    )]
+    enabled_lints: Vec<String>,
+
+    /// Overrides the severity of a lint built into starlark, e.g.
+    /// `--lint-severity=unused-load=error`. The severity is one of `error`,
+    /// `warning`, `info`, or `ignore`. Can be given multiple times.
+    #[arg(
+        long = "lint-severity",
+        value_name = "LINT=SEVERITY",
+        value_parser = lint::parse_lint_severity,
+    )]
+    lint_severities: Vec<(String, EvalSeverity)>,
+
+    /// The maximum number of directories, files, and targets to offer when
+    /// completing a path or label. Clients are told that more are available, so
+    /// they ask again as the user types. By default, all of them are offered.
+    #[arg(long)]
+    max_filesystem_completions: Option<usize>,
+
+    /// Also offer files and directories starting with `.`, e.g. `.git`, when
+    /// completing a path or label.
+    #[arg(long)]
+    include_hidden_files: bool,
+
+    /// Never run bazel, e.g. in sandboxes where it can't be run.
+    ///
+    /// Completions and navigation then only use what can be found on the
+    /// filesystem.
+    #[arg(long)]
+    offline: bool,
+
+    /// Complete packages and targets only from bazel queries, without reading
+    /// directories, e.g. on network filesystems where that is slow.
+    #[arg(long)]
+    query_only_completions: bool,
+
+    /// The number of seconds after which bazel queries are cancelled, so that
+    /// slow queries don't block the server. Use 0 to never cancel queries.
+    #[arg(long, default_value_t = 30)]
+    query_timeout: u64,
+
+    /// A startup option to pass to bazel before the command, e.g.
+    /// `--bazel-startup-flag=--host_jvm_args=-Xmx2g`. Can be given multiple times.
+    #[arg(
+        long = "bazel-startup-flag",
+        value_name = "FLAG",
+        allow_hyphen_values = true
+    )]
+    bazel_startup_flags: Vec<String>,
+
+    /// An option for `bazel cquery` that selects the configuration in which
+    /// `select()`s are resolved, e.g. `--cquery-flag=--platforms=//:arm`. Can
+    /// be given multiple times.
+    #[arg(long = "cquery-flag", value_name = "FLAG", allow_hyphen_values = true)]
+    cquery_flags: Vec<String>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -55,9 +136,28 @@ fn main() -> anyhow::Result<()> {
         )
     };
 
-    let ctx = BazelContext::new(BazelCli::new(args.bazel), query_output_base)?;
+    let ctx = BazelContext::new(
+        BazelCli::new(
+            args.bazel,
+            (args.query_timeout > 0).then(|| Duration::from_secs(args.query_timeout)),
+            args.bazel_startup_flags,
+        ),
+        query_output_base,
+        ContextOptions {
+            relative_paths: args.relative_paths,
+            enabled_lints: args.enabled_lints.into_iter().collect(),
+            lint_severities: args.lint_severities.into_iter().collect(),
+            max_filesystem_completions: args.max_filesystem_completions,
+            include_hidden_files: args.include_hidden_files,
+            cquery_flags: args.cquery_flags,
+            offline: args.offline,
+            query_only_completions: args.query_only_completions,
+        },
+    )?;
 
-    starlark_lsp::server::stdio_server(ctx)?;
+    let (connection, io_threads) = Connection::stdio();
+    server::run(connection, ctx)?;
+    io_threads.join()?;
 
     Ok(())
 }
@@ -0,0 +1,139 @@
+//! Module for parsing the output of bazel queries
+
+use starlark::syntax::{AstModule, Dialect};
+use starlark_syntax::syntax::{ast::ArgumentP, module::AstModuleFields};
+
+use crate::ast;
+
+/// The maximum number of attributes shown in a target summary.
+const MAX_SUMMARY_ATTRIBUTES: usize = 8;
+/// The maximum length of an attribute value shown in a target summary.
+const MAX_SUMMARY_VALUE_LENGTH: usize = 80;
+
+/// A target, as output by `bazel query --output=build`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BuildTarget {
+    /// The rule kind of the target, e.g. `cc_library`.
+    pub(crate) kind: String,
+    /// The attributes of the target and the source text of their values, excluding `name`.
+    pub(crate) attributes: Vec<(String, String)>,
+}
+
+impl BuildTarget {
+    /// Parses the first target from the output of `bazel query --output=build`.
+    pub(crate) fn parse(output: &str) -> Option<Self> {
+        let ast = AstModule::parse("BUILD", output.to_owned(), &Dialect::Extended).ok()?;
+
+        let mut target = None;
+        ast::visit_calls(&ast, |call| {
+            if target.is_some() {
+                return;
+            }
+
+            if let Some(kind) = call.function {
+                let attributes = call
+                    .args
+                    .iter()
+                    .filter_map(|arg| match &arg.node {
+                        ArgumentP::Named(name, value) if name.node != "name" => Some((
+                            name.node.clone(),
+                            ast.codemap().source_span(value.span).to_owned(),
+                        )),
+                        _ => None,
+                    })
+                    .collect();
+
+                target = Some(BuildTarget {
+                    kind: kind.to_owned(),
+                    attributes,
+                });
+            }
+        });
+
+        target
+    }
+
+    /// Whether the target is `testonly`, i.e. can only be depended on by other `testonly`
+    /// targets. Tests are always `testonly`, even if the attribute isn't set.
+    pub(crate) fn is_testonly(&self) -> bool {
+        self.kind.ends_with("_test")
+            || self
+                .attributes
+                .iter()
+                .any(|(name, value)| name == "testonly" && (value == "True" || value == "1"))
+    }
+
+    /// Renders a short markdown summary of the target, for use in hovers.
+    pub(crate) fn to_markdown(&self, label: &str) -> String {
+        let mut markdown = format!("**{}** `{}`\n", self.kind, label);
+
+        for (name, value) in self
+            .attributes
+            .iter()
+            // Attributes added by macros are rarely interesting.
+            .filter(|(name, _)| !name.starts_with("generator_"))
+            .take(MAX_SUMMARY_ATTRIBUTES)
+        {
+            let value = if value.len() > MAX_SUMMARY_VALUE_LENGTH {
+                let end = (0..=MAX_SUMMARY_VALUE_LENGTH)
+                    .rev()
+                    .find(|index| value.is_char_boundary(*index))
+                    .unwrap_or_default();
+                format!("{}…", &value[..end])
+            } else {
+                value.clone()
+            };
+
+            markdown.push_str(&format!("\n- `{}`: `{}`", name, value));
+        }
+
+        markdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuildTarget;
+
+    const OUTPUT: &str = r#"# /workspace/foo/BUILD:1:11
+cc_library(
+  name = "main",
+  generator_name = "main",
+  srcs = ["//foo:main.cc"],
+)
+# Rule main instantiated at (most recent call last):
+#   /workspace/foo/BUILD:1:11 in <toplevel>
+"#;
+
+    #[test]
+    fn test_parsing_build_output() {
+        assert_eq!(
+            BuildTarget::parse(OUTPUT),
+            Some(BuildTarget {
+                kind: "cc_library".into(),
+                attributes: vec![
+                    ("generator_name".into(), "\"main\"".into()),
+                    ("srcs".into(), "[\"//foo:main.cc\"]".into()),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_rendering_target_summary() {
+        assert_eq!(
+            BuildTarget::parse(OUTPUT)
+                .unwrap()
+                .to_markdown("//foo:main"),
+            "**cc_library** `//foo:main`\n\n- `srcs`: `[\"//foo:main.cc\"]`"
+        );
+    }
+
+    #[test]
+    fn test_tests_are_testonly() {
+        assert!(!BuildTarget::parse(OUTPUT).unwrap().is_testonly());
+        assert!(BuildTarget::parse("cc_test(\n  name = \"test\",\n)\n")
+            .unwrap()
+            .is_testonly());
+    }
+}
@@ -0,0 +1,666 @@
+//! Renaming and highlighting of symbols within a single module, and renaming of the labels
+//! that refer to a target.
+
+use std::collections::HashMap;
+
+use lsp_types::{DocumentHighlight, DocumentHighlightKind, Position, Range, TextEdit};
+use starlark::{
+    codemap::{ResolvedPos, Span},
+    syntax::AstModule,
+};
+use starlark_syntax::syntax::{
+    ast::{
+        AstAssignIdent, AstAssignTarget, AstExpr, AstNoPayload, AstStmt, ClauseP, ExprP,
+        ForClauseP, ParameterP, StmtP,
+    },
+    module::AstModuleFields,
+};
+
+use crate::{ast, label::Label};
+
+/// Keywords of Starlark, which can't be used as names.
+static KEYWORDS: &[&str] = &[
+    "and", "break", "continue", "def", "elif", "else", "for", "if", "in", "lambda", "load", "not",
+    "or", "pass", "return", "while",
+];
+
+/// Errors when a symbol cannot be renamed.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum RenameError {
+    /// There is no identifier at the position.
+    #[error("There is no symbol to rename at this position")]
+    NoSymbol,
+    /// The symbol is a builtin, rule, or otherwise not defined in the module.
+    #[error("`{}` is not defined in this file, so it can't be renamed", .0)]
+    NotDefined(String),
+    /// The new name is not a valid identifier.
+    #[error("`{}` is not a valid name", .0)]
+    InvalidName(String),
+    /// The new name is already used in the scope of the symbol.
+    #[error("`{}` is already defined", .0)]
+    AlreadyDefined(String),
+    /// The symbol is shadowed by a comprehension or lambda, which isn't supported yet.
+    #[error("`{}` is shadowed by a comprehension or lambda, so it can't be renamed", .0)]
+    Shadowed(String),
+}
+
+/// An identifier that refers to a name.
+#[derive(Debug)]
+struct Occurrence {
+    span: Span,
+    /// The span of the loaded symbol if the name is bound by a `load` without an alias, e.g.
+    /// `load(":foo.bzl", "bar")`.
+    loaded: Option<Span>,
+    /// Whether the identifier binds the name, rather than reading it.
+    binds: bool,
+}
+
+/// The occurrences of a name within a scope.
+#[derive(Debug, Default)]
+struct Occurrences {
+    /// Whether the name is bound in the scope, rather than referring to an outer scope.
+    bound: bool,
+    /// Whether the name is bound by a comprehension or lambda within the scope.
+    shadowed: bool,
+    spans: Vec<Occurrence>,
+}
+
+/// The names used in a scope, i.e. the module or a top-level function.
+type Scope<'a> = HashMap<&'a str, Occurrences>;
+
+fn bind<'a>(scope: &mut Scope<'a>, ident: &'a AstAssignIdent) {
+    let occurrences = scope.entry(&ident.node.ident).or_default();
+    occurrences.bound = true;
+    occurrences.spans.push(Occurrence {
+        span: ident.span,
+        loaded: None,
+        binds: true,
+    });
+}
+
+fn bind_target<'a>(scope: &mut Scope<'a>, target: &'a AstAssignTarget) {
+    target.visit_lvalue(|ident| bind(scope, ident));
+    target.visit_expr(|expr| visit_expr(expr, scope));
+}
+
+fn shadow<'a>(scope: &mut Scope<'a>, name: &'a str) {
+    scope.entry(name).or_default().shadowed = true;
+}
+
+fn visit_expr<'a>(expr: &'a AstExpr, scope: &mut Scope<'a>) {
+    match &expr.node {
+        ExprP::Identifier(ident) => {
+            scope
+                .entry(&ident.node.ident)
+                .or_default()
+                .spans
+                .push(Occurrence {
+                    span: ident.span,
+                    loaded: None,
+                    binds: false,
+                })
+        }
+        ExprP::Lambda(lambda) => {
+            for param in &lambda.params {
+                if let Some(name) = parameter_name(&param.node) {
+                    shadow(scope, name);
+                }
+            }
+        }
+        ExprP::ListComprehension(_, for_clause, clauses)
+        | ExprP::DictComprehension(_, for_clause, clauses) => {
+            let mut shadow_for = |for_clause: &'a ForClauseP<AstNoPayload>| {
+                for_clause
+                    .var
+                    .visit_lvalue(|ident| shadow(scope, &ident.node.ident))
+            };
+
+            shadow_for(&**for_clause);
+            for clause in clauses {
+                if let ClauseP::For(for_clause) = clause {
+                    shadow_for(for_clause);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    expr.visit_expr(|expr| visit_expr(expr, scope));
+}
+
+fn parameter_name(param: &ParameterP<AstNoPayload>) -> Option<&str> {
+    match param {
+        ParameterP::Normal(name, _, _)
+        | ParameterP::Args(name, _)
+        | ParameterP::KwArgs(name, _) => Some(&name.node.ident),
+        ParameterP::NoArgs | ParameterP::Slash => None,
+    }
+}
+
+fn visit_stmt<'a>(stmt: &'a AstStmt, scope: &mut Scope<'a>) {
+    match &stmt.node {
+        StmtP::Assign(assign) => {
+            bind_target(scope, &assign.lhs);
+            visit_expr(&assign.rhs, scope);
+        }
+        StmtP::AssignModify(lhs, _, rhs) => {
+            bind_target(scope, lhs);
+            visit_expr(rhs, scope);
+        }
+        StmtP::For(for_stmt) => {
+            bind_target(scope, &for_stmt.var);
+            visit_expr(&for_stmt.over, scope);
+            visit_stmt(&for_stmt.body, scope);
+        }
+        StmtP::Def(def) => {
+            bind(scope, &def.name);
+            for param in &def.params {
+                match &param.node {
+                    ParameterP::Normal(name, _, default_value) => {
+                        bind(scope, name);
+                        if let Some(default_value) = default_value {
+                            visit_expr(default_value, scope);
+                        }
+                    }
+                    ParameterP::Args(name, _) | ParameterP::KwArgs(name, _) => bind(scope, name),
+                    ParameterP::NoArgs | ParameterP::Slash => {}
+                }
+            }
+            visit_stmt(&def.body, scope);
+        }
+        StmtP::Load(load) => {
+            for arg in &load.args {
+                let occurrences = scope.entry(&arg.local.node.ident).or_default();
+                occurrences.bound = true;
+                occurrences.spans.push(Occurrence {
+                    span: arg.local.span,
+                    loaded: (arg.local.span == arg.their.span).then_some(arg.their.span),
+                    binds: true,
+                });
+            }
+        }
+        _ => {
+            stmt.visit_expr(|expr| visit_expr(expr, scope));
+            stmt.visit_stmt(|stmt| visit_stmt(stmt, scope));
+        }
+    }
+}
+
+/// Collects the scopes of a module: the module itself, and every top-level function. Nested
+/// functions are considered part of the function they are defined in.
+fn scopes(ast: &AstModule) -> (Scope<'_>, Vec<Scope<'_>>) {
+    let mut module = Scope::new();
+    let mut functions = Vec::new();
+
+    for stmt in ast::top_level_statements(ast) {
+        match &stmt.node {
+            StmtP::Def(def) => {
+                bind(&mut module, &def.name);
+
+                let mut function = Scope::new();
+                for param in &def.params {
+                    match &param.node {
+                        // Default values are evaluated in the scope of the module.
+                        ParameterP::Normal(name, _, default_value) => {
+                            bind(&mut function, name);
+                            if let Some(default_value) = default_value {
+                                visit_expr(default_value, &mut module);
+                            }
+                        }
+                        ParameterP::Args(name, _) | ParameterP::KwArgs(name, _) => {
+                            bind(&mut function, name);
+                        }
+                        ParameterP::NoArgs | ParameterP::Slash => {}
+                    }
+                }
+                visit_stmt(&def.body, &mut function);
+
+                functions.push(function);
+            }
+            _ => visit_stmt(stmt, &mut module),
+        }
+    }
+
+    (module, functions)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+fn resolve(ast: &AstModule, span: Span) -> Range {
+    let span = ast.codemap().resolve_span(span);
+    Range::new(
+        Position::new(span.begin.line as u32, span.begin.column as u32),
+        Position::new(span.end.line as u32, span.end.column as u32),
+    )
+}
+
+/// Finds the name at `position`, along with whether it is bound by the top-level function it
+/// is used in, and the scopes in which it refers to the same symbol.
+fn find_symbol<'s, 'a>(
+    ast: &AstModule,
+    module: &'s Scope<'a>,
+    functions: &'s [Scope<'a>],
+    position: ResolvedPos,
+) -> Option<(&'a str, bool, Vec<&'s Scope<'a>>)> {
+    let position = Position::new(position.line as u32, position.column as u32);
+
+    let (scope_index, name) = std::iter::once(module)
+        .chain(functions.iter())
+        .enumerate()
+        .find_map(|(index, scope)| {
+            scope.iter().find_map(|(name, occurrences)| {
+                occurrences
+                    .spans
+                    .iter()
+                    .any(|occurrence| {
+                        let range = resolve(ast, occurrence.span);
+                        range.start <= position && position <= range.end
+                    })
+                    .then_some((index, *name))
+            })
+        })?;
+
+    // A name in a function refers to the module, unless the function binds it itself.
+    let in_function = scope_index > 0 && functions[scope_index - 1][name].bound;
+    let scopes = if in_function {
+        vec![&functions[scope_index - 1]]
+    } else {
+        std::iter::once(module)
+            .chain(
+                functions
+                    .iter()
+                    .filter(|function| function.get(name).map_or(true, |name| !name.bound)),
+            )
+            .collect()
+    };
+
+    Some((name, in_function, scopes))
+}
+
+/// Computes the edits to rename the local variable, function, or loaded symbol at `position`
+/// to `new_name`. Names that are bound by comprehensions or lambdas aren't supported, since
+/// these aren't tracked as separate scopes.
+pub(crate) fn rename(
+    ast: &AstModule,
+    position: ResolvedPos,
+    new_name: &str,
+) -> Result<Vec<TextEdit>, RenameError> {
+    if !is_valid_name(new_name) {
+        return Err(RenameError::InvalidName(new_name.to_owned()));
+    }
+
+    let (module, functions) = scopes(ast);
+    let (name, in_function, scopes) =
+        find_symbol(ast, &module, &functions, position).ok_or(RenameError::NoSymbol)?;
+
+    if !in_function
+        && !module
+            .get(name)
+            .map_or(false, |occurrences| occurrences.bound)
+    {
+        return Err(RenameError::NotDefined(name.to_owned()));
+    }
+    if scopes.iter().any(|scope| {
+        scope
+            .get(new_name)
+            .map_or(false, |occurrences| occurrences.bound)
+    }) {
+        return Err(RenameError::AlreadyDefined(new_name.to_owned()));
+    }
+    if scopes.iter().any(|scope| {
+        scope
+            .get(name)
+            .map_or(false, |occurrences| occurrences.shadowed)
+    }) {
+        return Err(RenameError::Shadowed(name.to_owned()));
+    }
+
+    let mut edits: Vec<TextEdit> = scopes
+        .iter()
+        .filter_map(|scope| scope.get(name))
+        .flat_map(|occurrences| occurrences.spans.iter())
+        .map(|occurrence| TextEdit {
+            range: resolve(ast, occurrence.span),
+            new_text: match occurrence.loaded {
+                // Loads without an alias become aliased, so that the same symbol is loaded.
+                Some(loaded) => format!("{} = {}", new_name, ast.codemap().source_span(loaded)),
+                None => new_name.to_owned(),
+            },
+        })
+        .collect();
+    edits.sort_by_key(|edit| edit.range.start);
+
+    Ok(edits)
+}
+
+/// Returns the range of the contents of a single-line string literal, i.e. without its quotes.
+fn literal_contents_range(ast: &AstModule, span: Span) -> Option<Range> {
+    let source = ast.codemap().source_span(span);
+    let quote = source.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    // Triple-quoted strings are rare for labels, so they aren't supported.
+    if source.len() < 2
+        || !source.ends_with(quote)
+        || source.starts_with(&quote.to_string().repeat(3))
+    {
+        return None;
+    }
+
+    let range = resolve(ast, span);
+    if range.start.line != range.end.line {
+        return None;
+    }
+    Some(Range::new(
+        Position::new(range.start.line, range.start.character + 1),
+        Position::new(range.end.line, range.end.character - 1),
+    ))
+}
+
+/// Finds the `name` of the target at `position` in a BUILD file, i.e. a string literal passed
+/// as `name` to a top-level call. Returns the name, and the range of the literal's contents.
+pub(crate) fn find_target_name(ast: &AstModule, position: ResolvedPos) -> Option<(String, Range)> {
+    let position = Position::new(position.line as u32, position.column as u32);
+    let mut result = None;
+
+    ast::visit_string_literals(ast, |literal, span, context| {
+        if result.is_some()
+            || context.calls.len() != 1
+            || context.calls[0].argument.as_deref() != Some("name")
+        {
+            return;
+        }
+
+        let range = resolve(ast, span);
+        if range.start <= position && position <= range.end {
+            result = literal_contents_range(ast, span).map(|range| (literal.to_owned(), range));
+        }
+    });
+
+    result
+}
+
+/// Computes the edits that make the labels in a BUILD file of `current_package` which refer to
+/// the target `name` in `package` of the main repository refer to `new_name` instead. Only
+/// string literals written as labels, i.e. starting with `:`, `//`, or `@`, are updated.
+pub(crate) fn rename_target_references(
+    ast: &AstModule,
+    current_package: &str,
+    package: &str,
+    name: &str,
+    new_name: &str,
+) -> Vec<TextEdit> {
+    let mut edits = Vec::new();
+
+    ast::visit_string_literals(ast, |literal, span, _| {
+        if !(literal.starts_with(':') || literal.starts_with("//") || literal.starts_with('@')) {
+            return;
+        }
+        let label = match Label::parse(literal) {
+            Ok(label) => label,
+            Err(_) => return,
+        };
+        if label
+            .repo
+            .as_ref()
+            .map_or(false, |repo| !repo.name.is_empty())
+            || label.package.as_deref().unwrap_or(current_package) != package
+            || label.name != name
+        {
+            return;
+        }
+
+        // Labels like `//foo` have an implicit name, which has to be spelled out when it changes.
+        let new_text = match literal.rsplit_once(':') {
+            Some((prefix, _)) => format!("{}:{}", prefix, new_name),
+            None => format!("{}:{}", literal, new_name),
+        };
+        if let Some(range) = literal_contents_range(ast, span) {
+            edits.push(TextEdit { range, new_text });
+        }
+    });
+    edits.sort_by_key(|edit| edit.range.start);
+
+    edits
+}
+
+/// Computes the highlights of the symbol at `position`, i.e. every occurrence of the name that
+/// refers to the same symbol. Occurrences that bind the name are highlighted as writes.
+pub(crate) fn highlights(ast: &AstModule, position: ResolvedPos) -> Vec<DocumentHighlight> {
+    let (module, functions) = scopes(ast);
+    let (name, _, scopes) = match find_symbol(ast, &module, &functions, position) {
+        Some(symbol) => symbol,
+        None => return Vec::new(),
+    };
+
+    let mut highlights: Vec<DocumentHighlight> = scopes
+        .iter()
+        .filter_map(|scope| scope.get(name))
+        .flat_map(|occurrences| occurrences.spans.iter())
+        .map(|occurrence| DocumentHighlight {
+            range: resolve(ast, occurrence.span),
+            kind: Some(if occurrence.binds {
+                DocumentHighlightKind::WRITE
+            } else {
+                DocumentHighlightKind::READ
+            }),
+        })
+        .collect();
+    highlights.sort_by_key(|highlight| highlight.range.start);
+
+    highlights
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{DocumentHighlightKind, Position, Range, TextEdit};
+    use starlark::{
+        codemap::ResolvedPos,
+        syntax::{AstModule, Dialect},
+    };
+
+    use super::{find_target_name, highlights, rename, rename_target_references, RenameError};
+
+    fn rename_at(
+        source: &str,
+        line: usize,
+        column: usize,
+        new_name: &str,
+    ) -> Result<Vec<TextEdit>, RenameError> {
+        let ast = AstModule::parse("defs.bzl", source.to_owned(), &Dialect::Extended).unwrap();
+
+        rename(&ast, ResolvedPos { line, column }, new_name)
+    }
+
+    fn edit(line: u32, begin: u32, end: u32, new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(Position::new(line, begin), Position::new(line, end)),
+            new_text: new_text.to_owned(),
+        }
+    }
+
+    const SOURCE: &str = r#"load(":defs.bzl", "exported", lib = "library")
+
+def helper(name):
+    return name + "_helper"
+
+def my_macro(name, helper = helper):
+    lib(name = helper(name))
+    exported(name = name)
+"#;
+
+    #[test]
+    fn renames_local_def() {
+        // Parameters with the same name are a different symbol.
+        assert_eq!(
+            rename_at(SOURCE, 2, 5, "make_name"),
+            Ok(vec![
+                edit(2, 4, 10, "make_name"),
+                edit(5, 28, 34, "make_name"),
+            ])
+        );
+        assert_eq!(
+            rename_at(SOURCE, 6, 16, "make_name"),
+            Ok(vec![
+                edit(5, 19, 25, "make_name"),
+                edit(6, 15, 21, "make_name"),
+            ])
+        );
+    }
+
+    #[test]
+    fn renames_loaded_symbols() {
+        assert_eq!(
+            rename_at(SOURCE, 6, 4, "library"),
+            Ok(vec![edit(0, 30, 33, "library"), edit(6, 4, 7, "library")])
+        );
+        // Loads without an alias become aliased.
+        assert_eq!(
+            rename_at(SOURCE, 7, 4, "other"),
+            Ok(vec![
+                edit(0, 18, 28, "other = \"exported\""),
+                edit(7, 4, 12, "other"),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_renames() {
+        assert_eq!(
+            rename_at("cc_library(name = 'foo')", 0, 3, "foo"),
+            Err(RenameError::NotDefined("cc_library".into()))
+        );
+        assert_eq!(
+            rename_at(SOURCE, 2, 5, "my_macro"),
+            Err(RenameError::AlreadyDefined("my_macro".into()))
+        );
+        assert_eq!(
+            rename_at(SOURCE, 2, 5, "def"),
+            Err(RenameError::InvalidName("def".into()))
+        );
+        assert_eq!(
+            rename_at("x = 1\ny = [x for x in []]", 0, 0, "z"),
+            Err(RenameError::Shadowed("x".into()))
+        );
+        assert_eq!(rename_at(SOURCE, 1, 0, "foo"), Err(RenameError::NoSymbol));
+    }
+
+    fn highlights_at(
+        source: &str,
+        line: usize,
+        column: usize,
+    ) -> Vec<(u32, u32, u32, DocumentHighlightKind)> {
+        let ast = AstModule::parse("defs.bzl", source.to_owned(), &Dialect::Extended).unwrap();
+
+        highlights(&ast, ResolvedPos { line, column })
+            .into_iter()
+            .map(|highlight| {
+                (
+                    highlight.range.start.line,
+                    highlight.range.start.character,
+                    highlight.range.end.character,
+                    highlight.kind.unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn highlights_reused_local_variable() {
+        let source = r#"def my_macro(name):
+    srcs = [name + ".cc"]
+    srcs += [name + ".h"]
+    native.cc_library(name = name, srcs = srcs)
+"#;
+
+        assert_eq!(
+            highlights_at(source, 3, 43),
+            vec![
+                (1, 4, 8, DocumentHighlightKind::WRITE),
+                (2, 4, 8, DocumentHighlightKind::WRITE),
+                (3, 42, 46, DocumentHighlightKind::READ),
+            ]
+        );
+        // Keyword arguments aren't occurrences of the parameter.
+        assert_eq!(
+            highlights_at(source, 0, 14),
+            vec![
+                (0, 13, 17, DocumentHighlightKind::WRITE),
+                (1, 12, 16, DocumentHighlightKind::READ),
+                (2, 13, 17, DocumentHighlightKind::READ),
+                (3, 29, 33, DocumentHighlightKind::READ),
+            ]
+        );
+        assert_eq!(highlights_at(SOURCE, 1, 0), vec![]);
+    }
+
+    #[test]
+    fn highlights_loaded_symbols() {
+        assert_eq!(
+            highlights_at(SOURCE, 7, 4),
+            vec![
+                (0, 18, 28, DocumentHighlightKind::WRITE),
+                (7, 4, 12, DocumentHighlightKind::READ),
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_target_references() {
+        let ast = AstModule::parse(
+            "BUILD",
+            r#"cc_library(
+    name = "main",
+    deps = [":main", "//foo:main", "@//foo:main", "//foo", "@other//foo:main", "main.cc"],
+)
+"#
+            .to_owned(),
+            &Dialect::Extended,
+        )
+        .unwrap();
+
+        assert_eq!(
+            find_target_name(
+                &ast,
+                ResolvedPos {
+                    line: 1,
+                    column: 13
+                }
+            ),
+            Some((
+                "main".to_owned(),
+                Range::new(Position::new(1, 12), Position::new(1, 16))
+            ))
+        );
+        assert_eq!(
+            find_target_name(
+                &ast,
+                ResolvedPos {
+                    line: 2,
+                    column: 14
+                }
+            ),
+            None
+        );
+
+        assert_eq!(
+            rename_target_references(&ast, "foo", "foo", "main", "core"),
+            vec![
+                edit(2, 13, 18, ":core"),
+                edit(2, 22, 32, "//foo:core"),
+                edit(2, 36, 47, "@//foo:core"),
+            ]
+        );
+        // Relative labels in other packages refer to their own package.
+        assert_eq!(
+            rename_target_references(&ast, "bar", "foo", "foo", "core"),
+            vec![edit(2, 51, 56, "//foo:core")]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Selection ranges, which let clients expand the selection outward through the structure of a
+//! module, e.g. from an identifier to the argument it's in, to the call, to the statement, and
+//! finally to the whole file.
+
+use lsp_types::{Position, Range, SelectionRange};
+use starlark::{
+    codemap::{ResolvedPos, Span},
+    syntax::AstModule,
+};
+use starlark_syntax::syntax::{
+    ast::{ArgumentP, AstExpr, AstStmt, ExprP},
+    module::AstModuleFields,
+};
+
+use crate::ast;
+
+/// Returns the selection range at a position: the innermost expression containing it, whose
+/// parents are the ranges enclosing it, up to the whole file.
+pub(crate) fn selection_range(ast: &AstModule, position: ResolvedPos) -> SelectionRange {
+    let mut spans = vec![ast.codemap().full_span()];
+    visit_stmt_spans(ast, ast.statement(), position, &mut spans);
+
+    // Siblings may both contain a position between them, so only spans nested in the previous
+    // one are kept. Nodes with the same span, e.g. a statement that is a call, are merged.
+    let mut nested: Vec<Range> = Vec::new();
+    for span in spans {
+        let range = resolve(ast, span);
+        match nested.last() {
+            Some(parent) if *parent == range => {}
+            Some(parent) if parent.start > range.start || range.end > parent.end => {}
+            _ => nested.push(range),
+        }
+    }
+
+    let mut selection = None;
+    for range in nested {
+        selection = Some(SelectionRange {
+            range,
+            parent: selection.map(Box::new),
+        });
+    }
+    // NOTE: Safe to `unwrap()` here, since the span of the whole file is always there.
+    selection.unwrap()
+}
+
+fn visit_stmt_spans(ast: &AstModule, stmt: &AstStmt, position: ResolvedPos, spans: &mut Vec<Span>) {
+    if !ast::span_contains(ast, stmt.span, position) {
+        return;
+    }
+    spans.push(stmt.span);
+
+    stmt.visit_expr(|expr| visit_expr_spans(ast, expr, position, spans));
+    stmt.visit_stmt(|stmt| visit_stmt_spans(ast, stmt, position, spans));
+}
+
+fn visit_expr_spans(ast: &AstModule, expr: &AstExpr, position: ResolvedPos, spans: &mut Vec<Span>) {
+    if !ast::span_contains(ast, expr.span, position) {
+        return;
+    }
+    spans.push(expr.span);
+
+    // Arguments aren't expressions, so they are visited separately to include the `name =` of
+    // named arguments.
+    if let ExprP::Call(function, args) = &expr.node {
+        visit_expr_spans(ast, function, position, spans);
+        for arg in &args.args {
+            if !ast::span_contains(ast, arg.span, position) {
+                continue;
+            }
+            spans.push(arg.span);
+
+            match &arg.node {
+                ArgumentP::Named(name, value) => {
+                    if ast::span_contains(ast, name.span, position) {
+                        spans.push(name.span);
+                    }
+                    visit_expr_spans(ast, value, position, spans);
+                }
+                ArgumentP::Positional(value)
+                | ArgumentP::Args(value)
+                | ArgumentP::KwArgs(value) => visit_expr_spans(ast, value, position, spans),
+            }
+        }
+    } else {
+        expr.visit_expr(|expr| visit_expr_spans(ast, expr, position, spans));
+    }
+}
+
+fn resolve(ast: &AstModule, span: Span) -> Range {
+    let span = ast.codemap().resolve_span(span);
+    Range::new(
+        Position::new(span.begin.line as u32, span.begin.column as u32),
+        Position::new(span.end.line as u32, span.end.column as u32),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Position, Range, SelectionRange};
+    use starlark::{
+        codemap::ResolvedPos,
+        syntax::{AstModule, Dialect},
+    };
+
+    use super::selection_range;
+
+    fn ranges(selection: SelectionRange) -> Vec<Range> {
+        let mut ranges = vec![selection.range];
+        let mut parent = selection.parent;
+        while let Some(selection) = parent {
+            ranges.push(selection.range);
+            parent = selection.parent;
+        }
+        ranges
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+    }
+
+    #[test]
+    fn nests_arguments_in_calls() {
+        let ast = AstModule::parse(
+            "BUILD",
+            r#"load("//a:a.bzl", "a")
+
+cc_library(
+    name = "foo",
+    deps = [":bar"],
+)
+"#
+            .to_owned(),
+            &Dialect::Extended,
+        )
+        .unwrap();
+
+        let ranges = ranges(selection_range(
+            &ast,
+            ResolvedPos {
+                line: 4,
+                column: 14,
+            },
+        ));
+        assert_eq!(
+            ranges[..4],
+            [
+                // The label, the list, the argument and the call.
+                range((4, 12), (4, 18)),
+                range((4, 11), (4, 19)),
+                range((4, 4), (4, 19)),
+                range((2, 0), (5, 1)),
+            ]
+        );
+        // The outermost range is the whole file.
+        assert_eq!(ranges.last(), Some(&range((0, 0), (6, 0))));
+    }
+}
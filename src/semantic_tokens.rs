@@ -0,0 +1,168 @@
+//! Semantic tokens, which let clients highlight rules, attributes, and labels in BUILD files more
+//! precisely than a Starlark grammar can.
+
+use std::collections::HashSet;
+
+use lsp_types::{SemanticToken, SemanticTokenType};
+use starlark::{codemap::Span, syntax::AstModule};
+use starlark_syntax::syntax::{
+    ast::{ArgumentP, AstExpr, AstStmt, ExprP},
+    module::AstModuleFields,
+};
+
+use crate::{
+    ast,
+    label::{self, Label},
+};
+
+/// The types of tokens reported by [`semantic_tokens()`], in the order of their index in the
+/// legend sent to clients.
+pub(crate) const TOKEN_TYPES: &[SemanticTokenType] = &[
+    // Calls of rules in the build language.
+    SemanticTokenType::FUNCTION,
+    // Calls of loaded symbols, which are usually macros or rules defined in Starlark.
+    SemanticTokenType::MACRO,
+    // Names of attributes passed to calls.
+    SemanticTokenType::PROPERTY,
+    // String literals that are labels, e.g. `"//foo:bar"`.
+    SemanticTokenType::new("label"),
+];
+
+const FUNCTION: u32 = 0;
+const MACRO: u32 = 1;
+const PROPERTY: u32 = 2;
+const LABEL: u32 = 3;
+
+/// Classifies the rule names, attribute names, and labels of a module, see [`TOKEN_TYPES`].
+/// `rules` are the names of the rules in the build language. Tokens are delta-encoded relative
+/// to each other, as expected by clients.
+pub(crate) fn semantic_tokens(ast: &AstModule, rules: &HashSet<String>) -> Vec<SemanticToken> {
+    let loaded: HashSet<&str> = ast::loads(ast)
+        .into_iter()
+        .flat_map(|load| load.symbols.into_iter().map(|symbol| symbol.local))
+        .collect();
+
+    let mut tokens = Vec::new();
+    visit_stmt_exprs(ast.statement(), &mut |expr| match &expr.node {
+        ExprP::Call(function, args) => {
+            if let ExprP::Identifier(ident) = &function.node {
+                let name = ident.node.ident.as_str();
+                if loaded.contains(name) {
+                    tokens.push((function.span, MACRO));
+                } else if rules.contains(name) {
+                    tokens.push((function.span, FUNCTION));
+                }
+            }
+
+            for arg in &args.args {
+                if let ArgumentP::Named(name, _) = &arg.node {
+                    tokens.push((name.span, PROPERTY));
+                }
+            }
+        }
+        _ => {
+            if let Some(value) = ast::string_literal(expr) {
+                // Only valid labels are highlighted, rather than e.g. file names or flags.
+                if label::looks_like_label(value, false) && Label::parse(value).is_ok() {
+                    tokens.push((expr.span, LABEL));
+                }
+            }
+        }
+    });
+
+    encode(ast, tokens)
+}
+
+/// Sorts tokens by their position and encodes them relative to the previous token. Tokens that
+/// span multiple lines are dropped, since not all clients support them.
+fn encode(ast: &AstModule, mut tokens: Vec<(Span, u32)>) -> Vec<SemanticToken> {
+    tokens.sort_by_key(|(span, _)| span.begin());
+
+    let mut encoded = Vec::new();
+    let (mut previous_line, mut previous_start) = (0, 0);
+    for (span, token_type) in tokens {
+        let span = ast.codemap().resolve_span(span);
+        if span.begin.line != span.end.line {
+            continue;
+        }
+
+        let line = span.begin.line as u32;
+        let start = span.begin.column as u32;
+        encoded.push(SemanticToken {
+            delta_line: line - previous_line,
+            delta_start: if line == previous_line {
+                start - previous_start
+            } else {
+                start
+            },
+            length: (span.end.column - span.begin.column) as u32,
+            token_type,
+            token_modifiers_bitset: 0,
+        });
+        (previous_line, previous_start) = (line, start);
+    }
+
+    encoded
+}
+
+fn visit_stmt_exprs(stmt: &AstStmt, f: &mut dyn FnMut(&AstExpr)) {
+    stmt.visit_expr(|expr| visit_expr(expr, f));
+    stmt.visit_stmt(|stmt| visit_stmt_exprs(stmt, f));
+}
+
+fn visit_expr(expr: &AstExpr, f: &mut dyn FnMut(&AstExpr)) {
+    f(expr);
+    expr.visit_expr(|expr| visit_expr(expr, f));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use starlark::syntax::{AstModule, Dialect};
+
+    use super::{semantic_tokens, FUNCTION, LABEL, MACRO, PROPERTY};
+
+    #[test]
+    fn classifies_rules_attributes_and_labels() {
+        let ast = AstModule::parse(
+            "BUILD",
+            r#"load("//lib:defs.bzl", "my_macro")
+
+cc_library(
+    name = "foo",
+    deps = [":bar"],
+)
+my_macro(name = "baz")
+"#
+            .to_owned(),
+            &Dialect::Extended,
+        )
+        .unwrap();
+        let rules = HashSet::from(["cc_library".to_owned()]);
+
+        let tokens: Vec<_> = semantic_tokens(&ast, &rules)
+            .into_iter()
+            .map(|token| {
+                (
+                    token.delta_line,
+                    token.delta_start,
+                    token.length,
+                    token.token_type,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (2, 0, 10, FUNCTION),
+                (1, 4, 4, PROPERTY),
+                (1, 4, 4, PROPERTY),
+                (0, 8, 6, LABEL),
+                (2, 0, 8, MACRO),
+                (0, 9, 4, PROPERTY),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1561 @@
+//! The language server, which runs the server of `starlark_lsp` and extends it with the requests
+//! and notifications that [`LspContext`] has no hooks for, e.g. signature help.
+//!
+//! The `starlark_lsp` server runs on its own thread, and calls the [`BazelContext`] through a
+//! [`RemoteContext`], which runs each call on the main thread, since the context isn't
+//! thread-safe. The main thread passes messages between the client and the `starlark_lsp`
+//! server, and answers the requests that the latter doesn't support itself.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::anyhow;
+use crossbeam_channel::{Receiver, Sender};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidChangeWorkspaceFolders, DidCloseTextDocument,
+        DidOpenTextDocument, DidSaveTextDocument, LogMessage, Notification as LspNotification,
+        PublishDiagnostics, ShowMessage,
+    },
+    request::{
+        CodeActionRequest, Completion, DocumentHighlightRequest, DocumentLinkRequest,
+        DocumentLinkResolve, ExecuteCommand, FoldingRangeRequest, GotoDefinition,
+        GotoTypeDefinition, HoverRequest, Rename, Request as LspRequest, SelectionRangeRequest,
+        SemanticTokensFullRequest, SignatureHelpRequest,
+    },
+    CodeActionKind, CodeActionOptions, CodeActionParams, CodeActionProviderCapability,
+    CompletionItem, CompletionList, CompletionParams, CompletionResponse, CompletionTextEdit,
+    Diagnostic, DidChangeTextDocumentParams, DidOpenTextDocumentParams, DocumentLinkOptions,
+    ExecuteCommandOptions, ExecuteCommandParams, FoldingRangeProviderCapability, HoverParams,
+    InitializeParams, LogMessageParams, MessageType, OneOf, Position, PublishDiagnosticsParams,
+    Range, RenameParams, SelectionRangeProviderCapability, SemanticTokensFullOptions,
+    SemanticTokensLegend, SemanticTokensOptions, SemanticTokensServerCapabilities,
+    ServerCapabilities, ShowMessageParams, SignatureHelpOptions, SignatureHelpParams,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextDocumentSyncOptions, TextDocumentSyncSaveOptions, TextEdit,
+    TypeDefinitionProviderCapability, Url, WorkspaceFolder, WorkspaceFoldersServerCapabilities,
+    WorkspaceServerCapabilities,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use starlark::{codemap::ResolvedPos, docs::DocModule};
+use starlark_lsp::{
+    completion::{StringCompletionResult, StringCompletionType},
+    server::{LspContext, LspEvalResult, LspUrl, StringLiteralResult},
+};
+
+use crate::{
+    bazel::{
+        BazelContext, ResolveLoadError, ResolvedStringLiteral, StringCompletions,
+        ASSOCIATE_PATH_COMMAND, BUILD_COMMAND, CANONICALIZE_LABEL_COMMAND, REFRESH_COMMAND,
+    },
+    client::BazelClient,
+    completion::BazelCompletion,
+    file_type::FileType,
+    semantic_tokens,
+};
+
+/// A call of the [`BazelContext`] from the thread of the `starlark_lsp` server.
+type Job<Client> = Box<dyn FnOnce(&BazelContext<Client>) + Send>;
+
+/// The [`LspContext`] of the `starlark_lsp` server, which runs every call on the main thread.
+struct RemoteContext<Client> {
+    jobs: Sender<Job<Client>>,
+    /// The messages of errors the user can fix, which are shown if they happened while going
+    /// to a definition, see [`resolve_error_message()`].
+    resolve_errors: Sender<ShowMessageParams>,
+}
+
+impl<Client: BazelClient + 'static> RemoteContext<Client> {
+    /// Runs `f` with the context on the main thread, and waits for its result.
+    fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&BazelContext<Client>) -> R + Send + 'static,
+    ) -> R {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        // NOTE: Safe to `unwrap()` here, since the main thread handles jobs until the
+        // `starlark_lsp` server has stopped.
+        self.jobs
+            .send(Box::new(move |context| {
+                let _ = sender.send(f(context));
+            }))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+}
+
+impl<Client: BazelClient + 'static> LspContext for RemoteContext<Client> {
+    fn parse_file_with_contents(&self, uri: &LspUrl, content: String) -> LspEvalResult {
+        let uri = uri.clone();
+        self.call(move |context| context.parse_file_with_contents(&uri, content))
+    }
+
+    fn resolve_load(
+        &self,
+        path: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<LspUrl> {
+        let (path, current_file) = (path.to_owned(), current_file.clone());
+        let workspace_root = workspace_root.map(Path::to_owned);
+        self.call(move |context| {
+            context.resolve_load(&path, &current_file, workspace_root.as_deref())
+        })
+    }
+
+    fn render_as_load(
+        &self,
+        target: &LspUrl,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<String> {
+        let (target, current_file) = (target.clone(), current_file.clone());
+        let workspace_root = workspace_root.map(Path::to_owned);
+        self.call(move |context| {
+            context.render_as_load(&target, &current_file, workspace_root.as_deref())
+        })
+    }
+
+    fn resolve_string_literal(
+        &self,
+        literal: &str,
+        current_file: &LspUrl,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Option<StringLiteralResult>> {
+        let (literal, current_file) = (literal.to_owned(), current_file.clone());
+        let workspace_root = workspace_root.map(Path::to_owned);
+        // The location finder of the result isn't `Send`, so it's built on this thread from the
+        // location that the context found.
+        let result = self
+            .call(move |context| {
+                context.resolve_string_literal_location(
+                    &literal,
+                    &current_file,
+                    workspace_root.as_deref(),
+                )
+            })
+            .map(|result| result.map(ResolvedStringLiteral::into_result));
+        if let Some(message) = result.as_ref().err().and_then(resolve_error_message) {
+            // NOTE: Safe to ignore, since the main thread only stops receiving once the
+            // `starlark_lsp` server has stopped.
+            let _ = self.resolve_errors.send(message);
+        }
+        result
+    }
+
+    fn get_load_contents(&self, uri: &LspUrl) -> anyhow::Result<Option<String>> {
+        let uri = uri.clone();
+        self.call(move |context| context.get_load_contents(&uri))
+    }
+
+    fn get_environment(&self, uri: &LspUrl) -> DocModule {
+        let uri = uri.clone();
+        self.call(move |context| context.get_environment(&uri))
+    }
+
+    fn get_url_for_global_symbol(
+        &self,
+        current_file: &LspUrl,
+        symbol: &str,
+    ) -> anyhow::Result<Option<LspUrl>> {
+        let (current_file, symbol) = (current_file.clone(), symbol.to_owned());
+        self.call(move |context| context.get_url_for_global_symbol(&current_file, &symbol))
+    }
+
+    fn get_string_completion_options(
+        &self,
+        document_uri: &LspUrl,
+        kind: StringCompletionType,
+        current_value: &str,
+        workspace_root: Option<&Path>,
+    ) -> anyhow::Result<Vec<StringCompletionResult>> {
+        let (document_uri, current_value) = (document_uri.clone(), current_value.to_owned());
+        let workspace_root = workspace_root.map(Path::to_owned);
+        self.call(move |context| {
+            context.get_string_completion_options(
+                &document_uri,
+                kind,
+                &current_value,
+                workspace_root.as_deref(),
+            )
+        })
+    }
+}
+
+/// The message to show when a string literal can't be resolved, if the user can fix the
+/// error, e.g. a load from an unknown repository. Other errors are expected, e.g. for string
+/// literals that aren't labels, so they aren't shown.
+fn resolve_error_message(error: &anyhow::Error) -> Option<ShowMessageParams> {
+    match error.downcast_ref::<ResolveLoadError>()? {
+        error @ ResolveLoadError::UnknownRepository(..) => Some(ShowMessageParams {
+            typ: MessageType::WARNING,
+            message: error.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Runs the language server on a connection to the client until it exits.
+pub(crate) fn run<Client: BazelClient + 'static>(
+    connection: Connection,
+    context: BazelContext<Client>,
+) -> anyhow::Result<()> {
+    let (jobs_sender, jobs) = crossbeam_channel::unbounded::<Job<Client>>();
+    let (resolve_errors_sender, resolve_errors) = crossbeam_channel::unbounded();
+    let (starlark, starlark_connection) = Connection::memory();
+    let starlark_thread = thread::spawn(move || {
+        starlark_lsp::server::server_with_connection(
+            starlark_connection,
+            RemoteContext {
+                jobs: jobs_sender,
+                resolve_errors: resolve_errors_sender,
+            },
+        )
+    });
+
+    let mut server = Server {
+        context,
+        client: connection,
+        starlark,
+        queue: VecDeque::new(),
+        in_flight: None,
+        starlark_methods: HashSet::new(),
+        resolve_errors,
+        diagnostics: HashMap::new(),
+        workspace_folders: Vec::new(),
+    };
+    let client_messages = server.client.receiver.clone();
+    let starlark_messages = server.starlark.receiver.clone();
+    loop {
+        crossbeam_channel::select! {
+            recv(client_messages) -> message => match message {
+                Ok(message) => server.queue.push_back(message),
+                Err(_) => break,
+            },
+            recv(starlark_messages) -> message => match message {
+                Ok(message) => server.handle_starlark_message(message)?,
+                Err(_) => break,
+            },
+            recv(jobs) -> job => match job {
+                Ok(job) => job(&server.context),
+                Err(_) => break,
+            },
+        }
+        server.handle_queued_messages()?;
+    }
+
+    // Dropping the connection lets the `starlark_lsp` server stop if the client went away.
+    drop(server);
+    starlark_thread
+        .join()
+        .map_err(|_| anyhow!("The starlark_lsp server panicked"))?
+}
+
+/// A request that the `starlark_lsp` server is handling, see [`Server::in_flight`].
+enum InFlight {
+    Initialize,
+    /// A completion, whose result gets the given completions added, and the details of the
+    /// completions of string literals.
+    Completion(Vec<CompletionItem>),
+    /// A definition, after which the errors of resolving string literals are shown, see
+    /// [`resolve_error_message()`].
+    Definition,
+    Other,
+}
+
+struct Server<Client> {
+    context: BazelContext<Client>,
+    client: Connection,
+    /// The connection to the `starlark_lsp` server.
+    starlark: Connection,
+    /// Messages from the client that are handled once the `starlark_lsp` server has answered
+    /// the request in flight, so that all messages are handled in the order they were sent.
+    queue: VecDeque<Message>,
+    in_flight: Option<(RequestId, InFlight)>,
+    /// The methods of the requests that the `starlark_lsp` server answers, according to the
+    /// capabilities it announced.
+    starlark_methods: HashSet<&'static str>,
+    /// The errors of resolving string literals while answering the request in flight, see
+    /// [`RemoteContext::resolve_errors`].
+    resolve_errors: Receiver<ShowMessageParams>,
+    diagnostics: HashMap<Url, Diagnostics>,
+    /// The workspace folders opened in the client, see [`BazelContext::set_workspace_folders()`].
+    workspace_folders: Vec<PathBuf>,
+}
+
+/// The diagnostics of a file from different sources, which are published together.
+#[derive(Default)]
+struct Diagnostics {
+    /// The version of the document that the `starlark_lsp` server published diagnostics for.
+    version: Option<i32>,
+    /// The diagnostics of the `starlark_lsp` server, which parses documents as they change.
+    starlark: Vec<Diagnostic>,
+    /// The diagnostics of the document when it was last opened or saved, see
+    /// [`BazelContext::semantic_diagnostics()`].
+    semantic: Vec<Diagnostic>,
+    /// The errors and warnings of the last build, see [`BUILD_COMMAND`].
+    build: Vec<Diagnostic>,
+}
+
+/// The requests that the `starlark_lsp` server may answer, along with the capability that it
+/// announces for each of them.
+const STARLARK_REQUESTS: &[(&str, &str)] = &[
+    ("definitionProvider", "textDocument/definition"),
+    ("completionProvider", "textDocument/completion"),
+    ("hoverProvider", "textDocument/hover"),
+    ("documentSymbolProvider", "textDocument/documentSymbol"),
+    ("referencesProvider", "textDocument/references"),
+];
+
+/// The capabilities of the requests this server answers on its own, which are added to the
+/// capabilities of the `starlark_lsp` server.
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        // Like the `starlark_lsp` server, the full contents are synced on every change, but
+        // saved documents are also reported, see [`Diagnostics::semantic`].
+        text_document_sync: Some(TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::FULL),
+                save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                ..Default::default()
+            },
+        )),
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec!["(".to_owned(), ",".to_owned()]),
+            retrigger_characters: None,
+            work_done_progress_options: Default::default(),
+        }),
+        rename_provider: Some(OneOf::Left(true)),
+        workspace: Some(WorkspaceServerCapabilities {
+            workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                supported: Some(true),
+                change_notifications: Some(OneOf::Left(true)),
+            }),
+            file_operations: None,
+        }),
+        execute_command_provider: Some(ExecuteCommandOptions {
+            commands: vec![
+                CANONICALIZE_LABEL_COMMAND.to_owned(),
+                REFRESH_COMMAND.to_owned(),
+                ASSOCIATE_PATH_COMMAND.to_owned(),
+                BUILD_COMMAND.to_owned(),
+            ],
+            work_done_progress_options: Default::default(),
+        }),
+        code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+            code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+            work_done_progress_options: Default::default(),
+            resolve_provider: None,
+        })),
+        document_link_provider: Some(DocumentLinkOptions {
+            resolve_provider: Some(true),
+            work_done_progress_options: Default::default(),
+        }),
+        type_definition_provider: Some(TypeDefinitionProviderCapability::Simple(true)),
+        document_highlight_provider: Some(OneOf::Left(true)),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: semantic_tokens::TOKEN_TYPES.to_vec(),
+                    token_modifiers: Vec::new(),
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                range: None,
+                work_done_progress_options: Default::default(),
+            },
+        )),
+        selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        ..Default::default()
+    }
+}
+
+/// Parses the parameters of a request, if it is of type `R`.
+fn request_params<R: LspRequest>(request: &Request) -> Option<anyhow::Result<R::Params>> {
+    (request.method == R::METHOD)
+        .then(|| serde_json::from_value(request.params.clone()).map_err(anyhow::Error::from))
+}
+
+/// Parses the parameters of a notification, if it is of type `N`.
+fn notification_params<N: LspNotification>(
+    notification: &Notification,
+) -> Option<anyhow::Result<N::Params>> {
+    (notification.method == N::METHOD)
+        .then(|| serde_json::from_value(notification.params.clone()).map_err(anyhow::Error::from))
+}
+
+/// Converts a completion of the text on the line of `position` that starts at the completion's
+/// [`BazelCompletion::insert_text_offset`], so that the text it completes is replaced, even if
+/// clients don't consider it a word, e.g. `--config`.
+fn replacing_completion_item(completion: BazelCompletion, position: Position) -> CompletionItem {
+    let range = Range::new(
+        Position::new(position.line, completion.insert_text_offset as u32),
+        position,
+    );
+    let new_text = completion
+        .insert_text
+        .clone()
+        .unwrap_or_else(|| completion.value.clone());
+
+    CompletionItem {
+        text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, new_text))),
+        insert_text: None,
+        ..CompletionItem::from(completion)
+    }
+}
+
+/// Adds the details of the completions of string literals to the result of a completion request
+/// answered by the `starlark_lsp` server, along with a sort text that keeps them in the order
+/// they were offered in, e.g. the best matches first. If some completions were left out, the
+/// result is marked as incomplete, so that clients ask again as the user types.
+fn describe_string_completions(
+    result: &mut Value,
+    string_completions: &StringCompletions,
+) -> anyhow::Result<()> {
+    let completions = &string_completions.completions;
+    if completions.is_empty() && !string_completions.is_incomplete {
+        return Ok(());
+    }
+
+    let mut response = match serde_json::from_value::<Option<CompletionResponse>>(result.take())? {
+        Some(CompletionResponse::Array(items)) if string_completions.is_incomplete => {
+            CompletionResponse::List(CompletionList {
+                is_incomplete: true,
+                items,
+            })
+        }
+        Some(CompletionResponse::List(mut list)) => {
+            list.is_incomplete |= string_completions.is_incomplete;
+            CompletionResponse::List(list)
+        }
+        Some(response) => response,
+        None => return Ok(()),
+    };
+    let items = match &mut response {
+        CompletionResponse::Array(items) => items,
+        CompletionResponse::List(list) => &mut list.items,
+    };
+    for item in items {
+        if let Some(index) = completions
+            .iter()
+            .position(|completion| completion.value == item.label)
+        {
+            item.detail = item
+                .detail
+                .take()
+                .or_else(|| completions[index].detail.clone());
+            item.sort_text = Some(sort_text(index));
+        }
+    }
+
+    *result = serde_json::to_value(response)?;
+    Ok(())
+}
+
+/// The sort text of the completion at `index`, so that clients keep the order of completions
+/// rather than sorting them by their labels.
+fn sort_text(index: usize) -> String {
+    format!("{:05}", index)
+}
+
+/// Converts completions to completion items that are shown in the same order.
+fn sorted_completion_items(completions: Vec<BazelCompletion>) -> Vec<CompletionItem> {
+    completions
+        .into_iter()
+        .enumerate()
+        .map(|(index, completion)| CompletionItem {
+            sort_text: Some(sort_text(index)),
+            ..CompletionItem::from(completion)
+        })
+        .collect()
+}
+
+/// Adds completions to the result of a completion request answered by the `starlark_lsp`
+/// server, replacing its completions with the same labels.
+fn add_completions(result: &mut Value, completions: Vec<CompletionItem>) -> anyhow::Result<()> {
+    if completions.is_empty() {
+        return Ok(());
+    }
+
+    let mut response = serde_json::from_value::<Option<CompletionResponse>>(result.take())?
+        .unwrap_or_else(|| CompletionResponse::Array(Vec::new()));
+    let items = match &mut response {
+        CompletionResponse::Array(items) => items,
+        CompletionResponse::List(list) => &mut list.items,
+    };
+    items.retain(|item| {
+        !completions
+            .iter()
+            .any(|completion| completion.label == item.label)
+    });
+    items.extend(completions);
+
+    *result = serde_json::to_value(response)?;
+    Ok(())
+}
+
+/// Parses the argument of a command at `index`.
+fn command_argument<T: DeserializeOwned>(
+    params: &ExecuteCommandParams,
+    index: usize,
+) -> anyhow::Result<T> {
+    let argument = params
+        .arguments
+        .get(index)
+        .ok_or_else(|| anyhow!("`{}` is missing argument {}", params.command, index + 1))?;
+
+    Ok(serde_json::from_value(argument.clone())?)
+}
+
+fn lsp_url(url: Url) -> anyhow::Result<LspUrl> {
+    LspUrl::try_from(url).map_err(|error| anyhow!("{}", error))
+}
+
+impl<Client: BazelClient> Server<Client> {
+    fn send_to_client(&self, message: impl Into<Message>) -> anyhow::Result<()> {
+        Ok(self.client.sender.send(message.into())?)
+    }
+
+    fn send_to_starlark(&self, message: impl Into<Message>) -> anyhow::Result<()> {
+        Ok(self.starlark.sender.send(message.into())?)
+    }
+
+    /// Handles the queued messages from the client, until a request has to wait for the
+    /// `starlark_lsp` server.
+    fn handle_queued_messages(&mut self) -> anyhow::Result<()> {
+        while self.in_flight.is_none() {
+            match self.queue.pop_front() {
+                Some(Message::Request(request)) => self.handle_request(request)?,
+                Some(Message::Notification(notification)) => {
+                    self.handle_notification(notification)?
+                }
+                // The client only answers requests of the `starlark_lsp` server.
+                Some(Message::Response(response)) => self.send_to_starlark(response)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the context where the cursor of a request is, before it's forwarded to the
+    /// `starlark_lsp` server, see [`BazelContext::set_cursor()`].
+    fn set_cursor(&self, params: &TextDocumentPositionParams) {
+        if let Ok(uri) = lsp_url(params.text_document.uri.clone()) {
+            self.context.set_cursor(Some((uri, params.position)));
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) -> anyhow::Result<()> {
+        if request.method == "initialize" {
+            return self.forward_request(request, InFlight::Initialize);
+        }
+
+        let id = request.id.clone();
+        let result = match self.answer_request(&request) {
+            Some(result) => result,
+            None if request.method == "shutdown"
+                || self.starlark_methods.contains(request.method.as_str()) =>
+            {
+                self.context.set_cursor(None);
+                let in_flight = match request_params::<Completion>(&request) {
+                    Some(Ok(params)) => {
+                        // Drop stale string completions, in case `starlark_lsp` doesn't ask for
+                        // any this time.
+                        self.context.take_string_completions();
+                        self.set_cursor(&params.text_document_position);
+                        InFlight::Completion(self.rule_completions(params))
+                    }
+                    _ => match request_params::<GotoDefinition>(&request) {
+                        Some(Ok(params)) => {
+                            self.set_cursor(&params.text_document_position_params);
+                            InFlight::Definition
+                        }
+                        _ => InFlight::Other,
+                    },
+                };
+                return self.forward_request(request, in_flight);
+            }
+            None => {
+                return self.send_to_client(Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request `{}`", request.method),
+                ))
+            }
+        };
+        self.send_to_client(match result {
+            Ok(result) => Response::new_ok(id, result),
+            Err(error) => Response::new_err(id, ErrorCode::InternalError as i32, error.to_string()),
+        })
+    }
+
+    /// Answers a request on its own, rather than by the `starlark_lsp` server. Returns `None` to
+    /// pass the request on to the `starlark_lsp` server, if it supports it.
+    fn answer_request(&mut self, request: &Request) -> Option<anyhow::Result<Value>> {
+        let result = if let Some(params) = request_params::<SignatureHelpRequest>(request) {
+            params.and_then(|params| self.signature_help(params))
+        } else if let Some(params) = request_params::<HoverRequest>(request) {
+            params.and_then(|params| self.hover(params))
+        } else if let Some(params) = request_params::<Completion>(request) {
+            params.and_then(|params| self.completion(params))
+        } else if let Some(params) = request_params::<Rename>(request) {
+            params.and_then(|params| self.rename(params))
+        } else if let Some(params) = request_params::<FoldingRangeRequest>(request) {
+            params.and_then(|params| {
+                let uri = lsp_url(params.text_document.uri)?;
+                Ok(Some(serde_json::to_value(
+                    self.context.folding_ranges(&uri),
+                )?))
+            })
+        } else if let Some(params) = request_params::<SelectionRangeRequest>(request) {
+            params.and_then(|params| {
+                let uri = lsp_url(params.text_document.uri)?;
+                let ranges = self.context.selection_ranges(&uri, &params.positions);
+                Ok(Some(serde_json::to_value(ranges)?))
+            })
+        } else if let Some(params) = request_params::<SemanticTokensFullRequest>(request) {
+            params.and_then(|params| {
+                let uri = lsp_url(params.text_document.uri)?;
+                Ok(Some(serde_json::to_value(
+                    self.context.semantic_tokens(&uri),
+                )?))
+            })
+        } else if let Some(params) = request_params::<DocumentHighlightRequest>(request) {
+            params.and_then(|params| {
+                let position = params.text_document_position_params;
+                let uri = lsp_url(position.text_document.uri)?;
+                let highlights = self.context.document_highlights(&uri, position.position);
+                Ok(Some(serde_json::to_value(highlights)?))
+            })
+        } else if let Some(params) = request_params::<GotoTypeDefinition>(request) {
+            params.and_then(|params| {
+                let position = params.text_document_position_params;
+                let uri = lsp_url(position.text_document.uri)?;
+                let location = self
+                    .context
+                    .provider_type_definition(&uri, position.position);
+                Ok(Some(serde_json::to_value(location)?))
+            })
+        } else if let Some(params) = request_params::<DocumentLinkRequest>(request) {
+            params.and_then(|params| {
+                let uri = lsp_url(params.text_document.uri)?;
+                Ok(Some(serde_json::to_value(
+                    self.context.document_links(&uri),
+                )?))
+            })
+        } else if let Some(link) = request_params::<DocumentLinkResolve>(request) {
+            link.and_then(|link| {
+                let link = self.context.resolve_document_link(link);
+                Ok(Some(serde_json::to_value(link)?))
+            })
+        } else if let Some(params) = request_params::<CodeActionRequest>(request) {
+            params.and_then(|params| self.code_actions(params))
+        } else if let Some(params) = request_params::<ExecuteCommand>(request) {
+            params.and_then(|params| self.execute_command(params))
+        } else {
+            return None;
+        };
+
+        result.transpose()
+    }
+
+    fn signature_help(&self, params: SignatureHelpParams) -> anyhow::Result<Option<Value>> {
+        let position = params.text_document_position_params;
+        let uri = lsp_url(position.text_document.uri)?;
+
+        let signature_help = self.context.signature_help(&uri, position.position);
+        Ok(Some(serde_json::to_value(signature_help)?))
+    }
+
+    /// Answers hovers of labels and `select()`s, see [`BazelContext::hover()`], and leaves
+    /// other hovers to the `starlark_lsp` server.
+    fn hover(&self, params: HoverParams) -> anyhow::Result<Option<Value>> {
+        let position = params.text_document_position_params;
+        let uri = lsp_url(position.text_document.uri)?;
+
+        let hover = self.context.hover(&uri, position.position);
+        Ok(hover.map(serde_json::to_value).transpose()?)
+    }
+
+    fn rename(&self, params: RenameParams) -> anyhow::Result<Option<Value>> {
+        let position = params.text_document_position;
+        let uri = lsp_url(position.text_document.uri)?;
+
+        let edit = self
+            .context
+            .rename(&uri, position.position, &params.new_name)?;
+        Ok(Some(serde_json::to_value(edit)?))
+    }
+
+    /// Offers the code actions of a range in a document, which are only quick fixes.
+    fn code_actions(&self, params: CodeActionParams) -> anyhow::Result<Option<Value>> {
+        let uri = lsp_url(params.text_document.uri)?;
+
+        let mut actions = self
+            .context
+            .missing_load_code_actions(&uri, &params.context.diagnostics);
+        actions.extend(
+            self.context
+                .normalize_label_path_code_actions(&uri, params.range),
+        );
+        actions.extend(self.context.wrap_in_list_code_actions(&uri, params.range));
+        Ok(Some(serde_json::to_value(actions)?))
+    }
+
+    fn execute_command(&mut self, params: ExecuteCommandParams) -> anyhow::Result<Option<Value>> {
+        match params.command.as_str() {
+            CANONICALIZE_LABEL_COMMAND => {
+                let position: TextDocumentPositionParams = command_argument(&params, 0)?;
+                let uri = lsp_url(position.text_document.uri)?;
+                let label = self
+                    .context
+                    .label_at(&uri, position.position)
+                    .ok_or_else(|| anyhow!("There is no label under the cursor"))?;
+
+                let canonical = self.context.canonicalize_label(&label, &uri, None)?;
+                Ok(Some(Value::String(canonical)))
+            }
+            REFRESH_COMMAND => {
+                self.context.refresh();
+                let urls = self.diagnostics.keys().cloned().collect();
+                self.update_semantic_diagnostics(urls)?;
+                Ok(None)
+            }
+            ASSOCIATE_PATH_COMMAND => {
+                let uri = lsp_url(command_argument(&params, 0)?)?;
+                let path: PathBuf = command_argument(&params, 1)?;
+                self.context.associate_path(&uri, path);
+                Ok(None)
+            }
+            BUILD_COMMAND => {
+                self.build(command_argument(&params, 0)?)?;
+                Ok(None)
+            }
+            command => Err(anyhow!("Unknown command `{}`", command)),
+        }
+    }
+
+    /// Completes `.bazelrc` files, fields of providers, and members of values of known types,
+    /// and leaves other completions to the `starlark_lsp` server, which only completes
+    /// identifiers and string literals of Starlark files.
+    fn completion(&self, params: CompletionParams) -> anyhow::Result<Option<Value>> {
+        let position = params.text_document_position;
+        let uri = lsp_url(position.text_document.uri)?;
+        if FileType::from_lsp_url(&uri) == FileType::Bazelrc {
+            let completions = self.context.bazelrc_completions(
+                &uri,
+                ResolvedPos {
+                    line: position.position.line as usize,
+                    column: position.position.character as usize,
+                },
+            );
+            let items = completions
+                .into_iter()
+                .enumerate()
+                .map(|(index, completion)| CompletionItem {
+                    sort_text: Some(sort_text(index)),
+                    ..replacing_completion_item(completion, position.position)
+                })
+                .collect();
+            return Ok(Some(serde_json::to_value(CompletionResponse::Array(
+                items,
+            ))?));
+        }
+
+        let line_prefix = self
+            .context
+            .line_prefix(&uri, position.position)
+            .unwrap_or_default();
+
+        let mut completions = self.context.provider_field_completions(&uri, &line_prefix);
+        if completions.is_empty() {
+            completions = self.context.member_completions(&uri, &line_prefix);
+        }
+        if completions.is_empty() {
+            return Ok(None);
+        }
+
+        let items = sorted_completion_items(completions);
+        Ok(Some(serde_json::to_value(CompletionResponse::Array(
+            items,
+        ))?))
+    }
+
+    /// Completes the names of rules as snippets, see [`BazelContext::rule_completions()`],
+    /// which are added to the completions of the `starlark_lsp` server.
+    fn rule_completions(&self, params: CompletionParams) -> Vec<CompletionItem> {
+        let position = params.text_document_position;
+        let uri = match lsp_url(position.text_document.uri) {
+            Ok(uri) => uri,
+            Err(_) => return Vec::new(),
+        };
+
+        match self.context.line_prefix(&uri, position.position) {
+            Some(line_prefix) => self.context.rule_completions(&uri, &line_prefix),
+            None => Vec::new(),
+        }
+    }
+
+    fn forward_request(&mut self, request: Request, in_flight: InFlight) -> anyhow::Result<()> {
+        if let InFlight::Initialize = in_flight {
+            let params: InitializeParams = serde_json::from_value(request.params.clone())?;
+            self.initialize(params);
+        }
+
+        self.in_flight = Some((request.id.clone(), in_flight));
+        self.send_to_starlark(request)
+    }
+
+    /// Sets the workspace folders of the context from the `initialize` request, which falls
+    /// back to the root of the client if it doesn't support workspace folders.
+    fn initialize(&mut self, params: InitializeParams) {
+        #[allow(deprecated)]
+        let folders = match (params.workspace_folders, params.root_uri) {
+            (Some(folders), _) => folders.into_iter().map(|folder| folder.uri).collect(),
+            (None, Some(root_uri)) => vec![root_uri],
+            (None, None) => Vec::new(),
+        };
+
+        self.workspace_folders = folders
+            .into_iter()
+            .filter_map(|url| url.to_file_path().ok())
+            .collect();
+        self.context
+            .set_workspace_folders(self.workspace_folders.clone());
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> anyhow::Result<()> {
+        // The contents of documents are kept up to date here as well, since requests that are
+        // answered by this server may be handled before the `starlark_lsp` server has parsed
+        // the new contents.
+        if let Some(Ok(params)) = notification_params::<DidOpenTextDocument>(&notification) {
+            let DidOpenTextDocumentParams { text_document } = params;
+            if let Ok(uri) = lsp_url(text_document.uri.clone()) {
+                self.context.set_document(&uri, text_document.text);
+                // These are published along with the diagnostics of the `starlark_lsp` server
+                // once it has parsed the document.
+                self.diagnostics
+                    .entry(text_document.uri)
+                    .or_default()
+                    .semantic = self.context.semantic_diagnostics(&uri);
+            }
+        } else if let Some(Ok(params)) = notification_params::<DidChangeTextDocument>(&notification)
+        {
+            let DidChangeTextDocumentParams {
+                text_document,
+                content_changes,
+            } = params;
+            // The `starlark_lsp` server asks for the full contents on every change.
+            if let (Ok(uri), Some(change)) = (
+                lsp_url(text_document.uri),
+                content_changes.into_iter().last(),
+            ) {
+                self.context.set_document(&uri, change.text);
+            }
+        } else if let Some(Ok(params)) = notification_params::<DidCloseTextDocument>(&notification)
+        {
+            let url = params.text_document.uri;
+            if let Ok(uri) = lsp_url(url.clone()) {
+                self.context.close_document(&uri);
+            }
+            // The diagnostics of the last build are kept, since builds report files that aren't
+            // open as well.
+            if let Some(diagnostics) = self.diagnostics.get_mut(&url) {
+                diagnostics.version = None;
+                diagnostics.starlark.clear();
+                diagnostics.semantic.clear();
+                if diagnostics.build.is_empty() {
+                    self.diagnostics.remove(&url);
+                }
+            }
+            self.publish_diagnostics(url)?;
+        } else if let Some(Ok(params)) = notification_params::<DidSaveTextDocument>(&notification) {
+            if let Ok(uri) = lsp_url(params.text_document.uri.clone()) {
+                self.context.invalidate(&uri);
+            }
+            // The `starlark_lsp` server doesn't handle saved documents.
+            return self.update_semantic_diagnostics(vec![params.text_document.uri]);
+        } else if let Some(Ok(params)) =
+            notification_params::<DidChangeWorkspaceFolders>(&notification)
+        {
+            let paths = |folders: Vec<WorkspaceFolder>| -> Vec<PathBuf> {
+                folders
+                    .into_iter()
+                    .filter_map(|folder| folder.uri.to_file_path().ok())
+                    .collect()
+            };
+            let removed = paths(params.event.removed);
+            self.workspace_folders
+                .retain(|folder| !removed.contains(folder));
+            self.workspace_folders.extend(paths(params.event.added));
+            self.context
+                .set_workspace_folders(self.workspace_folders.clone());
+            // The `starlark_lsp` server doesn't support workspace folders.
+            return Ok(());
+        }
+
+        self.send_to_starlark(notification)
+    }
+
+    fn handle_starlark_message(&mut self, message: Message) -> anyhow::Result<()> {
+        match message {
+            Message::Response(mut response) => {
+                let in_flight = match self.in_flight.take() {
+                    Some((id, in_flight)) if id == response.id => Some(in_flight),
+                    in_flight => {
+                        self.in_flight = in_flight;
+                        None
+                    }
+                };
+                // Errors are only shown for definitions, rather than e.g. on every hover.
+                let resolve_errors: Vec<_> = self.resolve_errors.try_iter().collect();
+                match (in_flight, response.result.as_mut()) {
+                    (Some(InFlight::Initialize), Some(result)) => self.add_capabilities(result)?,
+                    (Some(InFlight::Definition), _) => {
+                        for message in resolve_errors {
+                            self.send_to_client(Notification::new(
+                                ShowMessage::METHOD.to_owned(),
+                                message,
+                            ))?;
+                        }
+                    }
+                    (Some(InFlight::Completion(completions)), Some(result)) => {
+                        describe_string_completions(
+                            result,
+                            &self.context.take_string_completions(),
+                        )?;
+                        add_completions(result, completions)?
+                    }
+                    _ => {}
+                }
+                self.send_to_client(response)
+            }
+            Message::Notification(notification) => {
+                match notification_params::<PublishDiagnostics>(&notification) {
+                    // The diagnostics of closed documents are cleared, which doesn't need an
+                    // entry, see [`Self::handle_notification()`].
+                    Some(Ok(params))
+                        if params.diagnostics.is_empty()
+                            && !self.diagnostics.contains_key(&params.uri) =>
+                    {
+                        self.send_to_client(notification)
+                    }
+                    Some(Ok(params)) => {
+                        let diagnostics = self.diagnostics.entry(params.uri.clone()).or_default();
+                        diagnostics.version = params.version;
+                        diagnostics.starlark = params.diagnostics;
+                        self.publish_diagnostics(params.uri)
+                    }
+                    _ => self.send_to_client(notification),
+                }
+            }
+            message => self.send_to_client(message),
+        }
+    }
+
+    /// Reports the semantic diagnostics of documents again, see [`Diagnostics::semantic`].
+    fn update_semantic_diagnostics(&mut self, urls: Vec<Url>) -> anyhow::Result<()> {
+        for url in urls {
+            let uri = match lsp_url(url.clone()) {
+                Ok(uri) => uri,
+                Err(_) => continue,
+            };
+            self.diagnostics.entry(url.clone()).or_default().semantic =
+                self.context.semantic_diagnostics(&uri);
+            self.publish_diagnostics(url)?;
+        }
+        Ok(())
+    }
+
+    /// Publishes the diagnostics of a file from all sources.
+    fn publish_diagnostics(&self, url: Url) -> anyhow::Result<()> {
+        let (version, diagnostics) = match self.diagnostics.get(&url) {
+            Some(diagnostics) => (
+                diagnostics.version,
+                diagnostics
+                    .starlark
+                    .iter()
+                    .chain(&diagnostics.semantic)
+                    .chain(&diagnostics.build)
+                    .cloned()
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+
+        self.send_to_client(Notification::new(
+            PublishDiagnostics::METHOD.to_owned(),
+            PublishDiagnosticsParams::new(url, diagnostics, version),
+        ))
+    }
+
+    /// Builds the target under the cursor, see [`BazelContext::build_target_at()`]. The result
+    /// is shown to the user, and the errors and warnings of the build replace those of the
+    /// previous build.
+    fn build(&mut self, position: TextDocumentPositionParams) -> anyhow::Result<()> {
+        let uri = lsp_url(position.text_document.uri)?;
+        let report = self.context.build_target_at(
+            &uri,
+            ResolvedPos {
+                line: position.position.line as usize,
+                column: position.position.character as usize,
+            },
+            None,
+        )?;
+
+        let mut build_diagnostics: HashMap<Url, Vec<Diagnostic>> = HashMap::new();
+        for (path, diagnostic) in report.diagnostics {
+            if let Ok(url) = Url::from_file_path(&path) {
+                build_diagnostics.entry(url).or_default().push(diagnostic);
+            }
+        }
+        let mut changed: Vec<Url> = self
+            .diagnostics
+            .iter_mut()
+            .filter(|(_, diagnostics)| !diagnostics.build.is_empty())
+            .map(|(url, diagnostics)| {
+                diagnostics.build.clear();
+                url.clone()
+            })
+            .collect();
+        for (url, diagnostics) in build_diagnostics {
+            self.diagnostics.entry(url.clone()).or_default().build = diagnostics;
+            changed.push(url);
+        }
+        changed.sort();
+        changed.dedup();
+        for url in changed {
+            self.publish_diagnostics(url)?;
+        }
+
+        self.send_to_client(Notification::new(
+            LogMessage::METHOD.to_owned(),
+            LogMessageParams {
+                typ: MessageType::LOG,
+                message: report.output,
+            },
+        ))?;
+        let (typ, message) = if report.success {
+            (MessageType::INFO, format!("Built `{}`", report.label))
+        } else {
+            (
+                MessageType::ERROR,
+                format!("Building `{}` failed", report.label),
+            )
+        };
+        self.send_to_client(Notification::new(
+            ShowMessage::METHOD.to_owned(),
+            ShowMessageParams { typ, message },
+        ))
+    }
+
+    /// Records the requests that the `starlark_lsp` server answers, from the capabilities in the
+    /// result of the `initialize` request, and adds the capabilities of this server to them.
+    fn add_capabilities(&mut self, result: &mut Value) -> anyhow::Result<()> {
+        let starlark_capabilities = result
+            .get_mut("capabilities")
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| anyhow!("The starlark_lsp server announced no capabilities"))?;
+
+        self.starlark_methods = STARLARK_REQUESTS
+            .iter()
+            .filter(|(capability, _)| {
+                starlark_capabilities
+                    .get(*capability)
+                    .map_or(false, |value| {
+                        !value.is_null() && *value != Value::Bool(false)
+                    })
+            })
+            .map(|(_, method)| *method)
+            .collect();
+
+        if let Value::Object(capabilities) = serde_json::to_value(capabilities())? {
+            starlark_capabilities.extend(capabilities);
+        }
+
+        // Fields and members are completed after a `.`, which `starlark_lsp` doesn't trigger
+        // completions for.
+        if let Some(Value::Object(completion)) = starlark_capabilities.get_mut("completionProvider")
+        {
+            let trigger_characters = completion
+                .entry("triggerCharacters")
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(trigger_characters) = trigger_characters {
+                trigger_characters.push(Value::String(".".to_owned()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use lsp_server::{Connection, Message, Notification, Request, RequestId};
+    use lsp_types::{
+        notification::{
+            DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument, Exit, Initialized,
+            Notification as LspNotification, PublishDiagnostics,
+        },
+        request::{
+            CodeActionRequest, Completion, ExecuteCommand, GotoDefinition, Initialize,
+            MonikerRequest, Rename, Request as LspRequest, Shutdown, SignatureHelpRequest,
+        },
+        CodeActionOrCommand, CodeActionParams, CompletionItem, CompletionList, CompletionParams,
+        CompletionResponse, CompletionTextEdit, Diagnostic, DidCloseTextDocumentParams,
+        DidOpenTextDocumentParams, DidSaveTextDocumentParams, ExecuteCommandParams,
+        GotoDefinitionParams, InitializeParams, InitializedParams, InsertTextFormat, MessageType,
+        MonikerParams, NumberOrString, Position, PublishDiagnosticsParams, Range, RenameParams,
+        SignatureHelpParams, TextDocumentIdentifier, TextDocumentItem, TextDocumentPositionParams,
+        TextEdit, Url,
+    };
+
+    use crate::{
+        bazel::CANONICALIZE_LABEL_COMMAND,
+        completion::{BazelCompletion, CompletionCategory},
+        label::Label,
+        test_fixture::TestFixture,
+    };
+
+    use super::{
+        describe_string_completions, resolve_error_message, run, ResolveLoadError,
+        StringCompletions,
+    };
+
+    /// A client talking to a server on a thread of its own.
+    struct TestClient {
+        connection: Connection,
+        next_id: i32,
+        server: Option<thread::JoinHandle<anyhow::Result<()>>>,
+    }
+
+    impl TestClient {
+        fn start(fixture: &'static str) -> anyhow::Result<TestClient> {
+            let (connection, server_connection) = Connection::memory();
+            let server = thread::spawn(move || {
+                let fixture = TestFixture::new(fixture)?;
+                run(server_connection, fixture.context()?)
+            });
+
+            let mut client = TestClient {
+                connection,
+                next_id: 0,
+                server: Some(server),
+            };
+            client.request::<Initialize>(InitializeParams::default())?;
+            client.notify::<Initialized>(InitializedParams {})?;
+            Ok(client)
+        }
+
+        fn request<R: LspRequest>(&mut self, params: R::Params) -> anyhow::Result<R::Result> {
+            self.next_id += 1;
+            let id = RequestId::from(self.next_id);
+            self.connection
+                .sender
+                .send(Request::new(id.clone(), R::METHOD.to_owned(), params).into())?;
+
+            loop {
+                if let Message::Response(response) = self.connection.receiver.recv()? {
+                    if response.id == id {
+                        return match response.error {
+                            Some(error) => Err(anyhow::anyhow!(error.message)),
+                            None => {
+                                Ok(serde_json::from_value(response.result.unwrap_or_default())?)
+                            }
+                        };
+                    }
+                }
+            }
+        }
+
+        fn notify<N: LspNotification>(&self, params: N::Params) -> anyhow::Result<()> {
+            Ok(self
+                .connection
+                .sender
+                .send(Notification::new(N::METHOD.to_owned(), params).into())?)
+        }
+
+        fn open(&self, url: &Url, text: &str) -> anyhow::Result<()> {
+            self.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem {
+                    uri: url.clone(),
+                    language_id: "starlark".to_owned(),
+                    version: 0,
+                    text: text.to_owned(),
+                },
+            })
+        }
+
+        /// Waits for the diagnostics of a document to be published.
+        fn published_diagnostics(&self, url: &Url) -> anyhow::Result<Vec<Diagnostic>> {
+            loop {
+                if let Message::Notification(notification) = self.connection.receiver.recv()? {
+                    if notification.method == PublishDiagnostics::METHOD {
+                        let params: PublishDiagnosticsParams =
+                            serde_json::from_value(notification.params)?;
+                        if params.uri == *url {
+                            return Ok(params.diagnostics);
+                        }
+                    }
+                }
+            }
+        }
+
+        fn stop(mut self) -> anyhow::Result<()> {
+            self.request::<Shutdown>(())?;
+            self.notify::<Exit>(())?;
+            // NOTE: Safe to `unwrap()` here, since the server is only taken once.
+            self.server.take().unwrap().join().unwrap()
+        }
+    }
+
+    fn position_params(url: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: url.clone() },
+            position: Position::new(line, character),
+        }
+    }
+
+    #[test]
+    fn forwards_requests_to_starlark_lsp() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "x = 1\ny = x")?;
+        let definition = client.request::<GotoDefinition>(GotoDefinitionParams {
+            text_document_position_params: position_params(&url, 1, 4),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        })?;
+        assert!(definition.is_some());
+
+        // Requests that neither server supports are rejected.
+        assert!(client
+            .request::<MonikerRequest>(MonikerParams {
+                text_document_position_params: position_params(&url, 1, 4),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .is_err());
+
+        client.stop()
+    }
+
+    #[test]
+    fn answers_signature_help() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "cc_library(name = 'foo', srcs = [])")?;
+        let signature_help = client
+            .request::<SignatureHelpRequest>(SignatureHelpParams {
+                context: None,
+                text_document_position_params: position_params(&url, 0, 34),
+                work_done_progress_params: Default::default(),
+            })?
+            .unwrap();
+        assert!(signature_help.signatures[0]
+            .label
+            .starts_with("cc_library("));
+
+        client.stop()
+    }
+
+    fn completion_items(
+        client: &mut TestClient,
+        url: &Url,
+        line: u32,
+        character: u32,
+    ) -> anyhow::Result<Vec<CompletionItem>> {
+        let completions = client.request::<Completion>(CompletionParams {
+            text_document_position: position_params(url, line, character),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: None,
+        })?;
+        Ok(match completions {
+            Some(CompletionResponse::Array(items)) => items,
+            Some(CompletionResponse::List(list)) => list.items,
+            None => Vec::new(),
+        })
+    }
+
+    #[test]
+    fn renames_symbols() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("foo.bzl")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "def foo():\n    pass\n\nfoo()")?;
+        let edit = client
+            .request::<Rename>(RenameParams {
+                text_document_position: position_params(&url, 3, 1),
+                new_name: "bar".to_owned(),
+                work_done_progress_params: Default::default(),
+            })?
+            .unwrap();
+        assert_eq!(edit.changes.unwrap()[&url].len(), 2);
+
+        client.stop()
+    }
+
+    #[test]
+    fn offers_code_actions() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("foo").join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, r#"cc_library(name = "foo", deps = "//foo")"#)?;
+        let position = Position::new(0, 34);
+        let actions = client
+            .request::<CodeActionRequest>(CodeActionParams {
+                text_document: TextDocumentIdentifier { uri: url.clone() },
+                range: Range::new(position, position),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })?
+            .unwrap();
+        let titles: Vec<_> = actions
+            .into_iter()
+            .map(|action| match action {
+                CodeActionOrCommand::CodeAction(action) => action.title,
+                CodeActionOrCommand::Command(command) => command.title,
+            })
+            .collect();
+        assert_eq!(titles, vec!["Wrap `deps` in a list"]);
+
+        client.stop()
+    }
+
+    #[test]
+    fn canonicalizes_labels() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "cc_binary(name = 'bin', deps = [':main'])")?;
+        let canonical = client.request::<ExecuteCommand>(ExecuteCommandParams {
+            command: CANONICALIZE_LABEL_COMMAND.to_owned(),
+            arguments: vec![serde_json::to_value(position_params(&url, 0, 35))?],
+            work_done_progress_params: Default::default(),
+        })?;
+        assert_eq!(canonical, Some(serde_json::json!("@@//:main")));
+
+        client.stop()
+    }
+
+    #[test]
+    fn shows_resolve_errors_the_user_can_fix() -> anyhow::Result<()> {
+        let unknown_repository = anyhow::Error::from(ResolveLoadError::UnknownRepository(
+            Label::parse("@rules_rutt//rust:defs.bzl")?,
+            "rules_rutt".to_owned(),
+            true,
+        ));
+        let message = resolve_error_message(&unknown_repository).unwrap();
+        assert_eq!(message.typ, MessageType::WARNING);
+        assert!(message.message.contains("`@rules_rutt`"));
+        assert!(message.message.contains("bazel mod deps"));
+
+        // Other errors are expected, e.g. for literals that aren't labels.
+        let missing_file = ResolveLoadError::TargetNotFound("foo.bzl".to_owned()).into();
+        assert!(resolve_error_message(&missing_file).is_none());
+        assert!(resolve_error_message(&anyhow::anyhow!("Not a label")).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn describes_and_sorts_string_completions() -> anyhow::Result<()> {
+        let completion = |value: &str, detail: Option<&str>| BazelCompletion {
+            category: CompletionCategory::File,
+            value: value.to_owned(),
+            insert_text: None,
+            insert_text_offset: 0,
+            detail: detail.map(str::to_owned),
+        };
+        let item = |label: &str| CompletionItem {
+            label: label.to_owned(),
+            ..Default::default()
+        };
+
+        let mut result = serde_json::to_value(vec![item("b"), item("a"), item("other")])?;
+        describe_string_completions(
+            &mut result,
+            &StringCompletions {
+                completions: vec![completion("a", Some("`cc_library`")), completion("b", None)],
+                is_incomplete: false,
+            },
+        )?;
+        let items: Vec<CompletionItem> = serde_json::from_value(result.clone())?;
+        assert_eq!(
+            items
+                .iter()
+                .map(|item| (
+                    item.label.as_str(),
+                    item.detail.as_deref(),
+                    item.sort_text.as_deref()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("b", None, Some("00001")),
+                ("a", Some("`cc_library`"), Some("00000")),
+                ("other", None, None),
+            ]
+        );
+
+        // Completions that were left out make the result incomplete.
+        describe_string_completions(
+            &mut result,
+            &StringCompletions {
+                completions: vec![completion("a", None)],
+                is_incomplete: true,
+            },
+        )?;
+        let list: CompletionList = serde_json::from_value(result)?;
+        assert!(list.is_incomplete);
+        assert_eq!(list.items.len(), 3);
+
+        Ok(())
+    }
+
+    /// Returns the labels of the completions at a position.
+    fn completion_labels(
+        client: &mut TestClient,
+        url: &Url,
+        line: u32,
+        character: u32,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(completion_items(client, url, line, character)?
+            .into_iter()
+            .map(|item| item.label)
+            .collect())
+    }
+
+    #[test]
+    fn completes_provider_fields() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("foo.bzl")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "def _impl(ctx):\n    cc_info = dep[CcInfo].link")?;
+        assert_eq!(
+            completion_labels(&mut client, &url, 1, 30)?,
+            vec!["linking_context"]
+        );
+
+        client.stop()
+    }
+
+    #[test]
+    fn completes_members_of_known_types() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("foo.bzl")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "def _impl(ctx):\n    ctx.actions.")?;
+        assert!(completion_labels(&mut client, &url, 1, 16)?.contains(&"run".to_owned()));
+
+        client.stop()
+    }
+
+    #[test]
+    fn completes_bazelrc_options() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join(".bazelrc")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "build --keep_go")?;
+        let completions = completion_items(&mut client, &url, 0, 15)?;
+        assert_eq!(
+            completions
+                .iter()
+                .map(|item| item.label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["--keep_going"]
+        );
+        assert_eq!(
+            completions[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(Position::new(0, 6), Position::new(0, 15)),
+                "--keep_going".to_owned()
+            )))
+        );
+
+        client.stop()
+    }
+
+    #[test]
+    fn completes_rules_as_snippets() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        client.open(&url, "cc_")?;
+        let completions = completion_items(&mut client, &url, 0, 3)?;
+        let cc_library: Vec<_> = completions
+            .iter()
+            .filter(|item| item.label == "cc_library")
+            .collect();
+        // The snippet replaces the completion of the identifier.
+        assert_eq!(cc_library.len(), 1);
+        assert_eq!(
+            cc_library[0].insert_text_format,
+            Some(InsertTextFormat::SNIPPET)
+        );
+
+        client.stop()
+    }
+
+    #[test]
+    fn reports_semantic_diagnostics_of_opened_and_saved_documents() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let mut client = TestClient::start("simple")?;
+
+        let unknown_load_symbols = |diagnostics: Vec<Diagnostic>| {
+            diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.code == Some(NumberOrString::String("load-symbol-not-found".into()))
+                })
+                .count()
+        };
+
+        client.open(&url, "load('//lib:defs.bzl', 'does_not_exist')")?;
+        assert_eq!(unknown_load_symbols(client.published_diagnostics(&url)?), 1);
+
+        client.notify::<DidSaveTextDocument>(DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: url.clone() },
+            text: None,
+        })?;
+        assert_eq!(unknown_load_symbols(client.published_diagnostics(&url)?), 1);
+
+        client.stop()
+    }
+
+    #[test]
+    fn clears_diagnostics_of_closed_documents() -> anyhow::Result<()> {
+        let fixture = TestFixture::new("simple")?;
+        let url = Url::from_file_path(fixture.workspace_root().join("BUILD")).unwrap();
+        let client = TestClient::start("simple")?;
+
+        client.open(&url, "load('//lib:defs.bzl', 'does_not_exist')")?;
+        assert!(!client.published_diagnostics(&url)?.is_empty());
+
+        client.notify::<DidCloseTextDocument>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: url.clone() },
+        })?;
+        assert_eq!(client.published_diagnostics(&url)?, Vec::new());
+
+        client.stop()
+    }
+}
@@ -0,0 +1,262 @@
+//! Signature help for calls of rules and functions.
+
+use lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel, SignatureHelp,
+    SignatureInformation,
+};
+use starlark::{
+    docs::{DocFunction, DocParam, DocParams, DocString, DocStringKind},
+    syntax::AstModule,
+    typing::Ty,
+};
+use starlark_syntax::syntax::{
+    ast::{AstLiteral, AstNoPayload, DefP, ExprP, ParameterP, StmtP},
+    module::AstModuleFields,
+};
+
+use crate::ast::ActiveCall;
+
+/// The parameters of a function in the order they are declared, along with how they can be
+/// passed.
+fn ordered_params(params: &DocParams) -> Vec<(&DocParam, &'static str)> {
+    params
+        .pos_only
+        .iter()
+        .chain(params.pos_or_named.iter())
+        .map(|param| (param, ""))
+        .chain(params.args.iter().map(|param| (param, "*")))
+        .chain(params.named_only.iter().map(|param| (param, "")))
+        .chain(params.kwargs.iter().map(|param| (param, "**")))
+        .collect()
+}
+
+/// Finds the index of the parameter an argument is passed to, in the order of
+/// [`ordered_params()`].
+fn active_parameter(params: &DocParams, call: &ActiveCall) -> Option<usize> {
+    let ordered = ordered_params(params);
+
+    if let Some(name) = call.argument_name {
+        return ordered
+            .iter()
+            .position(|(param, prefix)| prefix.is_empty() && param.name == name)
+            .or_else(|| ordered.iter().position(|(_, prefix)| *prefix == "**"));
+    }
+
+    let positional = params.pos_only.len() + params.pos_or_named.len();
+    if call.argument_index < positional {
+        Some(call.argument_index)
+    } else if params.args.is_some() {
+        Some(positional)
+    } else {
+        None
+    }
+}
+
+fn render_docs(docs: &DocString) -> String {
+    match &docs.details {
+        Some(details) => format!("{}\n\n{}", docs.summary, details),
+        None => docs.summary.clone(),
+    }
+}
+
+/// Builds the signature help for a call of a function with the given documentation.
+pub(crate) fn signature_help(function: &DocFunction, call: &ActiveCall) -> SignatureHelp {
+    let parameters: Vec<ParameterInformation> = ordered_params(&function.params)
+        .into_iter()
+        .map(|(param, prefix)| ParameterInformation {
+            label: ParameterLabel::Simple(match &param.default_value {
+                Some(default_value) => format!("{}{}={}", prefix, param.name, default_value),
+                None => format!("{}{}", prefix, param.name),
+            }),
+            documentation: param.docs.as_ref().map(|docs| {
+                Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: render_docs(docs),
+                })
+            }),
+        })
+        .collect();
+
+    let label = format!(
+        "{}({})",
+        call.function,
+        parameters
+            .iter()
+            .map(|parameter| match &parameter.label {
+                ParameterLabel::Simple(label) => label.as_str(),
+                ParameterLabel::LabelOffsets(_) => "",
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let active_parameter = active_parameter(&function.params, call).map(|index| index as u32);
+
+    SignatureHelp {
+        signatures: vec![SignatureInformation {
+            label,
+            documentation: function.docs.as_ref().map(|docs| {
+                Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: render_docs(docs),
+                })
+            }),
+            parameters: Some(parameters),
+            active_parameter,
+        }],
+        active_signature: Some(0),
+        active_parameter,
+    }
+}
+
+/// Creates the documentation of a function defined in Starlark, from its parameters and
+/// docstring.
+pub(crate) fn def_to_doc_function(ast: &AstModule, def: &DefP<AstNoPayload>) -> DocFunction {
+    let mut params = DocParams::default();
+    // Parameters after `*` or `*args` can only be passed by name.
+    let mut named_only = false;
+
+    for param in &def.params {
+        let (name, default_value) = match &param.node {
+            ParameterP::Normal(name, _, default_value) => (
+                &name.node.ident,
+                default_value
+                    .as_ref()
+                    .map(|value| ast.codemap().source_span(value.span).to_owned()),
+            ),
+            ParameterP::Args(name, _) => {
+                params.args = Some(doc_param(&name.node.ident, None));
+                named_only = true;
+                continue;
+            }
+            ParameterP::KwArgs(name, _) => {
+                params.kwargs = Some(doc_param(&name.node.ident, None));
+                continue;
+            }
+            ParameterP::NoArgs => {
+                named_only = true;
+                continue;
+            }
+            ParameterP::Slash => {
+                params.pos_only.append(&mut params.pos_or_named);
+                continue;
+            }
+        };
+
+        let param = doc_param(name, default_value);
+        if named_only {
+            params.named_only.push(param);
+        } else {
+            params.pos_or_named.push(param);
+        }
+    }
+
+    DocFunction {
+        docs: docstring(def)
+            .and_then(|docstring| DocString::from_docstring(DocStringKind::Starlark, docstring)),
+        params,
+        ..Default::default()
+    }
+}
+
+fn doc_param(name: &str, default_value: Option<String>) -> DocParam {
+    DocParam {
+        name: name.to_owned(),
+        docs: None,
+        typ: Ty::any(),
+        default_value,
+    }
+}
+
+/// Returns the docstring of a function, i.e. a string literal that is its first statement.
+fn docstring(def: &DefP<AstNoPayload>) -> Option<&str> {
+    let first = match &def.body.node {
+        StmtP::Statements(statements) => statements.first()?,
+        _ => &def.body,
+    };
+
+    match &first.node {
+        StmtP::Expression(expr) => match &expr.node {
+            ExprP::Literal(AstLiteral::String(string)) => Some(&string.node),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::ParameterLabel;
+    use starlark::syntax::{AstModule, Dialect};
+
+    use super::{def_to_doc_function, signature_help};
+    use crate::ast::{self, ActiveCall};
+
+    fn signature(source: &str, call: ActiveCall) -> (String, Vec<String>, Option<u32>) {
+        let ast = AstModule::parse("defs.bzl", source.to_owned(), &Dialect::Extended).unwrap();
+        let function = def_to_doc_function(&ast, ast::find_def(&ast, call.function).unwrap());
+
+        let help = signature_help(&function, &call);
+        let signature = &help.signatures[0];
+        let parameters = signature
+            .parameters
+            .iter()
+            .flatten()
+            .map(|parameter| match &parameter.label {
+                ParameterLabel::Simple(label) => label.clone(),
+                ParameterLabel::LabelOffsets(_) => panic!(),
+            })
+            .collect();
+
+        (signature.label.clone(), parameters, help.active_parameter)
+    }
+
+    const SOURCE: &str = r#"
+def my_macro(name, srcs = [], *args, visibility = None, **kwargs):
+    """Does something."""
+    pass
+"#;
+
+    #[test]
+    fn test_signature_of_def() {
+        let (label, parameters, _) = signature(
+            SOURCE,
+            ActiveCall {
+                function: "my_macro",
+                argument_index: 0,
+                argument_name: None,
+            },
+        );
+
+        assert_eq!(
+            label,
+            "my_macro(name, srcs=[], *args, visibility=None, **kwargs)"
+        );
+        assert_eq!(
+            parameters,
+            vec!["name", "srcs=[]", "*args", "visibility=None", "**kwargs"]
+        );
+    }
+
+    #[test]
+    fn test_active_parameter() {
+        let active = |argument_index, argument_name| {
+            signature(
+                SOURCE,
+                ActiveCall {
+                    function: "my_macro",
+                    argument_index,
+                    argument_name,
+                },
+            )
+            .2
+        };
+
+        assert_eq!(active(1, None), Some(1));
+        // Extra positional arguments go to `*args`.
+        assert_eq!(active(5, None), Some(2));
+        assert_eq!(active(0, Some("visibility")), Some(3));
+        // Unknown named arguments go to `**kwargs`.
+        assert_eq!(active(0, Some("tags")), Some(4));
+    }
+}
@@ -1,38 +1,90 @@
 use std::{
     collections::HashMap,
-    fs,
+    env, fs, io,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use anyhow::anyhow;
+use prost::Message;
 
 use crate::{
-    bazel::BazelContext,
-    client::{BazelInfo, MockBazel, ProfilingClient},
+    bazel::{BazelContext, ContextOptions},
+    builtin::BuildLanguage,
+    client::{BazelInfo, BuildOutput, MockBazel, ProfilingClient},
 };
 
+/// Distinguishes the copies made by [`TestFixture::copy()`] within a test run.
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+
 pub struct TestFixture {
     path: PathBuf,
+    /// Whether `path` is a copy of the fixture, which is removed once the test is done.
+    copied: bool,
 }
 
 impl TestFixture {
     pub fn new(name: &str) -> anyhow::Result<TestFixture> {
         let fixture = TestFixture {
             path: fs::canonicalize(PathBuf::from(".").join("fixtures").join(name))?,
+            copied: false,
+        };
+
+        fixture.write_do_not_build_here()?;
+
+        Ok(fixture)
+    }
+
+    /// Copies a fixture to a temporary directory, for tests that modify its files. Tests run
+    /// in parallel, so changes to the checked-in fixture would be seen by other tests.
+    pub fn copy(name: &str) -> anyhow::Result<TestFixture> {
+        let path = env::temp_dir().join(format!(
+            "bazel-lsp-fixture-{}-{}-{}",
+            name,
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        copy_dir(&PathBuf::from(".").join("fixtures").join(name), &path)?;
+
+        let fixture = TestFixture {
+            path: fs::canonicalize(path)?,
+            copied: true,
         };
 
+        fixture.write_do_not_build_here()?;
+
+        Ok(fixture)
+    }
+
+    fn write_do_not_build_here(&self) -> anyhow::Result<()> {
         fs::write(
-            fixture.output_base().join("DO_NOT_BUILD_HERE"),
-            path_to_string(fixture.workspace_root())?,
+            self.output_base().join("DO_NOT_BUILD_HERE"),
+            path_to_string(self.workspace_root())?,
         )?;
 
-        Ok(fixture)
+        Ok(())
     }
 
     pub fn output_base(&self) -> PathBuf {
         self.path.join("output_base")
     }
 
+    pub fn install_base(&self) -> PathBuf {
+        self.path.join("install_base")
+    }
+
+    /// Where generated files are output to. This doesn't exist in the fixtures, so tests
+    /// create the files they need in a copy of the fixture, see [`TestFixture::copy()`].
+    pub fn bazel_bin(&self) -> PathBuf {
+        self.output_base()
+            .join("execroot")
+            .join("root")
+            .join("bazel-out")
+            .join("k8-fastbuild")
+            .join("bin")
+    }
+
     pub fn workspace_root(&self) -> PathBuf {
         self.path.join("root")
     }
@@ -54,16 +106,35 @@ impl TestFixture {
                         self.output_base().join("execroot").join("root"),
                     )?,
                     workspace: path_to_string(self.workspace_root())?,
+                    release: None,
+                    install_base: Some(path_to_string(self.install_base())?),
+                    bazel_bin: Some(path_to_string(self.bazel_bin())?),
                 },
                 queries: HashMap::new(),
+                partial_queries: HashMap::new(),
+                target_definitions: HashMap::new(),
+                configured_target_definitions: HashMap::new(),
+                mod_deps: None,
                 repo_mappings: HashMap::new(),
+                builds: HashMap::new(),
+                build_language: None,
             },
+            options: ContextOptions::default(),
         })
     }
 }
 
+impl Drop for TestFixture {
+    fn drop(&mut self) {
+        if self.copied {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
 pub(crate) struct ContextBuilder {
     client: MockBazel,
+    options: ContextOptions,
 }
 
 impl ContextBuilder {
@@ -73,6 +144,66 @@ impl ContextBuilder {
         self
     }
 
+    pub(crate) fn partial_query(mut self, query: &str, result: &str) -> Self {
+        self.client
+            .partial_queries
+            .insert(query.into(), result.into());
+
+        self
+    }
+
+    pub(crate) fn query_build(mut self, label: &str, result: &str) -> Self {
+        self.client
+            .target_definitions
+            .insert(label.into(), result.into());
+
+        self
+    }
+
+    pub(crate) fn cquery_build(mut self, label: &str, result: &str) -> Self {
+        self.client
+            .configured_target_definitions
+            .insert(label.into(), result.into());
+
+        self
+    }
+
+    pub(crate) fn build_output(mut self, label: &str, success: bool, stderr: &str) -> Self {
+        self.client.builds.insert(
+            label.into(),
+            BuildOutput {
+                success,
+                stderr: stderr.into(),
+            },
+        );
+
+        self
+    }
+
+    pub(crate) fn mod_deps_json(mut self, deps: serde_json::Value) -> Self {
+        self.client.mod_deps = Some(deps.to_string());
+
+        self
+    }
+
+    pub(crate) fn build_language(mut self, language: BuildLanguage) -> Self {
+        self.client.build_language = Some(language.encode_to_vec());
+
+        self
+    }
+
+    pub(crate) fn release(mut self, release: &str) -> Self {
+        self.client.info.release = Some(release.into());
+
+        self
+    }
+
+    pub(crate) fn options(mut self, options: ContextOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
     pub(crate) fn repo_mapping_json(
         mut self,
         repo: &str,
@@ -86,13 +217,25 @@ impl ContextBuilder {
     }
 
     pub(crate) fn build(self) -> anyhow::Result<BazelContext<ProfilingClient<MockBazel>>> {
-        BazelContext::new(
-            ProfilingClient::new(self.client),
-            None,
-        )
+        BazelContext::new(ProfilingClient::new(self.client), None, self.options)
     }
 }
 
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
 fn path_to_string<P: AsRef<Path>>(path: P) -> anyhow::Result<String> {
     Ok(path
         .as_ref()
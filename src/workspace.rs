@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     io,
     path::{Path, PathBuf},
 };
@@ -7,18 +8,90 @@ use std::{
 use ring::digest;
 use starlark_lsp::server::LspUrl;
 
-use crate::client::BazelInfo;
+use crate::{bazelrc, client::BazelInfo, exports};
+
+/// A version of Bazel, e.g. `7.1.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BazelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl BazelVersion {
+    /// Parses a version as reported by `bazel info release` (e.g. `release 7.1.0`), or as
+    /// written in `.bazelversion` (e.g. `7.1.0`, `8.0.0rc1` or `7.x`). Missing or wildcard
+    /// components are treated as 0.
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let version = version.strip_prefix("release ").unwrap_or(version);
+        // Forks of Bazel are written as `<fork>/<version>` in `.bazelversion`.
+        let version = version.rsplit('/').next()?;
+
+        let mut components = version.split('.').map(|component| {
+            let digits: String = component
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse::<u32>().ok()
+        });
+
+        Some(Self {
+            major: components.next()??,
+            minor: components.next().flatten().unwrap_or(0),
+            patch: components.next().flatten().unwrap_or(0),
+        })
+    }
+
+    /// Reads the version from the `.bazelversion` file in a workspace root.
+    pub fn from_bazelversion_file(workspace_root: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(workspace_root.join(".bazelversion")).ok()?;
+
+        Self::parse(contents.lines().next()?)
+    }
+}
 
 #[derive(Debug)]
 pub struct BazelWorkspace {
     pub root: PathBuf,
     /// The output base to use for querying. This allows queries to not
-    /// be blocked by concurrent builds.
+    /// be blocked by concurrent builds. Each workspace has its own, in the directory given to
+    /// [`BazelWorkspace::from_bazel_info()`]. If distinct output bases aren't used, this is the
+    /// output base configured in the workspace's `.bazelrc`, if any.
     pub query_output_base: Option<PathBuf>,
     pub workspace_name: Option<String>,
     pub external_output_base: PathBuf,
+    /// Repositories whose sources are in a local directory, keyed by their module or
+    /// repository name. See [`BazelWorkspace::get_repository_path()`].
+    pub local_repositories: HashMap<String, PathBuf>,
+    /// The version of Bazel used by the workspace, from `.bazelversion` or `bazel info`.
+    pub bazel_version: Option<BazelVersion>,
+    /// The sources of `@bazel_tools` embedded in Bazel, which are used until the repository is
+    /// added to the output base.
+    pub embedded_tools: Option<PathBuf>,
+    /// The repositories declared in the `WORKSPACE` file, which may not have been fetched yet.
+    pub declared_repositories: Vec<String>,
+    /// Repositories and modules that are pointed to a local directory with
+    /// `--override_repository` or `--override_module` in the `.bazelrc`, keyed by their name.
+    /// These take precedence over all other locations of a repository.
+    pub repository_overrides: HashMap<String, PathBuf>,
+    /// Options from the `.bazelrc` that change the results of queries, e.g. `--noimplicit_deps`.
+    /// These are passed to every query, see [`bazelrc::query_flags()`].
+    pub query_flags: Vec<String>,
+    /// Directories relative to the root that Bazel ignores, from the `.bazelignore` file.
+    pub ignored_directories: Vec<PathBuf>,
+    /// Whether external repositories are managed by Bzlmod rather than the `WORKSPACE` file.
+    /// Only Bzlmod has repo mappings, while apparent repository names in `WORKSPACE` mode are
+    /// the canonical names. See [`is_bzlmod_enabled()`].
+    pub bzlmod_enabled: bool,
+    /// The directory generated files are output to, e.g.
+    /// `<execution root>/bazel-out/k8-fastbuild/bin`. See [`BazelWorkspace::generated_path()`].
+    pub bazel_bin: Option<PathBuf>,
 }
 
+/// Repositories that are available in every workspace, without being declared.
+pub const BUILTIN_REPOSITORY_NAMES: [&str; 2] = ["bazel_tools", "platforms"];
+
 const DEFAULT_WORKSPACE_NAMES: [&'static str; 2] = ["__main__", "_main"];
 
 fn is_default_workspace_name(name: &str) -> bool {
@@ -32,8 +105,41 @@ impl BazelWorkspace {
         info: BazelInfo,
         query_output_base: Option<P>,
     ) -> io::Result<Self> {
+        let root = PathBuf::from(info.workspace);
+        let startup_output_base = bazelrc::startup_output_base(&root);
+        let query_flags = bazelrc::query_flags(&root);
+        let repository_overrides = bazelrc::repository_overrides(&root);
+        let ignored_directories = exports::read_bazelignore(&root);
+        let bazel_version = BazelVersion::from_bazelversion_file(&root).or_else(|| {
+            info.release
+                .as_deref()
+                .and_then(|release| BazelVersion::parse(release))
+        });
+        let bzlmod_enabled = is_bzlmod_enabled(&root, bazel_version);
+        let query_output_base = match query_output_base {
+            // Bazel can't share an output base between workspaces, so each workspace gets a
+            // distinct query output base in the given directory, keyed by its root. Workspaces
+            // that configure their own output base are keyed by it instead, so that they get
+            // a distinct query output base as well.
+            Some(output_base) => {
+                let hash = digest::digest(
+                    &digest::SHA256,
+                    startup_output_base
+                        .as_deref()
+                        .unwrap_or(&root)
+                        .as_os_str()
+                        .as_encoded_bytes(),
+                );
+                let hash_hex = hex::encode(&hash);
+                Some(output_base.as_ref().join(hash_hex))
+            }
+            None => startup_output_base,
+        };
+
         Ok(Self {
-            root: PathBuf::from(info.workspace),
+            root,
+            bzlmod_enabled,
+            bazel_version,
             workspace_name: PathBuf::from(info.execution_root)
                 .file_name()
                 .and_then(|name| match name.to_string_lossy().to_string() {
@@ -41,19 +147,34 @@ impl BazelWorkspace {
                     name => Some(name),
                 }),
             external_output_base: PathBuf::from(info.output_base).join("external"),
-            query_output_base: if let Some(output_base) = query_output_base {
-                let hash = digest::digest(
-                    &digest::SHA256,
-                    output_base.as_ref().as_os_str().as_encoded_bytes(),
-                );
-                let hash_hex = hex::encode(&hash);
-                Some(output_base.as_ref().join(hash_hex))
-            } else {
-                None
-            },
+            embedded_tools: info
+                .install_base
+                .map(|install_base| PathBuf::from(install_base).join("embedded_tools")),
+            bazel_bin: info.bazel_bin.map(PathBuf::from),
+            local_repositories: HashMap::new(),
+            declared_repositories: Vec::new(),
+            query_flags,
+            repository_overrides,
+            ignored_directories,
+            query_output_base,
         })
     }
 
+    /// Returns where a generated file is output to, given the path it would have among the
+    /// sources of the main repository or an external repository, e.g.
+    /// `bazel-bin/foo/gen.h` for `foo/gen.h`.
+    pub fn generated_path(&self, source_path: &Path) -> Option<PathBuf> {
+        let bazel_bin = self.bazel_bin.as_ref()?;
+
+        if let Ok(path) = source_path.strip_prefix(&self.root) {
+            Some(bazel_bin.join(path))
+        } else if let Ok(path) = source_path.strip_prefix(&self.external_output_base) {
+            Some(bazel_bin.join("external").join(path))
+        } else {
+            None
+        }
+    }
+
     pub fn get_repository_for_path<'a>(
         &'a self,
         path: &'a Path,
@@ -70,6 +191,19 @@ impl BazelWorkspace {
             })
     }
 
+    /// Finds the repository with local sources that contains a path, see
+    /// [`BazelWorkspace::local_repositories`] and [`BazelWorkspace::repository_overrides`].
+    /// Returns the name it is keyed by and its root.
+    pub fn get_local_repository_for_path(&self, path: &Path) -> Option<(&str, &Path)> {
+        self.local_repositories
+            .iter()
+            .chain(&self.repository_overrides)
+            .filter(|(_, root)| path.starts_with(root))
+            // Repositories may be nested, in which case the innermost one contains the path.
+            .max_by_key(|(_, root)| root.components().count())
+            .map(|(name, root)| (name.as_str(), root.as_path()))
+    }
+
     pub fn get_repository_for_lspurl<'a>(&'a self, url: &'a LspUrl) -> Option<Cow<'a, str>> {
         match url {
             LspUrl::File(path) => self.get_repository_for_path(path).map(|(repo, _)| repo),
@@ -77,8 +211,45 @@ impl BazelWorkspace {
         }
     }
 
+    /// Finds the root directory of a repository, given its canonical name. Repositories that are
+    /// overridden, see [`BazelWorkspace::repository_overrides`], or that have local sources
+    /// resolve to those directories, rather than to the output base.
     pub fn get_repository_path(&self, repository_name: &str) -> PathBuf {
-        self.external_output_base.join(repository_name)
+        // Overrides are keyed by the module name, while repositories are looked up by their
+        // canonical name.
+        let module_name = module_name(repository_name);
+
+        if let Some(path) = self
+            .repository_overrides
+            .get(repository_name)
+            .or_else(|| module_name.and_then(|name| self.repository_overrides.get(name)))
+            .or_else(|| self.local_repositories.get(repository_name))
+            .or_else(|| module_name.and_then(|name| self.local_repositories.get(name)))
+        {
+            return path.clone();
+        }
+
+        let path = self.external_output_base.join(repository_name);
+        match &self.embedded_tools {
+            Some(embedded_tools) if repository_name == "bazel_tools" && !path.exists() => {
+                embedded_tools.clone()
+            }
+            _ => path,
+        }
+    }
+
+    /// Finds the directory of a module that is a dependency of the root module. Its canonical
+    /// repository name depends on the version of Bazel, so whichever of the candidates exists is
+    /// used.
+    pub fn module_path(&self, name: &str, version: &str) -> Option<PathBuf> {
+        [
+            format!("{name}+"),
+            format!("{name}~"),
+            format!("{name}~{version}"),
+        ]
+        .iter()
+        .map(|repository_name| self.get_repository_path(repository_name))
+        .find(|path| path.is_dir())
     }
 
     pub fn get_repository_names(&self) -> Vec<Cow<str>> {
@@ -100,6 +271,170 @@ impl BazelWorkspace {
             }
         }
 
+        for name in self
+            .declared_repositories
+            .iter()
+            .map(String::as_str)
+            .chain(BUILTIN_REPOSITORY_NAMES)
+        {
+            if !names.contains(&Cow::Borrowed(name)) {
+                names.push(Cow::Borrowed(name));
+            }
+        }
+
         names
     }
 }
+
+/// Detects whether Bzlmod is enabled in a workspace. An `--enable_bzlmod` option in the
+/// `.bazelrc` takes precedence over the default of the Bazel version, which enables it since
+/// Bazel 7. If the version isn't known either, a `MODULE.bazel` file means Bzlmod is used.
+fn is_bzlmod_enabled(root: &Path, bazel_version: Option<BazelVersion>) -> bool {
+    bazelrc::bzlmod_enabled(root).unwrap_or_else(|| match bazel_version {
+        Some(version) => version.major >= 7,
+        None => root.join("MODULE.bazel").is_file(),
+    })
+}
+
+/// Returns the name of the module that a repository is for, given its canonical name of the
+/// form `module~version`, or `module+` in newer Bazel versions. Repositories that are created by
+/// module extensions, e.g. `module~~extension~repo` or `module++extension+repo`, aren't the
+/// module itself, so they have no module name.
+fn module_name(canonical_name: &str) -> Option<&str> {
+    let separator = canonical_name.find(|c| c == '~' || c == '+')?;
+    let (module_name, rest) = canonical_name.split_at(separator);
+    let separator = &rest[..1];
+
+    (!rest[1..].contains(separator)).then_some(module_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::client::BazelInfo;
+
+    use super::{BazelVersion, BazelWorkspace};
+
+    #[test]
+    fn test_parsing_bazel_versions() {
+        let version = |major, minor, patch| {
+            Some(BazelVersion {
+                major,
+                minor,
+                patch,
+            })
+        };
+
+        assert_eq!(BazelVersion::parse("release 7.1.0"), version(7, 1, 0));
+        assert_eq!(BazelVersion::parse("8.0.1\n"), version(8, 0, 1));
+        assert_eq!(BazelVersion::parse("8.0.0rc1"), version(8, 0, 0));
+        assert_eq!(BazelVersion::parse("7.x"), version(7, 0, 0));
+        assert_eq!(BazelVersion::parse("fork/6.4.0"), version(6, 4, 0));
+        assert_eq!(BazelVersion::parse("latest"), None);
+    }
+
+    #[test]
+    fn bzlmod_is_enabled_by_default_since_bazel_7() -> anyhow::Result<()> {
+        let workspace = |release: Option<&str>| {
+            BazelWorkspace::from_bazel_info(
+                BazelInfo {
+                    execution_root: "/output_base/execroot/_main".to_owned(),
+                    output_base: "/output_base".to_owned(),
+                    workspace: "/workspace".to_owned(),
+                    release: release.map(str::to_owned),
+                    install_base: None,
+                    bazel_bin: None,
+                },
+                None::<&str>,
+            )
+        };
+
+        assert!(workspace(Some("release 7.1.0"))?.bzlmod_enabled);
+        assert!(!workspace(Some("release 6.5.0"))?.bzlmod_enabled);
+        // Without a version, workspaces without a `MODULE.bazel` use `WORKSPACE`.
+        assert!(!workspace(None)?.bzlmod_enabled);
+
+        Ok(())
+    }
+
+    #[test]
+    fn workspaces_have_their_own_output_bases() -> anyhow::Result<()> {
+        let workspace = |name: &str| {
+            BazelWorkspace::from_bazel_info(
+                BazelInfo {
+                    execution_root: format!("/output_bases/{name}/execroot/_main"),
+                    output_base: format!("/output_bases/{name}"),
+                    workspace: format!("/workspaces/{name}"),
+                    release: None,
+                    install_base: None,
+                    bazel_bin: None,
+                },
+                Some("/tmp/bazel-lsp"),
+            )
+        };
+        let first = workspace("first")?;
+        let second = workspace("second")?;
+
+        // Queries of the workspaces don't share an output base.
+        let first_query_output_base = first.query_output_base.clone().unwrap();
+        let second_query_output_base = second.query_output_base.clone().unwrap();
+        assert_ne!(first_query_output_base, second_query_output_base);
+        assert!(first_query_output_base.starts_with("/tmp/bazel-lsp"));
+        assert!(second_query_output_base.starts_with("/tmp/bazel-lsp"));
+
+        // Repositories are resolved in the output base of their workspace.
+        assert_eq!(
+            first.get_repository_path("rules_foo"),
+            PathBuf::from("/output_bases/first/external/rules_foo")
+        );
+        assert_eq!(
+            second.get_repository_path("rules_foo"),
+            PathBuf::from("/output_bases/second/external/rules_foo")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn overridden_repositories_resolve_to_their_directory() -> anyhow::Result<()> {
+        let mut workspace = BazelWorkspace::from_bazel_info(
+            BazelInfo {
+                execution_root: "/output_base/execroot/_main".to_owned(),
+                output_base: "/output_base".to_owned(),
+                workspace: "/workspace".to_owned(),
+                release: None,
+                install_base: None,
+                bazel_bin: None,
+            },
+            None::<&str>,
+        )?;
+        workspace
+            .repository_overrides
+            .insert("rules_foo".to_owned(), PathBuf::from("/src/rules_foo"));
+
+        assert_eq!(
+            workspace.get_repository_path("rules_foo~1.0.0"),
+            PathBuf::from("/src/rules_foo")
+        );
+        assert_eq!(
+            workspace.get_repository_path("rules_foo+"),
+            PathBuf::from("/src/rules_foo")
+        );
+        assert_eq!(
+            workspace.get_repository_path("rules_bar~1.0.0"),
+            PathBuf::from("/output_base/external/rules_bar~1.0.0")
+        );
+        // Repositories of module extensions aren't the overridden module.
+        assert_eq!(
+            workspace.get_repository_path("rules_foo~~ext~repo"),
+            PathBuf::from("/output_base/external/rules_foo~~ext~repo")
+        );
+        assert_eq!(
+            workspace.get_repository_path("rules_foo++ext+repo"),
+            PathBuf::from("/output_base/external/rules_foo++ext+repo")
+        );
+
+        Ok(())
+    }
+}